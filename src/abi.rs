@@ -0,0 +1,356 @@
+// Contract ABI
+// ============
+//
+// Contracts talk to each other with `CALL`, passing flat constructors of
+// numbers as messages. The ABI of a contract describes which messages it
+// accepts and the shape of its state. It is written as annotations on the
+// comments above the contract's function:
+//
+//   // A simple counter.
+//   // @contract
+//   // @message {Inc}         increments the counter
+//   // @message {Add amount}  adds `amount` to the counter
+//   // @message {Get}         returns the counter
+//   // @state #count
+//   fun (Counter action) { ... }
+//
+// If no `@message` is given, the messages are inferred from the constructors
+// matched by the contract's rules. The ABI can be stored on a JSON sidecar
+// file, and used to generate Rust helpers that build the `run` statements
+// calling the contract.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::Name;
+use crate::constants;
+use crate::format::{lex, Comment, Tok, Token};
+use crate::hvm::{self, Statement, Term};
+use crate::imports;
+
+// Types
+// =====
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Abi {
+  pub contracts: Vec<Contract>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contract {
+  pub name: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub docs: Vec<String>,
+  pub messages: Vec<Message>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub state: Option<String>,
+}
+
+/// A message is a constructor whose fields are all numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+  pub name: String,
+  pub fields: Vec<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub docs: Option<String>,
+}
+
+/// Maximum arity of a constructor.
+const MAX_FIELDS: usize = 16;
+
+// Calls
+// =====
+
+/// Builds a `run` statement that calls `contract` with `message`, returning
+/// its result. The same as `run { ask x = (Call 'contract' message); (Done x) }`.
+pub fn call_statement(contract: Name, message: Term) -> Statement {
+  let call = Name::from_str_unsafe("CALL");
+  let done = Name::from_str_unsafe("DONE");
+  let x = Name::from_str_unsafe("x");
+  let cont = Term::lam(x, Box::new(Term::ctr(done, vec![Term::var(x)])));
+  let expr = Term::ctr(call, vec![Term::num(contract.into()), message, cont]);
+  Statement::Run { expr, nonce: None, until: None, sign: vec![] }
+}
+
+// Extraction
+// ==========
+
+// The comments above each top-level statement, in order.
+fn statement_comments(tokens: &[Token]) -> Vec<&[Comment]> {
+  let mut comments = Vec::new();
+  let mut depth: i64 = 0;
+  for token in tokens {
+    match &token.tok {
+      Tok::Sym("(") | Tok::Sym("{") | Tok::Sym("[") => depth += 1,
+      Tok::Sym(")") | Tok::Sym("}") | Tok::Sym("]") => depth -= 1,
+      Tok::Word(word)
+        if depth == 0
+          && ["fun", "ctr", "run", "reg", "own"].contains(&word.as_str()) =>
+      {
+        comments.push(&token.comments[..]);
+      }
+      _ => {}
+    }
+  }
+  comments
+}
+
+// Parses `{Name field ...} rest`.
+fn read_message(text: &str) -> Option<Message> {
+  let text = text.trim().strip_prefix('{')?;
+  let (head, rest) = text.split_once('}')?;
+  let mut words = head.split_whitespace().map(str::to_string);
+  let name = words.next()?;
+  let docs = Some(rest.trim().to_string()).filter(|docs| !docs.is_empty());
+  Some(Message { name, fields: words.collect(), docs })
+}
+
+// The docs, messages and state of a contract.
+type Annotations = (Vec<String>, Vec<Message>, Option<String>);
+
+// Reads the annotations of a function. Returns `None` if it isn't a contract.
+fn read_annotations(
+  comments: &[Comment],
+) -> Result<Option<Annotations>, String> {
+  let mut contract = false;
+  let mut docs = Vec::new();
+  let mut messages = Vec::new();
+  let mut state = None;
+  for comment in comments {
+    let line = comment.text.trim_start_matches('/').trim();
+    if let Some(rest) = line.strip_prefix("@contract") {
+      contract = true;
+      if !rest.trim().is_empty() {
+        docs.push(rest.trim().to_string());
+      }
+    } else if let Some(rest) = line.strip_prefix("@message") {
+      let message = read_message(rest)
+        .ok_or_else(|| format!("Invalid message annotation: '{}'.", line))?;
+      messages.push(message);
+    } else if let Some(rest) = line.strip_prefix("@state") {
+      state = Some(rest.trim().to_string());
+    } else if !line.is_empty() {
+      docs.push(line.to_string());
+    }
+  }
+  let annotated = contract || !messages.is_empty() || state.is_some();
+  Ok(if annotated { Some((docs, messages, state)) } else { None })
+}
+
+// Infers the messages from the constructors matched by the rules.
+fn infer_messages(
+  func: &hvm::Func,
+  arities: &HashMap<Name, Vec<String>>,
+) -> Vec<Message> {
+  let mut messages: Vec<Message> = Vec::new();
+  for rule in &func.rules {
+    let (name, args) = match &rule.lhs {
+      Term::Fun { args, .. } => match args.first() {
+        Some(Term::Ctr { name, args }) => (name, args),
+        _ => continue,
+      },
+      _ => continue,
+    };
+    if messages.iter().any(|msg| msg.name == name.to_string()) {
+      continue;
+    }
+    let fields = match arities.get(name) {
+      Some(fields) => fields.clone(),
+      None => args
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| match arg {
+          Term::Var { name } if !name.is_none() => name.to_string(),
+          _ => format!("x{}", i),
+        })
+        .collect(),
+    };
+    messages.push(Message { name: name.to_string(), fields, docs: None });
+  }
+  messages
+}
+
+fn check_contract(
+  contract: &Contract,
+  arities: &HashMap<Name, Vec<String>>,
+) -> Result<(), String> {
+  for msg in &contract.messages {
+    let name = Name::from_str(&msg.name).map_err(|err| {
+      format!(
+        "Invalid message name '{}' on '{}': {}",
+        msg.name, contract.name, err
+      )
+    })?;
+    if msg.fields.len() > MAX_FIELDS {
+      return Err(format!(
+        "Message '{}' on '{}' has more than {} fields.",
+        msg.name, contract.name, MAX_FIELDS
+      ));
+    }
+    if let Some(fields) = arities.get(&name) {
+      if fields.len() != msg.fields.len() {
+        return Err(format!(
+          "Message '{}' on '{}' has {} fields, but the constructor has {}.",
+          msg.name,
+          contract.name,
+          msg.fields.len(),
+          fields.len()
+        ));
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Extracts the ABI of the contracts annotated on a code file.
+pub fn extract(code: &str) -> Result<Abi, String> {
+  let (_, statements) = imports::parse_source(code)?;
+  let tokens = lex(code)?;
+  let comments = statement_comments(&tokens);
+
+  // Constructors declared on genesis and on the code, with their fields
+  let genesis = hvm::parse_code(constants::GENESIS_CODE)?;
+  let mut arities: HashMap<Name, Vec<String>> = HashMap::new();
+  for statement in genesis.iter().chain(statements.iter()) {
+    if let Statement::Ctr { name, args, .. } = statement {
+      arities.insert(*name, args.iter().map(|arg| arg.to_string()).collect());
+    }
+  }
+
+  let mut contracts = Vec::new();
+  for (statement, comments) in statements.iter().zip(comments) {
+    if let Statement::Fun { name, args, func, .. } = statement {
+      let (docs, messages, state) = match read_annotations(comments)? {
+        Some(annotations) => annotations,
+        None => continue,
+      };
+      if args.len() != 1 {
+        return Err(format!(
+          "Contract '{}' must take a single argument, the message.",
+          name
+        ));
+      }
+      let messages = if messages.is_empty() {
+        infer_messages(func, &arities)
+      } else {
+        messages
+      };
+      let contract = Contract { name: name.to_string(), docs, messages, state };
+      check_contract(&contract, &arities)?;
+      contracts.push(contract);
+    }
+  }
+  Ok(Abi { contracts })
+}
+
+// Rust bindings
+// =============
+
+const RUST_KEYWORDS: [&str; 38] = [
+  "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else",
+  "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop",
+  "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self",
+  "static", "struct", "super", "trait", "true", "type", "unsafe", "use",
+  "where", "while",
+];
+
+// `Foo.SendTo` becomes `foo_send_to`.
+fn snake_case(name: &str) -> String {
+  let mut snake = String::new();
+  let mut prev_lower = false;
+  for chr in name.chars() {
+    if chr == '.' || chr == '_' {
+      snake.push('_');
+      prev_lower = false;
+    } else if chr.is_ascii_uppercase() {
+      if prev_lower {
+        snake.push('_');
+      }
+      snake.push(chr.to_ascii_lowercase());
+      prev_lower = false;
+    } else {
+      snake.push(chr);
+      prev_lower = true;
+    }
+  }
+  if snake.starts_with(|c: char| c.is_ascii_digit())
+    || RUST_KEYWORDS.contains(&snake.as_str())
+  {
+    snake.push('_');
+  }
+  snake
+}
+
+fn rust_name(name: &str) -> Result<String, String> {
+  let numb = *Name::from_str(name)?;
+  Ok(format!("Name::from_u128_unchecked(0x{:x}) /* {} */", numb, name))
+}
+
+/// Generates a Rust module for each contract, with a function for each
+/// message that builds the `run` statement calling it.
+pub fn rust_bindings(abi: &Abi) -> Result<String, String> {
+  let mut code = String::new();
+  code.push_str("// Generated by `kindelia abi --rust`. Do not edit.\n\n");
+  code.push_str("use kindelia::abi::call_statement;\n");
+  code.push_str("use kindelia::common::{Name, U120};\n");
+  code.push_str("use kindelia::hvm::{Statement, Term};\n");
+  for contract in &abi.contracts {
+    code.push('\n');
+    for doc in &contract.docs {
+      code.push_str(&format!("/// {}\n", doc));
+    }
+    if let Some(state) = &contract.state {
+      if !contract.docs.is_empty() {
+        code.push_str("///\n");
+      }
+      code.push_str(&format!("/// State: `{}`\n", state));
+    }
+    code.push_str(&format!("pub mod {} {{\n", snake_case(&contract.name)));
+    code.push_str("  use super::*;\n\n");
+    code.push_str(&format!(
+      "  pub const CONTRACT: Name = {};\n",
+      rust_name(&contract.name)?
+    ));
+    let mut seen = Vec::new();
+    for msg in &contract.messages {
+      let ident = snake_case(msg.name.rsplit('.').next().unwrap_or(&msg.name));
+      if seen.contains(&ident) {
+        return Err(format!(
+          "Messages on '{}' have the same Rust name '{}'.",
+          contract.name, ident
+        ));
+      }
+      seen.push(ident.clone());
+      let fields: Vec<String> =
+        msg.fields.iter().map(|f| snake_case(f)).collect();
+      let params: Vec<String> =
+        fields.iter().map(|f| format!("{}: U120", f)).collect();
+      let args: Vec<String> =
+        fields.iter().map(|f| format!("Term::num({})", f)).collect();
+      let mut head = vec![msg.name.clone()];
+      head.extend(msg.fields.iter().cloned());
+      code.push('\n');
+      match &msg.docs {
+        Some(docs) => {
+          code.push_str(&format!("  /// `{{{}}}`: {}\n", head.join(" "), docs))
+        }
+        None => code.push_str(&format!("  /// `{{{}}}`\n", head.join(" "))),
+      }
+      code.push_str(&format!(
+        "  pub fn {}({}) -> Statement {{\n",
+        ident,
+        params.join(", ")
+      ));
+      code.push_str(&format!(
+        "    let message = Term::ctr({}, vec![{}]);\n",
+        rust_name(&msg.name)?,
+        args.join(", ")
+      ));
+      code.push_str("    call_statement(CONTRACT, message)\n");
+      code.push_str("  }\n");
+    }
+    code.push_str("}\n");
+  }
+  Ok(code)
+}
@@ -175,6 +175,7 @@ pub struct Stats {
   pub fun_count: u64,
   pub ctr_count: u64,
   pub reg_count: u64,
+  pub state_root: Hash,
 }
 
 impl From<&node::Transaction> for String {
@@ -268,6 +269,8 @@ pub struct BlockInfo {
   pub hash: Hash,
   pub height: u64,
   pub results: Option<Vec<hvm::StatementResult>>,
+  /// State root after the block, if it was computed.
+  pub state_root: Option<Hash>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -0,0 +1,226 @@
+// Kindelia language server
+// ========================
+//
+// Speaks the Language Server Protocol over stdio. The analysis itself lives on
+// `kindelia::lsp`; this binary only translates it to LSP messages.
+//
+// Go-to-definition of genesis names points to a copy of the genesis code,
+// written to the temp directory when the server starts.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::notification::{
+  DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+  Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{
+  Completion, GotoDefinition, HoverRequest, Request as _,
+};
+use lsp_types::{
+  CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams,
+  CompletionResponse, DiagnosticSeverity, GotoDefinitionParams,
+  GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+  HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf,
+  Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+  TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+use kindelia::constants;
+use kindelia::lsp::{Analyzer, DefKind, Document, Origin, Pos, Severity, Span};
+
+type Res<A> = Result<A, Box<dyn Error + Sync + Send>>;
+
+struct Server {
+  conn: Connection,
+  analyzer: Analyzer,
+  genesis_uri: Option<Url>,
+  docs: HashMap<Url, (String, Document)>,
+}
+
+fn to_pos(pos: Position) -> Pos {
+  Pos { line: pos.line, col: pos.character }
+}
+
+fn to_range(span: Span) -> Range {
+  Range {
+    start: Position { line: span.start.line, character: span.start.col },
+    end: Position { line: span.end.line, character: span.end.col },
+  }
+}
+
+fn write_genesis() -> Option<Url> {
+  let path = std::env::temp_dir().join("kindelia-genesis.kdl");
+  std::fs::write(&path, constants::GENESIS_CODE).ok()?;
+  Url::from_file_path(path).ok()
+}
+
+impl Server {
+  fn update(&mut self, uri: Url, text: String) -> Res<()> {
+    let doc = self.analyzer.analyze(&text);
+    let diagnostics = doc
+      .diagnostics
+      .iter()
+      .map(|diag| lsp_types::Diagnostic {
+        range: to_range(diag.span),
+        severity: Some(match diag.severity {
+          Severity::Error => DiagnosticSeverity::ERROR,
+          Severity::Warning => DiagnosticSeverity::WARNING,
+        }),
+        source: Some("kindelia".to_string()),
+        message: diag.message.clone(),
+        ..Default::default()
+      })
+      .collect();
+    let params =
+      PublishDiagnosticsParams { uri: uri.clone(), diagnostics, version: None };
+    let not = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+    self.conn.sender.send(Message::Notification(not))?;
+    self.docs.insert(uri, (text, doc));
+    Ok(())
+  }
+
+  fn handle_notification(&mut self, not: Notification) -> Res<()> {
+    match not.method.as_str() {
+      DidOpenTextDocument::METHOD => {
+        let params: lsp_types::DidOpenTextDocumentParams =
+          serde_json::from_value(not.params)?;
+        self.update(params.text_document.uri, params.text_document.text)?;
+      }
+      DidChangeTextDocument::METHOD => {
+        let params: lsp_types::DidChangeTextDocumentParams =
+          serde_json::from_value(not.params)?;
+        // Full sync: the last change has the whole text.
+        if let Some(change) = params.content_changes.into_iter().last() {
+          self.update(params.text_document.uri, change.text)?;
+        }
+      }
+      DidCloseTextDocument::METHOD => {
+        let params: lsp_types::DidCloseTextDocumentParams =
+          serde_json::from_value(not.params)?;
+        self.docs.remove(&params.text_document.uri);
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  fn handle_request(&mut self, req: Request) -> Res<()> {
+    let result = match req.method.as_str() {
+      GotoDefinition::METHOD => {
+        let params: GotoDefinitionParams = serde_json::from_value(req.params)?;
+        let pos = params.text_document_position_params;
+        let uri = pos.text_document.uri;
+        let def = self.docs.get(&uri).and_then(|(_, doc)| {
+          self.analyzer.definition(doc, to_pos(pos.position))
+        });
+        let location = def.and_then(|def| {
+          let uri = match def.origin {
+            Origin::Document => Some(uri),
+            Origin::Genesis => self.genesis_uri.clone(),
+          };
+          uri.map(|uri| {
+            GotoDefinitionResponse::Scalar(Location {
+              uri,
+              range: to_range(def.span),
+            })
+          })
+        });
+        serde_json::to_value(location)?
+      }
+      HoverRequest::METHOD => {
+        let params: HoverParams = serde_json::from_value(req.params)?;
+        let pos = params.text_document_position_params;
+        let text = self
+          .docs
+          .get(&pos.text_document.uri)
+          .and_then(|(_, doc)| self.analyzer.hover(doc, to_pos(pos.position)));
+        let hover = text.map(|value| Hover {
+          contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+          }),
+          range: None,
+        });
+        serde_json::to_value(hover)?
+      }
+      Completion::METHOD => {
+        let params: CompletionParams = serde_json::from_value(req.params)?;
+        let pos = params.text_document_position;
+        let items = match self.docs.get(&pos.text_document.uri) {
+          Some((text, doc)) => {
+            self.analyzer.completions(doc, text, to_pos(pos.position))
+          }
+          None => vec![],
+        };
+        let items: Vec<CompletionItem> = items
+          .into_iter()
+          .map(|item| CompletionItem {
+            kind: Some(match item.kind {
+              DefKind::Fun => CompletionItemKind::FUNCTION,
+              DefKind::Ctr => CompletionItemKind::CONSTRUCTOR,
+            }),
+            detail: Some(item.detail),
+            // IO constructors come first
+            sort_text: Some(format!(
+              "{}{}",
+              if item.io { 0 } else { 1 },
+              item.label
+            )),
+            label: item.label,
+            ..Default::default()
+          })
+          .collect();
+        serde_json::to_value(CompletionResponse::Array(items))?
+      }
+      _ => serde_json::Value::Null,
+    };
+    let resp = Response { id: req.id, result: Some(result), error: None };
+    self.conn.sender.send(Message::Response(resp))?;
+    Ok(())
+  }
+
+  fn run(&mut self) -> Res<()> {
+    while let Ok(msg) = self.conn.receiver.recv() {
+      match msg {
+        Message::Request(req) => {
+          if self.conn.handle_shutdown(&req)? {
+            return Ok(());
+          }
+          self.handle_request(req)?;
+        }
+        Message::Notification(not) => self.handle_notification(not)?,
+        Message::Response(_) => {}
+      }
+    }
+    Ok(())
+  }
+}
+
+fn main() -> Res<()> {
+  let (conn, io_threads) = Connection::stdio();
+  let capabilities = ServerCapabilities {
+    text_document_sync: Some(TextDocumentSyncCapability::Kind(
+      TextDocumentSyncKind::FULL,
+    )),
+    hover_provider: Some(HoverProviderCapability::Simple(true)),
+    definition_provider: Some(OneOf::Left(true)),
+    completion_provider: Some(CompletionOptions {
+      trigger_characters: Some(vec!["(".to_string(), "{".to_string()]),
+      ..Default::default()
+    }),
+    ..Default::default()
+  };
+  conn.initialize(serde_json::to_value(capabilities)?)?;
+  let mut server = Server {
+    conn,
+    analyzer: Analyzer::new(),
+    genesis_uri: write_genesis(),
+    docs: HashMap::new(),
+  };
+  server.run()?;
+  drop(server);
+  io_threads.join()?;
+  Ok(())
+}
@@ -458,33 +458,185 @@ impl<T: ProtoSerialize> ProtoSerialize for Option<T> {
   }
 }
 
+// Statement tags: the kind of statement on the 2 low bits, plus the flag 4 for
+// statements with many signatures, and the flag 8, which is read by kind:
+// - `fun` (0) never sets it, so the tag 8 is an ownership transfer, added later,
+//   whose new owner is followed by its nonce and optional last tick, as varlens
+// - `run` (2) sets it for runs with replay protection, which are followed by
+//   their optional nonce and last tick, as varlens, after the expression
+// - `reg` (3) sets it for namespaces registered to a multi-signature owner
+// Statements with a single owner and at most one signature keep the original
+// encoding.
+
+const STMT_MANY_SIGNS: u64 = 4;
+const STMT_FLAG: u64 = 8;
+
+fn statement_tag(kind: u64, sign: &[Signature]) -> u64 {
+  if sign.len() > 1 {
+    kind | STMT_MANY_SIGNS
+  } else {
+    kind
+  }
+}
+
+fn serialize_signs(sign: &[Signature], bits: &mut BitVec, names: &mut Names) {
+  if sign.len() > 1 {
+    serialize_list(sign, bits, names);
+  } else {
+    sign.first().cloned().proto_serialize(bits, names);
+  }
+}
+
+fn deserialize_signs(
+  tag: u64,
+  bits: &BitVec,
+  index: &mut usize,
+  names: &mut Names,
+) -> Option<Vec<Signature>> {
+  if tag & STMT_MANY_SIGNS != 0 {
+    let sign: Vec<Signature> = deserialize_list(bits, index, names)?;
+    // a list of less than 2 signatures has a shorter encoding
+    if sign.len() < 2 {
+      return None;
+    }
+    Some(sign)
+  } else {
+    let sign = Option::<Signature>::proto_deserialize(bits, index, names)?;
+    Some(sign.into_iter().collect())
+  }
+}
+
+fn serialize_u120(value: &U120, bits: &mut BitVec) {
+  serialize_fixlen_big(128, &U256::from(**value), bits);
+}
+
+fn deserialize_u120(bits: &BitVec, index: &mut usize) -> Option<U120> {
+  let value = deserialize_fixlen_big(128, bits, index)?.low_u128();
+  value.try_into().ok()
+}
+
+fn serialize_multisig(msig: &Multisig, bits: &mut BitVec) {
+  serialize_varlen(msig.thld as u128, bits);
+  for key in &msig.keys {
+    bits.push(true);
+    serialize_u120(key, bits);
+  }
+  bits.push(false);
+}
+
+fn deserialize_multisig(bits: &BitVec, index: &mut usize) -> Option<Multisig> {
+  let thld = deserialize_varlen(bits, index)?.try_into().ok()?;
+  let mut keys = Vec::new();
+  while bits.get(*index)? {
+    *index += 1;
+    keys.push(deserialize_u120(bits, index)?);
+  }
+  *index += 1;
+  Some(Multisig { thld, keys })
+}
+
+fn serialize_opt_varlen(value: &Option<u64>, bits: &mut BitVec) {
+  match value {
+    None => bits.push(false),
+    Some(value) => {
+      bits.push(true);
+      serialize_varlen(*value as u128, bits);
+    }
+  }
+}
+
+fn deserialize_opt_varlen(
+  bits: &BitVec,
+  index: &mut usize,
+) -> Option<Option<u64>> {
+  let has = bits.get(*index)?;
+  *index += 1;
+  if has {
+    Some(Some(deserialize_varlen(bits, index)?.try_into().ok()?))
+  } else {
+    Some(None)
+  }
+}
+
+// The new owner of a transfer: 0 when renounced, 1 for a subject, 2 for a
+// multisig
+fn serialize_new_owner(ownr: &Option<Owner>, bits: &mut BitVec) {
+  match ownr {
+    None => serialize_fixlen(2, 0, bits),
+    Some(Owner::Subj(subj)) => {
+      serialize_fixlen(2, 1, bits);
+      serialize_u120(subj, bits);
+    }
+    Some(Owner::Msig(msig)) => {
+      serialize_fixlen(2, 2, bits);
+      serialize_multisig(msig, bits);
+    }
+  }
+}
+
+fn deserialize_new_owner(
+  bits: &BitVec,
+  index: &mut usize,
+) -> Option<Option<Owner>> {
+  match deserialize_fixlen(2, bits, index)? {
+    0 => Some(None),
+    1 => Some(Some(Owner::Subj(deserialize_u120(bits, index)?))),
+    2 => Some(Some(Owner::Msig(deserialize_multisig(bits, index)?))),
+    _ => None,
+  }
+}
+
 impl ProtoSerialize for Statement {
   fn proto_serialize(&self, bits: &mut BitVec, names: &mut Names) {
     match self {
       Statement::Fun { name, args, func, init, sign } => {
-        serialize_fixlen(4, 0, bits);
+        serialize_fixlen(4, statement_tag(0, sign), bits);
         name.proto_serialize(bits, names);
         serialize_list(args, bits, names);
         func.proto_serialize(bits, names);
         init.proto_serialize(bits, names);
-        sign.proto_serialize(bits, names);
+        serialize_signs(sign, bits, names);
       }
       Statement::Ctr { name, args, sign } => {
-        serialize_fixlen(4, 1, bits);
+        serialize_fixlen(4, statement_tag(1, sign), bits);
         name.proto_serialize(bits, names);
         serialize_list(args, bits, names);
-        sign.proto_serialize(bits, names);
+        serialize_signs(sign, bits, names);
       }
-      Statement::Run { expr, sign } => {
-        serialize_fixlen(4, 2, bits);
-        expr.proto_serialize(bits, names);
-        sign.proto_serialize(bits, names);
+      Statement::Run { expr, nonce, until, sign } => {
+        if nonce.is_none() && until.is_none() {
+          serialize_fixlen(4, statement_tag(2, sign), bits);
+          expr.proto_serialize(bits, names);
+        } else {
+          serialize_fixlen(4, statement_tag(2, sign) | STMT_FLAG, bits);
+          expr.proto_serialize(bits, names);
+          serialize_opt_varlen(nonce, bits);
+          serialize_opt_varlen(until, bits);
+        }
+        serialize_signs(sign, bits, names);
       }
       Statement::Reg { name, ownr, sign } => {
-        serialize_fixlen(4, 3, bits);
+        match ownr {
+          Owner::Subj(subj) => {
+            serialize_fixlen(4, statement_tag(3, sign), bits);
+            name.proto_serialize(bits, names);
+            serialize_u120(subj, bits);
+          }
+          Owner::Msig(msig) => {
+            serialize_fixlen(4, statement_tag(3, sign) | STMT_FLAG, bits);
+            name.proto_serialize(bits, names);
+            serialize_multisig(msig, bits);
+          }
+        }
+        serialize_signs(sign, bits, names);
+      }
+      Statement::Own { name, ownr, nonce, until, sign } => {
+        serialize_fixlen(4, statement_tag(STMT_FLAG, sign), bits);
         name.proto_serialize(bits, names);
-        serialize_fixlen_big(128, &U256::from(**ownr), bits);
-        sign.proto_serialize(bits, names);
+        serialize_new_owner(ownr, bits);
+        serialize_varlen(*nonce as u128, bits);
+        serialize_opt_varlen(until, bits);
+        serialize_signs(sign, bits, names);
       }
     }
   }
@@ -495,66 +647,77 @@ impl ProtoSerialize for Statement {
     names: &mut Names,
   ) -> Option<Self> {
     let tag = deserialize_fixlen(4, bits, index)?;
-    match tag {
-      0 => {
+    let flag = tag & STMT_FLAG != 0;
+    match (tag & !STMT_MANY_SIGNS & !STMT_FLAG, flag) {
+      (0, false) => {
         let name = Name::proto_deserialize(bits, index, names)?;
         let args = deserialize_list(bits, index, names)?;
         let func = Func::proto_deserialize(bits, index, names)?;
         let init = Option::<Term>::proto_deserialize(bits, index, names)?;
-        let sign = Option::<Signature>::proto_deserialize(bits, index, names)?;
+        let sign = deserialize_signs(tag, bits, index, names)?;
         Some(Statement::Fun { name, args, func, init, sign })
       }
-      1 => {
+      (1, false) => {
         let name = Name::proto_deserialize(bits, index, names)?;
         let args = deserialize_list(bits, index, names)?;
-        let sign = Option::proto_deserialize(bits, index, names)?;
+        let sign = deserialize_signs(tag, bits, index, names)?;
         Some(Statement::Ctr { name, args, sign })
       }
-      2 => {
+      (2, false) => {
         let expr = Term::proto_deserialize(bits, index, names)?;
-        let sign = Option::proto_deserialize(bits, index, names)?;
-        Some(Statement::Run { expr, sign })
+        let sign = deserialize_signs(tag, bits, index, names)?;
+        Some(Statement::Run { expr, nonce: None, until: None, sign })
       }
-      3 => {
+      (2, true) => {
+        let expr = Term::proto_deserialize(bits, index, names)?;
+        let nonce = deserialize_opt_varlen(bits, index)?;
+        let until = deserialize_opt_varlen(bits, index)?;
+        // runs without replay protection have a shorter encoding
+        if nonce.is_none() && until.is_none() {
+          return None;
+        }
+        let sign = deserialize_signs(tag, bits, index, names)?;
+        Some(Statement::Run { expr, nonce, until, sign })
+      }
+      (3, false) => {
         let name = Name::proto_deserialize(bits, index, names)?;
-        let ownr = deserialize_fixlen_big(128, bits, index)?.low_u128();
-        let ownr: U120 = ownr.try_into().ok()?;
-        let sign = Option::proto_deserialize(bits, index, names)?;
+        let ownr = Owner::Subj(deserialize_u120(bits, index)?);
+        let sign = deserialize_signs(tag, bits, index, names)?;
         Some(Statement::Reg { name, ownr, sign })
       }
+      (3, true) => {
+        let name = Name::proto_deserialize(bits, index, names)?;
+        let ownr = Owner::Msig(deserialize_multisig(bits, index)?);
+        let sign = deserialize_signs(tag, bits, index, names)?;
+        Some(Statement::Reg { name, ownr, sign })
+      }
+      (0, true) => {
+        let name = Name::proto_deserialize(bits, index, names)?;
+        let ownr = deserialize_new_owner(bits, index)?;
+        let nonce = deserialize_varlen(bits, index)?.try_into().ok()?;
+        let until = deserialize_opt_varlen(bits, index)?;
+        let sign = deserialize_signs(tag, bits, index, names)?;
+        Some(Statement::Own { name, ownr, nonce, until, sign })
+      }
       _ => None,
     }
   }
 }
 
-impl ProtoSerialize for Option<Signature> {
+// The recovery id, on the first byte, also tells if the signature was made
+// with the Ethereum signed message prefix.
+impl ProtoSerialize for Signature {
   fn proto_serialize(&self, bits: &mut BitVec, _names: &mut Names) {
-    if let Some(sign) = self {
-      serialize_fixlen(1, 1, bits);
-      serialize_bytes(65, &sign.0, bits);
-    } else {
-      serialize_fixlen(1, 0, bits);
-    }
+    serialize_bytes(65, &self.0, bits);
   }
 
-  // The double Option layer keeps it consistent, since the returned value IS an Option
   fn proto_deserialize(
     bits: &BitVec,
     index: &mut usize,
     _names: &mut Names,
   ) -> Option<Self> {
-    match deserialize_fixlen(1, bits, index)? {
-      1 => {
-        let data: Option<[u8; 65]> =
-          deserialize_bytes(65, bits, index)?.try_into().ok();
-        if let Some(data) = data {
-          Some(Some(Signature(data)))
-        } else {
-          None
-        }
-      }
-      _ => Some(None),
-    }
+    let data = deserialize_bytes(65, bits, index)?.try_into().ok()?;
+    Some(Signature(data))
   }
 }
 
@@ -687,6 +850,28 @@ impl<A: ProtoAddr> ProtoSerialize for Message<A> {
           serialize_bytes(tx_len as u128, tx, bits);
         }
       }
+      Message::Hello {
+        magic,
+        version,
+        capabilities,
+        height,
+        genesis,
+        challenge,
+      } => {
+        serialize_fixlen(32, *magic as u64, bits);
+        serialize_fixlen(4, 3, bits);
+        serialize_fixlen(32, *version as u64, bits);
+        serialize_fixlen(64, *capabilities, bits);
+        serialize_varlen(*height, bits);
+        genesis.proto_serialize(bits, names);
+        serialize_fixlen(64, *challenge, bits);
+      }
+      Message::Observed { magic, address, challenge } => {
+        serialize_fixlen(32, *magic as u64, bits);
+        serialize_fixlen(4, 4, bits);
+        address.proto_serialize(bits, names);
+        serialize_fixlen(64, *challenge, bits);
+      }
     }
   }
   fn proto_deserialize(
@@ -715,6 +900,26 @@ impl<A: ProtoAddr> ProtoSerialize for Message<A> {
           tx: Transaction::new(data),
         })
       }
+      3 => {
+        let version = deserialize_fixlen(32, bits, index)? as u32;
+        let capabilities = deserialize_fixlen(64, bits, index)?;
+        let height = deserialize_varlen(bits, index)?;
+        let genesis = Hash::proto_deserialize(bits, index, names)?;
+        let challenge = deserialize_fixlen(64, bits, index)?;
+        Some(Message::Hello {
+          magic,
+          version,
+          capabilities,
+          height,
+          genesis,
+          challenge,
+        })
+      }
+      4 => {
+        let address = A::proto_deserialize(bits, index, names)?;
+        let challenge = deserialize_fixlen(64, bits, index)?;
+        Some(Message::Observed { magic, address, challenge })
+      }
       _ => None,
     }
   }
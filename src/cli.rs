@@ -1,6 +1,7 @@
+use std::collections::HashSet;
 use std::fmt;
 use std::io::Read;
-use std::net::UdpSocket;
+use std::net::{TcpListener, UdpSocket};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread;
@@ -9,13 +10,21 @@ use std::time::Duration;
 use clap::{Parser, Subcommand};
 use warp::Future;
 
+use kindelia::abi;
 use kindelia::api::{client as api_client, Hash, HexStatement};
 use kindelia::bits::ProtoSerialize;
-use kindelia::common::Name;
+use kindelia::common::{Name, U120};
 use kindelia::crypto;
+use kindelia::deploy;
 use kindelia::hvm::{self, view_statement, Statement};
-use kindelia::net;
+use kindelia::imports;
+use kindelia::keystore::{self, KeyFile, Keystore};
+use kindelia::nat;
+use kindelia::net::{self, PacketComm, ProtoComm};
 use kindelia::node;
+use kindelia::offline::{self, Batch};
+use kindelia::secure;
+use kindelia::tcp::TcpComm;
 use kindelia::util::bytes_to_bitvec;
 use kindelia::{config, events};
 
@@ -29,6 +38,14 @@ type NC = UdpSocket;
 
 kindelia test file.kdl
 
+// files can start with `import "other.kdl"` lines, on all commands below
+
+kindelia fmt file.kdl
+kindelia fmt --check file.kdl
+
+kindelia abi contract.kdl > contract.abi.json
+kindelia abi contract.abi.json --rust > contract.rs
+
 kindelia serialize code.kdl > code.hex.txt
 
 kindelia deserialize code.hex.txt
@@ -40,6 +57,10 @@ kindelia publish    --hex <<< a67bd36d75da
 kindelia sign code.hex.txt
 kindelia sign <<< a67bd36d75da > code.sig.hex.tx
 
+// multi-signature owners: each owner signs, offline, then
+kindelia sign --add code.sig.kdl --key bob > code.sig2.kdl
+kindelia combine code.sig.kdl code.sig2.kdl > code.signed.kdl
+
 kindelia completion zsh >> .zshrc
 
 == Remote ==
@@ -71,15 +92,40 @@ kindelia get stats reg-count
 
 kindelia [--api ""] run-remote  code.hex.txt
 kindelia [--api ""] publish     code.hex.txt
+kindelia [--api ""] deploy      project.kdl [--dry-run]
 
 == Node ==
 
 kindelia node start --mine --local --log-events --nice-ui?
 kindelia node clean [-f]       // asks confirmation
 
-== Accounts ==
+== Keys ==
+
+kindelia key new    alice
+kindelia key import alice secret.txt      // hex secret key, or keystore file
+kindelia key export alice [--secret]
+kindelia key list
+kindelia key show   alice
+
+kindelia sign    code.kdl --key alice
+kindelia publish code.kdl --key alice
 
-kindelia account ...
+// Ethereum wallets: signatures with the Ethereum signed message prefix
+kindelia sign code.kdl --key alice --eth-prefix
+kindelia sign code.kdl --eth-signature 0x<r ++ s ++ v>   // of personal_sign
+
+== Offline signing ==
+
+kindelia tx export  code.kdl > batch.json
+kindelia tx sign    batch.json --key alice [--add] > signed.json  // offline
+kindelia tx show    signed.json [-E]
+kindelia tx publish signed.json
+
+== Namespaces ==
+
+kindelia reg transfer Foo.Bar '#x123' --key alice > transfer.kdl
+kindelia reg transfer Foo.Bar '#2 of #x123 #x456' --key alice
+kindelia reg renounce Foo.Bar --key alice
 
 */
 
@@ -124,6 +170,25 @@ pub enum CliCommand {
     #[clap(long)]
     sudo: bool,
   },
+  /// Format a Kindelia code file (.kdl), preserving its comments.
+  Fmt {
+    /// The path to the file to format.
+    file: FileInput,
+    /// Only check if the file is formatted, failing if it is not.
+    #[clap(long)]
+    check: bool,
+    /// Overwrite the file with the formatted code, instead of printing it.
+    #[clap(long, short = 'w')]
+    write: bool,
+  },
+  /// Extract the ABI of the contracts on a code file, from its annotations.
+  Abi {
+    /// The code file (.kdl), or an ABI file (.json).
+    file: FileInput,
+    /// Generate Rust helpers that build the statements calling the contracts.
+    #[clap(long)]
+    rust: bool,
+  },
   /// Serialize a code file.
   Serialize {
     /// The path to the file to serialize.
@@ -144,8 +209,39 @@ pub enum CliCommand {
     /// The path to the file to sign.
     file: FileInput,
     /// File containing the 256-bit secret key, as a hex string
-    #[clap(long, short = 's')]
-    secret_file: PathBuf,
+    #[clap(
+      long,
+      short = 's',
+      required_unless_present_any = &["key", "eth-signature"]
+    )]
+    secret_file: Option<PathBuf>,
+    /// Alias of the keystore key to sign with, instead.
+    #[clap(long, short = 'k', conflicts_with = "secret-file")]
+    key: Option<String>,
+    /// Add a signature to already signed statements, for multi-signature
+    /// owners.
+    #[clap(long, short = 'a')]
+    add: bool,
+    /// Sign with the Ethereum signed message prefix, as Ethereum wallets do
+    /// on `personal_sign`.
+    #[clap(long)]
+    eth_prefix: bool,
+    /// Attach a signature made by an Ethereum wallet, with `personal_sign` of
+    /// the statement hash, instead of signing. As the `r ++ s ++ v` hex the
+    /// wallet returns.
+    #[clap(long, conflicts_with_all = &["secret-file", "key", "eth-prefix"])]
+    eth_signature: Option<String>,
+    #[clap(long, short = 'e')]
+    encoded: bool,
+    #[clap(long, short = 'E')]
+    encoded_output: bool,
+  },
+  /// Combine the signatures of copies of the same statements, signed
+  /// separately.
+  Combine {
+    /// The signed code files, with the same statements in the same order.
+    #[clap(required = true, min_values = 2)]
+    files: Vec<FileInput>,
     #[clap(long, short = 'e')]
     encoded: bool,
     #[clap(long, short = 'E')]
@@ -166,6 +262,21 @@ pub enum CliCommand {
     /// In case the input code is serialized.
     #[clap(long, short = 'e')]
     encoded: bool,
+    /// Alias of the keystore key to sign the statements with.
+    #[clap(long, short = 'k')]
+    key: Option<String>,
+  },
+  /// Deploy a Kindelia code file (.kdl), publishing its statements in
+  /// dependency order, in batches that fit in a block.
+  Deploy {
+    /// The path to the file to deploy.
+    file: FileInput,
+    /// Only show the deploy plan, without publishing it.
+    #[clap(long)]
+    dry_run: bool,
+    /// Seconds to wait for each batch to be included in a block.
+    #[clap(long, default_value = "300")]
+    timeout: u64,
   },
   // Post a (serialized) statement
   Post {
@@ -183,6 +294,24 @@ pub enum CliCommand {
   },
   /// Initialize the configuration file.
   Init,
+  /// Manage the password-encrypted keys of the keystore.
+  Key {
+    /// Which command run.
+    #[clap(subcommand)]
+    command: KeyCommand,
+  },
+  /// Sign batches of statements on an offline machine.
+  Tx {
+    /// Which command run.
+    #[clap(subcommand)]
+    command: TxCommand,
+  },
+  /// Transfer or renounce the ownership of registered namespaces.
+  Reg {
+    /// Which command run.
+    #[clap(subcommand)]
+    command: RegCommand,
+  },
   /// Node commands.
   Node {
     /// Which command run.
@@ -204,6 +333,104 @@ pub enum CliCommand {
   },
 }
 
+#[derive(Subcommand)]
+pub enum TxCommand {
+  /// Exports the statements of a code file to a batch file, unsigned, to be
+  /// signed offline.
+  Export {
+    /// The code file to export.
+    file: FileInput,
+    /// In case the input code is serialized.
+    #[clap(long, short = 'e')]
+    encoded: bool,
+  },
+  /// Signs all statements of a batch file, printing the signed batch.
+  Sign {
+    /// The batch file to sign.
+    file: FileInput,
+    /// File containing the 256-bit secret key, as a hex string
+    #[clap(long, short = 's', required_unless_present = "key")]
+    secret_file: Option<PathBuf>,
+    /// Alias of the keystore key to sign with, instead.
+    #[clap(long, short = 'k', conflicts_with = "secret-file")]
+    key: Option<String>,
+    /// Add a signature to already signed statements, for multi-signature
+    /// owners.
+    #[clap(long, short = 'a')]
+    add: bool,
+  },
+  /// Shows the statements of a batch file, with their signers.
+  Show {
+    /// The batch file to show.
+    file: FileInput,
+    /// Print the serialized statements, as `post` takes them, instead.
+    #[clap(long, short = 'E')]
+    encoded_output: bool,
+  },
+  /// Publishes the statements of a signed batch file.
+  Publish {
+    /// The batch file to publish.
+    file: FileInput,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum RegCommand {
+  /// Builds the statement transferring a namespace to a new owner, signed by
+  /// its current owner. Multi-signature owners sign it with `sign --add`.
+  Transfer {
+    /// The namespace to transfer.
+    name: Name,
+    /// The new owner: a subject, as `#x123` or `'Name'`, or a
+    /// multi-signature owner, as `#2 of #x123 #x456`.
+    owner: String,
+    #[clap(flatten)]
+    guard: Guard,
+    #[clap(flatten)]
+    signer: Signer,
+  },
+  /// Builds the statement renouncing a namespace, after which no one can
+  /// deploy on it.
+  Renounce {
+    /// The namespace to renounce.
+    name: Name,
+    #[clap(flatten)]
+    guard: Guard,
+    #[clap(flatten)]
+    signer: Signer,
+  },
+}
+
+/// The replay protection of a transfer.
+#[derive(clap::Args)]
+pub struct Guard {
+  /// The next nonce of the current owner, which the transfer consumes.
+  #[clap(long)]
+  nonce: u64,
+  /// The last tick the transfer can run on.
+  #[clap(long)]
+  until: Option<u64>,
+}
+
+impl Guard {
+  fn view(&self) -> String {
+    let until = self.until.map(|until| format!(" until #{}", until));
+    format!(" nonce #{}{}", self.nonce, until.unwrap_or_default())
+  }
+}
+
+#[derive(clap::Args)]
+pub struct Signer {
+  /// File containing the 256-bit secret key to sign with, as a hex string.
+  #[clap(long, short = 's')]
+  secret_file: Option<PathBuf>,
+  /// Alias of the keystore key to sign with, instead.
+  #[clap(long, short = 'k', conflicts_with = "secret-file")]
+  key: Option<String>,
+  #[clap(long, short = 'E')]
+  encoded_output: bool,
+}
+
 #[derive(Subcommand)]
 pub enum NodeCommand {
   /// Clean the node's data.
@@ -222,6 +449,106 @@ pub enum NodeCommand {
     /// Log events as JSON
     #[clap(long, short)]
     json: bool,
+    /// Check the runtime invariants after each block. Slow, for debugging.
+    #[clap(long)]
+    check_invariants: bool,
+    /// Authenticate and encrypt the traffic with peers, which must use it
+    /// too. Peers can be pinned to a key as `<public key hex>@<address>`.
+    #[clap(long)]
+    secure: bool,
+    /// Transport to talk to peers: `udp` or `tcp`. All peers must use the
+    /// same one.
+    #[clap(long)]
+    transport: Option<net::Transport>,
+    /// Address where peers can reach this node, shared with them. By
+    /// default, it is the one the peers see.
+    #[clap(long)]
+    advertise_address: Option<String>,
+    /// Map the node's port on the router, with UPnP or NAT-PMP.
+    #[clap(long)]
+    port_mapping: bool,
+  },
+  /// Checks the invariants of the node's saved runtime state, to debug
+  /// corruptions.
+  CheckState {
+    /// Tick of the saved state to check. Defaults to the latest saved state.
+    #[clap(long)]
+    tick: Option<u64>,
+  },
+  /// Shows the functions, constructors, namespace owners and stored states
+  /// that differ between two saved states of the node, or between a saved
+  /// state and a remote node.
+  StateDiff {
+    /// Tick of the saved state to compare from. Defaults to the latest saved
+    /// state.
+    #[clap(long)]
+    from: Option<u64>,
+    /// Tick of the saved state to compare to. Defaults to the latest saved
+    /// state.
+    #[clap(long, conflicts_with = "remote")]
+    to: Option<u64>,
+    /// Compare to the node serving the API on this url, instead. Only the
+    /// functions listed by its `/functions`, and their states, are compared.
+    #[clap(long)]
+    remote: Option<String>,
+  },
+  /// Exports or imports a snapshot of the node's state.
+  Snapshot {
+    #[clap(subcommand)]
+    command: SnapshotCommand,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotCommand {
+  /// Exports the saved runtime state, and the blocks up to it, to a file.
+  Export {
+    /// File to write the snapshot to.
+    file: PathBuf,
+    /// Tick of the state to export. Defaults to the latest saved state.
+    #[clap(long)]
+    tick: Option<u64>,
+  },
+  /// Imports a snapshot on a node without blocks, which starts from its state.
+  Import {
+    /// Snapshot file.
+    file: PathBuf,
+    /// Hash of the snapshot's state, in hex, as printed by `export`. Get it
+    /// from a source you trust: the snapshot is refused if it doesn't match.
+    #[clap(long)]
+    state_hash: String,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommand {
+  /// Generates a new key, saved with `alias`.
+  New {
+    /// Alias to save the key with.
+    alias: String,
+  },
+  /// Imports a key from a file with its 256-bit secret key, as a hex string,
+  /// or from an Ethereum keystore (v3) file.
+  Import {
+    /// Alias to save the key with.
+    alias: String,
+    /// The file to import.
+    file: FileInput,
+  },
+  /// Prints the keystore (v3) file of a key, or its secret key.
+  Export {
+    /// Alias of the key.
+    alias: String,
+    /// Print the secret key, as a hex string, instead.
+    #[clap(long)]
+    secret: bool,
+  },
+  /// Lists the keys, with their addresses and names.
+  List,
+  /// Shows the address and name of a key.
+  Show {
+    /// Alias of the key.
+    alias: String,
   },
 }
 
@@ -319,6 +646,8 @@ pub enum GetStatsKind {
   CtrCount,
   /// Get the number of namespaces.
   RegCount,
+  /// Get the state root.
+  StateRoot,
 }
 
 // Config Resolution
@@ -366,8 +695,11 @@ where
       return T::arg_from(env_value);
     }
     if let (Some(prop_path), Some(config_values)) = (self.prop, config_values) {
-      // If config file and argument prop path are set, read from config file
-      return Self::resolve_from_config_aux(config_values, prop_path);
+      // If config file and argument prop path are set, read from config file,
+      // when the prop is there
+      if Self::get_prop(config_values, prop_path).is_some() {
+        return Self::resolve_from_config_aux(config_values, prop_path);
+      }
     }
     (self.default_value)()
   }
@@ -382,10 +714,13 @@ where
     T: ArgumentFrom<toml::Value>,
   {
     if let Some(prop_path) = self.prop {
-      if let Some(config_values) = config_values {
-        Self::resolve_from_config_aux(config_values, prop_path)
-      } else {
-        (self.default_value)()
+      match config_values {
+        Some(config_values)
+          if Self::get_prop(config_values, prop_path).is_some() =>
+        {
+          Self::resolve_from_config_aux(config_values, prop_path)
+        }
+        _ => (self.default_value)(),
       }
     } else {
       panic!("Cannot resolve from config file config without 'prop' field set")
@@ -494,6 +829,14 @@ pub fn run_cli() -> Result<(), String> {
   }
   .resolve(parsed.config, None)?;
 
+  let keystore_path = ConfigSettings {
+    env: Some("KINDELIA_KEYSTORE_DIR"),
+    prop: None,
+    default_value: || Ok(default_kindelia_path()?.join("keystore")),
+  }
+  .resolve(None, None)?;
+  let keystore = Keystore::new(&keystore_path);
+
   let api_url = ConfigSettings {
     env: Some("KINDELIA_API_URL"),
     prop: None,
@@ -503,13 +846,15 @@ pub fn run_cli() -> Result<(), String> {
 
   match parsed.command {
     CliCommand::Test { file, sudo } => {
-      let code: String = file.read_to_string()?;
-      test_code(&code, sudo);
+      let stmts = load_code(&file, false)?;
+      test_code(&stmts, sudo);
       Ok(())
     }
+    CliCommand::Fmt { file, check, write } => fmt_code(&file, check, write),
+    CliCommand::Abi { file, rust } => abi_code(&file, rust),
     CliCommand::Serialize { file } => {
-      let code: String = file.read_to_string()?;
-      serialize_code(&code);
+      let stmts = load_code(&file, false)?;
+      serialize_code(&stmts);
       Ok(())
     }
     CliCommand::Deserialize { file } => {
@@ -517,53 +862,87 @@ pub fn run_cli() -> Result<(), String> {
       deserialize_code(&code)
     }
     CliCommand::Unserialize { stmt } => deserialize_code(&stmt),
-    CliCommand::Sign { file, secret_file, encoded, encoded_output } => {
-      let skey: String = arg_from_file_or_stdin(secret_file.into())?;
-      let skey = skey.trim();
-      let skey = hex::decode(skey).map_err(|err| {
-        format!("Secret key should be valid hex string: {}", err)
-      })?;
-      let skey: [u8; 32] = skey
-        .try_into()
-        .map_err(|_| "Secret key should have exactly 64 bytes".to_string())?;
-      let code = load_code(file, encoded)?;
-      let statement = match &code[..] {
-        [stmt] => sign_code(stmt, &skey),
-        _ => Err("Input file should contain exactly one statement".to_string()),
-      }?;
-      if encoded_output {
-        println!("{}", hex::encode(statement.proto_serialized().to_bytes()));
-      } else {
-        println!("{}", view_statement(&statement));
-      };
+    CliCommand::Sign {
+      file,
+      secret_file,
+      key,
+      add,
+      eth_prefix,
+      eth_signature,
+      encoded,
+      encoded_output,
+    } => {
+      let stmts = load_code(&file, encoded)?;
+      if stmts.is_empty() {
+        return Err("Input file should contain a statement".to_string());
+      }
+      if let Some(eth_signature) = eth_signature {
+        let stmt = match &stmts[..] {
+          [stmt] => stmt,
+          _ => return Err("Wallet signatures sign a single statement.".into()),
+        };
+        let bytes = hex::decode(eth_signature.trim_start_matches("0x"))
+          .map_err(|err| format!("Invalid signature hex: {}", err))?;
+        let sign = crypto::Signature::from_eth_rsv(&bytes)
+          .ok_or("Invalid Ethereum signature.")?;
+        let statement = attach_sign(stmt, sign, add)?;
+        print_statement(&statement, encoded_output);
+        return Ok(());
+      }
+      let skey = signing_key(&keystore, secret_file, key)?
+        .expect("clap requires a secret key");
+      let user = crypto::Account::from_private_key(&skey);
+      for stmt in &stmts {
+        let hash = hvm::hash_statement(stmt);
+        let sign =
+          if eth_prefix { user.sign_eth(&hash) } else { user.sign(&hash) };
+        let statement = attach_sign(stmt, sign, add)?;
+        print_statement(&statement, encoded_output);
+      }
+      Ok(())
+    }
+    CliCommand::Combine { files, encoded, encoded_output } => {
+      let codes = files
+        .iter()
+        .map(|file| load_code(file, encoded))
+        .collect::<Result<Vec<_>, _>>()?;
+      for statement in combine_signs(&codes)? {
+        print_statement(&statement, encoded_output);
+      }
       Ok(())
     }
     CliCommand::RunRemote { file, encoded } => {
       // TODO: client timeout
-      let code = file.read_to_string()?;
       let f = |client: api_client::ApiClient, stmts| async move {
         client.run_code(stmts).await
       };
-      let stmts = if encoded {
-        statements_from_hex_seq(&code)?
-      } else {
-        hvm::parse_code(&code)?
-      };
+      let stmts = load_code(&file, encoded)?;
+      let stmts = if encoded { stmts } else { skip_deployed(&api_url, stmts)? };
       let results = run_on_remote(&api_url, stmts, f)?;
       for result in results {
         println!("{}", result);
       }
       Ok(())
     }
-    CliCommand::Publish { file, encoded } => {
-      let code = file.read_to_string()?;
-      let stmts = if encoded {
-        statements_from_hex_seq(&code)?
-      } else {
-        hvm::parse_code(&code)?
+    CliCommand::Publish { file, encoded, key } => {
+      let stmts = load_code(&file, encoded)?;
+      let stmts = if encoded { stmts } else { skip_deployed(&api_url, stmts)? };
+      let stmts = match key {
+        Some(alias) => {
+          let skey = unlock_key(&keystore, &alias)?;
+          stmts
+            .iter()
+            .map(|stmt| sign_code(stmt, &skey))
+            .collect::<Result<_, _>>()?
+        }
+        None => stmts,
       };
       publish_code(&api_url, stmts)
     }
+    CliCommand::Deploy { file, dry_run, timeout } => {
+      let stmts = load_code(&file, false)?;
+      deploy_code(&api_url, stmts, dry_run, Duration::from_secs(timeout))
+    }
     CliCommand::Post { stmt } => {
       let stmts = statements_from_hex_seq(&stmt)?;
       publish_code(&api_url, stmts)
@@ -572,6 +951,9 @@ pub fn run_cli() -> Result<(), String> {
       let prom = get_info(kind, json, &api_url);
       run_async_blocking(prom)
     }
+    CliCommand::Key { command } => key_command(&keystore, command),
+    CliCommand::Tx { command } => tx_command(&keystore, &api_url, command),
+    CliCommand::Reg { command } => reg_command(&keystore, command),
     CliCommand::Init => {
       let path = default_config_path()?;
       eprintln!("Writing default configuration to '{}'...", path.display());
@@ -613,7 +995,17 @@ pub fn run_cli() -> Result<(), String> {
           }
           Ok(())
         }
-        NodeCommand::Start { initial_peers, network_id, mine, json } => {
+        NodeCommand::Start {
+          initial_peers,
+          network_id,
+          mine,
+          json,
+          check_invariants,
+          secure,
+          transport,
+          advertise_address,
+          port_mapping,
+        } => {
           // TODO: refactor config resolution out of command handling (how?)
 
           // Get arguments from cli, env or config
@@ -642,6 +1034,48 @@ pub fn run_cli() -> Result<(), String> {
             cfg = config,
           );
 
+          let check_invariants = resolve_cfg!(
+            env = "KINDELIA_CHECK_INVARIANTS",
+            prop = "node.debug.check_invariants",
+            default = false,
+            val = flag_to_option(check_invariants),
+            cfg = config,
+          );
+
+          let secure = resolve_cfg!(
+            env = "KINDELIA_SECURE",
+            prop = "node.network.secure",
+            default = false,
+            val = flag_to_option(secure),
+            cfg = config,
+          );
+
+          let transport = resolve_cfg!(
+            env = "KINDELIA_TRANSPORT",
+            prop = "node.network.transport",
+            default = net::Transport::Udp,
+            val = transport,
+            cfg = config,
+          );
+
+          let advertise_address = ConfigSettingsBuilder::default()
+            .env("KINDELIA_ADVERTISE_ADDRESS")
+            .prop("node.network.advertise_address")
+            .default_value(|| Ok(None))
+            .build()
+            .unwrap()
+            .resolve(advertise_address.map(Some), config)?
+            .map(|addr: String| net::parse_address(&addr));
+
+          let port_mapping = resolve_cfg!(
+            env = "KINDELIA_PORT_MAPPING",
+            prop = "node.network.port_mapping",
+            default = false,
+            val = flag_to_option(port_mapping),
+            cfg = config,
+          );
+          let port_mapping = port_mapping.then_some(transport);
+
           let slow_mining = ConfigSettingsBuilder::default()
             .env("KINDELIA_SLOW_MINING")
             .prop("node.debug.slow_mining")
@@ -657,12 +1091,20 @@ pub fn run_cli() -> Result<(), String> {
             .unwrap()
             .resolve_from_file_only(config)?;
 
+          let rollback_config = ConfigSettingsBuilder::default()
+            .prop("node.rollback")
+            .default_value(|| Ok(config::RollbackConfig::default()))
+            .build()
+            .unwrap()
+            .resolve_from_file_only(config)?;
+          rollback_config.validate()?;
+
           // Start
-          let node_comm = init_socket().expect("Could not open a UDP socket");
           let initial_peers = initial_peers
             .iter()
-            .map(|x| net::parse_address(x))
-            .collect::<Vec<_>>();
+            .map(|x| secure::parse_peer(x, net::parse_address))
+            .collect::<Result<Vec<_>, _>>()?;
+          let node_key_path = data_path.join("node.key");
 
           let node_cfg = config::NodeConfig {
             network_id,
@@ -674,12 +1116,109 @@ pub fn run_cli() -> Result<(), String> {
             }),
             api: Some(api_config),
             ws: None, // TODO: load from config file
+            rollback: rollback_config,
+            check_invariants,
           };
 
-          node::start(node_cfg, node_comm, initial_peers);
+          let node_key = if secure {
+            Some(secure::load_or_create_key(&node_key_path)?)
+          } else {
+            None
+          };
+          match transport {
+            net::Transport::Udp => {
+              let node_comm =
+                init_socket().expect("Could not open a UDP socket");
+              start_node(
+                node_cfg,
+                node_comm,
+                initial_peers,
+                node_key,
+                advertise_address,
+                port_mapping,
+              );
+            }
+            net::Transport::Tcp => {
+              let node_comm =
+                init_listener().expect("Could not open a TCP socket");
+              start_node(
+                node_cfg,
+                node_comm,
+                initial_peers,
+                node_key,
+                advertise_address,
+                port_mapping,
+              );
+            }
+          }
 
           Ok(())
         }
+        NodeCommand::CheckState { tick } => {
+          let (tick, errors) = node::check_saved_state(&data_path, tick)?;
+          if errors.is_empty() {
+            println!("The saved state at tick {} is consistent.", tick);
+            Ok(())
+          } else {
+            for error in &errors {
+              println!("{}", error);
+            }
+            Err(format!(
+              "The saved state at tick {} has {} invariant violations.",
+              tick,
+              errors.len()
+            ))
+          }
+        }
+        NodeCommand::StateDiff { from, to, remote } => {
+          let (from_tick, old) = node::saved_state_listing(&data_path, from)?;
+          let diff = if let Some(remote) = remote {
+            let (tick, new) = remote_state_listing(&remote)?;
+            println!(
+              "Comparing the saved state at tick {} to '{}' at tick {}.",
+              from_tick, remote, tick
+            );
+            hvm::diff_states(&only_functions(old), &new)
+          } else {
+            let (to_tick, new) = node::saved_state_listing(&data_path, to)?;
+            println!(
+              "Comparing the saved states at ticks {} and {}.",
+              from_tick, to_tick
+            );
+            hvm::diff_states(&old, &new)
+          };
+          if diff.is_empty() {
+            println!("The states are equal.");
+          } else {
+            print!("{}", diff);
+          }
+          Ok(())
+        }
+        NodeCommand::Snapshot { command } => match command {
+          SnapshotCommand::Export { file, tick } => {
+            let (tick, state_hash) =
+              node::export_snapshot(&data_path, tick, &file)?;
+            println!(
+              "Exported the state at tick {} to '{}'.",
+              tick,
+              file.display()
+            );
+            println!("State hash: {}", hex::encode(state_hash.0));
+            Ok(())
+          }
+          SnapshotCommand::Import { file, state_hash } => {
+            let state_hash = state_hash_from_hex(&state_hash)?;
+            let (tick, state_hash) =
+              node::import_snapshot(&data_path, &file, &state_hash)?;
+            println!(
+              "Imported the state at tick {} to '{}'.",
+              tick,
+              data_path.display()
+            );
+            println!("State hash: {}", hex::encode(state_hash.0));
+            Ok(())
+          }
+        },
       }
     }
     CliCommand::Util { command } => match command {
@@ -759,7 +1298,7 @@ pub async fn get_info(
             args: vec![Name::NONE],
             func,
             init: Some(hvm::Term::var(Name::NONE)), // to show that we are actually not returning the initial state
-            sign: None,
+            sign: vec![],
           };
           println!("{}", statement);
         }
@@ -802,12 +1341,13 @@ pub async fn get_info(
         }
         Some(stat_kind) => {
           let val = match stat_kind {
-            GetStatsKind::Tick => stats.tick,
-            GetStatsKind::Mana => stats.mana,
-            GetStatsKind::Space => stats.space,
-            GetStatsKind::FunCount => stats.fun_count,
-            GetStatsKind::CtrCount => stats.ctr_count,
-            GetStatsKind::RegCount => stats.reg_count,
+            GetStatsKind::Tick => stats.tick.to_string(),
+            GetStatsKind::Mana => stats.mana.to_string(),
+            GetStatsKind::Space => stats.space.to_string(),
+            GetStatsKind::FunCount => stats.fun_count.to_string(),
+            GetStatsKind::CtrCount => stats.ctr_count.to_string(),
+            GetStatsKind::RegCount => stats.reg_count.to_string(),
+            GetStatsKind::StateRoot => stats.state_root.to_string(),
           };
           println!("{}", val);
         }
@@ -824,9 +1364,7 @@ pub async fn get_info(
   }
 }
 
-pub fn serialize_code(code: &str) {
-  let statements =
-    hvm::read_statements(code).map_err(|err| err.erro).unwrap().1;
+pub fn serialize_code(statements: &[Statement]) {
   for statement in statements {
     println!("{}", hex::encode(statement.proto_serialized().to_bytes()));
   }
@@ -840,6 +1378,230 @@ pub fn deserialize_code(content: &str) -> Result<(), String> {
   Ok(())
 }
 
+// Keys
+// ----
+
+pub fn key_command(
+  keystore: &Keystore,
+  command: KeyCommand,
+) -> Result<(), String> {
+  match command {
+    KeyCommand::New { alias } => {
+      let account = crypto::Account::generate();
+      let password = read_password(&alias, true)?;
+      let key = KeyFile::encrypt(
+        &account.secret_key_bytes(),
+        &password,
+        keystore::Kdf::scrypt(keystore::SCRYPT_LOG_N),
+      )?;
+      keystore.insert(&alias, &key)?;
+      show_key(&alias, &key)
+    }
+    KeyCommand::Import { alias, file } => {
+      let content = file.read_to_string()?;
+      let key = match serde_json::from_str::<KeyFile>(&content) {
+        Ok(key) => {
+          // Checks the password, and that the address matches the secret key
+          let password = read_password(&alias, false)?;
+          let secret = key.decrypt(&password)?;
+          let account = crypto::Account::from_private_key(&secret);
+          if account.address.0 != key.get_address()?.0 {
+            return Err(
+              "The key address doesn't match its secret key.".to_string(),
+            );
+          }
+          key
+        }
+        Err(_) => {
+          let secret = secret_key_from_hex(&content)?;
+          let password = read_password(&alias, true)?;
+          KeyFile::encrypt(
+            &secret,
+            &password,
+            keystore::Kdf::scrypt(keystore::SCRYPT_LOG_N),
+          )?
+        }
+      };
+      keystore.insert(&alias, &key)?;
+      show_key(&alias, &key)
+    }
+    KeyCommand::Export { alias, secret } => {
+      if secret {
+        println!("{}", hex::encode(unlock_key(keystore, &alias)?));
+      } else {
+        let key = keystore.get(&alias)?;
+        println!("{}", serde_json::to_string_pretty(&key).unwrap());
+      }
+      Ok(())
+    }
+    KeyCommand::List => {
+      for alias in keystore.list()? {
+        let key = keystore.get(&alias)?;
+        let address = key.get_address()?;
+        println!(
+          "{}\t{}\t{}",
+          alias,
+          address.show(),
+          Name::from_address(&address)
+        );
+      }
+      Ok(())
+    }
+    KeyCommand::Show { alias } => show_key(&alias, &keystore.get(&alias)?),
+  }
+}
+
+fn show_key(alias: &str, key: &KeyFile) -> Result<(), String> {
+  let address = key.get_address()?;
+  println!("Alias:   {}", alias);
+  println!("Address: {}", address.show());
+  println!("Name:    {}", Name::from_address(&address));
+  Ok(())
+}
+
+/// Reads the password of a key from `KINDELIA_KEY_PASSWORD`, or asks for it.
+/// New passwords are asked twice.
+fn read_password(alias: &str, new: bool) -> Result<String, String> {
+  if let Ok(password) = std::env::var("KINDELIA_KEY_PASSWORD") {
+    return Ok(password);
+  }
+  let prompt = |msg: String| {
+    rpassword::prompt_password(msg)
+      .map_err(|err| format!("Could not read the password: {}", err))
+  };
+  if !new {
+    return prompt(format!("Password of '{}': ", alias));
+  }
+  let password = prompt(format!("New password of '{}': ", alias))?;
+  if prompt("Repeat the password: ".to_string())? != password {
+    return Err("The passwords don't match.".to_string());
+  }
+  Ok(password)
+}
+
+pub fn tx_command(
+  keystore: &Keystore,
+  api_url: &str,
+  command: TxCommand,
+) -> Result<(), String> {
+  match command {
+    TxCommand::Export { file, encoded } => {
+      let stmts = load_code(&file, encoded)?;
+      let stmts: Vec<_> = stmts.iter().map(hvm::remove_sign).collect();
+      print_batch(&Batch::new(&stmts));
+      Ok(())
+    }
+    TxCommand::Sign { file, secret_file, key, add } => {
+      let batch = load_batch(&file)?;
+      let skey = signing_key(keystore, secret_file, key)?
+        .expect("clap requires a secret key");
+      // Shows what is being signed, on the signing machine, from the decoded
+      // statements rather than from the fields of the batch file
+      for stmt in batch.get_statements()? {
+        let hash = hvm::hash_statement(&stmt);
+        let code = offline::view_unsigned(&stmt);
+        eprintln!("{}\n// hash: {}\n", code, hex::encode(hash.0));
+      }
+      print_batch(&batch.sign(&skey, add)?);
+      Ok(())
+    }
+    TxCommand::Show { file, encoded_output } => {
+      for stmt in load_batch(&file)?.get_statements()? {
+        if !encoded_output {
+          for signer in offline::signers(&stmt) {
+            match signer {
+              Some(name) => {
+                println!("// signed by #x{:0>30x}", *U120::from(name))
+              }
+              None => println!("// invalid signature"),
+            }
+          }
+        }
+        print_statement(&stmt, encoded_output);
+      }
+      Ok(())
+    }
+    TxCommand::Publish { file } => {
+      let stmts = load_batch(&file)?.get_statements()?;
+      publish_code(api_url, stmts)
+    }
+  }
+}
+
+fn load_batch(file: &FileInput) -> Result<Batch, String> {
+  serde_json::from_str(&file.read_to_string()?)
+    .map_err(|err| format!("Invalid batch file: {}", err))
+}
+
+fn print_batch(batch: &Batch) {
+  println!("{}", serde_json::to_string_pretty(batch).unwrap());
+}
+
+pub fn reg_command(
+  keystore: &Keystore,
+  command: RegCommand,
+) -> Result<(), String> {
+  let (code, signer) = match command {
+    RegCommand::Transfer { name, owner, guard, signer } => {
+      (format!("own {} {{ {} }}{}", name, owner, guard.view()), signer)
+    }
+    RegCommand::Renounce { name, guard, signer } => {
+      (format!("own {} {{}}{}", name, guard.view()), signer)
+    }
+  };
+  let stmts = hvm::parse_code(&code)
+    .map_err(|err| format!("Invalid owner: {}", err.trim()))?;
+  let stmt = match &stmts[..] {
+    [stmt @ Statement::Own { .. }] => stmt,
+    _ => return Err(format!("Invalid owner on '{}'.", code)),
+  };
+  let stmt = match signing_key(keystore, signer.secret_file, signer.key)? {
+    Some(skey) => sign_code(stmt, &skey)?,
+    None => stmt.clone(),
+  };
+  print_statement(&stmt, signer.encoded_output);
+  Ok(())
+}
+
+/// The secret key given by a file or a keystore alias, if any.
+fn signing_key(
+  keystore: &Keystore,
+  secret_file: Option<PathBuf>,
+  key: Option<String>,
+) -> Result<Option<[u8; 32]>, String> {
+  match (secret_file, key) {
+    (_, Some(alias)) => Ok(Some(unlock_key(keystore, &alias)?)),
+    (Some(secret_file), None) => {
+      let skey: String = arg_from_file_or_stdin(secret_file.into())?;
+      Ok(Some(secret_key_from_hex(&skey)?))
+    }
+    (None, None) => Ok(None),
+  }
+}
+
+/// Decrypts the secret key saved on the keystore with `alias`.
+fn unlock_key(keystore: &Keystore, alias: &str) -> Result<[u8; 32], String> {
+  let key = keystore.get(alias)?;
+  key.decrypt(&read_password(alias, false)?)
+}
+
+fn state_hash_from_hex(hex: &str) -> Result<crypto::Hash, String> {
+  let bytes = hex::decode(hex.trim().trim_start_matches("0x"))
+    .map_err(|err| format!("Invalid state hash hex: {}", err))?;
+  let bytes: [u8; 32] = bytes
+    .try_into()
+    .map_err(|_| "State hash should have exactly 32 bytes".to_string())?;
+  Ok(crypto::Hash(bytes))
+}
+
+fn secret_key_from_hex(hex: &str) -> Result<[u8; 32], String> {
+  let skey = hex::decode(hex.trim())
+    .map_err(|err| format!("Secret key should be valid hex string: {}", err))?;
+  skey
+    .try_into()
+    .map_err(|_| "Secret key should have exactly 64 bytes".to_string())
+}
+
 // TODO: should not open file
 pub fn sign_code(
   statement: &Statement,
@@ -847,19 +1609,61 @@ pub fn sign_code(
 ) -> Result<Statement, String> {
   let user = crypto::Account::from_private_key(skey);
   let hash = hvm::hash_statement(statement);
-  let sign = user.sign(&hash);
-  match statement {
-    Statement::Fun { sign, .. }
-    | Statement::Ctr { sign, .. }
-    | Statement::Run { sign, .. }
-    | Statement::Reg { sign, .. } => {
-      if sign.is_some() {
-        return Err("Statement already has a signature.".to_string());
+  attach_sign(statement, user.sign(&hash), false)
+}
+
+/// Adds a signature to a statement. Statements that are signed already fail,
+/// unless `add` is set, for multi-signature owners.
+pub fn attach_sign(
+  statement: &Statement,
+  sign: crypto::Signature,
+  add: bool,
+) -> Result<Statement, String> {
+  let signs = hvm::get_sign(statement);
+  if signs.contains(&sign) {
+    return Err("Statement already has this signature.".to_string());
+  }
+  if !signs.is_empty() && !add {
+    return Err("Statement already has a signature.".to_string());
+  }
+  if sign.signer_name(&hvm::hash_statement(statement)).is_none() {
+    return Err("Invalid signature.".to_string());
+  }
+  Ok(hvm::add_sign(statement, sign))
+}
+
+/// Merges the signatures of each statement on many copies of the same code.
+pub fn combine_signs(
+  codes: &[Vec<Statement>],
+) -> Result<Vec<Statement>, String> {
+  let (first, rest) = codes.split_first().ok_or("No code to combine.")?;
+  let mut stmts = first.clone();
+  for code in rest {
+    if code.len() != stmts.len() {
+      return Err(
+        "The files have different numbers of statements.".to_string(),
+      );
+    }
+    for (i, (stmt, other)) in stmts.iter_mut().zip(code).enumerate() {
+      if hvm::hash_statement(stmt) != hvm::hash_statement(other) {
+        return Err(format!("Statement #{} differs between the files.", i));
+      }
+      for sign in hvm::get_sign(other) {
+        if !hvm::get_sign(stmt).contains(sign) {
+          *stmt = hvm::add_sign(stmt, sign.clone());
+        }
       }
     }
-  };
-  let stat = hvm::set_sign(statement, sign);
-  Ok(stat)
+  }
+  Ok(stmts)
+}
+
+fn print_statement(statement: &Statement, encoded: bool) {
+  if encoded {
+    println!("{}", hex::encode(statement.proto_serialized().to_bytes()));
+  } else {
+    println!("{}", view_statement(statement));
+  }
 }
 
 pub fn publish_code(
@@ -882,8 +1686,198 @@ pub fn publish_code(
   Ok(())
 }
 
-pub fn test_code(code: &str, sudo: bool) {
-  hvm::test_statements_from_code(code, sudo);
+pub fn deploy_code(
+  api_url: &str,
+  stmts: Vec<Statement>,
+  dry_run: bool,
+  timeout: Duration,
+) -> Result<(), String> {
+  let stmts = deploy::order(skip_deployed(api_url, stmts)?);
+  if stmts.is_empty() {
+    println!("Nothing to deploy.");
+    return Ok(());
+  }
+  let client =
+    api_client::ApiClient::new(api_url, None).map_err(|e| e.to_string())?;
+  run_async_blocking(async move {
+    // Dry-runs everything to get the mana and size of each statement
+    let hexes = stmts.iter().cloned().map(HexStatement::from).collect();
+    let infos = client
+      .run_code(hexes)
+      .await
+      .map_err(|err| format!("Could not dry-run the code: {}", err))?;
+    let costs: Vec<deploy::Cost> = stmts
+      .iter()
+      .zip(infos.iter())
+      .map(|(stmt, info)| deploy::Cost::of(stmt, Some(info)))
+      .collect();
+    let batches = deploy::pack(stmts, &costs, &deploy::BLOCK_LIMITS)?;
+
+    let mut costs = costs.into_iter();
+    for (i, batch) in batches.iter().enumerate() {
+      let cost = costs
+        .by_ref()
+        .take(batch.len())
+        .fold(deploy::Cost::default(), |acc, cost| acc + cost);
+      println!(
+        "Batch #{}: {} statements, {} bytes, {} mana, {} size",
+        i,
+        batch.len(),
+        cost.bytes,
+        cost.mana,
+        cost.size
+      );
+    }
+    if dry_run {
+      return Ok(());
+    }
+
+    for (i, batch) in batches.into_iter().enumerate() {
+      let txs: Vec<String> = batch
+        .iter()
+        .map(|stmt| hex::encode(&*node::Transaction::from(stmt)))
+        .collect();
+      let hexes = batch.into_iter().map(HexStatement::from).collect();
+      // Errors mean the transaction is already on the mempool
+      client.publish_code(hexes).await?;
+      wait_inclusion(&client, txs, timeout).await?;
+      println!("Batch #{}: INCLUDED", i);
+    }
+    Ok(())
+  })
+}
+
+/// Waits until all transactions are included in a block, failing if any of
+/// them fails to run.
+async fn wait_inclusion(
+  client: &api_client::ApiClient,
+  txs: Vec<String>,
+  timeout: Duration,
+) -> Result<(), String> {
+  let mut pending: HashSet<String> = txs.into_iter().collect();
+  let start = std::time::Instant::now();
+  loop {
+    for block in client.get_blocks().await? {
+      for (i, tx) in block.block.body.iter().enumerate() {
+        if pending.remove(tx) {
+          let result = block.results.as_ref().and_then(|res| res.get(i));
+          if let Some(Err(err)) = result {
+            return Err(format!(
+              "Statement failed on block {}: {}",
+              block.hash, err.err
+            ));
+          }
+        }
+      }
+    }
+    if pending.is_empty() {
+      return Ok(());
+    }
+    if start.elapsed() > timeout {
+      return Err(format!(
+        "Timed out waiting for {} transactions to be included.",
+        pending.len()
+      ));
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+  }
+}
+
+pub fn abi_code(file: &FileInput, rust: bool) -> Result<(), String> {
+  let code = file.read_to_string()?;
+  let is_json = match file {
+    FileInput::Path { path } => path.extension().map_or(false, |e| e == "json"),
+    FileInput::Stdin => false,
+  };
+  let abi = if is_json {
+    serde_json::from_str(&code)
+      .map_err(|err| format!("Invalid ABI file '{}': {}", file, err))?
+  } else {
+    abi::extract(&code)?
+  };
+  if rust {
+    print!("{}", abi::rust_bindings(&abi)?);
+  } else {
+    let json = serde_json::to_string_pretty(&abi).map_err(|e| e.to_string())?;
+    println!("{}", json);
+  }
+  Ok(())
+}
+
+pub fn fmt_code(
+  file: &FileInput,
+  check: bool,
+  write: bool,
+) -> Result<(), String> {
+  let code = file.read_to_string()?;
+  let formatted = kindelia::format::format_code(&code)
+    .map_err(|err| format!("Could not format '{}': {}", file, err))?;
+  if check {
+    if formatted != code {
+      return Err(format!("'{}' is not formatted.", file));
+    }
+  } else if write {
+    match file {
+      FileInput::Path { path } => {
+        std::fs::write(path, formatted).map_err(|e| {
+          format!("Could not write to '{}': {}", path.display(), e)
+        })?
+      }
+      FileInput::Stdin => {
+        return Err(
+          "Cannot write the formatted code back to stdin.".to_string(),
+        )
+      }
+    }
+  } else {
+    print!("{}", formatted);
+  }
+  Ok(())
+}
+
+pub fn test_code(statements: &Vec<Statement>, sudo: bool) {
+  hvm::test_statements(statements, sudo);
+}
+
+/// Starts the node over `comm`, on the secure transport if there is a node
+/// key. Unless an address to advertise is given, the port is mapped on the
+/// router over `port_mapping`, if set.
+fn start_node<C>(
+  node_cfg: config::NodeConfig,
+  comm: C,
+  initial_peers: Vec<(net::Address, Option<net::PeerKey>)>,
+  node_key: Option<secp256k1::SecretKey>,
+  advertise_address: Option<net::Address>,
+  port_mapping: Option<net::Transport>,
+) where
+  C: ProtoComm<Address = net::Address>
+    + PacketComm<Address = net::Address>
+    + 'static,
+{
+  let advertise_address = advertise_address.or_else(|| {
+    let transport = port_mapping?;
+    let net::Address::IPv4 { port, .. } = comm.get_addr();
+    match nat::spawn_port_mapping(transport, port) {
+      Ok(address) => Some(address),
+      Err(err) => {
+        eprintln!("Could not map the port: {}", err);
+        None
+      }
+    }
+  });
+  let peer_addrs = initial_peers.iter().map(|(addr, _)| *addr).collect();
+  if let Some(node_key) = node_key {
+    let mut comm = secure::SecureComm::new(comm, node_key);
+    eprintln!("Node key: {}", hex::encode(comm.public_key().serialize()));
+    for (addr, key) in initial_peers {
+      if let Some(key) = key {
+        comm.pin(addr, key);
+      }
+    }
+    node::start(node_cfg, comm, peer_addrs, advertise_address);
+  } else {
+    node::start(node_cfg, comm, peer_addrs, advertise_address);
+  }
 }
 
 fn init_socket() -> Option<UdpSocket> {
@@ -898,6 +1892,17 @@ fn init_socket() -> Option<UdpSocket> {
   None
 }
 
+fn init_listener() -> Option<TcpComm> {
+  let try_ports =
+    [net::UDP_PORT, net::UDP_PORT + 1, net::UDP_PORT + 2, net::UDP_PORT + 3];
+  for port in try_ports {
+    if let Ok(listener) = TcpListener::bind(&format!("0.0.0.0:{}", port)) {
+      return TcpComm::new(listener).ok();
+    }
+  }
+  None
+}
+
 // Utils
 // =====
 
@@ -962,17 +1967,108 @@ fn init_config_file(path: &Path) -> Result<(), String> {
 // Code
 // ----
 
-fn load_code(file: FileInput, encoded: bool) -> Result<Vec<Statement>, String> {
-  let code = file.read_to_string()?;
-  handle_code(&code, encoded)
+/// Loads the statements of a code file and of the files it imports, or of a
+/// serialized file.
+fn load_code(
+  file: &FileInput,
+  encoded: bool,
+) -> Result<Vec<Statement>, String> {
+  match file {
+    _ if encoded => statements_from_hex_seq(&file.read_to_string()?),
+    FileInput::Path { path } => imports::load_file(path),
+    FileInput::Stdin => {
+      imports::load_code(&file.read_to_string()?, Path::new("."))
+    }
+  }
 }
 
-fn handle_code(code: &str, encoded: bool) -> Result<Vec<Statement>, String> {
-  if encoded {
-    statements_from_hex_seq(code)
-  } else {
-    hvm::parse_code(code)
+/// Drops the functions and constructors that are already deployed on the node,
+/// so imported libraries are not published again. Fails if one of them was
+/// deployed with a different definition.
+fn skip_deployed(
+  api_url: &str,
+  stmts: Vec<Statement>,
+) -> Result<Vec<Statement>, String> {
+  // The API answers 404 for names that are not defined
+  fn deployed<T>(res: Result<T, String>) -> Result<Option<T>, String> {
+    match res {
+      Ok(info) => Ok(Some(info)),
+      Err(err) if err.starts_with("Error 404") => Ok(None),
+      Err(err) => Err(err),
+    }
   }
+  let client =
+    api_client::ApiClient::new(api_url, None).map_err(|e| e.to_string())?;
+  run_async_blocking(async move {
+    let mut remaining = Vec::new();
+    for stmt in stmts {
+      let name = match &stmt {
+        Statement::Fun { name, func, .. } => {
+          match deployed(client.get_function(*name).await)? {
+            Some(info) if info.func == *func => Some(*name),
+            Some(_) => {
+              return Err(format!(
+              "Function '{}' is already deployed with a different definition.",
+              name
+            ))
+            }
+            None => None,
+          }
+        }
+        Statement::Ctr { name, args, .. } => {
+          match deployed(client.get_constructor(*name).await)? {
+            Some(info) if info.arit == args.len() as u64 => Some(*name),
+            Some(_) => {
+              return Err(format!(
+                "Constructor '{}' is already deployed with a different arity.",
+                name
+              ))
+            }
+            None => None,
+          }
+        }
+        Statement::Run { .. }
+        | Statement::Reg { .. }
+        | Statement::Own { .. } => None,
+      };
+      if let Some(name) = name {
+        eprintln!("Skipping '{}': already deployed.", name);
+      } else {
+        remaining.push(stmt);
+      }
+    }
+    Ok(remaining)
+  })
+}
+
+/// Lists the functions of a remote node, and their states, as the `/functions`
+/// API shows them. Returns the node's tick too.
+fn remote_state_listing(
+  api_url: &str,
+) -> Result<(u64, hvm::StateListing), String> {
+  let client =
+    api_client::ApiClient::new(api_url, None).map_err(|e| e.to_string())?;
+  run_async_blocking(async move {
+    let tick = client.get_stats().await?.tick;
+    let mut listing = hvm::StateListing::default();
+    for name in client.get_functions().await? {
+      let info = client.get_function(name).await?;
+      listing.funcs.insert(name, info.func);
+      // States too big to read back are answered with an error
+      let state = client.get_function_state(name).await.ok();
+      listing.states.insert(name, state);
+    }
+    Ok((tick, listing))
+  })
+}
+
+/// Restricts a listing to what `remote_state_listing` can list: the functions
+/// with a stored state, and their states.
+fn only_functions(listing: hvm::StateListing) -> hvm::StateListing {
+  let states = listing.states;
+  let funcs =
+    listing.funcs.into_iter().filter(|(name, _)| states.contains_key(name));
+  hvm::StateListing { funcs: funcs.collect(), states, ..Default::default() }
 }
 
 fn statements_from_hex_seq(txt: &str) -> Result<Vec<Statement>, String> {
@@ -1183,6 +2279,31 @@ impl ArgumentFrom<toml::Value> for Vec<String> {
   }
 }
 
+impl<T: ArgumentFrom<String>> ArgumentFrom<String> for Option<T> {
+  fn arg_from(t: String) -> Result<Self, String> {
+    T::arg_from(t).map(Some)
+  }
+}
+
+impl<T: ArgumentFrom<toml::Value>> ArgumentFrom<toml::Value> for Option<T> {
+  fn arg_from(t: toml::Value) -> Result<Self, String> {
+    T::arg_from(t).map(Some)
+  }
+}
+
+impl ArgumentFrom<String> for net::Transport {
+  fn arg_from(t: String) -> Result<Self, String> {
+    t.parse()
+  }
+}
+
+impl ArgumentFrom<toml::Value> for net::Transport {
+  fn arg_from(t: toml::Value) -> Result<Self, String> {
+    let t: String = ArgumentFrom::arg_from(t)?;
+    t.parse()
+  }
+}
+
 impl ArgumentFrom<toml::Value> for bool {
   fn arg_from(t: toml::Value) -> Result<Self, String> {
     t.as_bool().ok_or(format!("Invalid boolean value: {}", t))
@@ -1194,3 +2315,9 @@ impl ArgumentFrom<toml::Value> for config::ApiConfig {
     t.try_into().map_err(|_| "Could not convert value into array".to_string())
   }
 }
+
+impl ArgumentFrom<toml::Value> for config::RollbackConfig {
+  fn arg_from(t: toml::Value) -> Result<Self, String> {
+    t.try_into().map_err(|err| format!("Invalid rollback config: {}", err))
+  }
+}
@@ -22,6 +22,11 @@ pub struct NodeConfig {
   pub api: Option<ApiConfig>,
   #[builder(default)]
   pub ws: Option<WsConfig>,
+  #[builder(default)]
+  pub rollback: RollbackConfig,
+  /// Check the runtime invariants after each computed block (slow).
+  #[builder(default)]
+  pub check_invariants: bool,
 }
 
 // Mineration config
@@ -34,6 +39,46 @@ pub struct MineConfig {
   pub slow_mining: Option<u64>,
 }
 
+// Rollback config
+// ===============
+
+/// How the runtime keeps past states for block-reorg rollback. A snapshot is
+/// taken every `keep` ticks, and each older snapshot is kept for `keep` times
+/// longer than the one after it. With the defaults, there are 4 snapshots:
+/// 16 seconds old, 4 minutes old, 1 hour old and 1 day old, on average.
+#[derive(Debug, Clone, Builder, Serialize, Deserialize)]
+#[builder(setter(strip_option))]
+#[serde(default)]
+pub struct RollbackConfig {
+  /// Number of heaps (2 are used for draw/curr, the rest for snapshots).
+  pub max_heaps: u64,
+  /// Number of ticks between snapshots.
+  pub keep: u64,
+  /// Compact the heap memory when a snapshot is taken. Off by default.
+  pub compact: bool,
+}
+
+impl Default for RollbackConfig {
+  fn default() -> Self {
+    RollbackConfig { max_heaps: 6, keep: 16, compact: false }
+  }
+}
+
+impl RollbackConfig {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.max_heaps < 3 {
+      return Err(format!(
+        "Rollback needs at least 3 heaps, got {}.",
+        self.max_heaps
+      ));
+    }
+    if self.keep < 1 {
+      return Err("Rollback `keep` must be at least 1.".to_string());
+    }
+    Ok(())
+  }
+}
+
 // User Interface config
 // =====================
 
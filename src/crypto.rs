@@ -41,6 +41,13 @@ impl Hash {
     hasher.finalize(&mut output);
     Hash(output)
   }
+
+  /// The hash Ethereum wallets sign on `personal_sign` of this hash (EIP-191):
+  /// the hash of it with the Ethereum signed message prefix.
+  pub fn eth_message_hash(&self) -> Hash {
+    let prefix = b"\x19Ethereum Signed Message:\n32";
+    Hash::keccak256_from_bytes(&[&prefix[..], &self.0].concat())
+  }
 }
 
 /// Can be hashed with Keccak256.
@@ -155,6 +162,10 @@ impl Account {
     Account { secret_key, public_key: pubk, address: addr, name }
   }
 
+  pub fn secret_key_bytes(&self) -> [u8; 32] {
+    self.secret_key.secret_bytes()
+  }
+
   pub fn sign(&self, hash: &Hash) -> Signature {
     let secp = Secp256k1::new();
     let msg = &Message::from_slice(&hash.0).expect("32 bytes hash");
@@ -167,15 +178,30 @@ impl Account {
         .unwrap(),
     )
   }
+
+  /// Signs a hash as Ethereum wallets do on `personal_sign`, with the
+  /// Ethereum signed message prefix.
+  pub fn sign_eth(&self, hash: &Hash) -> Signature {
+    let mut sign = self.sign(&hash.eth_message_hash());
+    sign.0[0] += ETH_RECOVERY_OFFSET;
+    sign
+  }
 }
 
 // Signature
 // =========
 
+/// A recoverable ECDSA signature: the recovery id, followed by the 64 bytes of
+/// the signature. Recovery ids of 27 and 28, the `v` of Ethereum signatures,
+/// mark signatures made with the Ethereum signed message prefix (EIP-191), so
+/// statements can be signed by Ethereum wallets.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(into = "String", try_from = "&str")]
 pub struct Signature(pub [u8; 65]);
 
+/// What is added to the recovery id of signatures with the Ethereum prefix.
+pub const ETH_RECOVERY_OFFSET: u8 = 27;
+
 impl Signature {
   pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
     Some(Signature(bytes.try_into().ok()?))
@@ -185,12 +211,37 @@ impl Signature {
     Signature::from_bytes(hex::decode(hex).ok()?.as_slice())
   }
 
+  /// Converts a signature on the Ethereum layout, `r ++ s ++ v`, as wallets
+  /// return from `personal_sign`.
+  pub fn from_eth_rsv(bytes: &[u8]) -> Option<Self> {
+    if bytes.len() != 65 {
+      return None;
+    }
+    let (rs, v) = bytes.split_at(64);
+    let v = match v {
+      [v @ (27 | 28)] => *v,
+      [v @ (0 | 1)] => v + ETH_RECOVERY_OFFSET,
+      _ => return None,
+    };
+    Signature::from_bytes(&[&[v], rs].concat())
+  }
+
   pub fn to_hex(&self) -> String {
     hex::encode(self.0)
   }
 
+  /// Whether it was made with the Ethereum signed message prefix.
+  pub fn is_eth_prefixed(&self) -> bool {
+    self.0[0] >= ETH_RECOVERY_OFFSET
+  }
+
   pub fn signer_public_key(&self, hash: &Hash) -> Option<PublicKey> {
-    let recovery_id = RecoveryId::from_i32(self.0[0] as i32).ok()?;
+    let (recovery_id, hash) = if self.is_eth_prefixed() {
+      (self.0[0] - ETH_RECOVERY_OFFSET, hash.eth_message_hash())
+    } else {
+      (self.0[0], hash.clone())
+    };
+    let recovery_id = RecoveryId::from_i32(recovery_id as i32).ok()?;
     let sign_data = self.0[1..65].try_into().unwrap();
     let signature =
       RecoverableSignature::from_compact(sign_data, recovery_id).ok()?;
@@ -236,6 +287,13 @@ impl Name {
     Name::from_hash(&Account::hash_public_key(pubk))
   }
 
+  pub fn from_address(addr: &Address) -> Self {
+    let bytes = vec![addr.0[0..15].to_vec(), vec![0]].concat();
+    Name::from_u128_unchecked(
+      u128::from_be_bytes(bytes.try_into().unwrap()) >> 8,
+    )
+  }
+
   // A Kindelia name is the first 120 bits of an Ethereum address.
   // This corresponds to the bytes 12-27 of the ECDSA public key.
   pub fn from_hash(hash: &Hash) -> Self {
@@ -0,0 +1,234 @@
+// Deploy planner
+// ==============
+//
+// Plans the deploy of many statements, used by `kindelia deploy`. Statements
+// are ordered so that each one comes after the definitions it depends on, then
+// packed into batches that fit in a block. Transactions on the same block can
+// be run in any order, so a statement is never put on the same batch as a
+// statement it depends on.
+
+use std::collections::HashMap;
+
+use crate::common::Name;
+use crate::hvm::{self, Statement, StatementInfo, Term};
+use crate::node::{Transaction, MAX_BODY_SIZE};
+
+// Costs
+// =====
+
+/// What a statement takes from the block budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cost {
+  pub bytes: usize,
+  pub mana: u64,
+  pub size: u64,
+}
+
+/// The budget of a single block. One byte of the body holds the transaction
+/// count.
+pub const BLOCK_LIMITS: Cost = Cost {
+  bytes: MAX_BODY_SIZE - 1,
+  mana: hvm::BLOCK_MANA_LIMIT,
+  size: hvm::BLOCK_BITS_LIMIT / 128,
+};
+
+/// Maximum number of transactions on a block, as the count is a single byte.
+pub const MAX_BLOCK_TXS: usize = 255;
+
+impl Cost {
+  /// The cost of a statement. The mana and size are taken from the result of
+  /// a dry-run, if there is one.
+  pub fn of(statement: &Statement, info: Option<&StatementInfo>) -> Cost {
+    let bytes = Transaction::from(statement).len() + 2;
+    match info {
+      Some(StatementInfo::Run { used_mana, size_diff, .. }) => {
+        Cost { bytes, mana: *used_mana, size: (*size_diff).max(0) as u64 }
+      }
+      _ => Cost { bytes, mana: 0, size: 0 },
+    }
+  }
+
+  fn fits(&self, limits: &Cost) -> bool {
+    self.bytes <= limits.bytes
+      && self.mana <= limits.mana
+      && self.size <= limits.size
+  }
+}
+
+impl std::ops::Add for Cost {
+  type Output = Cost;
+  fn add(self, other: Cost) -> Cost {
+    Cost {
+      bytes: self.bytes + other.bytes,
+      mana: self.mana + other.mana,
+      size: self.size + other.size,
+    }
+  }
+}
+
+// Dependencies
+// ============
+
+fn collect_refs(term: &Term, refs: &mut Vec<Name>) {
+  match term {
+    Term::Var { .. } | Term::Num { .. } => {}
+    Term::Dup { expr, body, .. } => {
+      collect_refs(expr, refs);
+      collect_refs(body, refs);
+    }
+    Term::Lam { body, .. } => collect_refs(body, refs),
+    Term::App { func, argm } => {
+      collect_refs(func, refs);
+      collect_refs(argm, refs);
+    }
+    Term::Ctr { name, args } | Term::Fun { name, args } => {
+      refs.push(*name);
+      for arg in args {
+        collect_refs(arg, refs);
+      }
+    }
+    Term::Op2 { val0, val1, .. } => {
+      collect_refs(val0, refs);
+      collect_refs(val1, refs);
+    }
+  }
+}
+
+// The namespace a name is deployed under, e.g. `Foo.Bar` for `Foo.Bar.baz`.
+fn namespace(name: &Name) -> Option<Name> {
+  let name = name.to_string();
+  let (prefix, _) = name.rsplit_once('.')?;
+  Name::from_str(prefix).ok()
+}
+
+/// Returns, for each statement, the indices of the statements it depends on:
+/// - the definitions of the functions and constructors it uses;
+/// - the registration of the namespace its name is under, or of the namespace
+///   it transfers;
+/// - the previous `run`, as runs may depend on each other's effects.
+pub fn dependencies(statements: &[Statement]) -> Vec<Vec<usize>> {
+  let mut defined: HashMap<Name, usize> = HashMap::new();
+  let mut registered: HashMap<Name, usize> = HashMap::new();
+  for (idx, statement) in statements.iter().enumerate() {
+    match statement {
+      Statement::Fun { name, .. } | Statement::Ctr { name, .. } => {
+        defined.entry(*name).or_insert(idx);
+      }
+      Statement::Reg { name, .. } => {
+        registered.entry(*name).or_insert(idx);
+      }
+      Statement::Run { .. } | Statement::Own { .. } => {}
+    }
+  }
+  let mut last_run = None;
+  let mut deps = Vec::new();
+  for (idx, statement) in statements.iter().enumerate() {
+    let mut refs = Vec::new();
+    let mut stmt_deps = Vec::new();
+    match statement {
+      Statement::Fun { name, func, init, .. } => {
+        for rule in &func.rules {
+          collect_refs(&rule.lhs, &mut refs);
+          collect_refs(&rule.rhs, &mut refs);
+        }
+        if let Some(init) = init {
+          collect_refs(init, &mut refs);
+        }
+        stmt_deps.extend(namespace(name).and_then(|ns| registered.get(&ns)));
+      }
+      Statement::Ctr { name, .. } | Statement::Reg { name, .. } => {
+        stmt_deps.extend(namespace(name).and_then(|ns| registered.get(&ns)));
+      }
+      Statement::Run { expr, .. } => {
+        collect_refs(expr, &mut refs);
+        stmt_deps.extend(last_run);
+        last_run = Some(idx);
+      }
+      Statement::Own { name, .. } => {
+        stmt_deps.extend(registered.get(name));
+      }
+    }
+    stmt_deps.extend(refs.iter().filter_map(|name| defined.get(name)));
+    stmt_deps.retain(|dep| *dep != idx);
+    stmt_deps.sort_unstable();
+    stmt_deps.dedup();
+    deps.push(stmt_deps);
+  }
+  deps
+}
+
+// Planning
+// ========
+
+/// Orders the statements so that each one comes after the ones it depends on,
+/// keeping the original order otherwise. Cycles (e.g. mutually recursive
+/// functions) are broken on the original order.
+pub fn order(statements: Vec<Statement>) -> Vec<Statement> {
+  fn visit(
+    idx: usize,
+    deps: &[Vec<usize>],
+    state: &mut [u8], // 0: new, 1: visiting, 2: done
+    order: &mut Vec<usize>,
+  ) {
+    if state[idx] != 0 {
+      return;
+    }
+    state[idx] = 1;
+    for dep in &deps[idx] {
+      visit(*dep, deps, state, order);
+    }
+    state[idx] = 2;
+    order.push(idx);
+  }
+  let deps = dependencies(&statements);
+  let mut state = vec![0; statements.len()];
+  let mut indices = Vec::new();
+  for idx in 0..statements.len() {
+    visit(idx, &deps, &mut state, &mut indices);
+  }
+  let mut statements: Vec<Option<Statement>> =
+    statements.into_iter().map(Some).collect();
+  indices.into_iter().filter_map(|idx| statements[idx].take()).collect()
+}
+
+/// Packs ordered statements into batches that fit on the given limits, in
+/// order. A statement starts a new batch if it depends on a statement of the
+/// current one. Fails if a statement doesn't fit in a block by itself.
+pub fn pack(
+  statements: Vec<Statement>,
+  costs: &[Cost],
+  limits: &Cost,
+) -> Result<Vec<Vec<Statement>>, String> {
+  let deps = dependencies(&statements);
+  let mut batches: Vec<Vec<Statement>> = Vec::new();
+  let mut batch_start = 0;
+  let mut batch_cost = Cost::default();
+  for (idx, statement) in statements.into_iter().enumerate() {
+    let cost = costs[idx];
+    if !cost.fits(limits) {
+      return Err(format!(
+        "Statement #{} doesn't fit in a block: {:?} exceeds {:?}.\n{}",
+        idx,
+        cost,
+        limits,
+        hvm::view_statement(&statement)
+      ));
+    }
+    let full = match batches.last() {
+      None => true,
+      Some(batch) => {
+        batch.len() >= MAX_BLOCK_TXS
+          || !(batch_cost + cost).fits(limits)
+          || deps[idx].iter().any(|dep| (batch_start..idx).contains(dep))
+      }
+    };
+    if full {
+      batches.push(Vec::new());
+      batch_start = idx;
+      batch_cost = Cost::default();
+    }
+    batch_cost = batch_cost + cost;
+    batches.last_mut().unwrap().push(statement);
+  }
+  Ok(batches)
+}
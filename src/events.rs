@@ -11,6 +11,7 @@ use warp::{Filter, Rejection, Reply};
 
 use crate::api::Hash;
 use crate::config::{UiConfig, WsConfig};
+use crate::hvm;
 use crate::net::ProtoAddr;
 use crate::node::{HashedBlock, Peer};
 
@@ -138,6 +139,15 @@ pub enum HandleMessageEvent {
     magic: u32,
     trans: Hash, // shoul we guard the data of transaction too?
   },
+  Hello {
+    magic: u32,
+    version: u32,
+    height: u128,
+  },
+  Observed {
+    magic: u32,
+    address: String,
+  },
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -162,6 +172,32 @@ pub struct HeartbeatBlocks {
 pub struct HeartbeatRuntime {
   pub mana: HeartbeatStatInfo,
   pub size: HeartbeatStatInfo,
+  pub root: Hash,
+  pub compaction: Option<HeartbeatCompaction>,
+}
+
+/// Statistics of the latest heap compaction. See `hvm::Compaction`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HeartbeatCompaction {
+  pub tick: u64,
+  pub nodes: u64,
+  pub live: u64,
+  pub moved: u64,
+  pub span_before: u64,
+  pub span_after: u64,
+}
+
+impl From<hvm::Compaction> for HeartbeatCompaction {
+  fn from(compaction: hvm::Compaction) -> Self {
+    HeartbeatCompaction {
+      tick: compaction.tick,
+      nodes: compaction.nodes,
+      live: compaction.live,
+      moved: compaction.moved,
+      span_before: compaction.span_before,
+      span_after: compaction.span_after,
+    }
+  }
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -245,7 +281,12 @@ impl std::fmt::Display for HeartbeatBlocks {
 impl std::fmt::Display for HeartbeatRuntime {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     // Do not display mana stats right now as they are too verbose
-    f.write_fmt(format_args!("runtime: {{ size: {} }}", self.size))
+    f.write_fmt(format_args!(
+      "runtime: {{ size: {} | root: {} | compaction: {} }}",
+      self.size,
+      self.root,
+      show_opt(self.compaction.as_ref())
+    ))
     // f.write_fmt(format_args!(
     //   "[runtime] [mana] {} | [size] {}",
     //   self.mana, self.size
@@ -253,6 +294,15 @@ impl std::fmt::Display for HeartbeatRuntime {
   }
 }
 
+impl std::fmt::Display for HeartbeatCompaction {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_fmt(format_args!(
+      "tick: {} | live: {} | span: {} -> {}",
+      self.tick, self.live, self.span_before, self.span_after
+    ))
+  }
+}
+
 impl std::fmt::Display for HeartbeatStatInfo {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     f.write_fmt(format_args!(
@@ -285,6 +335,15 @@ impl std::fmt::Display for HandleMessageEvent {
       HandleMessageEvent::PleaseMineThisTransaction { magic, trans } => {
         format!("[mine_trans] magic: {} | trans: {}", magic, trans)
       }
+      HandleMessageEvent::Hello { magic, version, height } => {
+        format!(
+          "[hello] magic: {} | version: {} | height: {}",
+          magic, version, height
+        )
+      }
+      HandleMessageEvent::Observed { magic, address } => {
+        format!("[observed] magic: {} | address: {}", magic, address)
+      }
     };
     f.write_fmt(format_args!("{}", message))
   }
@@ -633,6 +692,15 @@ impl NodeEventType {
     };
     NodeEventType::HandleMessage { event }
   }
+  pub fn hello(magic: u32, version: u32, height: u128) -> Self {
+    let event = HandleMessageEvent::Hello { magic, version, height };
+    NodeEventType::HandleMessage { event }
+  }
+  pub fn observed<A: ProtoAddr>(magic: u32, address: &A) -> Self {
+    let address = format!("{}", address);
+    let event = HandleMessageEvent::Observed { magic, address };
+    NodeEventType::HandleMessage { event }
+  }
 }
 
 #[macro_export]
@@ -658,7 +726,9 @@ macro_rules! heartbeat {
         current: $size_cur:expr,
         limit: $size_lim:expr,
         available: $size_avail:expr,
-      }
+      },
+      root: $root:expr,
+      compaction: $compaction:expr,
     },
     tip_blocks: $tip_blocks:expr
   ) => {
@@ -684,6 +754,8 @@ macro_rules! heartbeat {
           limit: $size_lim,
           available: $size_avail,
         },
+        root: $root.into(),
+        compaction: $compaction.map(|stats| stats.into()),
       },
       tip_blocks: $tip_blocks.iter().map(|x: &U256| (*x).into()).collect(),
     }
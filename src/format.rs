@@ -0,0 +1,1072 @@
+// Canonical formatter for Kindelia code (.kdl)
+// ============================================
+//
+// `hvm::view_statement` prints the parsed `Statement`, which has already lost
+// comments and syntax sugar (`let`, `ask`, tuples, `'name'` literals, hex
+// numbers). This module has its own lexer and a concrete syntax tree that keeps
+// all of that, so formatting a file never changes its meaning nor drops any of
+// its comments.
+//
+// Layout rules:
+//
+//   - statements are separated by a single line break, or by a single blank
+//     line if the source had one or more blank lines between them;
+//   - `fun` rules, `run` and `with` blocks are indented by 2 spaces;
+//   - a rule whose right-hand side starts with `dup`, `let` or `ask` is broken
+//     after the `=`, with one binding per line, indented by 4 spaces;
+//   - any other term is printed on a single line if it fits in `MAX_WIDTH`
+//     columns; otherwise its arguments are broken one per line;
+//   - signatures are printed in 5 lines of 26 hex digits, like `view_statement`;
+//   - comments are kept where they were: trailing comments stay on the end of
+//     their line, and a comment on its own line stays on its own line. Comments
+//     inside of a token sequence that can't hold one (e.g. between `dup` and
+//     its names) are moved to before the enclosing term.
+
+use std::fmt;
+
+pub const MAX_WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+// Lexer
+// =====
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Tok {
+  Word(String),
+  Str(String), // the path of an `import`
+  Sym(&'static str),
+  Eof,
+}
+
+impl fmt::Display for Tok {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Tok::Word(word) => write!(f, "{}", word),
+      Tok::Str(path) => write!(f, "\"{}\"", path),
+      Tok::Sym(sym) => write!(f, "{}", sym),
+      Tok::Eof => write!(f, "end of file"),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Comment {
+  pub(crate) text: String,
+  own_line: bool, // false if the comment is at the end of a line with code
+  blank_before: bool, // true if there is an empty line before the comment
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+  pub(crate) tok: Tok,
+  pub(crate) line: usize,            // 1-based
+  pub(crate) col: usize,             // 0-based, in chars
+  pub(crate) comments: Vec<Comment>, // comments that appear before this token
+  blank_before: bool, // true if there is an empty line before this token
+}
+
+// Multi-char symbols must come before their prefixes.
+const SYMBOLS: [&str; 29] = [
+  "<<", "<=", ">>", ">=", "==", "!=", "(", ")", "{", "}", "[", "]", "@", "#",
+  "'", "=", ";", "!", "~", "+", "-", "*", "/", "%", "&", "|", "^", "<", ">",
+];
+
+const OPERATORS: [&str; 16] = [
+  "+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "<", "<=", "==", ">=",
+  ">", "!=",
+];
+
+fn is_name_char(chr: char) -> bool {
+  chr == '_' || chr == '.' || chr.is_ascii_alphanumeric()
+}
+
+pub(crate) fn lex(code: &str) -> Result<Vec<Token>, String> {
+  let chars: Vec<char> = code.chars().collect();
+  let mut tokens: Vec<Token> = Vec::new();
+  let mut comments: Vec<Comment> = Vec::new();
+  let mut line = 1;
+  let mut line_start = 0;
+  let mut newlines = 0; // since the last token or comment
+  let mut idx = 0;
+  loop {
+    let start = idx;
+    let tok = match chars.get(idx) {
+      None => Tok::Eof,
+      Some('\n') => {
+        line += 1;
+        newlines += 1;
+        idx += 1;
+        line_start = idx;
+        continue;
+      }
+      Some(chr) if chr.is_whitespace() => {
+        idx += 1;
+        continue;
+      }
+      Some('/') if chars.get(idx + 1) == Some(&'/') => {
+        while idx < chars.len() && chars[idx] != '\n' {
+          idx += 1;
+        }
+        let text: String = chars[start..idx].iter().collect();
+        let own_line = newlines > 0 || tokens.is_empty();
+        let blank_before = newlines > 1;
+        comments.push(Comment {
+          text: text.trim_end().to_string(),
+          own_line,
+          blank_before,
+        });
+        newlines = 0;
+        continue;
+      }
+      Some(chr) if is_name_char(*chr) => {
+        while idx < chars.len() && is_name_char(chars[idx]) {
+          idx += 1;
+        }
+        Tok::Word(chars[start..idx].iter().collect())
+      }
+      Some('"') => {
+        idx += 1;
+        while idx < chars.len() && chars[idx] != '"' && chars[idx] != '\n' {
+          idx += 1;
+        }
+        if chars.get(idx) != Some(&'"') {
+          return Err(format!("Line {}: unterminated string.", line));
+        }
+        idx += 1;
+        Tok::Str(chars[start + 1..idx - 1].iter().collect())
+      }
+      Some(chr) => {
+        let sym = SYMBOLS.iter().find(|sym| {
+          sym.chars().enumerate().all(|(i, c)| chars.get(idx + i) == Some(&c))
+        });
+        match sym {
+          Some(sym) => {
+            idx += sym.len();
+            Tok::Sym(sym)
+          }
+          None => {
+            return Err(format!(
+              "Line {}: unexpected character '{}'.",
+              line, chr
+            ));
+          }
+        }
+      }
+    };
+    let done = tok == Tok::Eof;
+    let comments = std::mem::take(&mut comments);
+    let col = start - line_start;
+    tokens.push(Token { tok, line, col, comments, blank_before: newlines > 1 });
+    newlines = 0;
+    if done {
+      return Ok(tokens);
+    }
+  }
+}
+
+// Concrete syntax tree
+// ====================
+
+#[derive(Debug, Clone)]
+struct Node {
+  comments: Vec<Comment>,
+  blank_before: bool,
+  syn: Syn,
+}
+
+#[derive(Debug, Clone)]
+enum Syn {
+  // `x`, `~`
+  Var(String),
+  // `#123`, `#x7b`, kept as written
+  Num(String),
+  // `'Name'`
+  Lit(String),
+  // `@x body`
+  Lam(String, Box<Node>),
+  // `dup a b = expr; body`, `let x = expr; body`, `ask x = expr; body`, `ask expr; body`
+  Bind {
+    keyword: &'static str,
+    names: Vec<String>,
+    expr: Box<Node>,
+    body: Box<Node>,
+  },
+  // `(Fun a b)`, `{Ctr a b}`, `(+ a b)`, `(f x)`, `(!f x)`, `[a b]`
+  List {
+    open: String,
+    close: &'static str,
+    style: ListStyle,
+    args: Vec<Node>,
+    tail: Vec<Comment>,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListStyle {
+  Padded, // `(Name a b)`: a space between the opener and the first argument
+  Glued,  // `(f x)`: the first argument is glued to the opener
+  Bare,   // `[a b]`: no space, but all arguments break
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+  term: Node,
+  tail: Vec<Comment>,
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+  Fun {
+    name: String,
+    args: Vec<String>,
+    rules: Vec<(Node, Node)>,
+    tail: Vec<Comment>,
+    init: Option<Block>,
+    sign: Vec<String>,
+  },
+  Ctr {
+    name: String,
+    args: Vec<String>,
+    sign: Vec<String>,
+  },
+  Run {
+    body: Block,
+    guard: Vec<(String, String)>,
+    sign: Vec<String>,
+  },
+  Reg {
+    name: Option<String>,
+    ownr: String,
+    sign: Vec<String>,
+  },
+  Own {
+    name: Option<String>,
+    ownr: Option<String>,
+    guard: Vec<(String, String)>,
+    sign: Vec<String>,
+  },
+  Import {
+    path: String,
+  },
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+  comments: Vec<Comment>,
+  blank_before: bool,
+  stmt: Stmt,
+}
+
+// Parser
+// ======
+
+struct Parser {
+  tokens: Vec<Token>,
+  index: usize,
+}
+
+type ParseResult<A> = Result<A, String>;
+
+impl Parser {
+  fn peek(&self) -> &Tok {
+    &self.tokens[self.index].tok
+  }
+
+  fn peek_word(&self, word: &str) -> bool {
+    matches!(self.peek(), Tok::Word(w) if w == word)
+  }
+
+  fn next(&mut self) -> Token {
+    let token = self.tokens[self.index].clone();
+    if token.tok != Tok::Eof {
+      self.index += 1;
+    }
+    token
+  }
+
+  fn error<A>(&self, expected: &str) -> ParseResult<A> {
+    let token = &self.tokens[self.index];
+    Err(format!(
+      "Line {}: expected {}, found '{}'.",
+      token.line, expected, token.tok
+    ))
+  }
+
+  // Consumes a token that can't hold comments, moving them to `hoist`.
+  fn skip(&mut self, hoist: &mut Vec<Comment>) {
+    for mut comment in self.next().comments {
+      comment.own_line = true;
+      hoist.push(comment);
+    }
+  }
+
+  fn expect(
+    &mut self,
+    sym: &'static str,
+    hoist: &mut Vec<Comment>,
+  ) -> ParseResult<()> {
+    if *self.peek() == Tok::Sym(sym) {
+      self.skip(hoist);
+      Ok(())
+    } else {
+      self.error(&format!("'{}'", sym))
+    }
+  }
+
+  // Consumes a closing symbol, returning the comments that precede it.
+  fn close(&mut self, sym: &'static str) -> ParseResult<Vec<Comment>> {
+    if *self.peek() == Tok::Sym(sym) {
+      Ok(self.next().comments)
+    } else {
+      self.error(&format!("'{}'", sym))
+    }
+  }
+
+  fn word(&mut self, hoist: &mut Vec<Comment>) -> ParseResult<String> {
+    match self.peek().clone() {
+      Tok::Word(word) => {
+        self.skip(hoist);
+        Ok(word)
+      }
+      _ => self.error("identifier"),
+    }
+  }
+
+  // A name that may be erased (`~`).
+  fn name(&mut self, hoist: &mut Vec<Comment>) -> ParseResult<String> {
+    if *self.peek() == Tok::Sym("~") {
+      self.skip(hoist);
+      Ok("~".to_string())
+    } else {
+      self.word(hoist)
+    }
+  }
+
+  fn terms_until(
+    &mut self,
+    sym: &'static str,
+  ) -> ParseResult<(Vec<Node>, Vec<Comment>)> {
+    let mut args = Vec::new();
+    while *self.peek() != Tok::Sym(sym) && *self.peek() != Tok::Eof {
+      args.push(self.term()?);
+    }
+    let tail = self.close(sym)?;
+    Ok((args, tail))
+  }
+
+  fn names_until(
+    &mut self,
+    sym: &'static str,
+    hoist: &mut Vec<Comment>,
+  ) -> ParseResult<Vec<String>> {
+    let mut names = Vec::new();
+    while *self.peek() != Tok::Sym(sym) && *self.peek() != Tok::Eof {
+      names.push(self.name(hoist)?);
+    }
+    self.expect(sym, hoist)?;
+    Ok(names)
+  }
+
+  fn term(&mut self) -> ParseResult<Node> {
+    let line = self.tokens[self.index].line;
+    let token = self.next();
+    let mut comments = token.comments;
+    let blank_before = token.blank_before;
+    let syn = match token.tok {
+      Tok::Sym("@") => {
+        let name = self.name(&mut comments)?;
+        let body = self.term()?;
+        Syn::Lam(name, Box::new(body))
+      }
+      Tok::Sym("(") => match self.peek().clone() {
+        Tok::Sym(oper) if OPERATORS.contains(&oper) => {
+          self.skip(&mut comments);
+          let (args, tail) = self.terms_until(")")?;
+          if args.len() != 2 {
+            return Err(format!(
+              "Line {}: operator '{}' expects 2 arguments.",
+              line, oper
+            ));
+          }
+          Syn::List {
+            open: format!("({}", oper),
+            close: ")",
+            style: ListStyle::Padded,
+            args,
+            tail,
+          }
+        }
+        Tok::Sym("!") => {
+          self.skip(&mut comments);
+          let (args, tail) = self.terms_until(")")?;
+          if args.len() != 2 {
+            return Err(format!("Line {}: application expects 2 terms.", line));
+          }
+          Syn::List {
+            open: "(!".to_string(),
+            close: ")",
+            style: ListStyle::Glued,
+            args,
+            tail,
+          }
+        }
+        Tok::Word(name)
+          if name.starts_with(|c: char| c.is_ascii_uppercase()) =>
+        {
+          self.skip(&mut comments);
+          let (args, tail) = self.terms_until(")")?;
+          Syn::List {
+            open: format!("({}", name),
+            close: ")",
+            style: ListStyle::Padded,
+            args,
+            tail,
+          }
+        }
+        _ => {
+          let (args, tail) = self.terms_until(")")?;
+          if args.len() != 2 {
+            return Err(format!("Line {}: application expects 2 terms.", line));
+          }
+          Syn::List {
+            open: "(".to_string(),
+            close: ")",
+            style: ListStyle::Glued,
+            args,
+            tail,
+          }
+        }
+      },
+      Tok::Sym("{") => {
+        let name = self.word(&mut comments)?;
+        let (args, tail) = self.terms_until("}")?;
+        Syn::List {
+          open: format!("{{{}", name),
+          close: "}",
+          style: ListStyle::Padded,
+          args,
+          tail,
+        }
+      }
+      Tok::Sym("[") => {
+        let (args, tail) = self.terms_until("]")?;
+        if args.len() > 12 {
+          return Err(format!("Line {}: tuple too long.", line));
+        }
+        Syn::List {
+          open: "[".to_string(),
+          close: "]",
+          style: ListStyle::Bare,
+          args,
+          tail,
+        }
+      }
+      Tok::Sym("#") => Syn::Num(self.word(&mut comments)?),
+      Tok::Sym("'") => {
+        let name = self.word(&mut comments)?;
+        self.expect("'", &mut comments)?;
+        Syn::Lit(name)
+      }
+      Tok::Sym("~") => Syn::Var("~".to_string()),
+      Tok::Word(word) if word == "dup" || word == "let" || word == "ask" => {
+        let keyword = match word.as_str() {
+          "dup" => "dup",
+          "let" => "let",
+          _ => "ask",
+        };
+        let names = if keyword == "ask" && *self.peek() == Tok::Sym("(") {
+          vec![]
+        } else {
+          let count = if keyword == "dup" { 2 } else { 1 };
+          let mut names = Vec::new();
+          for _ in 0..count {
+            names.push(self.name(&mut comments)?);
+          }
+          self.expect("=", &mut comments)?;
+          names
+        };
+        let expr = self.term()?;
+        self.expect(";", &mut comments)?;
+        let body = self.term()?;
+        Syn::Bind { keyword, names, expr: Box::new(expr), body: Box::new(body) }
+      }
+      Tok::Word(word) => Syn::Var(word),
+      tok => {
+        return Err(format!("Line {}: expected term, found '{}'.", line, tok));
+      }
+    };
+    Ok(Node { comments, blank_before, syn })
+  }
+
+  // The `nonce #N` and `until #N` of a run or a transfer, in either order.
+  // They're shown with the nonce first.
+  fn guard(
+    &mut self,
+    hoist: &mut Vec<Comment>,
+  ) -> ParseResult<Vec<(String, String)>> {
+    let mut guard: Vec<(String, String)> = Vec::new();
+    while let Some(keyword) =
+      ["nonce", "until"].into_iter().find(|keyword| self.peek_word(keyword))
+    {
+      let line = self.tokens[self.index].line;
+      if guard.iter().any(|(seen, _)| seen == keyword) {
+        return Err(format!("Line {}: repeated {}.", line, keyword));
+      }
+      self.skip(hoist);
+      self.expect("#", hoist)?;
+      let numb = format!("#{}", self.word(hoist)?);
+      guard.push((keyword.to_string(), numb));
+    }
+    guard.sort();
+    Ok(guard)
+  }
+
+  fn signs(&mut self, hoist: &mut Vec<Comment>) -> ParseResult<Vec<String>> {
+    let mut signs = Vec::new();
+    while self.peek_word("sign") {
+      signs.push(self.sign(hoist)?);
+    }
+    Ok(signs)
+  }
+
+  fn sign(&mut self, hoist: &mut Vec<Comment>) -> ParseResult<String> {
+    let line = self.tokens[self.index].line;
+    self.skip(hoist);
+    self.expect("{", hoist)?;
+    let mut hex = String::new();
+    while let Tok::Word(word) = self.peek().clone() {
+      self.skip(hoist);
+      hex.push_str(&word);
+    }
+    self.expect("}", hoist)?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(format!("Line {}: invalid signature.", line));
+    }
+    Ok(hex.to_lowercase())
+  }
+
+  // A registry owner: a key, or a multi-signature owner as `#2 of #x123 #x456`
+  fn owner(&mut self, hoist: &mut Vec<Comment>) -> ParseResult<String> {
+    let mut ownr = self.owner_key(hoist)?;
+    if self.peek_word("of") {
+      self.skip(hoist);
+      ownr.push_str(" of");
+      while *self.peek() != Tok::Sym("}") {
+        ownr.push(' ');
+        ownr.push_str(&self.owner_key(hoist)?);
+      }
+    }
+    Ok(ownr)
+  }
+
+  // A registry owner key: `#x123` or `'Name'`
+  fn owner_key(&mut self, hoist: &mut Vec<Comment>) -> ParseResult<String> {
+    match self.peek() {
+      Tok::Sym("#") => {
+        self.skip(hoist);
+        Ok(format!("#{}", self.word(hoist)?))
+      }
+      Tok::Sym("'") => {
+        self.skip(hoist);
+        let name = self.word(hoist)?;
+        self.expect("'", hoist)?;
+        Ok(format!("'{}'", name))
+      }
+      _ => self.error("a number representation"),
+    }
+  }
+
+  fn block(&mut self, hoist: &mut Vec<Comment>) -> ParseResult<Block> {
+    self.expect("{", hoist)?;
+    let term = self.term()?;
+    let tail = self.close("}")?;
+    Ok(Block { term, tail })
+  }
+
+  fn statement(&mut self) -> ParseResult<Statement> {
+    let keyword = match self.peek() {
+      Tok::Word(word)
+        if ["fun", "ctr", "run", "reg", "own", "import"]
+          .contains(&word.as_str()) =>
+      {
+        word.clone()
+      }
+      _ => return self.error("statement"),
+    };
+    let token = self.next();
+    let blank_before = token.blank_before;
+    let mut comments = token.comments;
+    let stmt = match keyword.as_str() {
+      "fun" => {
+        self.expect("(", &mut comments)?;
+        let name = self.word(&mut comments)?;
+        let args = self.names_until(")", &mut comments)?;
+        self.expect("{", &mut comments)?;
+        let mut rules = Vec::new();
+        while *self.peek() != Tok::Sym("}") && *self.peek() != Tok::Eof {
+          let mut lhs = self.term()?;
+          self.expect("=", &mut lhs.comments)?;
+          let rhs = self.term()?;
+          rules.push((lhs, rhs));
+        }
+        let tail = self.close("}")?;
+        let init = if self.peek_word("with") {
+          self.skip(&mut comments);
+          Some(self.block(&mut comments)?)
+        } else {
+          None
+        };
+        let sign = self.signs(&mut comments)?;
+        Stmt::Fun { name, args, rules, tail, init, sign }
+      }
+      "ctr" => {
+        self.expect("{", &mut comments)?;
+        let name = self.word(&mut comments)?;
+        let args = self.names_until("}", &mut comments)?;
+        let sign = self.signs(&mut comments)?;
+        Stmt::Ctr { name, args, sign }
+      }
+      "run" => {
+        let body = self.block(&mut comments)?;
+        let guard = self.guard(&mut comments)?;
+        let sign = self.signs(&mut comments)?;
+        Stmt::Run { body, guard, sign }
+      }
+      "reg" => {
+        let name = if *self.peek() == Tok::Sym("{") {
+          None
+        } else {
+          Some(self.word(&mut comments)?)
+        };
+        self.expect("{", &mut comments)?;
+        let ownr = self.owner(&mut comments)?;
+        self.expect("}", &mut comments)?;
+        let sign = self.signs(&mut comments)?;
+        Stmt::Reg { name, ownr, sign }
+      }
+      "own" => {
+        let name = if *self.peek() == Tok::Sym("{") {
+          None
+        } else {
+          Some(self.word(&mut comments)?)
+        };
+        self.expect("{", &mut comments)?;
+        let ownr = if *self.peek() == Tok::Sym("}") {
+          None
+        } else {
+          Some(self.owner(&mut comments)?)
+        };
+        self.expect("}", &mut comments)?;
+        let guard = self.guard(&mut comments)?;
+        let sign = self.signs(&mut comments)?;
+        Stmt::Own { name, ownr, guard, sign }
+      }
+      "import" => match self.peek().clone() {
+        Tok::Str(path) => {
+          self.skip(&mut comments);
+          Stmt::Import { path }
+        }
+        _ => return self.error("a quoted path"),
+      },
+      _ => unreachable!(),
+    };
+    Ok(Statement { comments, blank_before, stmt })
+  }
+
+  fn statements(&mut self) -> ParseResult<(Vec<Statement>, Vec<Comment>)> {
+    let mut statements = Vec::new();
+    while *self.peek() != Tok::Eof {
+      statements.push(self.statement()?);
+    }
+    let tail = self.next().comments;
+    Ok((statements, tail))
+  }
+}
+
+// Documents
+// =========
+//
+// A minimal Wadler-style pretty printer. A `Group` is printed flat (`Line` as
+// a space, `Soft` as nothing) if it fits in the remaining width and contains
+// no `Hard` line; otherwise its lines are broken.
+
+#[derive(Debug, Clone)]
+enum Doc {
+  Text(String),
+  Line,
+  Soft,
+  Hard,
+  Blank, // an empty line, if not at the start of the output or of a block
+  Nest(Vec<Doc>),
+  Group(Vec<Doc>),
+  Seq(Vec<Doc>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+  Flat,
+  Break,
+}
+
+fn text(txt: impl Into<String>) -> Doc {
+  Doc::Text(txt.into())
+}
+
+fn has_hard(doc: &Doc) -> bool {
+  match doc {
+    Doc::Hard | Doc::Blank => true,
+    Doc::Nest(docs) | Doc::Group(docs) | Doc::Seq(docs) => {
+      docs.iter().any(has_hard)
+    }
+    _ => false,
+  }
+}
+
+// Checks if `doc` fits in `width` columns, in flat mode, followed by the
+// `rest` of the stack up to its next line break.
+fn fits(width: usize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+  let mut width = width as isize;
+  let mut stack: Vec<(Mode, &Doc)> = vec![(Mode::Flat, doc)];
+  let mut rest = rest.iter().rev();
+  loop {
+    let (mode, doc) = match stack.pop() {
+      Some(item) => item,
+      None => match rest.next() {
+        Some((_, mode, doc)) => (*mode, *doc),
+        None => return true,
+      },
+    };
+    match doc {
+      Doc::Text(txt) => width -= txt.chars().count() as isize,
+      Doc::Line if mode == Mode::Flat => width -= 1,
+      Doc::Soft if mode == Mode::Flat => {}
+      Doc::Line | Doc::Soft | Doc::Hard | Doc::Blank => return true,
+      Doc::Nest(docs) | Doc::Group(docs) | Doc::Seq(docs) => {
+        for doc in docs.iter().rev() {
+          stack.push((mode, doc));
+        }
+      }
+    }
+    if width < 0 {
+      return false;
+    }
+  }
+}
+
+fn render(doc: &Doc) -> String {
+  fn newline(out: &mut String) {
+    while out.ends_with(' ') {
+      out.pop();
+    }
+    if !out.is_empty() {
+      out.push('\n');
+    }
+  }
+  let mut out = String::new();
+  let mut col = 0;
+  let mut stack: Vec<(usize, Mode, &Doc)> = vec![(0, Mode::Break, doc)];
+  while let Some((indent, mode, doc)) = stack.pop() {
+    match doc {
+      Doc::Text(txt) => {
+        if out.is_empty() || out.ends_with('\n') {
+          out.push_str(&" ".repeat(indent));
+          col = indent;
+        }
+        out.push_str(txt);
+        col += txt.chars().count();
+      }
+      Doc::Line if mode == Mode::Flat => {
+        out.push(' ');
+        col += 1;
+      }
+      Doc::Soft if mode == Mode::Flat => {}
+      Doc::Line | Doc::Soft | Doc::Hard => {
+        newline(&mut out);
+        col = indent;
+      }
+      Doc::Blank => {
+        let last_line = out.trim_end_matches('\n').lines().last().unwrap_or("");
+        let opener = last_line.ends_with(|c| "({[".contains(c));
+        if out.ends_with('\n') && !out.ends_with("\n\n") && !opener {
+          out.push('\n');
+        }
+      }
+      Doc::Nest(docs) => {
+        for doc in docs.iter().rev() {
+          stack.push((indent + INDENT, mode, doc));
+        }
+      }
+      Doc::Seq(docs) => {
+        for doc in docs.iter().rev() {
+          stack.push((indent, mode, doc));
+        }
+      }
+      Doc::Group(docs) => {
+        let flat = mode == Mode::Flat
+          || (!has_hard(doc)
+            && fits(MAX_WIDTH.saturating_sub(col), doc, &stack));
+        let mode = if flat { Mode::Flat } else { Mode::Break };
+        for doc in docs.iter().rev() {
+          stack.push((indent, mode, doc));
+        }
+      }
+    }
+  }
+  newline(&mut out);
+  out
+}
+
+// Printer
+// =======
+
+// Prints `body` after `sep`, with the comments that precede it. A trailing
+// comment stays on the end of the previous line; a comment on its own line
+// replaces `sep` by a line break. If `top` is set, the blank line before the
+// body is kept even without comments.
+fn commented(
+  comments: &[Comment],
+  blank_before: bool,
+  top: bool,
+  sep: Doc,
+  body: Doc,
+) -> Doc {
+  let mut docs = Vec::new();
+  if comments.is_empty() {
+    docs.push(sep);
+  } else {
+    docs.extend(view_comments(comments));
+    docs.push(Doc::Hard);
+  }
+  if blank_before && (top || !comments.is_empty()) {
+    docs.push(Doc::Blank);
+  }
+  docs.push(body);
+  Doc::Seq(docs)
+}
+
+fn view_comments(comments: &[Comment]) -> Vec<Doc> {
+  let mut docs = Vec::new();
+  for (i, comment) in comments.iter().enumerate() {
+    if i == 0 && !comment.own_line {
+      docs.push(text(format!(" {}", comment.text)));
+    } else {
+      docs.push(Doc::Hard);
+      if comment.blank_before {
+        docs.push(Doc::Blank);
+      }
+      docs.push(text(comment.text.clone()));
+    }
+  }
+  docs
+}
+
+fn view_node(node: &Node, sep: Doc, block: bool) -> Doc {
+  commented(
+    &node.comments,
+    node.blank_before,
+    false,
+    sep,
+    view_syn(&node.syn, block),
+  )
+}
+
+// `block` is set when the term is the body of a rule or of a `run`/`with`
+// block, in which case bindings are always printed one per line.
+fn view_syn(syn: &Syn, block: bool) -> Doc {
+  match syn {
+    Syn::Var(name) => text(name.clone()),
+    Syn::Num(numb) => text(format!("#{}", numb)),
+    Syn::Lit(name) => text(format!("'{}'", name)),
+    Syn::Lam(name, body) => Doc::Seq(vec![
+      text(format!("@{}", name)),
+      view_node(body, text(" "), block),
+    ]),
+    Syn::Bind { keyword, names, expr, body } => {
+      let head = if names.is_empty() {
+        keyword.to_string()
+      } else {
+        format!("{} {} =", keyword, names.join(" "))
+      };
+      let sep = if block { Doc::Hard } else { Doc::Line };
+      let docs = vec![
+        text(head),
+        view_node(expr, text(" "), false),
+        text(";"),
+        view_node(body, sep, block),
+      ];
+      if block {
+        Doc::Seq(docs)
+      } else {
+        Doc::Group(docs)
+      }
+    }
+    Syn::List { open, close, style, args, tail } => {
+      let mut inner = Vec::new();
+      for (i, arg) in args.iter().enumerate() {
+        let sep = match (i, style) {
+          (0, ListStyle::Glued) => Doc::Seq(vec![]),
+          (0, ListStyle::Bare) => Doc::Soft,
+          _ => Doc::Line,
+        };
+        inner.push(view_node(arg, sep, false));
+      }
+      inner.extend(view_comments(tail));
+      let close_sep = if args.is_empty() && tail.is_empty() {
+        Doc::Seq(vec![])
+      } else {
+        Doc::Soft
+      };
+      Doc::Group(vec![
+        text(open.clone()),
+        Doc::Nest(inner),
+        close_sep,
+        text(*close),
+      ])
+    }
+  }
+}
+
+fn view_block(block: &Block) -> Doc {
+  let mut inner = vec![view_node(&block.term, Doc::Hard, true)];
+  inner.extend(view_comments(&block.tail));
+  Doc::Seq(vec![text("{"), Doc::Nest(inner), Doc::Hard, text("}")])
+}
+
+fn view_guard(guard: &[(String, String)]) -> String {
+  guard.iter().map(|(keyword, numb)| format!(" {} {}", keyword, numb)).collect()
+}
+
+fn view_sign(sign: &[String]) -> Doc {
+  let mut docs = Vec::new();
+  for hex in sign {
+    let mut lines = Vec::new();
+    for chunk in hex.as_bytes().chunks(26) {
+      lines.push(Doc::Hard);
+      lines.push(text(String::from_utf8_lossy(chunk).to_string()));
+    }
+    docs.push(text(" sign {"));
+    docs.push(Doc::Nest(lines));
+    docs.push(Doc::Hard);
+    docs.push(text("}"));
+  }
+  Doc::Seq(docs)
+}
+
+fn view_rule(lhs: &Node, rhs: &Node) -> Doc {
+  fn is_bind(syn: &Syn) -> bool {
+    match syn {
+      Syn::Bind { .. } => true,
+      Syn::Lam(_, body) => is_bind(&body.syn),
+      _ => false,
+    }
+  }
+  let lhs = Doc::Seq(vec![view_syn(&lhs.syn, false), text(" =")]);
+  if is_bind(&rhs.syn) {
+    Doc::Seq(vec![lhs, Doc::Nest(vec![view_node(rhs, Doc::Hard, true)])])
+  } else {
+    Doc::Group(vec![lhs, Doc::Nest(vec![view_node(rhs, Doc::Line, false)])])
+  }
+}
+
+fn view_stmt(stmt: &Stmt) -> Doc {
+  match stmt {
+    Stmt::Fun { name, args, rules, tail, init, sign } => {
+      let head = std::iter::once(name)
+        .chain(args.iter())
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(" ");
+      let mut docs = vec![text(format!("fun ({}) {{", head))];
+      if rules.is_empty() && tail.is_empty() {
+        docs.push(text("}"));
+      } else {
+        let mut inner = Vec::new();
+        for (lhs, rhs) in rules {
+          inner.push(commented(
+            &lhs.comments,
+            lhs.blank_before,
+            true,
+            Doc::Hard,
+            view_rule(lhs, rhs),
+          ));
+        }
+        inner.extend(view_comments(tail));
+        docs.push(Doc::Nest(inner));
+        docs.push(Doc::Hard);
+        docs.push(text("}"));
+      }
+      if let Some(init) = init {
+        docs.push(text(" with "));
+        docs.push(view_block(init));
+      }
+      docs.push(view_sign(sign));
+      Doc::Seq(docs)
+    }
+    Stmt::Ctr { name, args, sign } => {
+      let head = std::iter::once(name)
+        .chain(args.iter())
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(" ");
+      Doc::Seq(vec![text(format!("ctr {{{}}}", head)), view_sign(sign)])
+    }
+    Stmt::Run { body, guard, sign } => Doc::Seq(vec![
+      text("run "),
+      view_block(body),
+      text(view_guard(guard)),
+      view_sign(sign),
+    ]),
+    Stmt::Reg { name, ownr, sign } => {
+      let name = match name {
+        Some(name) => format!("{} ", name),
+        None => String::new(),
+      };
+      Doc::Seq(vec![
+        text(format!("reg {}{{ {} }}", name, ownr)),
+        view_sign(sign),
+      ])
+    }
+    Stmt::Own { name, ownr, guard, sign } => {
+      let name = match name {
+        Some(name) => format!("{} ", name),
+        None => String::new(),
+      };
+      let ownr = match ownr {
+        Some(ownr) => format!(" {} ", ownr),
+        None => String::new(),
+      };
+      Doc::Seq(vec![
+        text(format!("own {}{{{}}}{}", name, ownr, view_guard(guard))),
+        view_sign(sign),
+      ])
+    }
+    Stmt::Import { path } => text(format!("import \"{}\"", path)),
+  }
+}
+
+// API
+// ===
+
+/// Formats a Kindelia code file, preserving its comments.
+///
+/// Only the syntax is checked, so files that fail on `parse_code` for other
+/// reasons (e.g. number literal overflows) can still be formatted.
+pub fn format_code(code: &str) -> Result<String, String> {
+  let tokens = lex(code)?;
+  let mut parser = Parser { tokens, index: 0 };
+  let (statements, tail) = parser.statements()?;
+  let mut docs = Vec::new();
+  for (i, statement) in statements.iter().enumerate() {
+    let sep = if i == 0 { Doc::Seq(vec![]) } else { Doc::Hard };
+    let body = view_stmt(&statement.stmt);
+    docs.push(commented(
+      &statement.comments,
+      statement.blank_before,
+      true,
+      sep,
+      body,
+    ));
+  }
+  docs.extend(view_comments(&tail));
+  Ok(render(&Doc::Seq(docs)))
+}
@@ -245,10 +245,10 @@ use std::collections::{hash_map, HashMap, HashSet};
 use std::fmt::{self, Write};
 use std::hash::{BuildHasherDefault, Hash, Hasher};
 use std::path::PathBuf;
-use std::fs::File;
 use std::sync::Arc;
 use std::time::Instant;
 
+use primitive_types::U256;
 use serde::{Serialize, Deserialize};
 use serde_with::{serde_as, DisplayFromStr};
 
@@ -260,7 +260,8 @@ use crate::util::{LocMap, NameMap, U128Map, U120Map};
 use crate::NoHashHasher::NoHashHasher;
 
 use crate::common::{Name, U120};
-use crate::persistence::DiskSer;
+use crate::config::RollbackConfig;
+use crate::persistence::{self, DiskSer, PersistWorker};
 
 /// This is the HVM's term type. It is used to represent an expression. It is not used in rewrite
 /// rules. Instead, it is stored on HVM's heap using its memory model, which will be elaborated
@@ -380,6 +381,27 @@ pub struct Ownrs {
   pub ownrs: NameMap<U120>,
 }
 
+// A map of `OwnerID -> Multisig`
+// Stores the key sets of the multi-signature namespace owners.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Msigs {
+  pub msigs: U120Map<Multisig>,
+}
+
+// A map of `Subject -> Nonce`
+// Stores the nonce the next signed `run` of each subject must have.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Noncs {
+  pub noncs: U120Map<u64>,
+}
+
+// A map of `FunctionID -> Hash`
+// Stores the hash of each stored state on the state root, computed when it's saved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Leafs {
+  pub leafs: U120Map<crypto::Hash>,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Indxs {
   pub indxs: NameMap<u128>
@@ -393,13 +415,87 @@ pub struct Store {
   pub links: U120Map<RawCell>,
 }
 
-/// A global statement that alters the state of the blockchain
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// A global statement that alters the state of the blockchain. Statements are usually signed by
+/// a single subject, but statements on namespaces of multi-signature owners carry a signature of
+/// each of the signing keys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Statement {
-  Fun { name: Name, args: Vec<Name>, func: Func, init: Option<Term>, sign: Option<crypto::Signature> },
-  Ctr { name: Name, args: Vec<Name>, sign: Option<crypto::Signature> },
-  Run { expr: Term, sign: Option<crypto::Signature> },
-  Reg { name: Name, ownr: U120, sign: Option<crypto::Signature> },
+  Fun { name: Name, args: Vec<Name>, func: Func, init: Option<Term>, sign: Vec<crypto::Signature> },
+  Ctr { name: Name, args: Vec<Name>, sign: Vec<crypto::Signature> },
+  /// Runs an IO expression. Signed runs can be protected from being replayed: with a `nonce`, it
+  /// only runs if that is the next nonce of its subject, which it increments, and with `until`,
+  /// only up to that tick.
+  Run { expr: Term, nonce: Option<u64>, until: Option<u64>, sign: Vec<crypto::Signature> },
+  Reg { name: Name, ownr: Owner, sign: Vec<crypto::Signature> },
+  /// Transfers a namespace to a new owner, or renounces it when `ownr` is `None`. Signed by the
+  /// current owner. Like a protected run, it consumes the `nonce` of its subject, so it can't be
+  /// replayed, and with `until`, only runs up to that tick.
+  Own { name: Name, ownr: Option<Owner>, nonce: u64, until: Option<u64>, sign: Vec<crypto::Signature> },
+}
+
+/// The owner of a namespace: a subject, or a set of subjects that must sign together.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Owner {
+  Subj(U120),
+  Msig(Multisig),
+}
+
+/// The owner of renounced namespaces, that no one can sign for.
+pub const NO_OWNER: U120 = U120::MAX;
+
+/// A multi-signature owner: statements on its namespaces must be signed by at least `thld` of its
+/// `keys`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Multisig {
+  pub thld: u64,
+  pub keys: Vec<U120>,
+}
+
+/// Maximum number of keys of a multi-signature owner.
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+impl Multisig {
+  /// The owner id of this key set: a hash of its threshold and keys, which doesn't depend on the
+  /// order of the keys.
+  pub fn id(&self) -> U120 {
+    let mut keys = self.keys.clone();
+    keys.sort_by_key(|key| **key);
+    let mut bytes = self.thld.to_le_bytes().to_vec();
+    for key in keys {
+      bytes.extend_from_slice(&key.to_le_bytes());
+    }
+    let hash = crypto::Hash::keccak256_from_bytes(&bytes);
+    U120::from_u128_unchecked(u128::from_le_bytes(hash.0[0..16].try_into().unwrap()) & *U120::MAX)
+  }
+
+  pub fn check(&self) -> Result<(), String> {
+    if self.keys.len() > MAX_MULTISIG_KEYS {
+      return Err(format!("Multi-signature owners can't have more than {} keys.", MAX_MULTISIG_KEYS));
+    }
+    if self.thld == 0 || self.thld > self.keys.len() as u64 {
+      return Err(format!("Threshold {} is invalid for {} keys.", self.thld, self.keys.len()));
+    }
+    let unique: HashSet<_> = self.keys.iter().collect();
+    if unique.len() != self.keys.len() {
+      return Err("Multi-signature owners can't repeat keys.".to_string());
+    }
+    Ok(())
+  }
+
+  // Whether these subjects are enough to sign for this owner
+  fn signed_by(&self, subjs: &[U120]) -> bool {
+    self.keys.iter().filter(|key| subjs.contains(key)).count() as u64 >= self.thld
+  }
+}
+
+impl Owner {
+  /// The value stored as owner of a namespace.
+  pub fn id(&self) -> U120 {
+    match self {
+      Owner::Subj(subj) => *subj,
+      Owner::Msig(msig) => msig.id(),
+    }
+  }
 }
 
 /// RawCell
@@ -503,6 +599,9 @@ pub struct Heap {
   pub indx: Indxs, // function name to position in heap
   pub hash: Hashs,
   pub ownr: Ownrs, // namespace owners
+  pub msig: Msigs, // key sets of multi-signature owners
+  pub nonc: Noncs, // nonces of the subjects of signed runs
+  pub leaf: Leafs, // hashes of the stored states
   pub tick: u64,  // tick counter
   pub time: u128,  // block timestamp
   pub meta: u128,  // block metadata
@@ -515,6 +614,8 @@ pub struct Heap {
   pub size: u64,  // total used memory (in 64-bit words)
   pub mcap: u64,  // memory capacity (in 64-bit words)
   pub next: u64,  // memory index that *may* be empty
+  pub rot0: u128,  // state root, low half
+  pub rot1: u128,  // state root, high half
   // TODO: store run results (Num). (block_idx, stmt_idx) [as u128] -> U120
 }
 
@@ -533,12 +634,16 @@ pub enum Rollback {
 
 // The current and past states
 pub struct Runtime {
-  heap: Vec<Heap>,      // heap objects
-  draw: u64,            // drawing heap index
-  curr: u64,            // current heap index
-  nuls: Vec<u64>,       // reuse heap indices
-  back: Arc<Rollback>,  // past states
-  path: PathBuf,        // where to save runtime state
+  heap: Vec<Arc<Heap>>,     // heap objects, shared with the persistence worker while it saves them
+  draw: u64,                // drawing heap index
+  curr: u64,                // current heap index
+  nuls: Vec<u64>,           // reuse heap indices
+  back: Arc<Rollback>,      // past states
+  logs: Vec<Vec<Journal>>,  // undo journals of each heap, one per tick
+  conf: RollbackConfig,     // snapshot schedule
+  path: PathBuf,            // where to save runtime state
+  save: PersistWorker,      // writes runtime state on the background
+  last_compaction: Option<Compaction>, // statistics of the latest compaction
 }
 
 #[derive(Debug, Clone)]
@@ -579,30 +684,6 @@ pub enum EffectFailure {
   InvalidIONonCtr { ptr: RawCell },
 }
 
-//pub fn heaps_invariant(rt: &Runtime) -> (bool, Vec<u8>, Vec<u64>) {
-  //let mut seen = vec![0u8; 10];
-  //let mut heaps = vec![0u64; 0];
-  //let mut push = |id: u64| {
-    //let idx = id as usize;
-    //seen[idx] += 1;
-    //heaps.push(id);
-  //};
-  //push(rt.draw);
-  //push(rt.curr);
-  //for nul in &rt.nuls {
-    //push(*nul);
-  //}
-  //{
-    //let mut back = &*rt.back;
-    //while let Rollback::Cons { keep, life, head, tail } = back {
-      //push(*head);
-      //back = &*tail;
-    //}
-  //}
-  //let failed = seen.iter().all(|c| *c == 1);
-  //(failed, seen, heaps)
-//}
-
 pub type StatementResult = Result<StatementInfo, StatementErr>;
 
 // TODO: refactor (de)serialization out or simplify
@@ -621,6 +702,7 @@ pub enum StatementInfo {
     end_size: u64,
   },
   Reg { name: Name, ownr: U120 },
+  Own { name: Name, ownr: U120 },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -639,19 +721,19 @@ pub struct ParseErr {
 // Constants
 // ---------
 
+// State metadata file: the keeps, lifes and uuids of the Rollback list
+const STATE_FILE: &str = "_state_.bin";
+// Files of the state metadata before the file format was versioned
+const LEGACY_STATE_FILES: [&str; 3] = ["_keeps_", "_lifes_", "_uuids_"];
+
+// Names of the sections of a heap file, one for each part of the heap. Before the file format was
+// versioned, each one was persisted on its own file.
+const HEAP_BUFFERS: [&str; 8] = ["memo", "disk", "file", "arit", "indx", "stmt_hashes", "ownr", "stat"];
+
 const U128_PER_KB: u128 = (1024 / U128_SIZE) as u128;
 const U128_PER_MB: u128 = U128_PER_KB << 10;
 const U128_PER_GB: u128 = U128_PER_MB << 10;
 
-// With the constants below, we pre-alloc 6 heaps, which is enough for
-// 4 snapshots: 16 seconds old, 4 minutes old, 1 hour old and 1 day old, on
-// average.
-
-/// Number of heaps (2 are used for draw/curr, the rest for rollbacks)
-const MAX_HEAPS: u64 = 6;
-// Number of heaps for snapshots
-const MAX_ROLLBACK: u64 = MAX_HEAPS - 2;
-
 pub const MAX_TERM_DEPTH: u128 = 256; // maximum depth of a LHS or RHS term
 
 // Size of each RawCell field in bits
@@ -725,6 +807,7 @@ pub const U64_NONE: u64 = u64::MAX; //TODO: rewrite as FFF's?if think it is easi
 //   (FROM           then) : (IO r)
 //   (TICK           then) : (IO r)
 //   (TIME           then) : (IO r)
+//   (NONC subj      then) : (IO r)
 const IO_DONE : u128 = 0x39960f; // name_to_u128("DONE")
 const IO_TAKE : u128 = 0x78b54f; // name_to_u128("TAKE")
 const IO_SAVE : u128 = 0x74b80f; // name_to_u128("SAVE")
@@ -740,7 +823,14 @@ const IO_HAX1 : u128 = 0x48b882; // name_to_u128("HAX1")
 const IO_GIDX : u128 = 0x4533a2; // name_to_u128("GIDX")
 const IO_STH0 : u128 = 0x75e481; // name_to_u128("STH0")
 const IO_STH1 : u128 = 0x75e482; // name_to_u128("STH1")
+const IO_NONC : u128 = 0x61960d; // name_to_u128("NONC")
 // TODO: STH0 & STH1 -> get hash of statement (by (block_idx, stmt_idx))
+
+// Names of the constructors above, as written on Kindelia code
+pub const IO_NAMES : [&str; 16] = [
+  "DONE", "TAKE", "SAVE", "CALL", "SUBJ", "FROM", "LOAD", "TICK", "TIME", "META",
+  "HAX0", "HAX1", "GIDX", "STH0", "STH1", "NONC",
+];
 // TODO: GRUN -> get run result
 
 // Maximum mana that can be spent in a block
@@ -821,7 +911,12 @@ fn DupEraMana() -> u64 {
   return 2;
 }
 
-fn count_allocs(body: &Term) -> u64 {
+// Reading back a saved state, to hash it on the state root
+fn SaveMana(state: &Term) -> u64 {
+  return 1 + count_allocs(state);
+}
+
+pub fn count_allocs(body: &Term) -> u64 {
   match body {
     Term::Var { name } => {
       0
@@ -1000,75 +1095,49 @@ pub fn get_namespace(name: Name) -> Option<Name> {
 // Statements
 // ----------
 
-// Removes the signature from a statement
-pub fn remove_sign(statement: &Statement) -> Statement {
-  match statement {
-    Statement::Fun { name, args, func, init, sign } => {
-      Statement::Fun {
-        name: *name,
-        args: args.clone(),
-        func: func.clone(),
-        init: init.clone(),
-        sign: None,
-      }
-    }
-    Statement::Ctr { name, args, sign } => {
-      Statement::Ctr {
-        name: *name,
-        args: args.clone(),
-        sign: None,
-      }
-    }
-    Statement::Run { expr, sign } => {
-      Statement::Run {
-        expr: expr.clone(),
-        sign: None,
-      }
-    }
-    Statement::Reg { name, ownr, sign } => {
-      Statement::Reg {
-        name: *name,
-        ownr: *ownr,
-        sign: None,
-      }
-    }
+// Replaces the signatures of a statement
+fn with_sign(statement: &Statement, new_sign: Vec<crypto::Signature>) -> Statement {
+  let mut statement = statement.clone();
+  match &mut statement {
+    Statement::Fun { sign, .. }
+    | Statement::Ctr { sign, .. }
+    | Statement::Run { sign, .. }
+    | Statement::Reg { sign, .. }
+    | Statement::Own { sign, .. } => *sign = new_sign,
   }
+  statement
 }
 
-pub fn set_sign(statement: &Statement, new_sign: crypto::Signature) -> Statement {
+// Gets the signatures of a statement
+pub fn get_sign(statement: &Statement) -> &[crypto::Signature] {
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
-      Statement::Fun {
-        name: *name,
-        args: args.clone(),
-        func: func.clone(),
-        init: init.clone(),
-        sign: Some(new_sign),
-      }
-    }
-    Statement::Ctr { name, args, sign } => {
-      Statement::Ctr {
-        name: *name,
-        args: args.clone(),
-        sign: Some(new_sign),
-      }
-    }
-    Statement::Run { expr, sign } => {
-      Statement::Run {
-        expr: expr.clone(),
-        sign: Some(new_sign),
-      }
-    }
-    Statement::Reg { name, ownr, sign } => {
-      Statement::Reg {
-        name: *name,
-        ownr: *ownr,
-        sign: Some(new_sign),
-      }
-    }
+    Statement::Fun { sign, .. }
+    | Statement::Ctr { sign, .. }
+    | Statement::Run { sign, .. }
+    | Statement::Reg { sign, .. }
+    | Statement::Own { sign, .. } => sign,
   }
 }
 
+// Removes the signatures from a statement
+pub fn remove_sign(statement: &Statement) -> Statement {
+  with_sign(statement, vec![])
+}
+
+pub fn set_sign(statement: &Statement, new_sign: crypto::Signature) -> Statement {
+  with_sign(statement, vec![new_sign])
+}
+
+// Adds a signature to a statement, as multi-signature owners require, keeping
+// them sorted by signer
+pub fn add_sign(statement: &Statement, new_sign: crypto::Signature) -> Statement {
+  let hash = hash_statement(statement);
+  let mut sign = get_sign(statement).to_vec();
+  sign.push(new_sign);
+  sign.sort_by_key(|sign| sign.signer_name(&hash).map(|name| *name));
+  with_sign(statement, sign)
+}
+
 // StatementInfo
 // =============
 
@@ -1078,6 +1147,7 @@ impl fmt::Display for StatementInfo {
       StatementInfo::Ctr { name, args } => write!(f, "[ctr] {}", name),
       StatementInfo::Fun { name, args } => write!(f, "[fun] {}", name),
       StatementInfo::Reg { name, .. } => write!(f, "[reg] {}", name),
+      StatementInfo::Own { name, .. } => write!(f, "[own] {}", name),
       StatementInfo::Run { done_term, used_mana, size_diff, .. } =>
         write!(f, "[run] {} \x1b[2m[{} mana | {} size]\x1b[0m", view_term(&done_term), used_mana, size_diff)
     }
@@ -1095,6 +1165,123 @@ fn absorb_u64(a: u64, b: u64, overwrite: bool) -> u64 {
   if b == U64_NONE { a } else if overwrite || a == U64_NONE { b } else { a }
 }
 
+// The values a tick overwrote on a heap, used to undo it. `None` means that
+// the key was absent, so it must be looked up on past heaps.
+struct Journal {
+  tick: u64, // tick it was committed on, or U64_NONE if not committed yet
+  memo: LocMap<Option<RawCell>>,
+  disk: U120Map<Option<RawCell>>,
+  file: NameMap<Option<Arc<CompFunc>>>,
+  arit: NameMap<Option<u64>>,
+  ownr: NameMap<Option<U120>>,
+  msig: U120Map<Option<Multisig>>,
+  nonc: U120Map<Option<u64>>,
+  leaf: U120Map<Option<crypto::Hash>>,
+  indx: NameMap<Option<u128>>,
+  hash: U128Map<Option<crypto::Hash>>,
+  stat: [u128; 14],
+}
+
+// Saves, for each key written on `new`, its value on `old`, unless already saved
+fn journal_keys<K: Copy + Eq + Hash, V: Clone, S: std::hash::BuildHasher, T: std::hash::BuildHasher>(undo: &mut HashMap<K, Option<V>, T>, old: &HashMap<K, V, S>, new: &HashMap<K, V, S>) {
+  for key in new.keys() {
+    undo.entry(*key).or_insert_with(|| old.get(key).cloned());
+  }
+}
+
+// Restores the saved values
+fn undo_keys<K: Copy + Eq + Hash, V, S: std::hash::BuildHasher, T>(map: &mut HashMap<K, V, S>, undo: HashMap<K, Option<V>, T>) {
+  for (key, val) in undo {
+    match val {
+      Some(val) => { map.insert(key, val); }
+      None => { map.remove(&key); }
+    }
+  }
+}
+
+// Fills absent keys with the values of a past heap that is being absorbed
+fn rebase_keys<K: Copy + Eq + Hash, V: Clone, S: std::hash::BuildHasher, T>(undo: &mut HashMap<K, Option<V>, T>, base: &HashMap<K, V, S>) {
+  for (key, val) in undo.iter_mut() {
+    if val.is_none() {
+      *val = base.get(key).cloned();
+    }
+  }
+}
+
+impl Journal {
+  fn new(heap: &Heap) -> Self {
+    Journal {
+      tick: U64_NONE,
+      memo: init_loc_map(),
+      disk: init_u120_map(),
+      file: init_name_map(),
+      arit: init_name_map(),
+      ownr: init_name_map(),
+      msig: init_u120_map(),
+      nonc: init_u120_map(),
+      leaf: init_u120_map(),
+      indx: init_name_map(),
+      hash: init_u128_map(),
+      stat: heap.get_stat(),
+    }
+  }
+  // Saves the values of `old` that `new` will overwrite
+  fn record(&mut self, old: &Heap, new: &Heap) {
+    journal_keys(&mut self.memo, &old.memo.nodes, &new.memo.nodes);
+    journal_keys(&mut self.disk, &old.disk.links, &new.disk.links);
+    journal_keys(&mut self.file, &old.file.funcs, &new.file.funcs);
+    journal_keys(&mut self.arit, &old.arit.arits, &new.arit.arits);
+    journal_keys(&mut self.ownr, &old.ownr.ownrs, &new.ownr.ownrs);
+    journal_keys(&mut self.msig, &old.msig.msigs, &new.msig.msigs);
+    journal_keys(&mut self.nonc, &old.nonc.noncs, &new.nonc.noncs);
+    journal_keys(&mut self.leaf, &old.leaf.leafs, &new.leaf.leafs);
+    journal_keys(&mut self.indx, &old.indx.indxs, &new.indx.indxs);
+    journal_keys(&mut self.hash, &old.hash.stmt_hashes, &new.hash.stmt_hashes);
+  }
+  // Reverts the heap to its state before this tick
+  fn undo(self, heap: &mut Heap) {
+    undo_keys(&mut heap.memo.nodes, self.memo);
+    undo_keys(&mut heap.disk.links, self.disk);
+    undo_keys(&mut heap.file.funcs, self.file);
+    undo_keys(&mut heap.arit.arits, self.arit);
+    undo_keys(&mut heap.ownr.ownrs, self.ownr);
+    undo_keys(&mut heap.msig.msigs, self.msig);
+    undo_keys(&mut heap.nonc.noncs, self.nonc);
+    undo_keys(&mut heap.leaf.leafs, self.leaf);
+    undo_keys(&mut heap.indx.indxs, self.indx);
+    undo_keys(&mut heap.hash.stmt_hashes, self.hash);
+    heap.set_stat(self.stat);
+  }
+  // Updates the journal for when its heap absorbs an older heap
+  fn rebase(&mut self, base: &Heap) {
+    rebase_keys(&mut self.memo, &base.memo.nodes);
+    rebase_keys(&mut self.disk, &base.disk.links);
+    rebase_keys(&mut self.file, &base.file.funcs);
+    rebase_keys(&mut self.arit, &base.arit.arits);
+    rebase_keys(&mut self.ownr, &base.ownr.ownrs);
+    rebase_keys(&mut self.msig, &base.msig.msigs);
+    rebase_keys(&mut self.nonc, &base.nonc.noncs);
+    rebase_keys(&mut self.leaf, &base.leaf.leafs);
+    rebase_keys(&mut self.indx, &base.indx.indxs);
+    rebase_keys(&mut self.hash, &base.hash.stmt_hashes);
+    let mut stat = base.get_stat();
+    for (i, val) in self.stat.iter().enumerate() {
+      if !is_stat_none(i, *val) {
+        stat[i] = *val;
+      }
+    }
+    self.stat = stat;
+  }
+}
+
+// The statistics of a heap are stored as u128 on journals, in the order below
+fn is_stat_none(index: usize, val: u128) -> bool {
+  match index {
+    1 | 2 | 3 | 4 | 12 | 13 => val == U128_NONE,
+    _ => val == U64_NONE as u128,
+  }
+}
+
 impl Heap {
   fn write(&mut self, idx: Loc, val: RawCell) {
     return self.memo.write(idx, val);
@@ -1126,6 +1313,24 @@ impl Heap {
   fn read_ownr(&self, name: &Name) -> Option<U120> {
     return self.ownr.read(name);
   }
+  fn write_msig(&mut self, id: U120, msig: Multisig) {
+    return self.msig.write(id, msig);
+  }
+  fn read_msig(&self, id: &U120) -> Option<Multisig> {
+    return self.msig.read(id);
+  }
+  fn write_nonc(&mut self, subj: U120, nonce: u64) {
+    return self.nonc.write(subj, nonce);
+  }
+  fn read_nonc(&self, subj: &U120) -> Option<u64> {
+    return self.nonc.read(subj);
+  }
+  fn write_leaf(&mut self, name: U120, leaf: Option<crypto::Hash>) {
+    return self.leaf.write(name, leaf);
+  }
+  fn read_leaf(&self, name: &U120) -> Option<Option<crypto::Hash>> {
+    return self.leaf.read(name);
+  }
   fn write_indx(&mut self, name: Name, pos: u128) {
     return self.indx.write(name, pos);
   }
@@ -1211,11 +1416,44 @@ impl Heap {
   fn get_next(&self) -> u64 {
     return self.next;
   }
+  fn get_stat(&self) -> [u128; 14] {
+    return [
+      self.tick as u128, self.time, self.meta, self.hax0, self.hax1, self.funs as u128,
+      self.dups as u128, self.rwts as u128, self.mana as u128, self.size as u128,
+      self.mcap as u128, self.next as u128, self.rot0, self.rot1,
+    ];
+  }
+  fn set_stat(&mut self, stat: [u128; 14]) {
+    let [tick, time, meta, hax0, hax1, funs, dups, rwts, mana, size, mcap, next, rot0, rot1] = stat;
+    self.tick = tick as u64;
+    self.time = time;
+    self.meta = meta;
+    self.hax0 = hax0;
+    self.hax1 = hax1;
+    self.funs = funs as u64;
+    self.dups = dups as u64;
+    self.rwts = rwts as u64;
+    self.mana = mana as u64;
+    self.size = size as u64;
+    self.mcap = mcap as u64;
+    self.next = next as u64;
+    self.rot0 = rot0;
+    self.rot1 = rot1;
+  }
+  // Every map is absorbed and cleared, including the namespace owners, indexes and statement
+  // hashes, so that they go to the snapshots, are saved, and are undone by rollbacks, like the rest
+  // of the state. Before, these three stayed on the drawing heap forever.
   fn absorb(&mut self, other: &mut Self, overwrite: bool) {
     self.memo.absorb(&mut other.memo, overwrite);
     self.disk.absorb(&mut other.disk, overwrite);
     self.file.absorb(&mut other.file, overwrite);
     self.arit.absorb(&mut other.arit, overwrite);
+    self.ownr.absorb(&mut other.ownr, overwrite);
+    self.msig.absorb(&mut other.msig, overwrite);
+    self.nonc.absorb(&mut other.nonc, overwrite);
+    self.leaf.absorb(&mut other.leaf, overwrite);
+    self.indx.absorb(&mut other.indx, overwrite);
+    self.hash.absorb(&mut other.hash, overwrite);
     self.tick = absorb_u64(self.tick, other.tick, overwrite);
     self.time = absorb_u128(self.time, other.time, overwrite);
     self.meta = absorb_u128(self.meta, other.meta, overwrite);
@@ -1228,6 +1466,8 @@ impl Heap {
     self.size = absorb_u64(self.size, other.size, overwrite);
     self.mcap = absorb_u64(self.mcap, other.mcap, overwrite);
     self.next = absorb_u64(self.next, other.next, overwrite);
+    self.rot0 = absorb_u128(self.rot0, other.rot0, overwrite);
+    self.rot1 = absorb_u128(self.rot1, other.rot1, overwrite);
   }
   fn clear(&mut self) {
     self.uuid = fastrand::u128(..);
@@ -1235,6 +1475,12 @@ impl Heap {
     self.disk.clear();
     self.file.clear();
     self.arit.clear();
+    self.ownr.clear();
+    self.msig.clear();
+    self.nonc.clear();
+    self.leaf.clear();
+    self.indx.clear();
+    self.hash.clear();
     self.tick = U64_NONE;
     self.time = U128_NONE;
     self.meta = U128_NONE;
@@ -1247,24 +1493,62 @@ impl Heap {
     self.size = U64_NONE;
     self.mcap = U64_NONE;
     self.next = U64_NONE;
-  }
-  pub fn serialize(self: &Heap, path: &PathBuf, append: bool) -> std::io::Result<()> {
-    fn open_writer(heap: &Heap, path: &PathBuf, buffer_name: &str, append: bool) -> std::io::Result<File> {
-      let file_path = Heap::buffer_file_path(heap.uuid, buffer_name, path);
-      std::fs::OpenOptions::new()
-        .write(true)
-        .append(append)
-        .create(true)
-        .open(file_path)
-    }
-    self.memo.nodes.disk_serialize(&mut open_writer(self, path, "memo", append)?)?;
-    self.disk.links.disk_serialize(&mut open_writer(self, path, "disk", append)?)?;
-    self.file.funcs.disk_serialize(&mut open_writer(self, path, "file", append)?)?;
-    self.arit.arits.disk_serialize(&mut open_writer(self, path, "arit", append)?)?;
-    self.indx.indxs.disk_serialize(&mut open_writer(self, path, "indx", append)?)?;
-    self.hash.stmt_hashes.disk_serialize(&mut open_writer(self, path, "stmt_hashes", append)?)?;
-    self.ownr.ownrs.disk_serialize(&mut open_writer(self, path, "ownr", append)?)?;
-    let mut stat = open_writer(self, path, "stat", false)?;
+    self.rot0 = U128_NONE;
+    self.rot1 = U128_NONE;
+  }
+  // Serializes the heap into the contents of its buffer files, named as in `HEAP_BUFFERS`, plus
+  // the sections added after the file format was versioned
+  pub fn serialize(&self) -> std::io::Result<Vec<(&'static str, Vec<u8>)>> {
+    fn buffer<T: DiskSer>(name: &'static str, value: &T) -> std::io::Result<(&'static str, Vec<u8>)> {
+      let mut data = Vec::new();
+      value.disk_serialize(&mut data)?;
+      Ok((name, data))
+    }
+    Ok(vec![
+      buffer("memo", &self.memo.nodes)?,
+      buffer("disk", &self.disk.links)?,
+      buffer("file", &self.file.funcs)?,
+      buffer("arit", &self.arit.arits)?,
+      buffer("indx", &self.indx.indxs)?,
+      buffer("stmt_hashes", &self.hash.stmt_hashes)?,
+      buffer("ownr", &self.ownr.ownrs)?,
+      ("stat", self.serialize_stat()?),
+      buffer("msig", &self.msig.msigs)?,
+      buffer("nonc", &self.nonc.noncs)?,
+      buffer("leaf", &self.leaf.leafs)?,
+    ])
+  }
+  // Serializes the heap like `serialize`, but with the entries of each buffer sorted by key, so
+  // that heaps with equal contents have equal buffers
+  pub fn serialize_canonical(&self) -> std::io::Result<Vec<(&'static str, Vec<u8>)>> {
+    fn buffer<K: DiskSer, V: DiskSer, S>(name: &'static str, map: &HashMap<K, V, S>) -> std::io::Result<(&'static str, Vec<u8>)> {
+      let mut entries = Vec::with_capacity(map.len());
+      for (key, val) in map {
+        let mut key_data = Vec::new();
+        key.disk_serialize(&mut key_data)?;
+        let mut val_data = Vec::new();
+        val.disk_serialize(&mut val_data)?;
+        entries.push((key_data, val_data));
+      }
+      entries.sort_unstable();
+      Ok((name, entries.into_iter().flat_map(|(key, val)| key.into_iter().chain(val)).collect()))
+    }
+    Ok(vec![
+      buffer("memo", &self.memo.nodes)?,
+      buffer("disk", &self.disk.links)?,
+      buffer("file", &self.file.funcs)?,
+      buffer("arit", &self.arit.arits)?,
+      buffer("indx", &self.indx.indxs)?,
+      buffer("stmt_hashes", &self.hash.stmt_hashes)?,
+      buffer("ownr", &self.ownr.ownrs)?,
+      ("stat", self.serialize_stat()?),
+      buffer("msig", &self.msig.msigs)?,
+      buffer("nonc", &self.nonc.noncs)?,
+      buffer("leaf", &self.leaf.leafs)?,
+    ])
+  }
+  fn serialize_stat(&self) -> std::io::Result<Vec<u8>> {
+    let mut stat = Vec::new();
     self.tick.disk_serialize(&mut stat)?;
     self.time.disk_serialize(&mut stat)?;
     self.meta.disk_serialize(&mut stat)?;
@@ -1277,31 +1561,53 @@ impl Heap {
     self.size.disk_serialize(&mut stat)?;
     self.mcap.disk_serialize(&mut stat)?;
     self.next.disk_serialize(&mut stat)?;
-    Ok(())
+    self.rot0.disk_serialize(&mut stat)?;
+    self.rot1.disk_serialize(&mut stat)?;
+    Ok(stat)
   }
+  // Loads a heap from its file, checking its header and checksums. Heaps saved before the file
+  // format was versioned, with a file per buffer, are loaded as version 0 and migrated.
   pub fn deserialize(uuid: u128, path: &PathBuf) -> std::io::Result<Heap> {
-    fn open_reader(uuid: u128, path: &PathBuf, buffer_name: &str) -> std::io::Result<File> {
-      let file_path = Heap::buffer_file_path(uuid, buffer_name, path);
-      std::fs::OpenOptions::new()
-        .read(true)
-        .open(file_path)
-    }
-    fn read_hash_map_from_file<K: DiskSer + Eq + std::hash::Hash + crate::NoHashHasher::IsEnabled, V: DiskSer>
-      (uuid: u128, path: &PathBuf, buffer_name: &str) -> std::io::Result<HashMap<K, V, std::hash::BuildHasherDefault<NoHashHasher<K>>>> {
-      HashMap::disk_deserialize(&mut open_reader(uuid, path, buffer_name)?)?
+    let sections = match std::fs::read(Heap::file_path(uuid, path)) {
+      Ok(data) => {
+        let (file_uuid, sections) = persistence::decode_versioned(&persistence::HEAP_MAGIC, &data)?;
+        if file_uuid != uuid {
+          let msg = format!("Heap file of {:0>32x} has uuid {:0>32x}.", uuid, file_uuid);
+          return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+        }
+        sections
+      }
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        let mut sections = vec![];
+        for name in HEAP_BUFFERS {
+          sections.push((name.to_string(), std::fs::read(Heap::buffer_file_path(uuid, name, path))?));
+        }
+        persistence::migrate_sections(0, sections)?
+      }
+      Err(err) => return Err(err),
+    };
+    Heap::from_sections(uuid, &sections)
+  }
+  pub fn from_sections(uuid: u128, sections: &persistence::Sections) -> std::io::Result<Heap> {
+    fn read_hash_map<K: DiskSer + Eq + std::hash::Hash + crate::NoHashHasher::IsEnabled, V: DiskSer>
+      (sections: &persistence::Sections, name: &str) -> std::io::Result<HashMap<K, V, std::hash::BuildHasherDefault<NoHashHasher<K>>>> {
+      HashMap::disk_deserialize(&mut persistence::get_section(sections, name)?)?
         .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
     }
-    fn read_num<T: DiskSer>(file: &mut File) -> std::io::Result<T>{
-      T::disk_deserialize(file)?.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
-    }
-    let memo = Nodes { nodes: read_hash_map_from_file(uuid, path, "memo")? };
-    let disk = Store { links: read_hash_map_from_file(uuid, path, "disk")? };
-    let file = Funcs { funcs: read_hash_map_from_file(uuid, path, "file")? };
-    let arit = Arits { arits: read_hash_map_from_file(uuid, path, "arit")? };
-    let indx = Indxs { indxs: read_hash_map_from_file(uuid, path, "indx")? };
-    let hash = Hashs { stmt_hashes: read_hash_map_from_file(uuid, path, "stmt_hashes")? };    
-    let ownr = Ownrs { ownrs: read_hash_map_from_file(uuid, path, "ownr")? };
-    let mut stat = open_reader(uuid, path, "stat")?;
+    fn read_num<T: DiskSer>(stat: &mut &[u8]) -> std::io::Result<T>{
+      T::disk_deserialize(stat)?.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    }
+    let memo = Nodes { nodes: read_hash_map(sections, "memo")? };
+    let disk = Store { links: read_hash_map(sections, "disk")? };
+    let file = Funcs { funcs: read_hash_map(sections, "file")? };
+    let arit = Arits { arits: read_hash_map(sections, "arit")? };
+    let indx = Indxs { indxs: read_hash_map(sections, "indx")? };
+    let hash = Hashs { stmt_hashes: read_hash_map(sections, "stmt_hashes")? };
+    let ownr = Ownrs { ownrs: read_hash_map(sections, "ownr")? };
+    let msig = Msigs { msigs: read_hash_map(sections, "msig")? };
+    let nonc = Noncs { noncs: read_hash_map(sections, "nonc")? };
+    let leaf = Leafs { leafs: read_hash_map(sections, "leaf")? };
+    let mut stat = persistence::get_section(sections, "stat")?;
     let tick = read_num(&mut stat)?;
     let time = read_num(&mut stat)?;
     let meta = read_num(&mut stat)?;
@@ -1314,25 +1620,27 @@ impl Heap {
     let size = read_num(&mut stat)?;
     let mcap = read_num(&mut stat)?;
     let next = read_num(&mut stat)?;
-    Ok( Heap { uuid, memo, disk, file, arit, indx, hash, ownr, tick, time, meta, hax0, hax1, funs, dups, rwts,  mana, size, mcap, next })
+    let rot0 = read_num(&mut stat)?;
+    let rot1 = read_num(&mut stat)?;
+    Ok( Heap { uuid, memo, disk, file, arit, indx, hash, ownr, msig, nonc, leaf, tick, time, meta, hax0, hax1, funs, dups, rwts,  mana, size, mcap, next, rot0, rot1 })
   }
 
+  // Builds the contents of the heap file, with a versioned header and a checksummed section per
+  // buffer
+  pub fn encode(&self) -> std::io::Result<Vec<u8>> {
+    Ok(persistence::encode_versioned(&persistence::HEAP_MAGIC, self.uuid, &self.serialize()?))
+  }
+  // Writes the heap file to `path`, synchronously
+  pub fn save_to(&self, path: &PathBuf) -> std::io::Result<()> {
+    persistence::write_atomic(&Heap::file_path(self.uuid, path), &self.encode()?)
+  }
+  pub fn file_path(uuid: u128, path: &PathBuf) -> PathBuf {
+    path.join(format!("{:0>32x}.heap.bin", uuid))
+  }
+  // Path of a buffer file of the legacy (version 0) format
   fn buffer_file_path(uuid: u128, buffer_name: &str, path: &PathBuf) -> PathBuf {
     path.join(format!("{:0>32x}.{}.bin", uuid, buffer_name))
   }
-  fn delete_buffer(&self, uuid: u128, buffer_name: &str, path: &PathBuf) -> std::io::Result<()> {
-    std::fs::remove_file(Heap::buffer_file_path(uuid, buffer_name, path))
-  }
-  fn delete_buffers(&mut self, path: &PathBuf) -> std::io::Result<()> {
-    self.delete_buffer(self.uuid, "memo", path)?;
-    self.delete_buffer(self.uuid, "disk", path)?;
-    self.delete_buffer(self.uuid, "file", path)?;
-    self.delete_buffer(self.uuid, "arit", path)?;
-    self.delete_buffer(self.uuid, "indx", path)?;
-    self.delete_buffer(self.uuid, "ownr", path)?;
-    self.delete_buffer(self.uuid, "stat", path)?;
-    return Ok(());
-  }
   pub fn get_fn_count(&self) -> u64 {
     return self.file.funcs.len() as u64
   }
@@ -1352,6 +1660,9 @@ pub fn init_heap() -> Heap {
     file: Funcs { funcs: init_name_map() },
     arit: Arits { arits: init_name_map() },
     ownr: Ownrs { ownrs: init_name_map() },
+    msig: Msigs { msigs: init_u120_map() },
+    nonc: Noncs { noncs: init_u120_map() },
+    leaf: Leafs { leafs: init_u120_map() },
     indx: Indxs { indxs: init_name_map() },
     hash: Hashs { stmt_hashes: init_u128_map() },
     tick: U64_NONE,
@@ -1366,6 +1677,8 @@ pub fn init_heap() -> Heap {
     size: U64_NONE,
     mcap: U64_NONE,
     next: U64_NONE,
+    rot0: U128_NONE,
+    rot1: U128_NONE,
   }
 }
 
@@ -1448,7 +1761,7 @@ impl Arits {
 
 impl Ownrs {
   fn write(&mut self, name: Name, val: U120) {
-    self.ownrs.entry(name).or_insert(val);
+    self.ownrs.insert(name, val);
   }
   fn read(&self, name: &Name) -> Option<U120> {
     return self.ownrs.get(name).map(|x| *x);
@@ -1465,6 +1778,64 @@ impl Ownrs {
   }
 }
 
+impl Msigs {
+  fn write(&mut self, id: U120, msig: Multisig) {
+    self.msigs.entry(id).or_insert(msig);
+  }
+  fn read(&self, id: &U120) -> Option<Multisig> {
+    return self.msigs.get(id).cloned();
+  }
+  fn clear(&mut self) {
+    self.msigs.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (id, msig) in other.msigs.drain() {
+      if overwrite || !self.msigs.contains_key(&id) {
+        self.msigs.insert(id, msig);
+      }
+    }
+  }
+}
+
+impl Leafs {
+  // A state that was taken is stored as a zero hash, which hides the hash on past heaps
+  fn write(&mut self, name: U120, leaf: Option<crypto::Hash>) {
+    self.leafs.insert(name, leaf.unwrap_or(crypto::Hash([0; 32])));
+  }
+  fn read(&self, name: &U120) -> Option<Option<crypto::Hash>> {
+    return self.leafs.get(name).map(|leaf| if leaf.0 == [0; 32] { None } else { Some(leaf.clone()) });
+  }
+  fn clear(&mut self) {
+    self.leafs.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (name, leaf) in other.leafs.drain() {
+      if overwrite || !self.leafs.contains_key(&name) {
+        self.leafs.insert(name, leaf);
+      }
+    }
+  }
+}
+
+impl Noncs {
+  fn write(&mut self, subj: U120, nonce: u64) {
+    self.noncs.insert(subj, nonce);
+  }
+  fn read(&self, subj: &U120) -> Option<u64> {
+    return self.noncs.get(subj).copied();
+  }
+  fn clear(&mut self) {
+    self.noncs.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (subj, nonce) in other.noncs.drain() {
+      if overwrite || !self.noncs.contains_key(&subj) {
+        self.noncs.insert(subj, nonce);
+      }
+    }
+  }
+}
+
 impl Indxs {
   fn write(&mut self, name: Name, pos: u128) {
     self.indxs.insert(name, pos);
@@ -1505,24 +1876,216 @@ impl Hashs {
 
 
 pub fn init_runtime(heaps_path: PathBuf, init_stmts: &[Statement]) -> Runtime {
+  init_runtime_with(heaps_path, init_stmts, RollbackConfig::default())
+}
+
+pub fn init_runtime_with(heaps_path: PathBuf, init_stmts: &[Statement], conf: RollbackConfig) -> Runtime {
+  let mut rt = empty_runtime(heaps_path, conf);
+  rt.run_statements(init_stmts, true, false);
+  rt.commit();
+  rt
+}
+
+// Loads the state saved on `heaps_path` by a previous run. If there is none, or it can't be read,
+// starts from `init_stmts`, like `init_runtime_with`.
+pub fn restore_runtime(heaps_path: PathBuf, init_stmts: &[Statement], conf: RollbackConfig) -> Runtime {
+  let mut rt = empty_runtime(heaps_path.clone(), conf.clone());
+  match rt.restore_state() {
+    Ok(()) if !matches!(*rt.back, Rollback::Nil) => {
+      return rt;
+    }
+    Ok(()) => {}
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+    Err(err) => {
+      eprintln!("WARN: Could not restore the runtime state: {}", err);
+    }
+  }
+  std::mem::drop(rt);
+  init_runtime_with(heaps_path, init_stmts, conf)
+}
+
+fn empty_runtime(heaps_path: PathBuf, conf: RollbackConfig) -> Runtime {
+  conf.validate().expect("Invalid rollback config.");
   // Default runtime store path
   std::fs::create_dir_all(&heaps_path).unwrap(); // TODO: handle unwrap
   let mut heap = Vec::new();
-  for i in 0 .. MAX_HEAPS {
-    heap.push(init_heap());
+  let mut logs = Vec::new();
+  for i in 0 .. conf.max_heaps {
+    heap.push(Arc::new(init_heap()));
+    logs.push(Vec::new());
   }
-  let mut rt = Runtime {
+  Runtime {
     heap,
     draw: 0,
     curr: 1,
-    nuls: (2 .. MAX_HEAPS).collect(),
+    nuls: (2 .. conf.max_heaps).collect(),
     back: Arc::new(Rollback::Nil),
+    logs,
+    conf,
     path: heaps_path,
-  };
+    save: PersistWorker::spawn(),
+    last_compaction: None,
+  }
+}
 
-  rt.run_statements(init_stmts, true, false);
-  rt.commit();
+// State root
+// ----------
+
+// The state root is a commitment to the contents of the runtime: stored states, function codes,
+// arities, namespace owners and their key sets, nonces of subjects, and statement indexes and
+// hashes. It's the sum, modulo 2^256, of a hash of each entry, so it is updated as entries are
+// written, and doesn't depend on the order they were written in, nor on how the state is split
+// between heaps.
+
+const ROOT_DISK: u8 = 0;
+const ROOT_FILE: u8 = 1;
+const ROOT_ARIT: u8 = 2;
+const ROOT_OWNR: u8 = 3;
+const ROOT_INDX: u8 = 4;
+const ROOT_HASH: u8 = 5;
+const ROOT_MSIG: u8 = 6;
+const ROOT_NONC: u8 = 7;
+
+// Hash of an entry of the state
+fn root_hash(kind: u8, key: u128, value: &[u8]) -> crypto::Hash {
+  let mut bytes = vec![kind];
+  bytes.extend_from_slice(&key.to_le_bytes());
+  bytes.extend_from_slice(value);
+  crypto::Hash::keccak256_from_bytes(&bytes)
+}
+
+fn root_leaf(kind: u8, key: u128, value: &[u8]) -> U256 {
+  U256::from_little_endian(&root_hash(kind, key, value).0)
+}
+
+fn disk_hash(name: U120, state: &Term) -> crypto::Hash {
+  root_hash(ROOT_DISK, *name, &util::bitvec_to_bytes(&state.proto_serialized()))
+}
+
+fn file_leaf(name: Name, func: &CompFunc) -> U256 {
+  root_leaf(ROOT_FILE, *name, &util::bitvec_to_bytes(&func.func.proto_serialized()))
+}
+
+fn arit_leaf(name: Name, arit: u64) -> U256 {
+  root_leaf(ROOT_ARIT, *name, &arit.to_le_bytes())
+}
+
+fn ownr_leaf(name: Name, ownr: U120) -> U256 {
+  root_leaf(ROOT_OWNR, *name, &ownr.to_le_bytes())
+}
+
+fn msig_leaf(id: U120, msig: &Multisig) -> U256 {
+  let mut data = Vec::new();
+  msig.disk_serialize(&mut data).expect("Serializes to memory");
+  root_leaf(ROOT_MSIG, *id, &data)
+}
+
+fn nonc_leaf(subj: U120, nonce: u64) -> U256 {
+  root_leaf(ROOT_NONC, *subj, &nonce.to_le_bytes())
+}
+
+fn indx_leaf(name: Name, pos: u128) -> U256 {
+  root_leaf(ROOT_INDX, *name, &pos.to_le_bytes())
+}
+
+fn hash_leaf(pos: u128, hash: &crypto::Hash) -> U256 {
+  root_leaf(ROOT_HASH, pos, &hash.0)
+}
+
+// Compaction
+// ----------
+
+// Allocation jumps to random indexes once memory is fragmented, and `collect` frees nodes where
+// they are, so long-running runtimes end up with live nodes scattered across the memory. When a
+// snapshot is taken, `compact` slides the nodes reachable from the stored states towards index 0,
+// in address order, skipping words used by anything else, and fixes the pointers to them. Only
+// memory words change: `size`, `mana` and the state root stay the same.
+
+/// Statistics of a compaction. Spans count the words from index 0 to the end of the last live
+/// node, so `1 - live / span` is the fraction of the memory the live nodes leave fragmented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+  pub tick: u64,        // tick of the snapshot it ran at
+  pub nodes: u64,       // live nodes
+  pub live: u64,        // words of the live nodes
+  pub moved: u64,       // nodes relocated
+  pub span_before: u64, // span of the live nodes before compacting
+  pub span_after: u64,  // span of the live nodes after compacting
+  pub written: u64,     // words written to the drawing heap
+}
+
+// Words of the node a cell points to, if it points to one
+fn node_size(rt: &Runtime, cell: RawCell) -> Option<u64> {
+  match get_tag(cell) {
+    DP0 | DP1 => Some(3),
+    VAR | LAM | APP | SUP | OP2 => Some(2),
+    CTR | FUN => rt.get_arity(&Name::new_unsafe(get_ext(cell))).filter(|arity| *arity > 0),
+    _ => None,
+  }
+}
+
+// Whether a cell holds a memory position, which compaction must update
+fn has_pointer(rt: &Runtime, cell: RawCell) -> bool {
+  get_tag(cell) == ARG || node_size(rt, cell).is_some()
+}
+
+// Builds the contents of the file that lists the saved heaps, from the newest to the oldest
+fn encode_state_metadata(keeps: &[u128], lifes: &[u128], uuids: &[u128]) -> Vec<u8> {
+  let sections = [
+    ("keeps", util::u128s_to_u8s(keeps)),
+    ("lifes", util::u128s_to_u8s(lifes)),
+    ("uuids", util::u128s_to_u8s(uuids)),
+  ];
+  persistence::encode_versioned(&persistence::META_MAGIC, 0, &sections)
+}
+
+// Reads the file that lists the saved heaps, or the files of the legacy format
+fn read_state_metadata(path: &PathBuf) -> std::io::Result<persistence::Sections> {
+  match std::fs::read(path.join(STATE_FILE)) {
+    Ok(data) => Ok(persistence::decode_versioned(&persistence::META_MAGIC, &data)?.1),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+      let mut sections = vec![];
+      for (name, file) in ["keeps", "lifes", "uuids"].iter().zip(LEGACY_STATE_FILES) {
+        sections.push((name.to_string(), std::fs::read(path.join(file))?));
+      }
+      persistence::migrate_sections(0, sections)
+    }
+    Err(err) => Err(err),
+  }
+}
 
+/// Reads the heaps of the state saved on `heaps_path`, from the oldest to the newest.
+pub fn read_saved_heaps(heaps_path: &PathBuf) -> std::io::Result<Vec<Heap>> {
+  let sections = read_state_metadata(heaps_path)?;
+  let uuids = util::u8s_to_u128s(persistence::get_section(&sections, "uuids")?);
+  let mut heaps = Vec::with_capacity(uuids.len());
+  for uuid in uuids.into_iter().rev() {
+    heaps.push(Heap::deserialize(uuid, heaps_path)?);
+  }
+  Ok(heaps)
+}
+
+/// Merges heaps, from the oldest to the newest, into a single heap with the state of the newest.
+pub fn merge_heaps(heaps: impl IntoIterator<Item = Heap>) -> Heap {
+  let mut merged = init_heap();
+  for mut heap in heaps {
+    merged.absorb(&mut heap, true);
+  }
+  merged
+}
+
+/// Saves `heap` on `heaps_path` as the only snapshot of the runtime state, which is then loaded
+/// by `restore_runtime`.
+pub fn write_saved_state(heaps_path: &PathBuf, heap: &Heap) -> std::io::Result<()> {
+  std::fs::create_dir_all(heaps_path)?;
+  heap.save_to(heaps_path)?;
+  persistence::write_atomic(&heaps_path.join(STATE_FILE), &encode_state_metadata(&[0], &[0], &[heap.uuid]))
+}
+
+/// Builds a runtime whose state is `heap`, to inspect a saved state without restoring it.
+pub fn runtime_from_heap(heaps_path: PathBuf, heap: Heap) -> Runtime {
+  let mut rt = empty_runtime(heaps_path, RollbackConfig::default());
+  rt.heap[rt.curr as usize] = Arc::new(heap);
   rt
 }
 
@@ -1536,13 +2099,15 @@ impl Runtime {
   }
 
   pub fn define_function(&mut self, name: Name, func: CompFunc, stmt_index: Option<usize>, stmt_hash: crypto::Hash) {
-    self.get_heap_mut(self.draw).write_arit(name, func.arity);
+    self.set_arity(name, func.arity);
+    let old = self.get_with(None, None, |heap| heap.read_file(&name)).map(|old| file_leaf(name, &old));
+    self.update_root(old, Some(file_leaf(name, &func)));
     self.get_heap_mut(self.draw).write_file(name, Arc::new(func));
     self.save_stmt_name(name, stmt_index, stmt_hash);
   }
 
   pub fn define_constructor(&mut self, name: Name, arity: u64, stmt_index: Option<usize>, stmt_hash: crypto::Hash) {
-    self.get_heap_mut(self.draw).write_arit(name, arity);
+    self.set_arity(name, arity);
     self.save_stmt_name(name, stmt_index, stmt_hash);
   }
 
@@ -1555,7 +2120,11 @@ impl Runtime {
     if let Some(idx) = stmt_index {
       let tick = self.get_tick() as u128;
       let pos = tick.wrapping_shl(60) | (idx as u128); //TODO: refactor to use less bits
+      let old = self.get_with(None, None, |heap| heap.read_indx(&name)).map(|old| indx_leaf(name, old));
+      self.update_root(old, Some(indx_leaf(name, pos)));
       self.get_heap_mut(self.draw).write_indx(name, pos);
+      let old = self.get_with(None, None, |heap| heap.read_stmt_hash(&pos).cloned()).map(|old| hash_leaf(pos, &old));
+      self.update_root(old, Some(hash_leaf(pos, &stmt_hash)));
       self.get_heap_mut(self.draw).write_stmt_hash(pos, stmt_hash);
     }
   }
@@ -1665,23 +2234,21 @@ impl Runtime {
     return &self.heap[index as usize];
   }
 
+  // Copies the heap first if the persistence worker is still saving it
   pub fn get_heap_mut(&mut self, index: u64) -> &mut Heap {
-    return &mut self.heap[index as usize];
+    return Arc::make_mut(&mut self.heap[index as usize]);
   }
 
   // Copies the contents of the absorbed heap into the absorber heap
   fn absorb_heap(&mut self, absorber: u64, absorbed: u64, overwrite: bool) {
-    // FIXME: can we satisfy the borrow checker without using unsafe pointers?
-    unsafe {
-      let a_arr = &mut self.heap as *mut Vec<Heap>;
-      let a_ref = &mut *(&mut (*a_arr)[absorber as usize] as *mut Heap);
-      let b_ref = &mut *(&mut (*a_arr)[absorbed as usize] as *mut Heap);
-      a_ref.absorb(b_ref, overwrite);
-    }
+    let (a, b) = (absorber as usize, absorbed as usize);
+    let (low, high) = self.heap.split_at_mut(a.max(b));
+    let (a_ref, b_ref) = if a < b { (&mut low[a], &mut high[0]) } else { (&mut high[0], &mut low[b]) };
+    Arc::make_mut(a_ref).absorb(Arc::make_mut(b_ref), overwrite);
   }
 
   fn clear_heap(&mut self, index: u64) {
-    self.heap[index as usize].clear();
+    self.get_heap_mut(index).clear();
   }
 
   fn undo(&mut self) {
@@ -1689,10 +2256,36 @@ impl Runtime {
   }
 
   fn draw(&mut self) {
+    self.journal();
     self.absorb_heap(self.curr, self.draw, true);
     self.clear_heap(self.draw);
   }
 
+  // Saves what the drawing heap will overwrite on the current heap, so that
+  // the tick can be undone
+  fn journal(&mut self) {
+    let curr = &self.heap[self.curr as usize];
+    let draw = &self.heap[self.draw as usize];
+    let logs = &mut self.logs[self.curr as usize];
+    if !matches!(logs.last(), Some(log) if log.tick == U64_NONE) {
+      logs.push(Journal::new(curr));
+    }
+    logs.last_mut().unwrap().record(curr, draw);
+  }
+
+  // Undoes the ticks after `tick` on a heap, if its journals cover them all
+  fn rewind(&mut self, index: u64, tick: u64) -> bool {
+    let logs = &mut self.logs[index as usize];
+    match logs.first() {
+      Some(log) if log.tick <= tick + 1 => {}
+      _ => return false,
+    }
+    while matches!(logs.last(), Some(log) if log.tick > tick) {
+      logs.pop().unwrap().undo(Arc::make_mut(&mut self.heap[index as usize]));
+    }
+    return true;
+  }
+
   // IO
   // --
 
@@ -1715,7 +2308,7 @@ impl Runtime {
             let cont = ask_arg(self, term, 0);
             if let Some(state) = self.read_disk(subject) {
               if state != RawCell(U128_NONE) {
-                self.write_disk(subject, RawCell(U128_NONE));
+                self.write_disk(subject, RawCell(U128_NONE), mana)?;
                 let cont = alloc_app(self, cont, state);
                 let done = self.run_io(subject, subject, cont, mana);
                 clear(self, host, 1);
@@ -1735,7 +2328,7 @@ impl Runtime {
             //println!("- IO_SAVE subject is {} {}", u128_to_name(subject), subject);
             let expr = ask_arg(self, term, 0);
             let save = self.compute(expr, mana)?;
-            self.write_disk(subject, save);
+            self.write_disk(subject, save, mana)?;
             let cont = ask_arg(self, term, 1);
             let cont = alloc_app(self, cont, Num(0));
             let done = self.run_io(subject, subject, cont, mana);
@@ -1812,6 +2405,16 @@ impl Runtime {
             clear(self, get_loc(term, 0), 2);
             return done;
           }
+          IO_NONC => {
+            let subj = ask_arg(self, term, 0);
+            let cont = ask_arg(self, term, 1);
+            let subj = self.check_num(subj, mana)?;
+            let cont = alloc_app(self, cont, Num(self.get_nonce(&subj) as u128));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 2);
+            return done;
+          }
           IO_SUBJ => {
             let cont = ask_arg(self, term, 0);
             let cont = alloc_app(self, cont, Num(*subject));
@@ -1890,13 +2493,45 @@ impl Runtime {
   /// - If there is a signature, but subject cannot be retrieved correctly,
   ///   returns `1`.
   /// - Else, returns the subject.
-  pub fn get_subject(&mut self, sign: &Option<crypto::Signature>, hash: &crypto::Hash) -> U120 {
-    match sign {
+  pub fn get_subject(&mut self, sign: &[crypto::Signature], hash: &crypto::Hash) -> U120 {
+    match sign.first() {
       None       => U120::from_u128_unchecked(0),
       Some(sign) => sign.signer_name(hash).map(|x| U120::from_u128_unchecked(*x)).unwrap_or_else(|| U120::from_u128_unchecked(1)),
     }
   }
 
+  /// Gets the subjects of all signatures, as `get_subject` gets the first one.
+  pub fn get_subjects(&mut self, sign: &[crypto::Signature], hash: &crypto::Hash) -> Vec<U120> {
+    if sign.is_empty() {
+      return vec![U120::from_u128_unchecked(0)];
+    }
+    sign.iter().map(|sign| self.get_subject(std::slice::from_ref(sign), hash)).collect()
+  }
+
+  // Many signatures must be sorted by signer, without repeats, so the same set
+  // can't be published again in another order, with another subject
+  fn check_signers(sign: &[crypto::Signature], hash: &crypto::Hash) -> Result<(), String> {
+    if sign.len() > 1 {
+      let names: Vec<_> = sign.iter().map(|sign| sign.signer_name(hash).map(|name| *name)).collect();
+      if names.contains(&None) || names.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err("Signatures must be sorted by signer, without repeats.".to_string());
+      }
+    }
+    Ok(())
+  }
+
+  // Are these subjects the owner, or enough keys of it?
+  fn is_owner(&self, subjs: &[U120], owner: Option<U120>) -> bool {
+    match owner {
+      None => false,
+      Some(owner) if owner == NO_OWNER => false,
+      Some(owner) => match self.get_multisig(&owner) {
+        Some(msig) => msig.signed_by(subjs),
+        None => subjs.contains(&owner),
+      },
+    }
+  }
+
   pub fn check_num(&mut self, ptr: RawCell, mana: u64) -> Result<U120, RuntimeError> {
     let num = self.compute(ptr, mana)?;
     match get_tag(num) {
@@ -1913,8 +2548,8 @@ impl Runtime {
     }
   }
 
-  // Can this subject deploy this name?
-  pub fn can_deploy(&mut self, subj: U120, name: &Name) -> bool {
+  // Can these subjects deploy this name?
+  pub fn can_deploy(&mut self, subjs: &[U120], name: &Name) -> bool {
     if name.is_empty() {
       // No one can deploy the empty name
       false
@@ -1926,21 +2561,21 @@ impl Runtime {
         }
         Some(namespace) => {
           // Only owner can deploy on its namespace
-          Some(subj) == self.get_owner(&namespace)
+          self.is_owner(subjs, self.get_owner(&namespace))
         }
       }
     }
   }
 
-  // Can this subject register this namespace?
-  pub fn can_register(&mut self, subj: U120, name: &Name) -> bool {
+  // Can these subjects register this namespace?
+  pub fn can_register(&mut self, subjs: &[U120], name: &Name) -> bool {
     if name.is_empty() {
       // Anyone can register the empty namespace (should happen on Genesis Block)
       true
     } else {
       // Only namespace owner can register a sub-namespace
       let namespace = get_namespace(*name).unwrap_or(Name::new_unsafe(0));
-      Some(subj) == self.get_owner(&namespace)
+      self.is_owner(subjs, self.get_owner(&namespace))
     }
   }
 
@@ -1968,8 +2603,9 @@ impl Runtime {
         if self.exists(name) {
           return error(self, "fun", format!("Can't redefine '{}'.", name));
         }
-        let subj = self.get_subject(&sign, &hash);
-        if !(self.can_deploy(subj, name) || sudo) {
+        let subj = self.get_subject(sign, &hash);
+        let subjs = self.get_subjects(sign, &hash);
+        if !(self.can_deploy(&subjs, name) || sudo) {
           return error(self, "fun", format!("Subject '#x{:0>30x}' not allowed to deploy '{}'.", *subj, name));
         }
         handle_runtime_err(self, "fun", check_func(&func))?;
@@ -1983,7 +2619,8 @@ impl Runtime {
           let state = handle_runtime_err(self, "fun", state)?;
           let state = self.compute(state, self.get_mana_limit());
           let state = handle_runtime_err(self, "fun", state)?;
-          self.write_disk(U120::from(name), state);
+          let done = self.write_disk(U120::from(name), state, self.get_mana_limit());
+          handle_runtime_err(self, "fun", done)?;
         }
         let args = args.iter().map(|x| *x).collect::<Vec<_>>();
         StatementInfo::Fun { name, args }
@@ -1992,8 +2629,9 @@ impl Runtime {
         if self.exists(name) {
           return error(self, "ctr", format!("Can't redefine '{}'.", name));
         }
-        let subj = self.get_subject(&sign, &hash);
-        if !(self.can_deploy(subj, name) || sudo) {
+        let subj = self.get_subject(sign, &hash);
+        let subjs = self.get_subjects(sign, &hash);
+        if !(self.can_deploy(&subjs, name) || sudo) {
           return error(self, "ctr", format!("Subject '#x{:0>30x}' not allowed to deploy '{}'.", *subj, name));
         }
         if args.len() > 16 {
@@ -2004,13 +2642,21 @@ impl Runtime {
         let args = args.iter().map(|x| *x).collect::<Vec<_>>();
         StatementInfo::Ctr { name, args }
       }
-      Statement::Run { expr, sign } => {
+      Statement::Run { expr, nonce, until, sign } => {
         let mana_ini = self.get_mana();
         let mana_lim = if !sudo { self.get_mana_limit() } else { u64::MAX }; // ugly
         let size_ini = self.get_size();
         let size_lim = self.get_size_limit();
         handle_runtime_err(self, "run", check_term(&expr))?; 
-        let subj = self.get_subject(&sign, &hash);
+        let subj = self.get_subject(sign, &hash);
+        // The nonce is consumed before running, so the expression sees the next one
+        if let Err(err) = Runtime::check_signers(sign, &hash) {
+          return error(self, "run", err);
+        }
+        let signer = sign.first().and_then(|sign| sign.signer_name(&hash)).map(|name| U120::from_u128_unchecked(*name));
+        if let Err(err) = self.check_guard(signer, *nonce, *until) {
+          return error(self, "run", err);
+        }
         let host = self.alloc_term(expr);
         let host = handle_runtime_err(self, "run", host)?;
         let done = self.run_io(subj, U120::from_u128_unchecked(0), host, mana_lim);
@@ -2051,19 +2697,55 @@ impl Runtime {
         // TODO: save run to statement array?
       }
       Statement::Reg { name, ownr, sign } => {
-        let ownr = *ownr;
-
         if self.exists(name) {
           return error(self, "run", format!("Can't redefine '{}'.", name));
         }
         let subj = self.get_subject(sign, &hash);
-        if !(self.can_register(subj, name) || sudo) {
+        let subjs = self.get_subjects(sign, &hash);
+        if !(self.can_register(&subjs, name) || sudo) {
           return error(self, "run", format!("Subject '{}' not allowed to register '{}'.", subj, name));
         }
+        if let Owner::Msig(msig) = ownr {
+          if let Err(err) = msig.check() {
+            return error(self, "run", err);
+          }
+        }
         let name = *name;
+        let id = ownr.id();
         self.define_register(name, stmt_index, hash);
-        self.set_owner(name, ownr);
-        StatementInfo::Reg { name, ownr }
+        if let Owner::Msig(msig) = ownr {
+          self.set_multisig(id, msig.clone());
+        }
+        self.set_owner(name, id);
+        StatementInfo::Reg { name, ownr: id }
+      }
+      Statement::Own { name, ownr, nonce, until, sign } => {
+        let subj = self.get_subject(sign, &hash);
+        let subjs = self.get_subjects(sign, &hash);
+        let owner = match self.get_owner(name) {
+          None => return error(self, "own", format!("Namespace '{}' is not registered.", name)),
+          Some(owner) => owner,
+        };
+        if !(self.is_owner(&subjs, Some(owner)) || sudo) {
+          return error(self, "own", format!("Subject '{}' not allowed to transfer '{}'.", subj, name));
+        }
+        if let Some(Owner::Msig(msig)) = ownr {
+          if let Err(err) = msig.check() {
+            return error(self, "own", err);
+          }
+        }
+        // The nonce is the owner's, not the signers', as a set of signatures
+        // can be reordered to change the subject
+        if let Err(err) = self.check_guard(Some(owner), Some(*nonce), *until) {
+          return error(self, "own", err);
+        }
+        let name = *name;
+        let id = ownr.as_ref().map_or(NO_OWNER, |ownr| ownr.id());
+        if let Some(Owner::Msig(msig)) = ownr {
+          self.set_multisig(id, msig.clone());
+        }
+        self.set_owner(name, id);
+        StatementInfo::Own { name, ownr: id }
       }
     };
     if !silent {
@@ -2097,60 +2779,103 @@ impl Runtime {
 
   /// Saves past states for rollback.
   pub fn commit(&mut self) {
+    if self.conf.compact && self.at_snapshot() {
+      self.compact();
+    }
     self.draw();
+    let tick = self.get_tick();
+    if let Some(log) = self.logs[self.curr as usize].last_mut() {
+      log.tick = tick;
+    }
     self.snapshot();
   }
 
   pub fn snapshot(&mut self) {
     //println!("tick self.curr={}", self.curr);
-    let (included, absorber, deleted, rollback) = rollback_push(self.curr, self.back.clone(), 0);
+    let (included, absorber, deleted, rollback) = rollback_push(self.curr, self.back.clone(), 0, &self.conf);
     // println!("- tick={} self.curr={}, included={:?} absorber={:?} deleted={:?} rollback={}", self.get_tick(), self.curr, included, absorber, deleted, view_rollback(&self.back));
     self.back = rollback;
     // println!(" - back {}", view_rollback(&self.back));
     if included {
-      self.save_state_metadata().expect("Error saving state metadata.");
-      let path = &self.get_dir_path();
-      // let _ = &self.heap[self.curr as usize].serialize(path, true).expect("Error saving buffers."); // heap persistence disabled
+      // Heaps are written before the metadata that lists them, and deleted after it
+      self.save_heap(self.curr);
       if let Some(deleted) = deleted {
         if let Some(absorber) = absorber {
+          for log in &mut self.logs[absorber as usize] {
+            log.rebase(&self.heap[deleted as usize]);
+          }
           self.absorb_heap(absorber, deleted, false);
-          // let _ = self.heap[absorber as usize].serialize(path, false).expect("Couldn't append buffers."); // heap persistence disabled
+          self.save_heap(absorber);
         }
-        // self.heap[deleted as usize].delete_buffers(path).expect("Couldn't delete buffers."); // heap persistence disabled
+        self.save_state_metadata();
+        self.delete_heap(deleted);
         self.clear_heap(deleted);
+        self.logs[deleted as usize].clear();
         self.curr = deleted;
       } else if let Some(empty) = self.nuls.pop() {
+        self.save_state_metadata();
         self.curr = empty;
       } else {
         //println!("- {} {} {:?} {}", self.draw, self.curr, self.nuls, view_rollback(&self.back));
         panic!("Not enough heaps.");
       }
+      // Only the latest snapshot keeps its journals
+      if let Rollback::Cons { head, .. } = &*self.back {
+        for (index, logs) in self.logs.iter_mut().enumerate() {
+          if index as u64 != *head {
+            logs.clear();
+          }
+        }
+      }
     }
   }
 
-  // Rolls back to the earliest state before or equal `tick`
+  // Rolls back to `tick`. Recent ticks are restored exactly, by undoing the journals of the current
+  // heap and of the latest snapshot. Otherwise, rolls back to the latest snapshot before `tick`.
   pub fn rollback(&mut self, tick: u64) {
+    self.undo();
     // If target tick is older than current tick
     if tick < self.get_tick() {
       eprintln!("- rolling back from {} to {}", self.get_tick(), tick);
+      let undone = self.get_tick() - tick;
+      if self.rewind(self.curr, tick) {
+        if let Rollback::Cons { keep, life, head, tail } = &*self.back {
+          self.back = Arc::new(Rollback::Cons { keep: keep.saturating_sub(undone), life: *life, head: *head, tail: tail.clone() });
+        }
+        self.ensure_root();
+        return;
+      }
       self.clear_heap(self.curr);
+      self.logs[self.curr as usize].clear();
       self.nuls.push(self.curr);
       let mut cuts = 0;
-      let path = self.get_dir_path();
+      let mut dead = Vec::new();
       // Removes heaps until the runtime's tick is larger than, or equal to, the target tick
       while tick < self.get_tick() {
         if let Rollback::Cons { keep, life, head, tail } = &*self.back.clone() {
-          // self.heap[*head as usize].delete_buffers(&path).expect("Couldn't delete buffers."); // heap persistence disabled
+          if self.rewind(*head, tick) {
+            self.save_heap(*head);
+            break;
+          }
+          dead.push(self.heap[*head as usize].uuid);
           self.clear_heap(*head);
+          self.logs[*head as usize].clear();
           self.nuls.push(*head);
           self.back = tail.clone();
           cuts += 1 + life;
+        } else {
+          break;
         }
       }
       if let Rollback::Cons { keep, life, head, tail } = &*self.back {
         self.back = Arc::new(Rollback::Cons { keep: 0, life: *life + cuts, head: *head, tail: tail.clone() });
       }
+      self.save_state_metadata();
+      for uuid in dead {
+        self.delete_buffers(uuid);
+      }
       self.curr = self.nuls.pop().expect("No heap available!");
+      self.ensure_root();
     }
     // println!("- rolled back to {}", self.get_tick());
   }
@@ -2162,11 +2887,37 @@ impl Runtime {
     return self.path.clone();
   }
 
+  // Queues the write of the file of a heap. The heap is encoded and written by the persistence
+  // worker, in the order they were queued, and each file is replaced atomically. The worker shares
+  // the heap, so a heap changed before it is saved is copied first, by `get_heap_mut`.
+  fn save_heap(&self, index: u64) {
+    let heap = self.heap[index as usize].clone();
+    let path = Heap::file_path(heap.uuid, &self.path);
+    self.save.write_with(path, move || heap.encode());
+  }
+
+  // Queues the deletion of the files of a heap
+  fn delete_heap(&self, index: u64) {
+    self.delete_buffers(self.heap[index as usize].uuid);
+  }
+
+  fn delete_buffers(&self, uuid: u128) {
+    self.save.delete(Heap::file_path(uuid, &self.path));
+    for name in HEAP_BUFFERS {
+      self.save.delete(Heap::buffer_file_path(uuid, name, &self.path));
+    }
+  }
+
+  /// Waits until all queued writes are on disk.
+  pub fn flush(&self) -> Result<(), String> {
+    self.save.flush()
+  }
+
   // Persists the current state. Since heaps are automatically saved to disk, function only saves
   // their uuids. Note that this will NOT save the current heap, nor anything after the last heap
   // included on the Rollback list. In other words, it forgets up to ~16 recent blocks. This
   // function is used to avoid re-processing the entire block history on node startup.
-  pub fn save_state_metadata(&self) -> std::io::Result<()> {
+  pub fn save_state_metadata(&self) {
     fn build_persistence_buffers(rt: &Runtime, rollback: &Rollback, keeps: &mut Vec<u128>, lifes: &mut Vec<u128>, uuids: &mut Vec<u128>) {
       match rollback {
         Rollback::Cons { keep, life, head, tail } => {
@@ -2182,24 +2933,27 @@ impl Runtime {
     let mut lifes : Vec<u128> = vec![];
     let mut uuids : Vec<u128> = vec![];
     build_persistence_buffers(self, &self.back,  &mut keeps, &mut lifes, &mut uuids);
-    std::fs::write(self.path.join("_keeps_"), &util::u128s_to_u8s(&keeps))?;
-    std::fs::write(self.path.join("_lifes_"), &util::u128s_to_u8s(&lifes))?;
-    std::fs::write(self.path.join("_uuids_"), &util::u128s_to_u8s(&uuids))?;
-    return Ok(());
+    self.save.write(self.path.join(STATE_FILE), encode_state_metadata(&keeps, &lifes, &uuids));
+    for name in LEGACY_STATE_FILES {
+      self.save.delete(self.path.join(name));
+    }
   }
 
   // Restores the saved state. This loads the persisted Rollback list and its heaps.
   pub fn restore_state(&mut self) -> std::io::Result<()> {
-    for i in 0 .. MAX_HEAPS {
-      self.heap[i as usize].clear();
+    self.flush().map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+    for i in 0 .. self.conf.max_heaps {
+      self.clear_heap(i);
+      self.logs[i as usize].clear();
     }
-    self.nuls = (2 .. MAX_HEAPS).collect();
+    self.nuls = (2 .. self.conf.max_heaps).collect();
     // for i in 0 .. std::cmp::max(uuids.len(), 8) {
     //   self.heap[i + 2].load_buffers(uuids[i])?;
     // }
-    let mut keeps = util::u8s_to_u128s(&std::fs::read(self.path.join("_keeps_"))?);
-    let mut lifes = util::u8s_to_u128s(&std::fs::read(self.path.join("_lifes_"))?);
-    let mut uuids = util::u8s_to_u128s(&std::fs::read(self.path.join("_uuids_"))?);
+    let sections = read_state_metadata(&self.path)?;
+    let mut keeps = util::u8s_to_u128s(persistence::get_section(&sections, "keeps")?);
+    let mut lifes = util::u8s_to_u128s(persistence::get_section(&sections, "lifes")?);
+    let mut uuids = util::u8s_to_u128s(persistence::get_section(&sections, "uuids")?);
     fn load_heaps(rt: &mut Runtime, keeps: &mut Vec<u128>, lifes: &mut Vec<u128>, uuids: &mut Vec<u128>, index: u64, back: Arc<Rollback>) -> std::io::Result<Arc<Rollback>> {
       let keep = keeps.pop();
       let life = lifes.pop();
@@ -2210,7 +2964,7 @@ impl Runtime {
           match next {
             Some(next) => {
               let path = rt.get_dir_path();
-              rt.heap[index as usize] = Heap::deserialize(uuid, &path)?;
+              rt.heap[index as usize] = Arc::new(Heap::deserialize(uuid, &path)?);
               rt.curr = index;
               return load_heaps(rt, keeps, lifes, uuids, next, Arc::new(Rollback::Cons { keep: keep as u64, life: life as u64, head: index, tail: back }));
             }
@@ -2231,12 +2985,384 @@ impl Runtime {
     self.curr = 1;
     self.back = load_heaps(self, &mut keeps, &mut lifes, &mut uuids, self.curr, Arc::new(Rollback::Nil))?;
     self.curr = self.nuls.pop().expect("No heap available!");
+    self.ensure_root();
     return Ok(());
   }
 
+  // Discards the current and the saved state, and starts again from `init_stmts`
+  pub fn reset(&mut self, init_stmts: &[Statement]) {
+    let mut back = self.back.clone();
+    while let Rollback::Cons { head, tail, .. } = &*back {
+      self.delete_heap(*head);
+      back = tail.clone();
+    }
+    if let Err(err) = self.flush() {
+      eprintln!("WARN: {}", err);
+    }
+    *self = init_runtime_with(self.path.clone(), init_stmts, self.conf.clone());
+  }
+
+  // State root
+  // ----------
+
+  /// Returns the state root. See `root_leaf`.
+  pub fn get_root(&self) -> U256 {
+    let rot0 = self.get_with(0, U128_NONE, |heap| heap.rot0);
+    let rot1 = self.get_with(0, U128_NONE, |heap| heap.rot1);
+    (U256::from(rot1) << 128) | U256::from(rot0)
+  }
+
+  fn set_root(&mut self, index: u64, root: U256) {
+    let heap = self.get_heap_mut(index);
+    heap.rot0 = root.low_u128();
+    heap.rot1 = (root >> 128).low_u128();
+  }
+
+  // Replaces the hash of an entry on the state root
+  fn update_root(&mut self, old: Option<U256>, new: Option<U256>) {
+    if old != new {
+      let mut root = self.get_root();
+      if let Some(old) = old {
+        root = root.overflowing_sub(old).0;
+      }
+      if let Some(new) = new {
+        root = root.overflowing_add(new).0;
+      }
+      self.set_root(self.draw, root);
+    }
+  }
+
+  // Hash of a state being saved. States are hashed by their readback, which doesn't depend on
+  // where they are on memory, and is charged to the run that saves them.
+  fn hash_state(&mut self, name: U120, state: RawCell, mana: u64) -> Result<Option<crypto::Hash>, RuntimeError> {
+    if state == RawCell(U128_NONE) {
+      return Ok(None);
+    }
+    let limit = mana.saturating_sub(self.get_mana());
+    let term = readback_term(self, state, Some(usize::try_from(limit).unwrap_or(usize::MAX)));
+    let term = term.ok_or(RuntimeError::NotEnoughMana)?;
+    self.set_mana(self.get_mana() + SaveMana(&term));
+    if self.get_mana() > mana {
+      return Err(RuntimeError::NotEnoughMana);
+    }
+    Ok(Some(disk_hash(name, &term)))
+  }
+
+  // Hash of a stored state on the state root. It's kept since the state was saved, except on
+  // heaps saved by older versions, where it's computed from the readback of the state.
+  fn disk_leaf(&self, name: U120) -> Option<U256> {
+    let hash = match self.get_with(None, None, |heap| heap.read_leaf(&name)) {
+      Some(hash) => hash,
+      None => {
+        let state = self.read_disk(name).filter(|state| *state != RawCell(U128_NONE))?;
+        Some(disk_hash(name, &readback_term(self, state, None)?))
+      }
+    };
+    hash.map(|hash| U256::from_little_endian(&hash.0))
+  }
+
+  /// Computes the state root from the contents of the heaps, instead of incrementally.
+  pub fn compute_root(&self) -> U256 {
+    fn keys<K: Copy + Eq + Hash, V, S>(rt: &Runtime, map: impl Fn(&Heap) -> &HashMap<K, V, S>) -> HashSet<K> {
+      let mut keys = HashSet::new();
+      rt.reduce_with(&mut keys, |keys, heap| keys.extend(map(heap).keys().copied()));
+      keys
+    }
+    let mut leafs = Vec::new();
+    for name in keys(self, |heap| &heap.disk.links) {
+      leafs.push(self.disk_leaf(name));
+    }
+    for name in keys(self, |heap| &heap.file.funcs) {
+      leafs.push(self.get_with(None, None, |heap| heap.read_file(&name)).map(|func| file_leaf(name, &func)));
+    }
+    for name in keys(self, |heap| &heap.arit.arits) {
+      leafs.push(self.get_arity(&name).map(|arit| arit_leaf(name, arit)));
+    }
+    for name in keys(self, |heap| &heap.ownr.ownrs) {
+      leafs.push(self.get_owner(&name).map(|ownr| ownr_leaf(name, ownr)));
+    }
+    for id in keys(self, |heap| &heap.msig.msigs) {
+      leafs.push(self.get_multisig(&id).map(|msig| msig_leaf(id, &msig)));
+    }
+    for subj in keys(self, |heap| &heap.nonc.noncs) {
+      leafs.push(self.get_with(None, None, |heap| heap.read_nonc(&subj)).map(|nonce| nonc_leaf(subj, nonce)));
+    }
+    for name in keys(self, |heap| &heap.indx.indxs) {
+      leafs.push(self.get_with(None, None, |heap| heap.read_indx(&name)).map(|pos| indx_leaf(name, pos)));
+    }
+    for pos in keys(self, |heap| &heap.hash.stmt_hashes) {
+      leafs.push(self.get_with(None, None, |heap| heap.read_stmt_hash(&pos).cloned()).map(|hash| hash_leaf(pos, &hash)));
+    }
+    leafs.into_iter().flatten().fold(U256::zero(), |root, leaf| root.overflowing_add(leaf).0)
+  }
+
+  // Heaps saved by older versions have no state root, so it's computed from their contents
+  fn ensure_root(&mut self) {
+    if self.get_with(U128_NONE, U128_NONE, |heap| heap.rot0) == U128_NONE {
+      let root = self.compute_root();
+      self.set_root(self.curr, root);
+    }
+  }
+
+  // Compaction
+  // ----------
+
+  /// Returns the statistics of the latest compaction, if any ran since the runtime started.
+  pub fn get_last_compaction(&self) -> Option<Compaction> {
+    self.last_compaction
+  }
+
+  // Whether `snapshot` will include the current heap on the rollback list
+  fn at_snapshot(&self) -> bool {
+    match &*self.back {
+      Rollback::Nil => true,
+      Rollback::Cons { keep, .. } => keep + 1 >= self.conf.keep,
+    }
+  }
+
+  /// Relocates the nodes reachable from the stored states into a dense region at the start of the
+  /// memory. The writes go to the drawing heap, like the ones of a statement, so they are undone by
+  /// rollbacks. Returns `None`, without changing anything, if a live node points to a word outside
+  /// the live nodes, which would be left dangling.
+  pub fn compact(&mut self) -> Option<Compaction> {
+    let mut names = HashSet::new();
+    self.reduce_with(&mut names, |names, heap| names.extend(heap.disk.links.keys().copied()));
+    let roots: Vec<(U120, RawCell)> =
+      names.into_iter().filter_map(|name| Some((name, self.read_disk(name)?))).collect();
+
+    // Finds the live nodes
+    let mut nodes: HashMap<u64, u64> = HashMap::new();
+    let mut stack: Vec<RawCell> = roots.iter().map(|(_, cell)| *cell).collect();
+    while let Some(cell) = stack.pop() {
+      if let Some(size) = node_size(self, cell) {
+        let base = get_val(cell);
+        if let hash_map::Entry::Vacant(entry) = nodes.entry(base) {
+          entry.insert(size);
+          for i in 0 .. size {
+            stack.push(self.read(Loc(base + i)));
+          }
+        }
+      }
+    }
+    let mut nodes: Vec<(u64, u64)> = nodes.into_iter().collect();
+    nodes.sort_unstable();
+    if nodes.windows(2).any(|pair| pair[0].0 + pair[0].1 > pair[1].0) {
+      eprintln!("WARN: overlapping nodes, skipping the heap compaction.");
+      return None;
+    }
+
+    // Slides each node to the first free words after the previous one. A node never moves to the
+    // right, and only over words of nodes that already moved, so moving them in order is safe.
+    let mut moves: HashMap<u64, u64> = HashMap::new();
+    for (base, size) in &nodes {
+      for i in 0 .. *size {
+        moves.insert(base + i, 0);
+      }
+    }
+    let used = |rt: &Runtime, pos: u64| !moves.contains_key(&pos) && rt.read(Loc(pos)) != RawCell(0);
+    let mut dests = Vec::with_capacity(nodes.len());
+    let mut cursor = 0;
+    for (base, size) in &nodes {
+      let mut dest = cursor;
+      while let Some(i) = (0 .. *size).rev().find(|i| used(self, dest + i)) {
+        dest += i + 1;
+      }
+      dests.push(dest);
+      cursor = dest + size;
+    }
+    for ((base, size), dest) in nodes.iter().zip(&dests) {
+      for i in 0 .. *size {
+        moves.insert(base + i, dest + i);
+      }
+    }
+
+    // Every pointer must be to a live word
+    let mut cells: Vec<RawCell> = roots.iter().map(|(_, cell)| *cell).collect();
+    for (base, size) in &nodes {
+      cells.extend((0 .. *size).map(|i| self.read(Loc(base + i))));
+    }
+    if cells.iter().any(|cell| has_pointer(self, *cell) && !moves.contains_key(&get_val(*cell))) {
+      eprintln!("WARN: dangling pointer, skipping the heap compaction.");
+      return None;
+    }
+    let relocate = |rt: &Runtime, cell: RawCell| {
+      if has_pointer(rt, cell) {
+        RawCell((*cell & !VAL_MASK) | moves[&get_val(cell)] as u128)
+      } else {
+        cell
+      }
+    };
+
+    let span_before = nodes.last().map(|(base, size)| base + size).unwrap_or(0);
+    let mut moved = 0;
+    let mut written = 0;
+    for ((base, size), dest) in nodes.iter().zip(&dests) {
+      // A node that stays in place is only rewritten where it points to nodes that moved
+      let words: Vec<(RawCell, RawCell)> =
+        (0 .. *size).map(|i| self.read(Loc(base + i))).map(|old| (old, relocate(self, old))).collect();
+      for (i, (old, word)) in words.into_iter().enumerate() {
+        if dest != base || word != old {
+          self.write(Loc(dest + i as u64), word);
+          written += 1;
+        }
+      }
+      if dest != base {
+        moved += 1;
+      }
+    }
+    let kept: HashSet<u64> = moves.values().copied().collect();
+    for pos in moves.keys() {
+      if !kept.contains(pos) {
+        self.write(Loc(*pos), RawCell(0));
+        written += 1;
+      }
+    }
+    // The states read back the same, so the state root doesn't change
+    for (name, cell) in roots {
+      let new = relocate(self, cell);
+      if new != cell {
+        self.get_heap_mut(self.draw).write_disk(name, new);
+      }
+    }
+    self.set_next(cursor);
+
+    let compaction = Compaction {
+      tick: self.get_tick(),
+      nodes: nodes.len() as u64,
+      live: nodes.iter().map(|(_, size)| size).sum(),
+      moved,
+      span_before,
+      span_after: cursor,
+      written,
+    };
+    self.last_compaction = Some(compaction);
+    Some(compaction)
+  }
+
+  // Invariants
+  // ----------
+
+  // Checks that the heap indices used by the runtime are valid and that no heap is used twice
+  fn heaps_invariant(&self) -> Result<(), String> {
+    let mut seen = vec![0u8; self.heap.len()];
+    let mut heaps = vec![self.draw, self.curr];
+    heaps.extend(&self.nuls);
+    let mut back = &self.back;
+    while let Rollback::Cons { head, tail, .. } = &**back {
+      heaps.push(*head);
+      back = tail;
+    }
+    for index in heaps {
+      match seen.get_mut(index as usize) {
+        Some(count) => *count += 1,
+        None => return Err(format!("Heap index {} is out of bounds.", index)),
+      }
+    }
+    match seen.iter().position(|count| *count > 1) {
+      Some(index) => Err(format!("Heap {} is used twice (draw: {}, curr: {}, nuls: {:?}, back: {}).", index, self.draw, self.curr, self.nuls, view_rollback(&self.back))),
+      None => Ok(()),
+    }
+  }
+
+  /// Checks the consistency of the runtime, to debug corruptions: heap indices are unique, every
+  /// pointer reachable from the stored states is to allocated words, with the arity its tag
+  /// expects, variables and duplications are linked both ways with their binders, and `size`
+  /// is the number of allocated words. Returns the violations found.
+  pub fn check_invariants(&self) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    if let Err(err) = self.heaps_invariant() {
+      errors.push(err);
+    }
+    let allocated = |pos: u64| !matches!(*self.read(Loc(pos)), 0 | U128_NONE);
+
+    let mut names = HashSet::new();
+    self.reduce_with(&mut names, |names, heap| names.extend(heap.disk.links.keys().copied()));
+    // Terms to visit, with the word they are stored at, if any
+    let mut stack: Vec<(RawCell, Option<u64>, U120)> = Vec::new();
+    for name in names {
+      if let Some(cell) = self.read_disk(name) {
+        stack.push((cell, None, name));
+      }
+    }
+    let mut seen: HashSet<u64> = HashSet::new();
+    while let Some((cell, pos, name)) = stack.pop() {
+      let base = get_val(cell);
+      let at = match pos {
+        Some(pos) => format!("at {} (state of {})", pos, Name::from(name)),
+        None => format!("on the state of {}", Name::from(name)),
+      };
+      let size = match get_tag(cell) {
+        // Variables must be bound by the binder slot they point to
+        VAR | DP0 | DP1 => {
+          let slot = if get_tag(cell) == DP1 { 1 } else { 0 };
+          if !matches!(pos, Some(pos) if self.read(Loc(base + slot)) == Arg(Loc(pos))) {
+            errors.push(format!("Dangling {} {}.", show_ptr(cell), at));
+            continue;
+          }
+          if get_tag(cell) == VAR { 0 } else { 3 }
+        }
+        LAM | APP | SUP | OP2 => 2,
+        CTR | FUN => match self.get_arity(&Name::new_unsafe(get_ext(cell))) {
+          Some(arity) => arity,
+          None => {
+            errors.push(format!("Undefined {} {}.", show_ptr(cell), at));
+            0
+          }
+        },
+        ERA | NUM => 0,
+        _ => {
+          errors.push(format!("Invalid cell {} {}.", show_ptr(cell), at));
+          0
+        }
+      };
+      if size == 0 || !seen.insert(base) {
+        continue;
+      }
+      if let Some(i) = (0 .. size).find(|i| !allocated(base + i)) {
+        errors.push(format!("{} {} points to the free word {}.", show_ptr(cell), at, base + i));
+        continue;
+      }
+      let slot = |i: u64| (self.read(Loc(base + i)), Some(base + i), name);
+      match get_tag(cell) {
+        // Binders must point to the variables bound by them
+        LAM | DP0 | DP1 => {
+          let (binders, body) = if get_tag(cell) == LAM { (0 .. 1, 1) } else { (0 .. 2, 2) };
+          for i in binders {
+            let binder = self.read(Loc(base + i));
+            let var = self.read(get_loc(binder, 0));
+            let bound = match (get_tag(binder), i) {
+              (ERA, _) => true,
+              (ARG, _) if get_tag(cell) == LAM => var == Var(Loc(base)),
+              (ARG, 0) => get_tag(var) == DP0 && get_val(var) == base,
+              (ARG, _) => get_tag(var) == DP1 && get_val(var) == base,
+              _ => false,
+            };
+            if !bound {
+              errors.push(format!("Binder {} at {} (state of {}) isn't linked to its variable.", show_ptr(binder), base + i, Name::from(name)));
+            }
+          }
+          stack.push(slot(body));
+        }
+        _ => {
+          for i in 0 .. size {
+            stack.push(slot(i));
+          }
+        }
+      }
+    }
+
+    let mut words = HashSet::new();
+    self.reduce_with(&mut words, |words, heap| words.extend(heap.memo.nodes.keys().map(|loc| **loc)));
+    let used = words.into_iter().filter(|pos| allocated(*pos)).count() as u64;
+    if used != self.get_size() {
+      errors.push(format!("Size is {}, but {} words are allocated.", self.get_size(), used));
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+  }
+
   // Reverts until the last 
   pub fn clear_current_heap(&mut self) {
-    self.heap[self.curr as usize].clear();
+    self.clear_heap(self.curr);
   }
 
   // Heap writers and readers
@@ -2316,8 +3442,14 @@ impl Runtime {
     return self.get_with(RawCell(0), RawCell(U128_NONE), |heap| heap.read(idx));
   }
 
-  pub fn write_disk(&mut self, name: U120, val: RawCell) {
-    return self.get_heap_mut(self.draw).write_disk(name, val);
+  pub fn write_disk(&mut self, name: U120, val: RawCell, mana: u64) -> Result<(), RuntimeError> {
+    let hash = self.hash_state(name, val, mana)?;
+    let old = self.disk_leaf(name);
+    self.update_root(old, hash.as_ref().map(|hash| U256::from_little_endian(&hash.0)));
+    let heap = self.get_heap_mut(self.draw);
+    heap.write_disk(name, val);
+    heap.write_leaf(name, hash);
+    Ok(())
   }
 
   pub fn read_disk(&self, name: U120) -> Option<RawCell> {
@@ -2338,6 +3470,8 @@ impl Runtime {
   }
 
   pub fn set_arity(&mut self, name: Name, arity: u64) {
+    let old = self.get_arity(&name).map(|old| arit_leaf(name, old));
+    self.update_root(old, Some(arit_leaf(name, arity)));
     self.get_heap_mut(self.draw).write_arit(name, arity);
   }
 
@@ -2346,9 +3480,55 @@ impl Runtime {
   }
 
   pub fn set_owner(&mut self, name: Name, owner: U120) {
+    let old = self.get_owner(&name).map(|old| ownr_leaf(name, old));
+    self.update_root(old, Some(ownr_leaf(name, owner)));
     self.get_heap_mut(self.draw).write_ownr(name, owner);
   }
 
+  pub fn get_multisig(&self, id: &U120) -> Option<Multisig> {
+    self.get_with(None, None, |heap| heap.read_msig(id))
+  }
+
+  // Key sets are never overwritten, as their id is a hash of them
+  pub fn set_multisig(&mut self, id: U120, msig: Multisig) {
+    if self.get_multisig(&id).is_none() {
+      self.update_root(None, Some(msig_leaf(id, &msig)));
+      self.get_heap_mut(self.draw).write_msig(id, msig);
+    }
+  }
+
+  // Checks the `nonce` and `until` of a statement, consuming the nonce of `subj`
+  fn check_guard(&mut self, subj: Option<U120>, nonce: Option<u64>, until: Option<u64>) -> Result<(), String> {
+    if let Some(until) = until {
+      if self.get_tick() > until {
+        return Err(format!("Statement expired on tick {}.", until));
+      }
+    }
+    if let Some(nonce) = nonce {
+      let subj = match subj {
+        None => return Err("Statements with a nonce must be signed.".to_string()),
+        Some(subj) => subj,
+      };
+      let next = self.get_nonce(&subj);
+      if nonce != next {
+        return Err(format!("Subject '#x{:0>30x}' expects nonce {}, not {}.", *subj, next, nonce));
+      }
+      self.set_nonce(subj, next + 1);
+    }
+    Ok(())
+  }
+
+  /// The nonce the next signed run or transfer of a subject must have.
+  pub fn get_nonce(&self, subj: &U120) -> u64 {
+    self.get_with(None, None, |heap| heap.read_nonc(subj)).unwrap_or(0)
+  }
+
+  pub fn set_nonce(&mut self, subj: U120, nonce: u64) {
+    let old = self.get_with(None, None, |heap| heap.read_nonc(&subj)).map(|old| nonc_leaf(subj, old));
+    self.update_root(old, Some(nonc_leaf(subj, nonce)));
+    self.get_heap_mut(self.draw).write_nonc(subj, nonce);
+  }
+
   pub fn get_index(&mut self, name: &Name) -> Option<u128> {
     self.get_with(None, None, |heap| heap.read_indx(name))
   }
@@ -2525,16 +3705,45 @@ impl Runtime {
     });
     ns
   }
+
+  /// Lists the entries compared by `diff_states`.
+  pub fn list_state(&self) -> StateListing {
+    let mut listing = StateListing::default();
+    for name in self.get_all_funs() {
+      if let Some(func) = self.read_file(&name) {
+        listing.funcs.insert(name, func.func);
+      }
+    }
+    for name in self.get_all_ctr() {
+      if let Some(arit) = self.get_arity(&name) {
+        listing.ctrs.insert(name, arit);
+      }
+    }
+    for name in self.get_all_ns() {
+      if let Some(ownr) = self.get_owner(&name) {
+        listing.ownrs.insert(name, ownr);
+      }
+    }
+    let mut names = HashSet::new();
+    self.reduce_with(&mut names, |names, heap| names.extend(heap.disk.links.keys().copied()));
+    for name in names {
+      if let Some(state) = self.read_disk(name) {
+        // Same limit as the API's `/functions/{name}/state`, so listings of remote nodes compare
+        listing.states.insert(Name::from(name), readback_term(self, state, Some(1 << 16)));
+      }
+    }
+    listing
+  }
 }
 
 // Attempts to include a heap state on the list of past heap states. It only keeps at most
-// `log_16(tick)` heaps in memory, rejecting heaps that it doesn't need to store. It returns:
+// `log_keep(tick)` heaps in memory, rejecting heaps that it doesn't need to store. It returns:
 // - included : Bool             = true if the heap was included, false if it was rejected
 // - absorber : Option<Box<u64>> = the index of the dropped heap absorber (if any)
 // - deleted  : Option<Box<u64>> = the index of the dropped heap (if any)
 // - rollback : Rollback         = the updated rollback object
-pub fn rollback_push(elem: u64, back: Arc<Rollback>, depth: u64) -> (bool, Option<u64>, Option<u64>, Arc<Rollback>) {
-  if depth >= MAX_ROLLBACK {
+pub fn rollback_push(elem: u64, back: Arc<Rollback>, depth: u64, conf: &RollbackConfig) -> (bool, Option<u64>, Option<u64>, Arc<Rollback>) {
+  if depth >= conf.max_heaps - 2 {
     return (false, None, Some(elem), Arc::new(Rollback::Nil));
   } else {
     match &*back {
@@ -2543,13 +3752,13 @@ pub fn rollback_push(elem: u64, back: Arc<Rollback>, depth: u64) -> (bool, Optio
         return (true, None, None, rollback);
       }
       Rollback::Cons { keep, life, head, tail } => {
-        if *keep == 0xF {
+        if *keep + 1 >= conf.keep {
           if *life > 0 {
             let tail = Arc::new(Rollback::Cons { keep: 0, life: life - 1, head: *head, tail: tail.clone() });
             let back = Arc::new(Rollback::Cons { keep: 0, life: 0, head: elem, tail });
             return (true, None, None, back);
           } else {
-            let (included, absorber, deleted, tail) = rollback_push(*head, tail.clone(), depth + 1);
+            let (included, absorber, deleted, tail) = rollback_push(*head, tail.clone(), depth + 1, conf);
             let absorber = if !included { Some(elem) } else { absorber };
             let rollback = Arc::new(Rollback::Cons { keep: 0, life: *life, head: elem, tail });
             return (true, absorber, deleted, rollback);
@@ -2575,6 +3784,98 @@ pub fn view_rollback(back: &Arc<Rollback>) -> String {
 }
 
 
+// State diff
+// ----------
+
+/// The functions, constructor arities, namespace owners and stored states of a runtime. States
+/// are read back as terms, and are `None` if they can't be.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StateListing {
+  pub funcs: HashMap<Name, Func>,
+  pub ctrs: HashMap<Name, u64>,
+  pub ownrs: HashMap<Name, U120>,
+  pub states: HashMap<Name, Option<Term>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryDiff<T> {
+  Added(T),
+  Changed(T, T),
+  Removed(T),
+}
+
+/// The entries that differ between two states, sorted by name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StateDiff {
+  pub funcs: Vec<(Name, EntryDiff<Func>)>,
+  pub ctrs: Vec<(Name, EntryDiff<u64>)>,
+  pub ownrs: Vec<(Name, EntryDiff<U120>)>,
+  pub states: Vec<(Name, EntryDiff<Option<Term>>)>,
+}
+
+impl StateDiff {
+  pub fn is_empty(&self) -> bool {
+    self.funcs.is_empty() && self.ctrs.is_empty() && self.ownrs.is_empty() && self.states.is_empty()
+  }
+}
+
+/// Compares the entries of two states.
+pub fn diff_states(old: &StateListing, new: &StateListing) -> StateDiff {
+  fn diff<T: Clone + PartialEq>(old: &HashMap<Name, T>, new: &HashMap<Name, T>) -> Vec<(Name, EntryDiff<T>)> {
+    let mut diffs = Vec::new();
+    for (name, old_value) in old {
+      match new.get(name) {
+        None => diffs.push((*name, EntryDiff::Removed(old_value.clone()))),
+        Some(new_value) if new_value != old_value => {
+          diffs.push((*name, EntryDiff::Changed(old_value.clone(), new_value.clone())));
+        }
+        Some(_) => {}
+      }
+    }
+    for (name, new_value) in new {
+      if !old.contains_key(name) {
+        diffs.push((*name, EntryDiff::Added(new_value.clone())));
+      }
+    }
+    diffs.sort_by_cached_key(|(name, _)| name.to_string());
+    diffs
+  }
+  StateDiff {
+    funcs: diff(&old.funcs, &new.funcs),
+    ctrs: diff(&old.ctrs, &new.ctrs),
+    ownrs: diff(&old.ownrs, &new.ownrs),
+    states: diff(&old.states, &new.states),
+  }
+}
+
+// Lines added, changed and removed are prefixed by `+`, `~` and `-`. Changes show the old value,
+// then the new one.
+impl fmt::Display for StateDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fn write_diffs<T>(f: &mut fmt::Formatter<'_>, kind: &str, diffs: &[(Name, EntryDiff<T>)], view: impl Fn(&T) -> String) -> fmt::Result {
+      for (name, diff) in diffs {
+        match diff {
+          EntryDiff::Added(new) => writeln!(f, "+ {} {}: {}", kind, name, view(new))?,
+          EntryDiff::Changed(old, new) => writeln!(f, "~ {} {}: {}\n  => {}", kind, name, view(old), view(new))?,
+          EntryDiff::Removed(old) => writeln!(f, "- {} {}: {}", kind, name, view(old))?,
+        }
+      }
+      Ok(())
+    }
+    fn view_func(func: &Func) -> String {
+      let rules: Vec<String> = func.rules.iter().map(|rule| format!("{} = {}", view_term(&rule.lhs), view_term(&rule.rhs))).collect();
+      rules.join("; ")
+    }
+    write_diffs(f, "fun", &self.funcs, view_func)?;
+    write_diffs(f, "ctr", &self.ctrs, |arit| format!("{} fields", arit))?;
+    write_diffs(f, "reg", &self.ownrs, |ownr| format!("#x{:0>30x}", **ownr))?;
+    write_diffs(f, "state", &self.states, |state| match state {
+      Some(term) => view_term(term),
+      None => "<unreadable>".to_string(),
+    })
+  }
+}
+
 // Constructors
 // ------------
 
@@ -4078,7 +5379,7 @@ pub fn show_term(rt: &Runtime, term: RawCell, focus: Option<RawCell>) -> String
 }
 
 
-fn show_runtime_error(err: RuntimeError) -> String {
+pub fn show_runtime_error(err: RuntimeError) -> String {
   match err {
     RuntimeError::NotEnoughMana => "Not enough mana".to_string(),
     RuntimeError::NotEnoughSpace => "Not enough space".to_string(),
@@ -4414,7 +5715,7 @@ fn nth(code: &str, index: u128) -> char {
   return head(drop(code, index));
 }
 
-fn skip(code: &str) -> &str {
+pub fn skip(code: &str) -> &str {
   let mut code = code;
   loop {
     if " \n\r\t".contains(head(code)) {
@@ -4779,6 +6080,88 @@ pub fn read_sign(code: &str) -> ParseResult<Option<crypto::Signature>> {
   return Ok((code, None));
 }
 
+// Reads the signatures of a statement: `sign { ... }`, repeated for each signing key
+pub fn read_signs(code: &str) -> ParseResult<Vec<crypto::Signature>> {
+  let mut code = code;
+  let mut signs = Vec::new();
+  while let (rest, Some(sign)) = read_sign(code)? {
+    code = rest;
+    signs.push(sign);
+  }
+  return Ok((code, signs));
+}
+
+// Reads the owner of a namespace: a number or a quoted name, or a multi-signature owner, as in
+// `#2 of #x123 'Foo' #x456`
+pub fn read_owner(code: &str) -> ParseResult<Owner> {
+  fn read_subj(code: &str) -> ParseResult<U120> {
+    let code = skip(code);
+    match head(code) {
+      '#' => {
+        let code = tail(code);
+        read_numb(code)
+      },
+      '\'' => {
+        let code = tail(code);
+        let (code, name) = read_name(code)?;
+        let (code, unit) = read_char(code, '\'')?;
+        let numb: U120 = name.into();
+        Ok((code, numb))
+      },
+      _ => Err(ParseErr::new(code, "Expected a number representation"))
+    }
+  }
+  let (code, subj) = read_subj(code)?;
+  let code = skip(code);
+  if let ('o','f') = (nth(code,0), nth(code,1)) {
+    let (code, keys) = read_until(drop(code,2), '}', read_subj)?;
+    if *subj > MAX_MULTISIG_KEYS as u128 {
+      return Err(ParseErr::new(code, format!("Multi-signature thresholds can't be larger than {}.", MAX_MULTISIG_KEYS)));
+    }
+    let thld = *subj as u64;
+    return Ok((code, Owner::Msig(Multisig { thld, keys })));
+  }
+  let (code, unit) = read_char(code, '}')?;
+  return Ok((code, Owner::Subj(subj)));
+}
+
+// Reads the optional `nonce #N` or `until #N` of a run or a transfer
+fn read_run_guard<'a>(code: &'a str, keyword: &str) -> ParseResult<'a, Option<u64>> {
+  let code = skip(code);
+  match code.strip_prefix(keyword) {
+    Some(rest) if !rest.starts_with(is_name_char) => {
+      let (code, unit) = read_char(rest, '#')?;
+      let (rest, numb) = read_numb::<U120>(code)?;
+      match u64::try_from(*numb) {
+        Ok(numb) => Ok((rest, Some(numb))),
+        Err(_) => Err(ParseErr::new(code, format!("The {} must fit in 64 bits", keyword))),
+      }
+    }
+    _ => Ok((code, None)),
+  }
+}
+
+// Reads the `nonce #N` and `until #N` of a run or a transfer, each optional, in either order
+fn read_run_guards(code: &str) -> ParseResult<'_, (Option<u64>, Option<u64>)> {
+  let (mut code, mut nonce, mut until) = (code, None, None);
+  loop {
+    let mut found = false;
+    for (keyword, value) in [("nonce", &mut nonce), ("until", &mut until)] {
+      if let (rest, Some(numb)) = read_run_guard(code, keyword)? {
+        if value.is_some() {
+          return Err(ParseErr::new(code, format!("Repeated {}.", keyword)));
+        }
+        *value = Some(numb);
+        code = rest;
+        found = true;
+      }
+    }
+    if !found {
+      return Ok((code, (nonce, until)));
+    }
+  }
+}
+
 pub fn read_statement(code: &str) -> ParseResult<Statement> {
   let code = skip(code);
   match (nth(code,0), nth(code,1), nth(code,2)) {
@@ -4799,7 +6182,7 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       } else {
         (code, None)
       };
-      let (code, sign) = read_sign(code)?;
+      let (code, sign) = read_signs(code)?;
       let func = Func { rules: ruls };
       return Ok((code, Statement::Fun { name, args, func, init, sign }));
     }
@@ -4808,7 +6191,7 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       let (code, unit) = read_char(code, '{')?;
       let (code, name) = read_name(code)?;
       let (code, args) = read_until(code, '}', read_name)?;
-      let (code, sign) = read_sign(code)?;
+      let (code, sign) = read_signs(code)?;
       return Ok((code, Statement::Ctr { name, args, sign }));
     }
     ('r','u','n') => {
@@ -4816,10 +6199,12 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       let (code, unit) = read_char(code, '{')?;
       let (code, expr) = read_term(code)?;
       let (code, unit) = read_char(code, '}')?;
-      let (code, sign) = read_sign(code)?;
-      return Ok((code, Statement::Run { expr, sign  }));
+      let (code, (nonce, until)) = read_run_guards(code)?;
+      let (code, sign) = read_signs(code)?;
+      return Ok((code, Statement::Run { expr, nonce, until, sign }));
     }
     // reg Foo.Bar { #x123456 } sign { signature }
+    // reg Foo.Bar { #2 of #x123456 #x789abc } sign { signature } sign { signature }
     ('r','e','g') => {
       let code = skip(drop(code, 3));
       let (code, name) =
@@ -4829,25 +6214,33 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
           read_name(code)?
         };
       let (code, unit) = read_char(code, '{')?;
-      let code = skip(code);
-      let (code, ownr) = match head(code) {
-        '#' => {
-          let code = tail(code);
-          read_numb(code)?
-        },
-        '\'' => {
-          let code = tail(code);
-          let (code, name) = read_name(code)?;
-          let (code, unit) = read_char(code, '\'')?;
-          let numb: U120 = name.into();
-          (code, numb)
-        },
-        _ => return Err(ParseErr::new(code, "Expected a number representation"))
-      };
-      let (code, unit) = read_char(code, '}')?;
-      let (code, sign) = read_sign(code)?;
+      let (code, ownr) = read_owner(code)?;
+      let (code, sign) = read_signs(code)?;
       return Ok((code, Statement::Reg { name, ownr, sign }));
     }
+    // own Foo.Bar { #x123456 } nonce #0 sign { signature }
+    // own Foo.Bar {} nonce #1 until #100 sign { signature }
+    ('o','w','n') => {
+      let code = skip(drop(code, 3));
+      let (code, name) =
+        if nth(code, 0) == '{' {
+          (code, Name::EMPTY)
+        } else {
+          read_name(code)?
+        };
+      let (code, unit) = read_char(code, '{')?;
+      let (code, ownr) =
+        if nth(skip(code), 0) == '}' {
+          (tail(skip(code)), None)
+        } else {
+          let (code, ownr) = read_owner(code)?;
+          (code, Some(ownr))
+        };
+      let (code, (nonce, until)) = read_run_guards(code)?;
+      let nonce = nonce.ok_or_else(|| ParseErr::new(code, "Transfers must have a nonce."))?;
+      let (code, sign) = read_signs(code)?;
+      return Ok((code, Statement::Own { name, ownr, nonce, until, sign }));
+    }
     _ => {
       return Err(ParseErr { code: code.to_string(),  erro: "Expected statement.".to_string() });
     }
@@ -4999,7 +6392,7 @@ pub fn view_oper(oper: &Oper) -> String {
 }
 
 pub fn view_statement(statement: &Statement) -> String {
-  fn view_sign(sign: &Option<crypto::Signature>) -> String {
+  fn view_sign(sign: &[crypto::Signature]) -> String {
     fn format_sign(sign: &crypto::Signature) -> String {
       let hex = sign.to_hex();
       let mut text = String::new();
@@ -5010,10 +6403,7 @@ pub fn view_statement(statement: &Statement) -> String {
       }
       return text;
     }
-    match sign {
-      None       => String::new(),
-      Some(sign) => format!(" sign {{\n{}}}", format_sign(sign)),
-    }
+    sign.iter().map(|sign| format!(" sign {{\n{}}}", format_sign(sign))).collect()
   }
   match statement {
     Statement::Fun { name, args, func, init, sign } => {
@@ -5036,17 +6426,38 @@ pub fn view_statement(statement: &Statement) -> String {
       let sign = view_sign(sign);
       return format!("ctr {{{}{}}}{}", name, args, sign);
     }
-    Statement::Run { expr, sign } => {
+    Statement::Run { expr, nonce, until, sign } => {
       let expr = view_term(expr);
+      let nonce = nonce.map(|nonce| format!(" nonce #{}", nonce)).unwrap_or_default();
+      let until = until.map(|until| format!(" until #{}", until)).unwrap_or_default();
       let sign = view_sign(sign);
-      return format!("run {{\n  {}\n}}{}", expr, sign);
+      return format!("run {{\n  {}\n}}{}{}{}", expr, nonce, until, sign);
     }
     Statement::Reg { name, ownr, sign } => {
       let name = name;
-      let ownr = format!("#x{:0>30x}", **ownr);
+      let ownr = view_owner(ownr);
       let sign = view_sign(sign);
       return format!("reg {} {{ {} }}{}", name, ownr, sign);
     }
+    Statement::Own { name, ownr, nonce, until, sign } => {
+      let ownr = match ownr {
+        Some(ownr) => format!(" {} ", view_owner(ownr)),
+        None => String::new(),
+      };
+      let until = until.map(|until| format!(" until #{}", until)).unwrap_or_default();
+      let sign = view_sign(sign);
+      return format!("own {} {{{}}} nonce #{}{}{}", name, ownr, nonce, until, sign);
+    }
+  }
+}
+
+pub fn view_owner(ownr: &Owner) -> String {
+  match ownr {
+    Owner::Subj(subj) => format!("#x{:0>30x}", **subj),
+    Owner::Msig(msig) => {
+      let keys = msig.keys.iter().map(|key| format!(" #x{:0>30x}", **key)).collect::<String>();
+      format!("#{} of{}", msig.thld, keys)
+    }
   }
 }
 
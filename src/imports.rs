@@ -0,0 +1,188 @@
+// Imports
+// =======
+//
+// Lets a code file use the statements of other files, so that a contract
+// doesn't have to live in a single huge `.kdl` file:
+//
+//   import "list.kdl"
+//   import "../lib/token.kdl"
+//
+//   run { ... }
+//
+// Imports must come before the statements of the file, and their paths are
+// relative to the importing file. A file is loaded only once, even if it is
+// imported from many places, and its statements come before the statements of
+// the files importing it.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::common::Name;
+use crate::hvm::{self, Statement};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+  pub path: String,
+  /// Byte range of the directive on the code.
+  pub range: Range<usize>,
+}
+
+fn line_of(code: &str, offset: usize) -> usize {
+  code[..offset].matches('\n').count() + 1
+}
+
+/// Finds the `import` directives on the top of the code. They are returned
+/// along with the code where they were replaced by spaces, so that offsets on
+/// it still match the original code.
+pub fn strip_imports(code: &str) -> Result<(Vec<Import>, String), String> {
+  let mut imports = Vec::new();
+  let mut stripped = code.to_string();
+  let mut rest = code;
+  loop {
+    rest = hvm::skip(rest);
+    let start = code.len() - rest.len();
+    let after = match rest.strip_prefix("import") {
+      Some(after)
+        if !after.starts_with(|c: char| c == '_' || c.is_alphanumeric()) =>
+      {
+        after
+      }
+      _ => break,
+    };
+    let line = line_of(code, start);
+    let path = after
+      .trim_start_matches([' ', '\t'])
+      .strip_prefix('"')
+      .and_then(|path| path.split_once('"'))
+      .filter(|(path, _)| !path.contains('\n'));
+    let (path, after) = match path {
+      Some((path, after)) if !path.is_empty() => (path, after),
+      _ => {
+        return Err(format!(
+          "Line {}: expected a quoted path after 'import'.",
+          line
+        ))
+      }
+    };
+    rest = after;
+    let end = code.len() - rest.len();
+    stripped.replace_range(start..end, &" ".repeat(end - start));
+    imports.push(Import { path: path.to_string(), range: start..end });
+  }
+  Ok((imports, stripped))
+}
+
+/// Parses code that may start with `import` directives.
+pub fn parse_source(
+  code: &str,
+) -> Result<(Vec<Import>, Vec<Statement>), String> {
+  let (imports, code) = strip_imports(code)?;
+  let statements = hvm::parse_code(&code)?;
+  Ok((imports, statements))
+}
+
+/// Loads the statements of a file and of all files it imports, in dependency
+/// order.
+pub fn load_file(path: &Path) -> Result<Vec<Statement>, String> {
+  let mut loader = Loader::default();
+  loader.load_file(path)?;
+  Ok(loader.statements)
+}
+
+/// Loads the statements of code that is not on a file (e.g. read from stdin),
+/// resolving its imports relative to `base`.
+pub fn load_code(code: &str, base: &Path) -> Result<Vec<Statement>, String> {
+  let mut loader = Loader::default();
+  loader.load_code(code, "<stdin>", base)?;
+  Ok(loader.statements)
+}
+
+// Loader
+// ======
+
+#[derive(Default)]
+struct Loader {
+  loaded: HashSet<PathBuf>, // canonical paths of the files already loaded
+  stack: Vec<(PathBuf, String)>, // files being loaded, to detect cycles
+  defined: HashMap<(&'static str, Name), (usize, String)>,
+  statements: Vec<Statement>,
+}
+
+fn definition(statement: &Statement) -> Option<(&'static str, Name)> {
+  match statement {
+    Statement::Fun { name, .. } => Some(("Function", *name)),
+    Statement::Ctr { name, .. } => Some(("Constructor", *name)),
+    Statement::Reg { name, .. } => Some(("Namespace", *name)),
+    Statement::Run { .. } | Statement::Own { .. } => None,
+  }
+}
+
+impl Loader {
+  fn load_file(&mut self, path: &Path) -> Result<(), String> {
+    let file = path.display().to_string();
+    let canon = path
+      .canonicalize()
+      .map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+    if let Some(idx) = self.stack.iter().position(|(p, _)| *p == canon) {
+      let cycle: Vec<&str> = self.stack[idx..]
+        .iter()
+        .map(|(_, file)| file.as_str())
+        .chain([file.as_str()])
+        .collect();
+      return Err(format!("Import cycle: {}.", cycle.join(" -> ")));
+    }
+    if !self.loaded.insert(canon.clone()) {
+      return Ok(());
+    }
+    let code = std::fs::read_to_string(&canon)
+      .map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    self.stack.push((canon, file.clone()));
+    self.load_code(&code, &file, base)?;
+    self.stack.pop();
+    Ok(())
+  }
+
+  fn load_code(
+    &mut self,
+    code: &str,
+    file: &str,
+    base: &Path,
+  ) -> Result<(), String> {
+    let (imports, statements) =
+      parse_source(code).map_err(|err| format!("{}: {}", file, err))?;
+    for import in imports {
+      self.load_file(&base.join(&import.path))?;
+    }
+    for statement in statements {
+      self.add(statement, file)?;
+    }
+    Ok(())
+  }
+
+  // Adds a statement, skipping definitions that were already added by another
+  // file. Conflicting definitions on different files are an error; inside a
+  // single file they are left for the runtime to reject, as usual.
+  fn add(&mut self, statement: Statement, file: &str) -> Result<(), String> {
+    if let Some(key) = definition(&statement) {
+      if let Some((idx, other)) = self.defined.get(&key) {
+        if other == file {
+          self.statements.push(statement);
+          return Ok(());
+        }
+        if self.statements[*idx] == statement {
+          return Ok(());
+        }
+        let (kind, name) = key;
+        return Err(format!(
+          "{} '{}' is defined differently on '{}' and on '{}'.",
+          kind, name, other, file
+        ));
+      }
+      self.defined.insert(key, (self.statements.len(), file.to_string()));
+    }
+    self.statements.push(statement);
+    Ok(())
+  }
+}
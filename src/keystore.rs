@@ -0,0 +1,345 @@
+// Keystore
+// ========
+//
+// Password-encrypted secret keys, stored as Ethereum keystore (v3) files, so
+// they can be moved to and from Ethereum wallets. Keys are derived from the
+// password with scrypt (or PBKDF2, on imported files), the secret key is
+// encrypted with AES-128-CTR and authenticated with a Keccak256 MAC. Each key
+// is saved on the keystore directory as `<alias>.json`.
+
+use std::path::{Path, PathBuf};
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
+
+use crate::crypto::{Account, Address, Hash};
+use crate::persistence::write_secret;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+// Key files
+// =========
+
+/// An encrypted secret key, on the Ethereum keystore v3 format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyFile {
+  pub version: u32,
+  pub id: String,
+  /// Address of the key, as lowercase hex without `0x`.
+  pub address: String,
+  pub crypto: KeyCrypto,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyCrypto {
+  pub cipher: String,
+  pub cipherparams: CipherParams,
+  #[serde_as(as = "Hex")]
+  pub ciphertext: Vec<u8>,
+  #[serde(flatten)]
+  pub kdf: Kdf,
+  #[serde_as(as = "Hex")]
+  pub mac: Vec<u8>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CipherParams {
+  #[serde_as(as = "Hex")]
+  pub iv: Vec<u8>,
+}
+
+/// How the encryption key is derived from the password.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kdf", content = "kdfparams", rename_all = "lowercase")]
+pub enum Kdf {
+  Scrypt {
+    dklen: usize,
+    n: u64,
+    r: u32,
+    p: u32,
+    #[serde_as(as = "Hex")]
+    salt: Vec<u8>,
+  },
+  Pbkdf2 {
+    dklen: usize,
+    c: u32,
+    prf: String,
+    #[serde_as(as = "Hex")]
+    salt: Vec<u8>,
+  },
+}
+
+/// The scrypt cost used by new keys, as `log2(n)`. The same as Ethereum
+/// wallets use.
+pub const SCRYPT_LOG_N: u8 = 18;
+
+// Bounds on the parameters of key files, so that reading a crafted file can't
+// take unbounded memory or time. Wallets use far less.
+const MAX_SCRYPT_LOG_N: u32 = 20;
+const MAX_SCRYPT_R: u32 = 8;
+const MAX_SCRYPT_P: u32 = 4;
+const MAX_PBKDF2_C: u32 = 1 << 22;
+
+/// Length of the derived keys: half for the cipher, half for the MAC.
+const DKLEN: usize = 32;
+
+impl Kdf {
+  /// Scrypt with cost `2^log_n`, and a random salt.
+  pub fn scrypt(log_n: u8) -> Kdf {
+    Kdf::Scrypt {
+      dklen: DKLEN,
+      n: 1 << log_n,
+      r: 8,
+      p: 1,
+      salt: random_bytes(32),
+    }
+  }
+
+  fn derive_key(&self, password: &str) -> Result<Vec<u8>, String> {
+    match self {
+      Kdf::Scrypt { dklen, n, r, p, salt } => {
+        check_dklen(*dklen)?;
+        if !n.is_power_of_two()
+          || *n < 2
+          || n.trailing_zeros() > MAX_SCRYPT_LOG_N
+        {
+          return Err(format!("Invalid scrypt parameter n = {}.", n));
+        }
+        if *r > MAX_SCRYPT_R || *p > MAX_SCRYPT_P {
+          return Err(format!(
+            "Invalid scrypt parameters r = {}, p = {}.",
+            r, p
+          ));
+        }
+        let log_n = n.trailing_zeros() as u8;
+        let params = scrypt::Params::new(log_n, *r, *p)
+          .map_err(|err| format!("Invalid scrypt parameters: {}.", err))?;
+        let mut key = vec![0; *dklen];
+        scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)
+          .map_err(|err| format!("Invalid scrypt key length: {}.", err))?;
+        Ok(key)
+      }
+      Kdf::Pbkdf2 { dklen, c, prf, salt } => {
+        check_dklen(*dklen)?;
+        if prf != "hmac-sha256" {
+          return Err(format!("Unsupported PBKDF2 function '{}'.", prf));
+        }
+        if *c == 0 || *c > MAX_PBKDF2_C {
+          return Err(format!("Invalid PBKDF2 parameter c = {}.", c));
+        }
+        let mut key = vec![0; *dklen];
+        pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha256>>(
+          password.as_bytes(),
+          salt,
+          *c,
+          &mut key,
+        );
+        Ok(key)
+      }
+    }
+  }
+}
+
+fn check_dklen(dklen: usize) -> Result<(), String> {
+  if dklen != DKLEN {
+    return Err(format!("The derived key must have {} bytes.", DKLEN));
+  }
+  Ok(())
+}
+
+/// MAC of the ciphertext: the Keccak256 of the second half of the derived key
+/// followed by the ciphertext.
+fn key_mac(derived: &[u8], ciphertext: &[u8]) -> Hash {
+  Hash::keccak256_from_bytes(&[&derived[16..32], ciphertext].concat())
+}
+
+/// Compares two MACs in a time that doesn't depend on where they differ.
+fn macs_match(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len()
+    && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+  (0..len).map(|_| rand::random()).collect()
+}
+
+impl KeyFile {
+  /// Encrypts a secret key with a password.
+  pub fn encrypt(
+    secret: &[u8; 32],
+    password: &str,
+    kdf: Kdf,
+  ) -> Result<KeyFile, String> {
+    let account = Account::from_private_key(secret);
+    let derived = kdf.derive_key(password)?;
+    let iv = random_bytes(16);
+    let mut ciphertext = secret.to_vec();
+    Aes128Ctr::new(derived[0..16].into(), iv.as_slice().into())
+      .apply_keystream(&mut ciphertext);
+    let mac = key_mac(&derived, &ciphertext).0.to_vec();
+    Ok(KeyFile {
+      version: 3,
+      id: random_uuid(),
+      address: hex::encode(account.address.0),
+      crypto: KeyCrypto {
+        cipher: "aes-128-ctr".to_string(),
+        cipherparams: CipherParams { iv },
+        ciphertext,
+        kdf,
+        mac,
+      },
+    })
+  }
+
+  /// Decrypts the secret key. Fails if the password is wrong.
+  pub fn decrypt(&self, password: &str) -> Result<[u8; 32], String> {
+    if self.version != 3 {
+      return Err(format!("Unsupported keystore version {}.", self.version));
+    }
+    let crypto = &self.crypto;
+    if crypto.cipher != "aes-128-ctr" {
+      return Err(format!("Unsupported cipher '{}'.", crypto.cipher));
+    }
+    if crypto.cipherparams.iv.len() != 16 {
+      return Err("The cipher IV must have 16 bytes.".to_string());
+    }
+    let derived = crypto.kdf.derive_key(password)?;
+    if !macs_match(&key_mac(&derived, &crypto.ciphertext).0, &crypto.mac) {
+      return Err("Wrong password.".to_string());
+    }
+    let mut secret = crypto.ciphertext.clone();
+    Aes128Ctr::new(
+      derived[0..16].into(),
+      crypto.cipherparams.iv.as_slice().into(),
+    )
+    .apply_keystream(&mut secret);
+    let secret: [u8; 32] = secret
+      .try_into()
+      .map_err(|_| "The secret key must have 32 bytes.".to_string())?;
+    if secp256k1::SecretKey::from_slice(&secret).is_err() {
+      return Err("Invalid secret key.".to_string());
+    }
+    Ok(secret)
+  }
+
+  /// Address of the key, as saved on the file.
+  pub fn get_address(&self) -> Result<Address, String> {
+    let address = self.address.trim_start_matches("0x");
+    let bytes = hex::decode(address)
+      .map_err(|err| format!("Invalid address '{}': {}.", self.address, err))?;
+    let bytes = bytes
+      .try_into()
+      .map_err(|_| format!("Invalid address '{}'.", self.address))?;
+    Ok(Address(bytes))
+  }
+}
+
+/// A random (version 4) UUID, used as the id of key files.
+fn random_uuid() -> String {
+  let mut bytes = random_bytes(16);
+  bytes[6] = (bytes[6] & 0x0f) | 0x40;
+  bytes[8] = (bytes[8] & 0x3f) | 0x80;
+  let hex = hex::encode(bytes);
+  format!(
+    "{}-{}-{}-{}-{}",
+    &hex[0..8],
+    &hex[8..12],
+    &hex[12..16],
+    &hex[16..20],
+    &hex[20..32]
+  )
+}
+
+// Keystore
+// ========
+
+/// A directory of key files, named by alias.
+pub struct Keystore {
+  path: PathBuf,
+}
+
+impl Keystore {
+  pub fn new(path: &Path) -> Keystore {
+    Keystore { path: path.to_path_buf() }
+  }
+
+  fn key_path(&self, alias: &str) -> Result<PathBuf, String> {
+    let valid =
+      |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+    if alias.is_empty() || alias.starts_with('.') || !alias.chars().all(valid) {
+      return Err(format!(
+        "Invalid key alias '{}': use letters, digits, '_', '-' and '.'.",
+        alias
+      ));
+    }
+    Ok(self.path.join(format!("{}.json", alias)))
+  }
+
+  /// Aliases of the keys on the keystore, sorted.
+  pub fn list(&self) -> Result<Vec<String>, String> {
+    let entries = match std::fs::read_dir(&self.path) {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        return Ok(vec![])
+      }
+      Err(err) => {
+        return Err(format!(
+          "Could not read the keystore '{}': {}",
+          self.path.display(),
+          err
+        ))
+      }
+    };
+    let mut aliases = vec![];
+    for entry in entries {
+      let path = entry.map_err(|err| err.to_string())?.path();
+      if path.extension().is_some_and(|ext| ext == "json") {
+        if let Some(alias) = path.file_stem().and_then(|stem| stem.to_str()) {
+          aliases.push(alias.to_string());
+        }
+      }
+    }
+    aliases.sort();
+    Ok(aliases)
+  }
+
+  /// Reads the key file saved with `alias`.
+  pub fn get(&self, alias: &str) -> Result<KeyFile, String> {
+    let path = self.key_path(alias)?;
+    let json = std::fs::read_to_string(&path).map_err(|err| {
+      if err.kind() == std::io::ErrorKind::NotFound {
+        format!("There is no key '{}' on the keystore.", alias)
+      } else {
+        format!("Could not read the key '{}': {}", path.display(), err)
+      }
+    })?;
+    serde_json::from_str(&json)
+      .map_err(|err| format!("Invalid key file '{}': {}", path.display(), err))
+  }
+
+  /// Saves a key file with `alias`. Fails if the alias is taken.
+  pub fn insert(&self, alias: &str, key: &KeyFile) -> Result<(), String> {
+    let path = self.key_path(alias)?;
+    if path.exists() {
+      return Err(format!(
+        "There is already a key '{}' on the keystore.",
+        alias
+      ));
+    }
+    std::fs::create_dir_all(&self.path).map_err(|err| {
+      format!(
+        "Could not create the keystore '{}': {}",
+        self.path.display(),
+        err
+      )
+    })?;
+    let json = serde_json::to_string_pretty(key).unwrap();
+    write_secret(&path, json.as_bytes()).map_err(|err| {
+      format!("Could not write the key '{}': {}", path.display(), err)
+    })
+  }
+}
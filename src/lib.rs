@@ -2,17 +2,27 @@
 
 #[allow(non_snake_case)]
 pub mod NoHashHasher;
+pub mod abi;
 pub mod api;
 pub mod bits;
 pub mod common;
 pub mod constants;
 pub mod crypto;
+pub mod deploy;
+pub mod format;
 pub mod hvm;
+pub mod imports;
+pub mod keystore;
+pub mod lsp;
+pub mod nat;
 pub mod net;
 pub mod node;
+pub mod offline;
 pub mod util;
 pub mod config;
 pub mod persistence;
+pub mod secure;
+pub mod tcp;
 
 #[cfg(feature = "events")]
 pub mod events;
@@ -0,0 +1,532 @@
+// Language server analysis
+// ========================
+//
+// Editor-independent analysis of a Kindelia code file, used by the
+// `kindelia-lsp` binary. It reuses the HVM parser and checkers for the
+// diagnostics, and the formatter lexer to locate names in the source.
+//
+// Positions are 0-based lines and columns, like on the Language Server
+// Protocol. Columns are counted in chars.
+
+use std::collections::HashMap;
+
+use crate::common::Name;
+use crate::constants;
+use crate::format::{lex, Tok, Token};
+use crate::hvm::{self, Statement, Term};
+use crate::imports::strip_imports;
+
+// Types
+// =====
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pos {
+  pub line: u32,
+  pub col: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+  pub start: Pos,
+  pub end: Pos,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+  Error,
+  Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+  pub span: Span,
+  pub severity: Severity,
+  pub message: String,
+}
+
+/// Where a definition comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+  Document,
+  Genesis,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DefKind {
+  Fun,
+  Ctr,
+}
+
+#[derive(Debug, Clone)]
+pub struct Definition {
+  pub name: Name,
+  pub kind: DefKind,
+  pub args: Vec<String>,
+  pub origin: Origin,
+  pub span: Span,        // span of the name on its definition
+  pub docs: Vec<String>, // comment lines right before the statement
+  pub rules: Vec<hvm::Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+  pub label: String,
+  pub kind: DefKind,
+  pub detail: String,
+  pub io: bool,
+}
+
+// A top-level statement, as located on the source
+struct Located {
+  keyword: Span,
+  tokens: std::ops::Range<usize>,
+}
+
+/// The result of analysing a document.
+pub struct Document {
+  tokens: Vec<Token>,
+  defs: Vec<Definition>,
+  pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Analyses documents against the definitions of the genesis block.
+pub struct Analyzer {
+  genesis: Vec<Definition>,
+}
+
+// Positions
+// =========
+
+fn token_span(token: &Token) -> Span {
+  let len = match &token.tok {
+    Tok::Word(word) => word.chars().count(),
+    Tok::Str(path) => path.chars().count() + 2,
+    Tok::Sym(sym) => sym.len(),
+    Tok::Eof => 0,
+  };
+  let line = token.line as u32 - 1;
+  let col = token.col as u32;
+  Span { start: Pos { line, col }, end: Pos { line, col: col + len as u32 } }
+}
+
+fn offset_to_pos(text: &str, offset: usize) -> Pos {
+  let before = &text[..offset.min(text.len())];
+  let line = before.matches('\n').count() as u32;
+  let col = before.rsplit('\n').next().unwrap_or("").chars().count() as u32;
+  Pos { line, col }
+}
+
+fn contains(span: &Span, pos: Pos) -> bool {
+  span.start <= pos && pos <= span.end
+}
+
+// Removes the colored context that the parser appends to its messages.
+fn clean_parse_error(erro: &str) -> String {
+  let erro = erro.split(" Context:").next().unwrap_or(erro);
+  erro.lines().next().unwrap_or("").to_string()
+}
+
+// Definitions
+// ===========
+
+// Finds the top-level statements on the token stream. A keyword on the first
+// column always starts a statement, so that an unclosed term that is still
+// being typed doesn't hide the statements below it.
+fn locate_statements(tokens: &[Token]) -> Vec<Located> {
+  let mut located: Vec<Located> = Vec::new();
+  let mut depth: i64 = 0;
+  for (i, token) in tokens.iter().enumerate() {
+    match &token.tok {
+      Tok::Sym("(") | Tok::Sym("{") | Tok::Sym("[") => depth += 1,
+      Tok::Sym(")") | Tok::Sym("}") | Tok::Sym("]") => depth -= 1,
+      Tok::Word(word)
+        if (depth == 0 || token.col == 0)
+          && ["fun", "ctr", "run", "reg", "own"].contains(&word.as_str()) =>
+      {
+        depth = 0;
+        if let Some(last) = located.last_mut() {
+          last.tokens.end = i;
+        }
+        located.push(Located {
+          keyword: token_span(token),
+          tokens: i..tokens.len(),
+        });
+      }
+      _ => {}
+    }
+  }
+  located
+}
+
+fn find_definitions(code: &str, origin: Origin) -> Vec<Definition> {
+  let tokens = match lex(code) {
+    Ok(tokens) => tokens,
+    Err(_) => return vec![],
+  };
+  let stmts =
+    strip_imports(code).ok().and_then(|(_, code)| hvm::parse_code(&code).ok());
+  let located = locate_statements(&tokens);
+  let mut defs = Vec::new();
+  for (i, loc) in located.iter().enumerate() {
+    let toks = &tokens[loc.tokens.clone()];
+    let (kind, close) = match (&toks[0].tok, toks.get(1).map(|t| &t.tok)) {
+      (Tok::Word(kw), Some(Tok::Sym("("))) if kw == "fun" => {
+        (DefKind::Fun, ")")
+      }
+      (Tok::Word(kw), Some(Tok::Sym("{"))) if kw == "ctr" => {
+        (DefKind::Ctr, "}")
+      }
+      _ => continue,
+    };
+    let name_tok = match toks.get(2) {
+      Some(tok) => tok,
+      None => continue,
+    };
+    let name = match &name_tok.tok {
+      Tok::Word(name) => match Name::from_str(name) {
+        Ok(name) => name,
+        Err(_) => continue,
+      },
+      _ => continue,
+    };
+    let args = toks[3..]
+      .iter()
+      .take_while(|t| t.tok != Tok::Sym(close))
+      .map(|t| t.tok.to_string())
+      .collect();
+    let docs = toks[0]
+      .comments
+      .iter()
+      .map(|c| c.text.trim_start_matches('/').trim().to_string())
+      .collect();
+    let rules = match stmts.as_ref().and_then(|stmts| stmts.get(i)) {
+      Some(Statement::Fun { func, .. }) => func.rules.clone(),
+      _ => vec![],
+    };
+    defs.push(Definition {
+      name,
+      kind,
+      args,
+      origin,
+      span: token_span(name_tok),
+      docs,
+      rules,
+    });
+  }
+  defs
+}
+
+// Collects the functions and constructors used on a term, with their arities.
+fn collect_refs(term: &Term, refs: &mut Vec<(DefKind, Name, usize)>) {
+  match term {
+    Term::Var { .. } | Term::Num { .. } => {}
+    Term::Dup { expr, body, .. } => {
+      collect_refs(expr, refs);
+      collect_refs(body, refs);
+    }
+    Term::Lam { body, .. } => collect_refs(body, refs),
+    Term::App { func, argm } => {
+      collect_refs(func, refs);
+      collect_refs(argm, refs);
+    }
+    Term::Ctr { name, args } => {
+      refs.push((DefKind::Ctr, *name, args.len()));
+      for arg in args {
+        collect_refs(arg, refs);
+      }
+    }
+    Term::Fun { name, args } => {
+      refs.push((DefKind::Fun, *name, args.len()));
+      for arg in args {
+        collect_refs(arg, refs);
+      }
+    }
+    Term::Op2 { val0, val1, .. } => {
+      collect_refs(val0, refs);
+      collect_refs(val1, refs);
+    }
+  }
+}
+
+// Analyzer
+// ========
+
+impl Default for Analyzer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Analyzer {
+  pub fn new() -> Self {
+    Analyzer {
+      genesis: find_definitions(constants::GENESIS_CODE, Origin::Genesis),
+    }
+  }
+
+  pub fn analyze(&self, text: &str) -> Document {
+    let tokens = lex(text).unwrap_or_default();
+    let defs = find_definitions(text, Origin::Document);
+    let mut doc = Document { tokens, defs, diagnostics: vec![] };
+    doc.diagnostics = self.diagnostics(&doc, text);
+    doc
+  }
+
+  fn diagnostics(&self, doc: &Document, text: &str) -> Vec<Diagnostic> {
+    let error_at = |pos: Pos, message: String| Diagnostic {
+      span: Span { start: pos, end: Pos { line: pos.line, col: pos.col + 1 } },
+      severity: Severity::Error,
+      message,
+    };
+
+    // Syntax. Imported files are not analyzed, so names not found may still
+    // be defined on them.
+    let (imports, code) = match strip_imports(text) {
+      Ok(res) => res,
+      Err(err) => return vec![error_at(Pos { line: 0, col: 0 }, err)],
+    };
+    let text = code.as_str();
+    let stmts = match hvm::read_statements(text) {
+      Ok((rest, stmts)) => {
+        if !rest.trim().is_empty() {
+          let pos = offset_to_pos(text, text.len() - rest.len());
+          return vec![error_at(
+            pos,
+            "Your code was not parsed entirely.".to_string(),
+          )];
+        }
+        stmts
+      }
+      Err(err) => {
+        let offset = text.len().saturating_sub(err.code.len());
+        return vec![error_at(
+          offset_to_pos(text, offset),
+          clean_parse_error(&err.erro),
+        )];
+      }
+    };
+
+    // Definitions
+    let mut diags = Vec::new();
+    let located = locate_statements(&doc.tokens);
+    let mut arities: HashMap<(DefKind, Name), usize> = HashMap::new();
+    for def in self.genesis.iter().chain(doc.defs.iter()) {
+      arities.insert((def.kind, def.name), def.args.len());
+    }
+    for (i, stmt) in stmts.iter().enumerate() {
+      let loc = match located.get(i) {
+        Some(loc) => loc,
+        None => break,
+      };
+      let mut diag = |span: Span, severity: Severity, message: String| {
+        diags.push(Diagnostic { span, severity, message });
+      };
+      let mut terms: Vec<&Term> = Vec::new();
+      match stmt {
+        Statement::Fun { func, init, .. } => {
+          if let Err(err) = hvm::compile_func(func, false) {
+            diag(loc.keyword, Severity::Error, hvm::show_runtime_error(err));
+          }
+          for rule in &func.rules {
+            terms.push(&rule.lhs);
+            terms.push(&rule.rhs);
+          }
+          if let Some(init) = init {
+            if let Err(err) = hvm::check_term(init) {
+              diag(loc.keyword, Severity::Error, hvm::show_runtime_error(err));
+            }
+            terms.push(init);
+          }
+        }
+        Statement::Run { expr, .. } => {
+          if let Err(err) = hvm::check_term(expr) {
+            diag(loc.keyword, Severity::Error, hvm::show_runtime_error(err));
+          }
+          terms.push(expr);
+        }
+        Statement::Ctr { .. }
+        | Statement::Reg { .. }
+        | Statement::Own { .. } => {}
+      }
+
+      // References
+      let mut refs = Vec::new();
+      for term in terms {
+        collect_refs(term, &mut refs);
+      }
+      for (kind, name, arity) in refs {
+        let span = self.find_ref(doc, loc, kind, name).unwrap_or(loc.keyword);
+        match arities.get(&(kind, name)) {
+          None if imports.is_empty() => {
+            let what =
+              if kind == DefKind::Fun { "Function" } else { "Constructor" };
+            let message = format!(
+              "{} '{}' is not defined in this file nor in genesis.",
+              what, name
+            );
+            diag(span, Severity::Warning, message);
+          }
+          Some(expected) if *expected != arity => {
+            let message = format!(
+              "Arity mismatch for '{}': expected {} args, got {}.",
+              name, expected, arity
+            );
+            diag(span, Severity::Error, message);
+          }
+          _ => {}
+        }
+      }
+    }
+    diags
+  }
+
+  // Finds where `name` is used as a function or constructor inside a statement.
+  fn find_ref(
+    &self,
+    doc: &Document,
+    loc: &Located,
+    kind: DefKind,
+    name: Name,
+  ) -> Option<Span> {
+    let open = if kind == DefKind::Fun { "(" } else { "{" };
+    let toks = &doc.tokens[loc.tokens.clone()];
+    toks.windows(2).find_map(|pair| match (&pair[0].tok, &pair[1].tok) {
+      (Tok::Sym(sym), Tok::Word(word))
+        if *sym == open && *word == name.to_string() =>
+      {
+        Some(token_span(&pair[1]))
+      }
+      _ => None,
+    })
+  }
+
+  fn word_at<'a>(&self, doc: &'a Document, pos: Pos) -> Option<&'a str> {
+    doc.tokens.iter().find_map(|token| match &token.tok {
+      Tok::Word(word) if contains(&token_span(token), pos) => {
+        Some(word.as_str())
+      }
+      _ => None,
+    })
+  }
+
+  // Looks for a definition on the document, then on genesis.
+  fn lookup<'a>(
+    &'a self,
+    doc: &'a Document,
+    word: &str,
+  ) -> Vec<&'a Definition> {
+    let name = match Name::from_str(word) {
+      Ok(name) => name,
+      Err(_) => return vec![],
+    };
+    let local: Vec<&Definition> =
+      doc.defs.iter().filter(|def| def.name == name).collect();
+    if !local.is_empty() {
+      return local;
+    }
+    self.genesis.iter().filter(|def| def.name == name).collect()
+  }
+
+  /// Returns the definition of the name at `pos`.
+  pub fn definition(&self, doc: &Document, pos: Pos) -> Option<Definition> {
+    let word = self.word_at(doc, pos)?;
+    // Prefers functions, as `(Foo)` and `{Foo}` usually go together.
+    let defs = self.lookup(doc, word);
+    defs
+      .iter()
+      .find(|def| def.kind == DefKind::Fun)
+      .or_else(|| defs.first())
+      .map(|def| (*def).clone())
+  }
+
+  /// Returns a markdown description of the name at `pos`.
+  pub fn hover(&self, doc: &Document, pos: Pos) -> Option<String> {
+    let word = self.word_at(doc, pos)?;
+    let defs = self.lookup(doc, word);
+    let io = hvm::IO_NAMES.contains(&word);
+    if defs.is_empty() {
+      return if io {
+        Some(format!("`{}`: IO constructor", word))
+      } else {
+        None
+      };
+    }
+    let mut text = Vec::new();
+    for def in defs {
+      text.push(format!("```\n{}\n```", view_signature(def)));
+      text.extend(def.docs.iter().cloned());
+      let mut info = vec![format!("- arity: {}", def.args.len())];
+      if io && def.kind == DefKind::Ctr {
+        info.push("- IO constructor".to_string());
+      }
+      if def.origin == Origin::Genesis {
+        info.push("- defined on genesis".to_string());
+      }
+      for (i, rule) in def.rules.iter().enumerate() {
+        let allocs = hvm::count_allocs(&rule.rhs);
+        info.push(format!(
+          "- rule {}: `{}` allocs {}, FUN-CTR mana {}",
+          i,
+          hvm::view_term(&rule.lhs),
+          allocs,
+          2 + allocs
+        ));
+      }
+      text.push(info.join("\n"));
+    }
+    Some(text.join("\n\n"))
+  }
+
+  /// Returns the completions for the name being typed at `pos`.
+  pub fn completions(
+    &self,
+    doc: &Document,
+    text: &str,
+    pos: Pos,
+  ) -> Vec<Completion> {
+    let line = text.lines().nth(pos.line as usize).unwrap_or("");
+    let before: Vec<char> = line.chars().take(pos.col as usize).collect();
+    let start = before
+      .iter()
+      .rposition(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '.'))
+      .map_or(0, |i| i + 1);
+    let prefix: String = before[start..].iter().collect();
+    let only = match start.checked_sub(1).map(|i| before[i]) {
+      Some('(') => Some(DefKind::Fun),
+      Some('{') => Some(DefKind::Ctr),
+      _ => None,
+    };
+
+    let mut items: Vec<Completion> = Vec::new();
+    let mut push = |label: String, kind: DefKind, detail: String, io: bool| {
+      let seen =
+        items.iter().any(|item| item.label == label && item.kind == kind);
+      if label.starts_with(&prefix) && only.unwrap_or(kind) == kind && !seen {
+        items.push(Completion { label, kind, detail, io });
+      }
+    };
+    for io in hvm::IO_NAMES {
+      let def = self
+        .genesis
+        .iter()
+        .find(|def| def.kind == DefKind::Ctr && def.name.to_string() == io);
+      let detail = match def {
+        Some(def) => view_signature(def),
+        None => "IO constructor".to_string(),
+      };
+      push(io.to_string(), DefKind::Ctr, detail, true);
+    }
+    for def in doc.defs.iter().chain(self.genesis.iter()) {
+      push(def.name.to_string(), def.kind, view_signature(def), false);
+    }
+    items
+  }
+}
+
+fn view_signature(def: &Definition) -> String {
+  let args: String = def.args.iter().map(|arg| format!(" {}", arg)).collect();
+  match def.kind {
+    DefKind::Fun => format!("fun ({}{})", def.name, args),
+    DefKind::Ctr => format!("ctr {{{}{}}}", def.name, args),
+  }
+}
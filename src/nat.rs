@@ -0,0 +1,175 @@
+// NAT traversal
+// =============
+
+// Maps the node's port on the home router, so that peers can reach a node
+// behind a NAT. UPnP is tried first, then NAT-PMP (RFC 6886) on the default
+// gateway. Mappings expire, so they are renewed on a thread while the node
+// runs. Without a mapping, the node still learns its external address from
+// the `Observed` messages of its peers (see `node.rs`).
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use crate::net::{from_socket_addr, Address, Transport};
+
+/// Requested lifetime of a mapping, in seconds.
+pub const MAPPING_LIFETIME: u32 = 2 * 60 * 60;
+
+/// Port where gateways answer NAT-PMP.
+pub const NATPMP_PORT: u16 = 5351;
+
+/// First wait for a NAT-PMP answer. It doubles on each retry.
+pub const NATPMP_TIMEOUT: Duration = Duration::from_millis(250);
+pub const NATPMP_TRIES: u32 = 4;
+
+/// Time to search for an UPnP gateway.
+pub const UPNP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A port mapped on the gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+  pub external: SocketAddrV4,
+  pub lifetime: u32, // seconds
+}
+
+/// Maps `port` on the gateway, with UPnP or NAT-PMP.
+pub fn map_port(transport: Transport, port: u16) -> Result<Mapping, String> {
+  map_upnp(transport, port).or_else(|upnp_err| {
+    let gateway = default_gateway()
+      .ok_or_else(|| format!("UPnP: {}. NAT-PMP: no gateway.", upnp_err))?;
+    let gateway = SocketAddrV4::new(gateway, NATPMP_PORT);
+    map_natpmp(gateway, transport, port)
+      .map_err(|err| format!("UPnP: {}. NAT-PMP: {}", upnp_err, err))
+  })
+}
+
+/// Maps `port` on the gateway, and keeps renewing it. Returns the external
+/// address.
+pub fn spawn_port_mapping(
+  transport: Transport,
+  port: u16,
+) -> Result<Address, String> {
+  let mut mapping = map_port(transport, port)?;
+  let external = from_socket_addr(&SocketAddr::V4(mapping.external));
+  std::thread::spawn(move || loop {
+    let renew_in = (mapping.lifetime as u64 / 2).max(60);
+    std::thread::sleep(Duration::from_secs(renew_in));
+    match map_port(transport, port) {
+      Ok(renewed) => mapping = renewed,
+      Err(err) => eprintln!("Could not renew the port mapping: {}", err),
+    }
+  });
+  Ok(external)
+}
+
+// UPnP
+// ====
+
+fn map_upnp(transport: Transport, port: u16) -> Result<Mapping, String> {
+  let options =
+    igd::SearchOptions { timeout: Some(UPNP_TIMEOUT), ..Default::default() };
+  let gateway = igd::search_gateway(options).map_err(|err| err.to_string())?;
+  let local_ip = local_ip_towards(gateway.addr)?;
+  let protocol = match transport {
+    Transport::Udp => igd::PortMappingProtocol::UDP,
+    Transport::Tcp => igd::PortMappingProtocol::TCP,
+  };
+  let local = SocketAddrV4::new(local_ip, port);
+  gateway
+    .add_port(protocol, port, local, MAPPING_LIFETIME, "Kindelia node")
+    .map_err(|err| err.to_string())?;
+  let ip = gateway.get_external_ip().map_err(|err| err.to_string())?;
+  let external = SocketAddrV4::new(ip, port);
+  Ok(Mapping { external, lifetime: MAPPING_LIFETIME })
+}
+
+/// Our address on the network of `remote`.
+fn local_ip_towards(remote: SocketAddrV4) -> Result<Ipv4Addr, String> {
+  let socket = UdpSocket::bind("0.0.0.0:0").map_err(|err| err.to_string())?;
+  socket.connect(remote).map_err(|err| err.to_string())?;
+  match socket.local_addr().map_err(|err| err.to_string())? {
+    SocketAddr::V4(addr) => Ok(*addr.ip()),
+    SocketAddr::V6(_) => Err("TODO: IPv6".to_string()),
+  }
+}
+
+// NAT-PMP
+// =======
+
+/// Maps `port` with NAT-PMP, asking the gateway its external address too.
+pub fn map_natpmp(
+  gateway: SocketAddrV4,
+  transport: Transport,
+  port: u16,
+) -> Result<Mapping, String> {
+  let socket = UdpSocket::bind("0.0.0.0:0").map_err(|err| err.to_string())?;
+  socket.connect(gateway).map_err(|err| err.to_string())?;
+
+  // External address: version, opcode 0
+  let answer = natpmp_request(&socket, &[0, 0], 12)?;
+  let ip = Ipv4Addr::new(answer[8], answer[9], answer[10], answer[11]);
+
+  // Mapping: version, opcode (1 UDP, 2 TCP), reserved, internal port,
+  // suggested external port, lifetime
+  let opcode = match transport {
+    Transport::Udp => 1,
+    Transport::Tcp => 2,
+  };
+  let mut request = vec![0, opcode, 0, 0];
+  request.extend_from_slice(&port.to_be_bytes());
+  request.extend_from_slice(&port.to_be_bytes());
+  request.extend_from_slice(&MAPPING_LIFETIME.to_be_bytes());
+  let answer = natpmp_request(&socket, &request, 16)?;
+  let external_port = u16::from_be_bytes([answer[10], answer[11]]);
+  let lifetime = u32::from_be_bytes(answer[12..16].try_into().unwrap());
+  let external = SocketAddrV4::new(ip, external_port);
+  Ok(Mapping { external, lifetime })
+}
+
+/// Sends a NAT-PMP request until the gateway answers it, with `len` bytes.
+fn natpmp_request(
+  socket: &UdpSocket,
+  request: &[u8],
+  len: usize,
+) -> Result<Vec<u8>, String> {
+  let mut timeout = NATPMP_TIMEOUT;
+  for _ in 0..NATPMP_TRIES {
+    socket.set_read_timeout(Some(timeout)).map_err(|err| err.to_string())?;
+    socket.send(request).map_err(|err| err.to_string())?;
+    let mut buffer = [0; 16];
+    while let Ok(size) = socket.recv(&mut buffer) {
+      // Answers echo the opcode plus 128, after the version
+      if size < len || buffer[0] != 0 || buffer[1] != 128 + request[1] {
+        continue;
+      }
+      let result = u16::from_be_bytes([buffer[2], buffer[3]]);
+      if result != 0 {
+        return Err(format!("the gateway answered with error {}", result));
+      }
+      return Ok(buffer[..len].to_vec());
+    }
+    timeout *= 2;
+  }
+  Err("the gateway didn't answer".to_string())
+}
+
+/// The default gateway, from the kernel's routing table (Linux only).
+fn default_gateway() -> Option<Ipv4Addr> {
+  let table = std::fs::read_to_string("/proc/net/route").ok()?;
+  parse_default_gateway(&table)
+}
+
+/// Finds the default gateway on a `/proc/net/route` table, where addresses
+/// are in little-endian hex.
+pub fn parse_default_gateway(table: &str) -> Option<Ipv4Addr> {
+  for line in table.lines().skip(1) {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() > 2 && fields[1] == "00000000" {
+      let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+      if gateway != 0 {
+        return Some(Ipv4Addr::from(gateway.to_le_bytes()));
+      }
+    }
+  }
+  None
+}
@@ -1,13 +1,13 @@
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
 pub use std::net::UdpSocket;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
 use bit_vec::BitVec;
 use serde;
 
 use crate::bits::ProtoSerialize;
-use crate::node::Message;
+use crate::node::{Message, MAX_UDP_SIZE_SLOW};
 use crate::util::bitvec_to_bytes;
 
 // Traits
@@ -43,6 +43,54 @@ where
   );
   fn proto_recv(&mut self) -> Vec<(Self::Address, Message<Self::Address>)>;
   fn get_addr(&self) -> Self::Address;
+  /// The size messages are kept under, when they can be split, like the
+  /// blocks sent to a peer.
+  fn max_message_size(&self) -> usize {
+    MAX_UDP_SIZE_SLOW
+  }
+  /// The static public key a peer proved to own, on transports that
+  /// authenticate their peers.
+  fn get_peer_key(&self, _address: &Self::Address) -> Option<PeerKey> {
+    None
+  }
+}
+
+/// The static public key identifying a node on authenticated transports.
+pub type PeerKey = secp256k1::PublicKey;
+
+/// Sends and receives raw packets, so that other `ProtoComm`s (like the
+/// `secure` one) can be layered on it.
+pub trait PacketComm
+where
+  Self: Sized + Send,
+{
+  type Address: ProtoAddr;
+  fn packet_send(&mut self, address: Self::Address, bytes: &[u8]);
+  fn packet_recv(&mut self) -> Vec<(Self::Address, Vec<u8>)>;
+  fn packet_addr(&self) -> Self::Address;
+  fn max_packet_size(&self) -> usize {
+    MAX_UDP_SIZE_SLOW
+  }
+}
+
+/// The transport used to talk to peers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Transport {
+  /// Datagrams, lost silently, and capped in size.
+  Udp,
+  /// Reliable streams, with messages of any size.
+  Tcp,
+}
+
+impl std::str::FromStr for Transport {
+  type Err = String;
+  fn from_str(code: &str) -> Result<Self, Self::Err> {
+    match code {
+      "udp" => Ok(Transport::Udp),
+      "tcp" => Ok(Transport::Tcp),
+      _ => Err(format!("Invalid transport '{}', expected udp or tcp.", code)),
+    }
+  }
 }
 
 // UDP Implementation
@@ -88,6 +136,26 @@ pub fn parse_address(code: &str) -> Address {
   }
 }
 
+pub(crate) fn to_socket_addr(address: &Address) -> SocketAddrV4 {
+  match *address {
+    Address::IPv4 { val0, val1, val2, val3, port } => {
+      SocketAddrV4::new(Ipv4Addr::new(val0, val1, val2, val3), port)
+    }
+  }
+}
+
+pub(crate) fn from_socket_addr(addr: &SocketAddr) -> Address {
+  match addr.ip() {
+    std::net::IpAddr::V4(v4addr) => {
+      let [val0, val1, val2, val3] = v4addr.octets();
+      Address::IPv4 { val0, val1, val2, val3, port: addr.port() }
+    }
+    _ => {
+      panic!("TODO: IPv6")
+    }
+  }
+}
+
 /// The UDP implementation based on `std::netUdpSocket` struct
 impl ProtoComm for UdpSocket {
   type Address = Address;
@@ -98,43 +166,44 @@ impl ProtoComm for UdpSocket {
   ) {
     let bytes = bitvec_to_bytes(&message.proto_serialized());
     for address in addresses {
-      match address {
-        Address::IPv4 { val0, val1, val2, val3, port } => {
-          let addr =
-            SocketAddrV4::new(Ipv4Addr::new(val0, val1, val2, val3), port);
-          self.send_to(bytes.as_slice(), addr).ok();
-        }
-      }
+      self.packet_send(address, &bytes);
     }
   }
   fn proto_recv(&mut self) -> Vec<(Self::Address, Message<Self::Address>)> {
-    let mut buffer = [0; 65536];
     let mut messages = Vec::new();
-    while let Ok((msg_len, sender_addr)) = self.recv_from(&mut buffer) {
-      let bits = BitVec::from_bytes(&buffer[0..msg_len]);
+    for (addr, bytes) in self.packet_recv() {
+      let bits = BitVec::from_bytes(&bytes);
       if let Some(msge) = Message::proto_deserialized(&bits) {
-        let addr = match sender_addr.ip() {
-          std::net::IpAddr::V4(v4addr) => {
-            let [val0, val1, val2, val3] = v4addr.octets();
-            Address::IPv4 { val0, val1, val2, val3, port: sender_addr.port() }
-          }
-          _ => {
-            panic!("TODO: IPv6")
-          }
-        };
         messages.push((addr, msge));
       }
     }
     messages
   }
   fn get_addr(&self) -> Self::Address {
+    self.packet_addr()
+  }
+}
+
+impl PacketComm for UdpSocket {
+  type Address = Address;
+  fn packet_send(&mut self, address: Self::Address, bytes: &[u8]) {
+    self.send_to(bytes, to_socket_addr(&address)).ok();
+  }
+  fn packet_recv(&mut self) -> Vec<(Self::Address, Vec<u8>)> {
+    let mut buffer = [0; 65536];
+    let mut packets = Vec::new();
+    while let Ok((msg_len, sender_addr)) = self.recv_from(&mut buffer) {
+      packets
+        .push((from_socket_addr(&sender_addr), buffer[0..msg_len].to_vec()));
+    }
+    packets
+  }
+  fn packet_addr(&self) -> Self::Address {
     // TODO: remove unwrap and panic
     let addr = self.local_addr().unwrap();
-    if let std::net::IpAddr::V4(v4addr) = addr.ip() {
-      let [val0, val1, val2, val3] = v4addr.octets();
-      Address::IPv4 { val0, val1, val2, val3, port: addr.port() }
-    } else {
+    if addr.is_ipv6() {
       panic!("TODO: IPv6")
     }
+    from_socket_addr(&addr)
   }
 }
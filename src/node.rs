@@ -1,8 +1,11 @@
 #![allow(clippy::style)]
 
+use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::JoinHandle;
 
@@ -16,11 +19,12 @@ use crate::api::{self, CtrInfo, RegInfo};
 use crate::api::{BlockInfo, FuncInfo, NodeRequest};
 use crate::bits::{serialized_block_size, ProtoSerialize};
 use crate::common::Name;
-use crate::config::{MineConfig, NodeConfig};
+use crate::config::{MineConfig, NodeConfig, RollbackConfig};
 use crate::constants;
 use crate::crypto::{self, Hashed, Keccakable};
 use crate::hvm::{self, *};
-use crate::net::{ProtoAddr, ProtoComm};
+use crate::net::{PeerKey, ProtoAddr, ProtoComm};
+use crate::persistence::{self, BlockStorage, KvBlockStorage, StorableBlock};
 use crate::util::*;
 
 use crate::events::{self, NodeEventEmittedInfo, NodeEventType};
@@ -225,6 +229,22 @@ impl crypto::Keccakable for Block {
   }
 }
 
+// Blocks are stored as their `ProtoSerialize` bytes
+impl StorableBlock for HashedBlock {
+  fn block_hash(&self) -> U256 {
+    self.get_hash().into()
+  }
+  fn to_bytes(&self) -> Vec<u8> {
+    bitvec_to_bytes(&self.proto_serialized())
+  }
+  fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    Block::proto_deserialized(&bytes_to_bitvec(bytes)).map(Block::hashed)
+  }
+}
+
+/// A block, with its index entries on the block storage.
+pub type StoredBlock = persistence::StoredBlock<HashedBlock>;
+
 // Node
 // ====
 
@@ -246,12 +266,13 @@ pub enum InclusionState {
 
 // TODO: refactor .block as map to struct? Better safety, less unwraps. Why not?
 #[rustfmt::skip]
-pub struct Node<C: ProtoComm> {
+pub struct Node<C: ProtoComm, S: BlockStorage> {
   pub data_path    : PathBuf,                           // path where files are saved
   pub network_id   : u32,                               // Network ID / magic number
   pub comm         : C,                                 // UDP socket
   pub addr         : C::Address,                        // UDP port
   pub runtime      : Runtime,                           // Kindelia's runtime
+  pub storage      : S,                                 // longest chain saved on disk
   pub query_recv   : mpsc::Receiver<NodeRequest<C>>,    // Receives an API request
   pub pool         : PriorityQueue<Transaction, u64>,   // transactions to be mined
   pub peers        : PeersStore<C::Address>,            // peers store and state control
@@ -266,6 +287,10 @@ pub struct Node<C: ProtoComm> {
   pub target     : U256Map<U256>,                  // block hash -> this block's target
   pub height     : U256Map<u128>,                  // block hash -> cached height
   pub results    : U256Map<Vec<StatementResult>>,  // block hash -> results of the statements in this block
+  pub roots      : U256Map<U256>,                  // block hash -> state root after this block
+  pub check_invariants : bool,                     // checks the runtime invariants after each block
+  pub advertise_address : Option<C::Address>,      // address peers reach us at, when set on the config
+  pub stop       : Arc<AtomicBool>,                // set on Ctrl-C, to save the state and exit
 
   #[cfg(feature = "events")]
   pub event_emitter : mpsc::Sender<NodeEventEmittedInfo>,
@@ -281,14 +306,64 @@ pub struct Peer<A: ProtoAddr> {
   pub address: A,
 }
 
+/// What was negotiated with a peer on its `Hello`.
+#[derive(
+  Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize,
+)]
+pub struct PeerProtocol {
+  pub version: u32,               // the lowest of both versions
+  pub capabilities: Capabilities, // the capabilities of both
+  pub height: u128,               // the best height it announced
+}
+
+/// Negotiates the protocol with a peer, from its `Hello`. Fails if the peer is
+/// on another chain, or its version is too old.
+pub fn negotiate(
+  genesis_hash: &U256,
+  version: u32,
+  capabilities: Capabilities,
+  height: u128,
+  genesis: &U256,
+) -> Result<PeerProtocol, String> {
+  if genesis != genesis_hash {
+    return Err(format!(
+      "Peer is on another chain, with genesis {:#x}.",
+      genesis
+    ));
+  }
+  let version = std::cmp::min(version, PROTOCOL_VERSION);
+  if version < MIN_PROTOCOL_VERSION {
+    return Err(format!("Peer protocol version {} is too old.", version));
+  }
+  let capabilities = capabilities & CAPABILITIES;
+  Ok(PeerProtocol { version, capabilities, height })
+}
+
 pub struct PeersStore<A: ProtoAddr> {
   seen: HashMap<A, Peer<A>>,
   active: HashMap<A, Peer<A>>,
+  protocols: HashMap<A, PeerProtocol>, // negotiated on `Hello`; absent for legacy peers
+  greeted: HashMap<A, u128>,           // when we last sent them our `Hello`
+  rejected: HashMap<A, u128>,          // incompatible peers, ignored until then
+  incompatible: HashMap<A, u128>,      // said a bad `Hello`, unsent until then
+  keys: HashMap<A, PeerKey>,           // proven on authenticated transports
+  observed: HashMap<A, A>,             // where each peer sees us, on `Observed`
+  challenges: RandomState,             // keys the challenge of our `Hello`s
 }
 
 impl<A: ProtoAddr> PeersStore<A> {
   pub fn new() -> PeersStore<A> {
-    PeersStore { seen: HashMap::new(), active: HashMap::new() }
+    PeersStore {
+      seen: HashMap::new(),
+      active: HashMap::new(),
+      protocols: HashMap::new(),
+      greeted: HashMap::new(),
+      rejected: HashMap::new(),
+      incompatible: HashMap::new(),
+      keys: HashMap::new(),
+      observed: HashMap::new(),
+      challenges: RandomState::new(),
+    }
   }
 
   /// This function checks and puts a peer as active on `PeerStore`.
@@ -308,6 +383,9 @@ impl<A: ProtoAddr> PeersStore<A> {
     >,
   ) {
     let addr = peer.address;
+    if self.is_rejected(&addr, get_time()) {
+      return;
+    }
     match self.seen.get(&addr) {
       // New peer, not seen before
       None => {
@@ -371,10 +449,122 @@ impl<A: ProtoAddr> PeersStore<A> {
     for addr in forget {
       self.inactivate_peer(&addr);
     }
+    let now = get_time();
+    self.rejected.retain(|_, until| *until > now);
+    self.incompatible.retain(|_, until| *until > now);
   }
 
+  /// Forgets an active peer, and what was negotiated with it, so it's greeted
+  /// again if it comes back.
   pub fn inactivate_peer(&mut self, addr: &A) {
     self.active.remove(addr);
+    self.protocols.remove(addr);
+    self.greeted.remove(addr);
+    self.observed.remove(addr);
+  }
+
+  /// The protocol negotiated with a peer. `None` for peers that didn't say
+  /// `Hello`, like the ones from before protocol versioning.
+  pub fn get_protocol(&self, addr: &A) -> Option<PeerProtocol> {
+    self.protocols.get(addr).copied()
+  }
+
+  /// Records the protocol negotiated with a peer. Returns if it was unknown.
+  pub fn set_protocol(&mut self, addr: &A, protocol: PeerProtocol) -> bool {
+    self.incompatible.remove(addr);
+    self.protocols.insert(*addr, protocol).is_none()
+  }
+
+  /// Stops sending to a peer whose `Hello` failed, for `INCOMPATIBLE_TIMEOUT`
+  /// or until it says a good one. Unlike `reject`, the peer is kept, as the
+  /// `Hello` may be forged.
+  pub fn set_incompatible(&mut self, addr: &A, now: u128) {
+    self.protocols.remove(addr);
+    self.incompatible.insert(*addr, now + INCOMPATIBLE_TIMEOUT);
+  }
+
+  /// Whether a message can be sent to a peer: it must understand it, by the
+  /// negotiated version, and not be incompatible. `Hello`s go to everyone, as
+  /// that's how versions are found, and older peers just drop them.
+  pub fn can_send(&self, addr: &A, msg: &Message<A>, now: u128) -> bool {
+    if let Message::Hello { .. } = msg {
+      return true;
+    }
+    let incompatible =
+      self.incompatible.get(addr).is_some_and(|until| *until > now);
+    let version = self.get_protocol(addr).map_or(1, |proto| proto.version);
+    !incompatible && version >= msg.version()
+  }
+
+  /// Forgets an incompatible peer, and ignores it for `REJECT_TIMEOUT`.
+  pub fn reject(&mut self, addr: &A, now: u128) {
+    self.seen.remove(addr);
+    self.active.remove(addr);
+    self.protocols.remove(addr);
+    self.greeted.remove(addr);
+    self.keys.remove(addr);
+    self.observed.remove(addr);
+    self.rejected.insert(*addr, now + REJECT_TIMEOUT);
+  }
+
+  /// The static key of a peer, if the transport authenticated it.
+  pub fn get_key(&self, addr: &A) -> Option<PeerKey> {
+    self.keys.get(addr).copied()
+  }
+
+  /// Records the static key a peer proved to own.
+  pub fn set_key(&mut self, addr: &A, key: PeerKey) {
+    self.keys.insert(*addr, key);
+  }
+
+  /// The challenge of our `Hello` to a peer. It's keyed by a secret, so only
+  /// who gets the `Hello` at that address knows it.
+  pub fn challenge(&self, addr: &A) -> u64 {
+    self.challenges.hash_one(addr)
+  }
+
+  /// Records the address a peer sees us at. Only peers that said `Hello`, and
+  /// echoed its `challenge`, are heard. When there are too many, new ones wait
+  /// for the old ones to go inactive, so a flood can't push out honest peers.
+  pub fn set_observed(&mut self, addr: &A, observed: A, challenge: u64) {
+    if !self.protocols.contains_key(addr) || challenge != self.challenge(addr) {
+      return;
+    }
+    if self.observed.len() < MAX_OBSERVERS || self.observed.contains_key(addr) {
+      self.observed.insert(*addr, observed);
+    }
+  }
+
+  /// Our address as seen from outside: the one most peers see us at, if at
+  /// least `OBSERVED_QUORUM` of them agree.
+  pub fn external_address(&self) -> Option<A> {
+    let mut votes: HashMap<A, usize> = HashMap::new();
+    for observed in self.observed.values() {
+      *votes.entry(*observed).or_default() += 1;
+    }
+    let (address, count) = votes.into_iter().max_by_key(|(_, count)| *count)?;
+    (count >= OBSERVED_QUORUM).then_some(address)
+  }
+
+  pub fn is_rejected(&self, addr: &A, now: u128) -> bool {
+    self.rejected.get(addr).is_some_and(|until| *until > now)
+  }
+
+  /// The active peers that didn't say `Hello` yet, and weren't greeted
+  /// recently. They are recorded as greeted now.
+  pub fn to_greet(&mut self, now: u128) -> Vec<A> {
+    let mut addrs = Vec::new();
+    for addr in self.active.keys() {
+      let greeted =
+        self.greeted.get(addr).is_some_and(|at| *at + GREET_DELAY > now);
+      if !self.protocols.contains_key(addr) && !greeted {
+        addrs.push(*addr);
+      }
+    }
+    for addr in &addrs {
+      self.greeted.insert(*addr, now);
+    }
+    addrs
   }
 
   pub fn get_all_active(&self) -> Vec<Peer<A>> {
@@ -428,8 +618,59 @@ pub enum Message<A: ProtoAddr> {
     magic: u32,
     tx: Transaction,
   },
+  /// Sent to new peers, so that both agree on the protocol, and replied with
+  /// our own `Hello` the first time a peer says it. The `challenge` is echoed
+  /// on `Observed`, proving the peer got it at the address it says hello from.
+  Hello {
+    magic: u32,
+    version: u32,
+    capabilities: Capabilities,
+    height: u128,
+    genesis: Hash,
+    challenge: u64,
+  },
+  /// Replied to a `Hello`, with the address the peer was seen at, so that
+  /// nodes behind a NAT learn their external address. Since version 2.
+  Observed {
+    magic: u32,
+    address: A,
+    challenge: u64,
+  },
+}
+
+impl<A: ProtoAddr> Message<A> {
+  /// The protocol version that introduced the message.
+  pub fn version(&self) -> u32 {
+    match self {
+      Message::Hello { .. } | Message::Observed { .. } => 2,
+      _ => 1,
+    }
+  }
 }
 
+/// Version of the peer protocol, announced on `Hello`. Every change to the
+/// messages must bump it. Peers talk on the lowest version of both.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version of the peer protocol this node talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of the protocol, announced on `Hello` as a bitset. Only
+/// the ones both peers have are used with a peer.
+pub type Capabilities = u64;
+
+/// The capabilities of this node.
+pub const CAPABILITIES: Capabilities = 0;
+
+/// Blocks off the longest chain this far below the tip are deleted on startup.
+pub const PRUNE_DEPTH: u128 = 10_000;
+
+/// How many peers must see us at the same address, for us to advertise it.
+pub const OBSERVED_QUORUM: usize = 3;
+
+/// Max number of peers whose view of our address is kept.
+pub const MAX_OBSERVERS: usize = 64;
+
 // Constants
 // =========
 
@@ -439,7 +680,8 @@ pub const _HASH_SIZE: usize = 32;
 // Size of a block's body, in bytes
 pub const MAX_BODY_SIZE: usize = 1280;
 
-// Max size of a big UDP packet, in bytes
+// Max size of a big UDP packet, in bytes. Messages are kept under it, unless
+// the transport allows more (see `ProtoComm::max_message_size`).
 pub const MAX_UDP_SIZE_SLOW: usize = 8000;
 
 // Max size of a fast UDP packet, in bytes
@@ -483,6 +725,15 @@ pub const INITIAL_DIFFICULTY: u128 = 256;
 // How many milliseconds without notice until we forget a peer?
 pub const PEER_TIMEOUT: u128 = 10 * 1000;
 
+// How many milliseconds until we say `Hello` again to a peer that didn't reply?
+pub const GREET_DELAY: u128 = 30 * 1000;
+
+// How many milliseconds an incompatible peer is ignored for?
+pub const REJECT_TIMEOUT: u128 = 10 * 60 * 1000;
+
+// How many milliseconds we don't send to a peer whose `Hello` failed?
+pub const INCOMPATIBLE_TIMEOUT: u128 = 60 * 1000;
+
 // How many peers we need to keep minimum?
 pub const _PEER_COUNT_MINIMUM: u128 = 256;
 
@@ -551,6 +802,12 @@ pub fn compute_next_target(last_target: U256, scale: U256) -> U256 {
   return difficulty_to_target(next_difficulty);
 }
 
+// Computes the target of a period, given how much time the last period took to complete.
+pub fn period_target(last_target: U256, period_time: u128) -> U256 {
+  let next_scaler = 2u128.pow(32) * TIME_PER_PERIOD / period_time;
+  compute_next_target(last_target, u256(next_scaler))
+}
+
 // Estimates how many hashes were necessary to get this one.
 pub fn get_hash_work(hash: U256) -> U256 {
   if hash == u256(0) {
@@ -712,12 +969,15 @@ pub fn miner_loop(
 // Node
 // ----
 
-impl<C: ProtoComm> Node<C> {
+impl<C: ProtoComm, S: BlockStorage> Node<C, S> {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
     data_path: PathBuf,
     network_id: u32,
+    rollback: RollbackConfig,
     initial_peers: Vec<C::Address>,
     comm: C,
+    storage: S,
     miner_comm: Option<MinerCommunication>,
     #[cfg(feature = "events")] event_emitter: mpsc::Sender<
       NodeEventEmittedInfo,
@@ -731,7 +991,8 @@ impl<C: ProtoComm> Node<C> {
     let genesis_block = genesis_block.hashed();
     let genesis_hash = genesis_block.get_hash().into();
 
-    let runtime = init_runtime(data_path.join("heaps"), &genesis_stmts);
+    let runtime =
+      restore_runtime(data_path.join("heaps"), &genesis_stmts, rollback);
 
     #[rustfmt::skip]
     let mut node = Node {
@@ -740,6 +1001,7 @@ impl<C: ProtoComm> Node<C> {
       addr: comm.get_addr(),
       comm,
       runtime,
+      storage,
       pool     : PriorityQueue:: new(),
       peers    : PeersStore:: new(),
 
@@ -754,6 +1016,10 @@ impl<C: ProtoComm> Node<C> {
       height   : u256map_from([(genesis_hash, 0               )]),
       target   : u256map_from([(genesis_hash, initial_target())]),
       results  : u256map_from([(genesis_hash, vec![]          )]),
+      roots    : u256map_new(),
+      check_invariants: false,
+      advertise_address: None,
+      stop: Arc::new(AtomicBool::new(false)),
 
       #[cfg(feature = "events")]
       event_emitter: event_emitter.clone(),
@@ -761,6 +1027,10 @@ impl<C: ProtoComm> Node<C> {
       miner_comm,
     };
 
+    if node.runtime.get_tick() == 0 {
+      node.roots.insert(genesis_hash, node.runtime.get_root());
+    }
+
     let now = get_time();
 
     initial_peers.iter().for_each(|address| {
@@ -871,16 +1141,25 @@ impl<C: ProtoComm> Node<C> {
             // Computes how much time the last period took to complete
             let period_time = btime - self.block[&checkpoint_hash].time;
             // Computes the target of this period
-            let last_target = self.target[&phash];
-            let next_scaler = 2u128.pow(32) * TIME_PER_PERIOD / period_time;
-            let next_target =
-              compute_next_target(last_target, u256(next_scaler));
+            let next_target = period_target(self.target[&phash], period_time);
             // Sets the new target
             self.target.insert(bhash, next_target);
           // Otherwise, keep the old target
           } else {
             self.target.insert(bhash, self.target[&phash]);
           }
+          // Saves the block and its index entries to disk
+          let stored = StoredBlock {
+            block: block.clone(),
+            height: self.height[&bhash],
+            work: self.work[&bhash],
+            target: self.target[&bhash],
+          };
+          self
+            .storage
+            .write_block(&stored)
+            .and_then(|()| self.storage.commit())
+            .expect("Couldn't save block to disk.");
           // Updates the tip work and block hash
           let cur_tip = self.tip;
           let new_tip = bhash;
@@ -925,18 +1204,14 @@ impl<C: ProtoComm> Node<C> {
                 old_bhash = self.block[&old_bhash].prev;
                 new_bhash = self.block[&new_bhash].prev;
               }
-              // 3. Saves overwritten blocks to disk
-              // TODO: on separate thread
+              // 3. Saves the new longest chain to disk
               for bhash_comp in must_compute.iter().rev() {
-                let file_path = self.get_blocks_path().join(format!(
-                  "{:0>16x}.kindelia_block.bin",
-                  self.height[bhash_comp]
-                ));
-                let file_buff =
-                  bitvec_to_bytes(&self.block[bhash_comp].proto_serialized());
-                std::fs::write(file_path, file_buff)
+                self
+                  .storage
+                  .write_chain(self.height[bhash_comp], bhash_comp)
                   .expect("Couldn't save block to disk.");
               }
+              self.storage.commit().expect("Couldn't save block to disk.");
               // 4. Reverts the runtime to a state older than that block
               //    On the example above, we'd find `runtime.tick = 1`
               let mut tick = self.height[&old_bhash];
@@ -1044,6 +1319,21 @@ impl<C: ProtoComm> Node<C> {
     let result = self.runtime.run_statements(&statements, false, false);
     self.results.insert(bhash, result);
     self.runtime.commit();
+    if self.check_invariants {
+      if let Err(errs) = self.runtime.check_invariants() {
+        panic!(
+          "Runtime invariants broken after block {:x}:\n{}",
+          bhash,
+          errs.join("\n")
+        );
+      }
+    }
+    let root = self.runtime.get_root();
+    self.roots.insert(bhash, root);
+    self
+      .storage
+      .write_state_root(&bhash, &root)
+      .expect("Couldn't save state root to disk.");
   }
 
   // Get the current target
@@ -1105,11 +1395,13 @@ impl<C: ProtoComm> Node<C> {
     let height = self.height.get(hash).expect("Missing block height.");
     let height: u64 = (*height).try_into().expect("Block height is too big.");
     let results = self.results.get(hash).map(|r| r.clone());
+    let state_root = self.roots.get(hash).map(|root| (*root).into());
     let info = BlockInfo {
       block: (&**block).into(),
       hash: (*hash).into(),
       height,
       results,
+      state_root,
     };
     Some(info)
   }
@@ -1173,6 +1465,7 @@ impl<C: ProtoComm> Node<C> {
           fun_count,
           ctr_count,
           reg_count,
+          state_root: self.runtime.get_root().into(),
         };
         handle_ans_err("GetStats", tx.send(stats));
       }
@@ -1277,9 +1570,15 @@ impl<C: ProtoComm> Node<C> {
     share_peers: u128,
   ) {
     let magic = self.network_id;
-    let peers = self.peers.get_random_active(share_peers);
+    let mut peers = self.peers.get_random_active(share_peers);
+    // Includes ourselves, so that peers learn where to reach us
+    if share_peers > 0 {
+      if let Some(address) = self.advertised_address() {
+        peers.push(Peer { address, seen_at: get_time() });
+      }
+    }
     let msg = Message::NoticeTheseBlocks { magic, gossip, blocks, peers };
-    self.comm.proto_send(addrs, &msg);
+    self.send(addrs, &msg);
   }
 
   // Returns the block inclusion state
@@ -1325,7 +1624,7 @@ impl<C: ProtoComm> Node<C> {
     if let Some(missing_ancestor) = self.find_missing_ancestor(bhash) {
       let magic = self.network_id;
       let msg = &Message::GiveMeThatBlock { magic, bhash: missing_ancestor };
-      self.comm.proto_send(vec![addr], msg);
+      self.send(vec![addr], msg);
     }
   }
 
@@ -1338,18 +1637,26 @@ impl<C: ProtoComm> Node<C> {
       match msg {
         Message::GiveMeThatBlock { magic, .. }
         | Message::NoticeTheseBlocks { magic, .. }
-        | Message::PleaseMineThisTransaction { magic, .. } => {
+        | Message::PleaseMineThisTransaction { magic, .. }
+        | Message::Hello { magic, .. }
+        | Message::Observed { magic, .. } => {
           if magic != &self.network_id {
             return;
           }
         }
       }
+      if self.peers.is_rejected(&addr, get_time()) {
+        return;
+      }
 
       self.peers.see_peer(
         Peer { address: addr, seen_at: get_time() },
         #[cfg(feature = "events")]
         self.event_emitter.clone(),
       );
+      if let Some(key) = self.comm.get_peer_key(&addr) {
+        self.peers.set_key(&addr, key);
+      }
 
       match msg {
         // Someone asked a block
@@ -1364,6 +1671,7 @@ impl<C: ProtoComm> Node<C> {
           let mut bhash = bhash;
           let mut chunk = vec![];
           let mut tsize = 0; // total size of the corresponding "NoticeTheseBlocks" message
+          let max_size = self.comm.max_message_size();
           loop {
             if !self.block.contains_key(&bhash) {
               break;
@@ -1375,7 +1683,7 @@ impl<C: ProtoComm> Node<C> {
             }
             let block = &self.block[bhash];
             let bsize = serialized_block_size(block) as usize;
-            if tsize + bsize > MAX_UDP_SIZE_SLOW {
+            if tsize + bsize > max_size {
               break;
             }
             chunk.push((**block).clone());
@@ -1398,7 +1706,11 @@ impl<C: ProtoComm> Node<C> {
           // TODO: validate if blocks are sorted by age?
 
           // Notice received peers
+          let advertised = self.advertised_address();
           for peer in peers {
+            if peer.address == self.addr || Some(peer.address) == advertised {
+              continue;
+            }
             self.peers.see_peer(
               *peer,
               #[cfg(feature = "events")]
@@ -1430,10 +1742,114 @@ impl<C: ProtoComm> Node<C> {
             self.gossip(5, msg);
           }
         }
+        // Someone said hello
+        Message::Hello {
+          magic,
+          version,
+          capabilities,
+          height,
+          genesis,
+          challenge,
+        } => {
+          emit_event!(
+            self.event_emitter,
+            NodeEventType::hello(*magic, *version, *height),
+            tags = handle_message,
+            hello
+          );
+          let protocol = negotiate(
+            &self.genesis_hash,
+            *version,
+            *capabilities,
+            *height,
+            genesis,
+          );
+          match protocol {
+            Ok(protocol) => {
+              if self.peers.set_protocol(&addr, protocol) {
+                let hello = self.hello(&addr);
+                self.send(vec![addr], &hello);
+              }
+              // only sent if the peer has version 2
+              let magic = self.network_id;
+              let challenge = *challenge;
+              let observed =
+                Message::Observed { magic, address: addr, challenge };
+              self.send(vec![addr], &observed);
+            }
+            // Only peers the transport authenticated are rejected, since
+            // anyone can send a `Hello` from another address over UDP. The
+            // others are just not sent to for a while.
+            Err(err) => {
+              if self.comm.get_peer_key(&addr).is_some() {
+                eprintln!("Rejected peer {}: {}", addr, err);
+                self.peers.reject(&addr, get_time());
+              } else {
+                eprintln!("Incompatible hello from {}: {}", addr, err);
+                self.peers.set_incompatible(&addr, get_time());
+              }
+            }
+          }
+        }
+        // Someone told us where they see us
+        Message::Observed { magic, address, challenge } => {
+          emit_event!(
+            self.event_emitter,
+            NodeEventType::observed(*magic, address),
+            tags = handle_message,
+            observed
+          );
+          let before = self.advertised_address();
+          self.peers.set_observed(&addr, *address, *challenge);
+          let after = self.advertised_address();
+          if after != before {
+            if let Some(address) = after {
+              eprintln!("External address: {}", address);
+            }
+          }
+        }
       }
     }
   }
 
+  /// The address peers can reach us at: the one on the config, or the one
+  /// peers see us at.
+  pub fn advertised_address(&self) -> Option<C::Address> {
+    self.advertise_address.or_else(|| self.peers.external_address())
+  }
+
+  // Our `Hello` to a peer
+  fn hello(&self, addr: &C::Address) -> Message<C::Address> {
+    Message::Hello {
+      magic: self.network_id,
+      version: PROTOCOL_VERSION,
+      capabilities: CAPABILITIES,
+      height: self.height[&self.tip],
+      genesis: self.genesis_hash,
+      challenge: self.peers.challenge(addr),
+    }
+  }
+
+  // Says hello to the active peers that didn't say it yet
+  fn greet_peers(&mut self) {
+    for addr in self.peers.to_greet(get_time()) {
+      let hello = self.hello(&addr);
+      self.send(vec![addr], &hello);
+    }
+  }
+
+  // Sends a message to the peers that understand it
+  fn send(&mut self, addrs: Vec<C::Address>, msg: &Message<C::Address>) {
+    let now = get_time();
+    let addrs: Vec<_> = addrs
+      .into_iter()
+      .filter(|addr| self.peers.can_send(addr, msg, now))
+      .collect();
+    if !addrs.is_empty() {
+      self.comm.proto_send(addrs, msg);
+    }
+  }
+
   pub fn gossip(&mut self, peer_count: u128, message: &Message<C::Address>) {
     let addrs = self
       .peers
@@ -1441,7 +1857,7 @@ impl<C: ProtoComm> Node<C> {
       .iter()
       .map(|x| x.address)
       .collect();
-    self.comm.proto_send(addrs, message);
+    self.send(addrs, message);
   }
 
   pub fn get_blocks_path(&self) -> PathBuf {
@@ -1466,26 +1882,161 @@ impl<C: ProtoComm> Node<C> {
     self.send_blocks_to(addrs, true, blocks, 3);
   }
 
-  fn load_blocks(&mut self) {
-    let blocks_dir = self.get_blocks_path();
-    std::fs::create_dir_all(&blocks_dir).ok();
-    let mut file_paths: Vec<(u64, PathBuf)> = vec![];
-    for entry in std::fs::read_dir(&blocks_dir).unwrap() {
-      // Extract block height from block file path for fast sort
-      let path = entry.unwrap().path();
-      let name = path.file_name().unwrap().to_str().unwrap();
-      let bnum = name.split('.').nth(0).unwrap();
-      let bnum = u64::from_str_radix(bnum, 16).unwrap();
-      file_paths.push((bnum, path));
-    }
-    file_paths.sort_unstable();
-    let num_blocks = file_paths.len();
+  // Loads the block tree saved on disk. Blocks were validated when added, so
+  // their index entries are restored as they are. Then, only the blocks of
+  // the longest chain after the restored runtime state are computed. If the
+  // storage is empty, imports the blocks saved by older versions.
+  pub fn load_blocks(&mut self) {
+    let read = |storage: &mut S| -> Result<_, String> {
+      Ok((storage.read_blocks()?, storage.read_chain()?))
+    };
+    let (blocks, chain): (Vec<StoredBlock>, _) = match read(&mut self.storage) {
+      Ok(stored) => stored,
+      Err(err) => {
+        eprintln!("WARN: {}", err);
+        (vec![], vec![])
+      }
+    };
+    if blocks.is_empty() {
+      self.import_legacy_blocks();
+      self.sync_runtime();
+      return;
+    }
+    let num_blocks = blocks.len();
     eprintln!("Loading {} blocks from disk...", num_blocks);
-    for (_, file_path) in file_paths {
-      let buffer = std::fs::read(&file_path).unwrap();
-      let block = Block::proto_deserialized(&bytes_to_bitvec(&buffer));
+    // Blocks come in order of height, so parents are loaded first
+    let mut max_work = self.genesis_hash;
+    for stored in blocks {
+      let bhash: U256 = stored.block.get_hash().into();
+      let phash = stored.block.prev;
+      if self.block.contains_key(&bhash) {
+        continue;
+      }
+      if !self.block.contains_key(&phash) {
+        eprintln!("WARN: Stored block {:#x} has no parent.", bhash);
+        self.storage.delete_block(&bhash).unwrap_or_else(|err| eprintln!("WARN: {}", err));
+        continue;
+      }
+      self.block.insert(bhash, stored.block);
+      self.work.insert(bhash, stored.work);
+      self.height.insert(bhash, stored.height);
+      self.target.insert(bhash, stored.target);
+      self.children.insert(bhash, vec![]);
+      self.children.entry(phash).or_insert_with(Vec::new).push(bhash);
+      if stored.work > self.work[&max_work] {
+        max_work = bhash;
+      }
+    }
+    self.tip = match chain.last() {
+      Some(tip) if self.block.contains_key(tip) => *tip,
+      _ => max_work,
+    };
+    match self.storage.read_state_roots() {
+      Ok(roots) => {
+        for (bhash, root) in roots {
+          if self.block.contains_key(&bhash) {
+            self.roots.insert(bhash, root);
+          }
+        }
+      }
+      Err(err) => eprintln!("WARN: {}", err),
+    }
+    eprintln!("Loaded {} blocks from disk.", num_blocks);
+    self.prune_blocks(PRUNE_DEPTH);
+    self.sync_runtime();
+  }
+
+  // Forgets the blocks off the longest chain that are `depth` blocks below the
+  // tip, with their descendants, and deletes them from the storage
+  pub fn prune_blocks(&mut self, depth: u128) {
+    let tip_height = self.height[&self.tip];
+    let on_chain: HashSet<U256> = self.get_longest_chain(None).into_iter().collect();
+    let mut stale: Vec<U256> = self
+      .block
+      .keys()
+      .filter(|bhash| **bhash != self.genesis_hash && !on_chain.contains(bhash))
+      .filter(|bhash| self.height[bhash] + depth <= tip_height)
+      .cloned()
+      .collect();
+    let mut pruned = 0;
+    while let Some(bhash) = stale.pop() {
+      if self.block.remove(&bhash).is_none() {
+        continue;
+      }
+      stale.extend(self.children.remove(&bhash).unwrap_or_default());
+      self.work.remove(&bhash);
+      self.height.remove(&bhash);
+      self.target.remove(&bhash);
+      self.roots.remove(&bhash);
+      self.storage.delete_block(&bhash).unwrap_or_else(|err| eprintln!("WARN: {}", err));
+      pruned += 1;
+    }
+    if pruned > 0 {
+      for children in self.children.values_mut() {
+        children.retain(|child| self.block.contains_key(child));
+      }
+      eprintln!("Pruned {} blocks off the longest chain.", pruned);
+    }
+  }
+
+  // Checks that the runtime state restored from disk is on the longest chain,
+  // starting from genesis otherwise, and computes the blocks after it.
+  fn sync_runtime(&mut self) {
+    let tick = self.runtime.get_tick();
+    let on_chain = match self.get_block_hash_by_index(tick) {
+      Some(_) if tick == 0 => true,
+      Some(bhash) => {
+        self.runtime.get_hax0() == (bhash >> 000).low_u128() >> 8
+          && self.runtime.get_hax1() == (bhash >> 120).low_u128() >> 8
+      }
+      None => false,
+    };
+    if !on_chain {
+      eprintln!("WARN: Runtime state at tick {} is not on the chain.", tick);
+      self.reset_runtime();
+    }
+    let tick = self.runtime.get_tick() as u128;
+    let must_compute: Vec<U256> = self
+      .get_longest_chain(None)
+      .into_iter()
+      .filter(|bhash| self.height[bhash] > tick)
+      .collect();
+    eprintln!(
+      "Computing {} blocks after the runtime state at tick {}...",
+      must_compute.len(),
+      tick
+    );
+    for bhash in must_compute {
+      self.check_stop();
+      self.compute_block(&self.block[&bhash].clone());
+    }
+  }
+
+  // Discards the runtime state, starting again from genesis
+  fn reset_runtime(&mut self) {
+    let genesis_stmts =
+      hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses");
+    self.runtime.reset(&genesis_stmts);
+  }
+
+  // Adds the blocks saved on the file per block layout of older versions,
+  // which saves them on the current storage
+  fn import_legacy_blocks(&mut self) {
+    let blocks_dir = self.get_blocks_path();
+    let blocks = persistence::read_legacy_blocks::<HashedBlock>(&blocks_dir);
+    if blocks.is_empty() {
+      return;
+    }
+    let num_blocks = blocks.len();
+    eprintln!("Importing {} blocks from {:?}...", num_blocks, blocks_dir);
+    // Blocks are computed from genesis as they are added
+    if self.runtime.get_tick() != 0 {
+      self.reset_runtime();
+    }
+    for (_, file_path, block) in blocks {
+      self.check_stop();
       if let Some(block) = block {
-        self.add_block(&block.hashed());
+        self.add_block(&block);
       } else {
         eprintln!(
           "WARN: Could not load block from file '{}'",
@@ -1493,7 +2044,7 @@ impl<C: ProtoComm> Node<C> {
         );
       }
     }
-    eprintln!("Loaded {} blocks from disk.", num_blocks);
+    eprintln!("Imported {} blocks.", num_blocks);
   }
 
   fn send_to_miner(&mut self, msg: MinerMessage) {
@@ -1591,7 +2142,9 @@ impl<C: ProtoComm> Node<C> {
           current: size_cur,
           limit: size_lim,
           available: size_avail,
-        }
+        },
+        root: self.runtime.get_root(),
+        compaction: self.runtime.get_last_compaction(),
       },
       tip_blocks: tip_blocks
     };
@@ -1599,9 +2152,31 @@ impl<C: ProtoComm> Node<C> {
     emit_event!(self.event_emitter, event, tags = heartbeat);
   }
 
+  // Waits for the pending state writes and exits
+  // Shuts down if the node was asked to stop. Called between blocks, so that
+  // long replays can be interrupted too.
+  fn check_stop(&self) {
+    if self.stop.load(Ordering::Relaxed) {
+      self.shutdown();
+    }
+  }
+
+  fn shutdown(&self) -> ! {
+    eprintln!("Saving state...");
+    if let Err(err) = self.runtime.flush() {
+      eprintln!("ERROR: {}", err);
+      std::process::exit(1);
+    }
+    std::process::exit(0);
+  }
+
   pub fn main(mut self) -> ! {
     eprintln!("Genesis hash: {:#34x}", self.genesis_hash);
     eprintln!("UDP/protocol port: {}", self.addr);
+    eprintln!("Protocol version: {}", PROTOCOL_VERSION);
+    if let Some(address) = self.advertise_address {
+      eprintln!("Advertised address: {}", address);
+    }
     eprintln!("Initial peers: ");
     for peer in self.peers.get_all_active() {
       eprintln!("  - {}", peer.address);
@@ -1610,9 +2185,9 @@ impl<C: ProtoComm> Node<C> {
     self.load_blocks();
 
     // A task that is executed continuously on the main loop
-    struct Task<C: ProtoComm> {
+    struct Task<C: ProtoComm, S: BlockStorage> {
       pub delay: u128,
-      pub action: fn(&mut Node<C>) -> (),
+      pub action: fn(&mut Node<C, S>) -> (),
     }
 
     // The vector of tasks
@@ -1624,6 +2199,13 @@ impl<C: ProtoComm> Node<C> {
           node.gossip_tip_block(8);
         },
       },
+      // Says hello to new peers
+      Task {
+        delay: 1_000,
+        action: |node| {
+          node.greet_peers();
+        },
+      },
       // Receives and handles incoming network messages
       Task {
         delay: HANDLE_MESSAGE_DELAY,
@@ -1686,6 +2268,7 @@ impl<C: ProtoComm> Node<C> {
     let mut last_tick_time: Vec<u128> = vec![0; tasks.len()];
 
     loop {
+      self.check_stop();
       let now = std::time::Instant::now();
       let system_time = get_time(); // Measured in milliseconds
       for (i, task) in tasks.iter().enumerate() {
@@ -1704,6 +2287,228 @@ impl<C: ProtoComm> Node<C> {
   }
 }
 
+// Snapshots
+// =========
+
+/// Reads the runtime state saved on `data_path`, merged into a single heap. Reads
+/// the latest saved state, or the one at `tick`, which must be the tick of a
+/// saved heap.
+pub fn read_saved_state(
+  data_path: &Path,
+  tick: Option<u64>,
+) -> Result<hvm::Heap, String> {
+  let heaps_path = data_path.join("heaps");
+  let mut heaps = hvm::read_saved_heaps(&heaps_path).map_err(|err| {
+    format!(
+      "Couldn't read the runtime state on '{}': {}",
+      heaps_path.display(),
+      err
+    )
+  })?;
+  if heaps.is_empty() {
+    return Err("There is no saved runtime state.".to_string());
+  }
+  if let Some(tick) = tick {
+    if !heaps.iter().any(|heap| heap.tick == tick) {
+      let ticks: Vec<String> =
+        heaps.iter().map(|heap| heap.tick.to_string()).collect();
+      return Err(format!(
+        "There is no saved state at tick {}. Saved ticks: {}.",
+        tick,
+        ticks.join(", ")
+      ));
+    }
+    heaps.retain(|heap| heap.tick <= tick);
+  }
+  Ok(hvm::merge_heaps(heaps))
+}
+
+/// Lists the entries of the runtime state saved on `data_path`, as
+/// `read_saved_state` reads it, with its tick.
+pub fn saved_state_listing(
+  data_path: &Path,
+  tick: Option<u64>,
+) -> Result<(u64, hvm::StateListing), String> {
+  let heap = read_saved_state(data_path, tick)?;
+  let tick = heap.tick;
+  let rt = hvm::runtime_from_heap(data_path.join("heaps"), heap);
+  Ok((tick, rt.list_state()))
+}
+
+/// Checks the invariants of the runtime state saved on `data_path`, as
+/// `read_saved_state` reads it. Returns its tick and the violations found.
+pub fn check_saved_state(
+  data_path: &Path,
+  tick: Option<u64>,
+) -> Result<(u64, Vec<String>), String> {
+  let heap = read_saved_state(data_path, tick)?;
+  let tick = heap.tick;
+  let rt = hvm::runtime_from_heap(data_path.join("heaps"), heap);
+  Ok((tick, rt.check_invariants().err().unwrap_or_default()))
+}
+
+/// Exports the runtime state saved on `data_path`, with the blocks of the
+/// longest chain up to it, to a snapshot file. Exports the latest saved state,
+/// or the one at `tick`, which must be the tick of a saved heap. Returns the
+/// tick and the hash of the exported state.
+pub fn export_snapshot(
+  data_path: &Path,
+  tick: Option<u64>,
+  file: &Path,
+) -> Result<(u64, crypto::Hash), String> {
+  let heap = read_saved_state(data_path, tick)?;
+  let tick = heap.tick;
+
+  let mut storage = KvBlockStorage::open(&data_path.join("blocks.kv"))?;
+  let chain = storage.read_chain()?;
+  if (chain.len() as u64) < tick {
+    return Err(format!(
+      "The saved chain has {} blocks, but the runtime state is at tick {}.",
+      chain.len(),
+      tick
+    ));
+  }
+  let mut blocks = Vec::with_capacity(tick as usize);
+  for bhash in &chain[..tick as usize] {
+    let stored = storage
+      .read_block(bhash)
+      .map_err(|err| format!("Couldn't read blocks: {}", err))?;
+    blocks.push(stored);
+  }
+  check_snapshot_state(&heap, &blocks)?;
+
+  let (data, state_hash) = persistence::encode_snapshot(&heap, &blocks)
+    .map_err(|err| format!("Couldn't encode the snapshot: {}", err))?;
+  persistence::write_atomic(file, &data)
+    .map_err(|err| format!("Couldn't write '{}': {}", file.display(), err))?;
+  Ok((tick, state_hash))
+}
+
+/// Imports a snapshot file on `data_path`, which must have no blocks nor
+/// runtime state yet. The state of the snapshot must hash to `state_hash`,
+/// obtained from a trusted source. The node then starts from it, and verifies
+/// the blocks after it. Returns the tick and the hash of the state.
+pub fn import_snapshot(
+  data_path: &Path,
+  file: &Path,
+  state_hash: &crypto::Hash,
+) -> Result<(u64, crypto::Hash), String> {
+  let data = std::fs::read(file)
+    .map_err(|err| format!("Couldn't read '{}': {}", file.display(), err))?;
+  let snapshot = persistence::decode_snapshot::<HashedBlock>(&data)
+    .map_err(|err| format!("Invalid snapshot '{}': {}", file.display(), err))?;
+  if snapshot.state_hash != *state_hash {
+    return Err(format!(
+      "The state hash of '{}' is {}, not {}.",
+      file.display(),
+      hex::encode(snapshot.state_hash.0),
+      hex::encode(state_hash.0)
+    ));
+  }
+
+  let genesis_stmts =
+    hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses");
+  let genesis_block = build_genesis_block(&genesis_stmts).hashed();
+  check_snapshot_chain(&genesis_block, &snapshot.blocks)?;
+  check_snapshot_state(&snapshot.heap, &snapshot.blocks)?;
+  let heaps_path = data_path.join("heaps");
+  check_snapshot_root(&heaps_path, &snapshot.heap)?;
+
+  let has_state = std::fs::read_dir(&heaps_path)
+    .map(|mut entries| entries.next().is_some())
+    .unwrap_or(false);
+  let mut storage = KvBlockStorage::open(&data_path.join("blocks.kv"))?;
+  if has_state || !storage.read_chain()?.is_empty() {
+    return Err(format!(
+      "'{}' already has a chain. Clean it before importing a snapshot.",
+      data_path.display()
+    ));
+  }
+
+  hvm::write_saved_state(&heaps_path, &snapshot.heap).map_err(|err| {
+    format!("Couldn't save the runtime state: {}", err)
+  })?;
+  for stored in &snapshot.blocks {
+    storage.write_block(stored)?;
+    storage.write_chain(stored.height, &stored.block.get_hash().into())?;
+  }
+  storage.commit()?;
+  Ok((snapshot.heap.tick, snapshot.state_hash))
+}
+
+// Checks that the blocks of a snapshot are a chain from genesis, and that their
+// heights, work and targets are the ones `add_block` computes
+fn check_snapshot_chain(
+  genesis: &HashedBlock,
+  blocks: &[StoredBlock],
+) -> Result<(), String> {
+  let mut chain = vec![genesis];
+  let mut work = u256(0);
+  let mut target = initial_target();
+  for stored in blocks {
+    let block = &stored.block;
+    let bhash: U256 = block.get_hash().into();
+    let parent = chain[chain.len() - 1];
+    let height = chain.len() as u128;
+    if block.prev != parent.get_hash().into() {
+      return Err(format!("Block {:#x} isn't on the chain.", bhash));
+    }
+    if bhash < target || block.time <= parent.time {
+      return Err(format!("Block {:#x} is invalid.", bhash));
+    }
+    work += get_hash_work(bhash);
+    if height > BLOCKS_PER_PERIOD && height % BLOCKS_PER_PERIOD == 1 {
+      let checkpoint = chain[chain.len() - BLOCKS_PER_PERIOD as usize];
+      target = period_target(target, block.time - checkpoint.time);
+    }
+    if stored.height != height || stored.work != work || stored.target != target
+    {
+      return Err(format!("Block {:#x} has wrong index entries.", bhash));
+    }
+    chain.push(block);
+  }
+  Ok(())
+}
+
+// Checks that a runtime state is the one after the last of `blocks`
+fn check_snapshot_state(
+  heap: &Heap,
+  blocks: &[StoredBlock],
+) -> Result<(), String> {
+  let on_chain = match blocks.last() {
+    Some(last) => {
+      let bhash: U256 = last.block.get_hash().into();
+      heap.tick as u128 == last.height
+        && heap.hax0 == (bhash >> 000).low_u128() >> 8
+        && heap.hax1 == (bhash >> 120).low_u128() >> 8
+    }
+    None => heap.tick == 0,
+  };
+  if !on_chain {
+    return Err(format!(
+      "The runtime state at tick {} isn't the one of the chain.",
+      heap.tick
+    ));
+  }
+  Ok(())
+}
+
+// Checks that the state root stored on a snapshot's heap is the one of its
+// contents, as the node trusts it from then on
+fn check_snapshot_root(heaps_path: &Path, heap: &Heap) -> Result<(), String> {
+  if heap.rot0 == hvm::U128_NONE {
+    return Ok(()); // not computed yet; the runtime computes it on load
+  }
+  let rt = hvm::runtime_from_heap(heaps_path.to_path_buf(), heap.clone());
+  if rt.compute_root() != rt.get_root() {
+    return Err(format!(
+      "The state root at tick {} doesn't match the runtime state.",
+      heap.tick
+    ));
+  }
+  Ok(())
+}
+
 // Main Thread
 // ===========
 
@@ -1712,6 +2517,7 @@ pub fn start<C: ProtoComm + 'static>(
   config: NodeConfig,
   comm: C,
   initial_peers: Vec<C::Address>,
+  advertise_address: Option<C::Address>,
 ) {
   eprintln!("Starting Kindelia node...");
   eprintln!("Store path: {:?}", config.data_path);
@@ -1737,16 +2543,27 @@ pub fn start<C: ProtoComm + 'static>(
   let (miner_comm, miner_thrds) = spawn_miner(config.mining, event_tx.clone());
   threads.extend(miner_thrds.into_iter());
 
+  // Blocks of the longest chain
+  let storage = KvBlockStorage::open(&config.data_path.join("blocks.kv"))
+    .expect("Couldn't open the block storage");
+
   // Node state object
-  let (node_query_sender, node) = Node::new(
+  let (node_query_sender, mut node) = Node::new(
     config.data_path,
     config.network_id,
+    config.rollback,
     initial_peers,
     comm,
+    storage,
     miner_comm,
     #[cfg(feature = "events")]
     event_tx,
   );
+  node.check_invariants = config.check_invariants;
+  node.advertise_address = advertise_address;
+
+  // Stops the node on Ctrl-C, after saving its state
+  spawn_signal_handler(node.stop.clone());
 
   // Spawns the API thread
   if let Some(api_config) = config.api {
@@ -1768,6 +2585,18 @@ pub fn start<C: ProtoComm + 'static>(
   }
 }
 
+fn spawn_signal_handler(stop: Arc<AtomicBool>) {
+  std::thread::spawn(move || {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .expect("Could not build the signal handler runtime");
+    if runtime.block_on(tokio::signal::ctrl_c()).is_ok() {
+      stop.store(true, Ordering::Relaxed);
+    }
+  });
+}
+
 fn spawn_miner(
   mine_config: MineConfig,
   #[cfg(feature = "events")] event_tx: mpsc::Sender<NodeEventEmittedInfo>,
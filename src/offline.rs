@@ -0,0 +1,123 @@
+// Offline signing
+// ===============
+//
+// Statements are signed on isolated machines by moving them around on a
+// portable batch file: the statements are exported, unsigned, from a code
+// file; the batch is signed offline, by one or many keys; and the signed batch
+// is brought back to be published. Each entry of the batch keeps the
+// statement on the protocol encoding, the hash its signers sign, and its code,
+// so it can be reviewed on the signing machine.
+
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
+
+use crate::bits::ProtoSerialize;
+use crate::common::Name;
+use crate::crypto::{Account, Hash};
+use crate::hvm::{self, Statement};
+use crate::util::{bitvec_to_bytes, bytes_to_bitvec};
+
+/// Version of the batch file layout.
+pub const BATCH_VERSION: u32 = 1;
+
+/// A batch of statements to be signed offline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+  pub version: u32,
+  pub statements: Vec<BatchEntry>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchEntry {
+  /// The statement, with the signatures it has so far, serialized.
+  #[serde_as(as = "Hex")]
+  pub statement: Vec<u8>,
+  /// The hash signers sign: the hash of the statement without signatures.
+  #[serde_as(as = "Hex")]
+  pub hash: Vec<u8>,
+  /// The code of the statement, without signatures.
+  pub code: String,
+}
+
+/// The code of a statement, without signatures, as signers review it.
+pub fn view_unsigned(statement: &Statement) -> String {
+  hvm::view_statement(&hvm::remove_sign(statement)).trim().to_string()
+}
+
+impl BatchEntry {
+  fn new(statement: &Statement) -> BatchEntry {
+    let unsigned = hvm::remove_sign(statement);
+    BatchEntry {
+      statement: bitvec_to_bytes(&statement.proto_serialized()),
+      hash: hvm::hash_statement(&unsigned).0.to_vec(),
+      code: view_unsigned(statement),
+    }
+  }
+
+  /// Decodes the statement, checking that it matches the hash and the code.
+  pub fn get_statement(&self) -> Result<Statement, String> {
+    let statement =
+      Statement::proto_deserialized(&bytes_to_bitvec(&self.statement))
+        .ok_or("Invalid statement encoding.")?;
+    if hvm::hash_statement(&statement).0[..] != self.hash[..] {
+      return Err("The statement doesn't match its hash.".to_string());
+    }
+    if view_unsigned(&statement) != self.code {
+      return Err("The statement doesn't match its code.".to_string());
+    }
+    Ok(statement)
+  }
+}
+
+impl Batch {
+  /// A batch of the given statements, with the signatures they have.
+  pub fn new(statements: &[Statement]) -> Batch {
+    let statements = statements.iter().map(BatchEntry::new).collect();
+    Batch { version: BATCH_VERSION, statements }
+  }
+
+  /// Decodes the statements of the batch.
+  pub fn get_statements(&self) -> Result<Vec<Statement>, String> {
+    if self.version != BATCH_VERSION {
+      return Err(format!("Unsupported batch version {}.", self.version));
+    }
+    self
+      .statements
+      .iter()
+      .enumerate()
+      .map(|(i, entry)| {
+        entry
+          .get_statement()
+          .map_err(|err| format!("Statement #{}: {}", i, err))
+      })
+      .collect()
+  }
+
+  /// Signs every statement with a secret key. Statements that are signed
+  /// already get one more signature if `add` is set, for multi-signature
+  /// owners, and fail otherwise.
+  pub fn sign(&self, skey: &[u8; 32], add: bool) -> Result<Batch, String> {
+    let account = Account::from_private_key(skey);
+    let mut signed = Vec::new();
+    for (i, statement) in self.get_statements()?.iter().enumerate() {
+      let sign = account.sign(&hvm::hash_statement(statement));
+      let signs = hvm::get_sign(statement);
+      if signs.contains(&sign) {
+        return Err(format!("Statement #{} already has this signature.", i));
+      }
+      if !signs.is_empty() && !add {
+        return Err(format!("Statement #{} already has a signature.", i));
+      }
+      signed.push(hvm::add_sign(statement, sign));
+    }
+    Ok(Batch::new(&signed))
+  }
+}
+
+/// The subjects that signed a statement.
+pub fn signers(statement: &Statement) -> Vec<Option<Name>> {
+  let hash: Hash = hvm::hash_statement(statement);
+  let signs = hvm::get_sign(statement);
+  signs.iter().map(|sign| sign.signer_name(&hash)).collect()
+}
@@ -1,10 +1,16 @@
-use std::io::{Read, Write, Result as IoResult, Error, ErrorKind};
+use std::io::{BufReader, BufWriter, Read, Write, Result as IoResult, Error, ErrorKind};
 use std::hash::{Hash, BuildHasher};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
 use std::ops::Deref;
-use crate::hvm::{CompFunc, Func, compile_func};
+use crate::common::U120;
+use crate::hvm::{CompFunc, Func, Heap, Multisig, compile_func};
+use primitive_types::U256;
 use crate::bits::ProtoSerialize;
+use crate::crypto;
 
 
 /// Trait that represents serialization of a type to memory.
@@ -183,6 +189,32 @@ impl DiskSer for CompFunc {
   }
 } 
 
+impl DiskSer for Multisig {
+  fn disk_serialize<W: Write>(&self, sink: &mut W) -> IoResult<usize> {
+    let mut written = self.thld.disk_serialize(sink)?;
+    written += (self.keys.len() as u64).disk_serialize(sink)?;
+    for key in &self.keys {
+      written += key.disk_serialize(sink)?;
+    }
+    Ok(written)
+  }
+  fn disk_deserialize<R: Read>(source: &mut R) -> IoResult<Option<Self>> {
+    let thld = match u64::disk_deserialize(source)? {
+      Some(thld) => thld,
+      None => return Ok(None),
+    };
+    let len = u64::disk_deserialize(source)?
+      .ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+    let mut keys = Vec::new();
+    for _ in 0..len {
+      let key = U120::disk_deserialize(source)?
+        .ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+      keys.push(key);
+    }
+    Ok(Some(Multisig { thld, keys }))
+  }
+}
+
 impl<T: DiskSer + Default + std::marker::Copy, const N: usize> DiskSer for [T; N]
 {
   fn disk_serialize<W: Write>(&self, sink: &mut W) -> IoResult<usize> {
@@ -254,3 +286,893 @@ impl DiskSer for crate::hvm::Loc {
     }
   }
 }
+
+// Persistence worker
+// ==================
+
+/// Maximum number of pending jobs. Queueing more blocks until the worker
+/// catches up.
+pub const PERSIST_QUEUE_SIZE: usize = 64;
+
+type Encoder = Box<dyn FnOnce() -> IoResult<Vec<u8>> + Send>;
+
+enum PersistJob {
+  Write { path: PathBuf, data: Encoder },
+  Delete { path: PathBuf },
+  Flush { done: mpsc::Sender<Option<String>> },
+}
+
+/// Writes files on a background thread, in the order they were queued, so that
+/// disk writes don't stall the caller. Dropping it waits for pending writes.
+pub struct PersistWorker {
+  sender: Option<mpsc::SyncSender<PersistJob>>,
+  thread: Option<JoinHandle<()>>,
+}
+
+/// Writes a file atomically: the data is written to a temporary file, synced,
+/// then renamed over `path`, and the rename is synced. A crash leaves either
+/// the old or the new file.
+pub fn write_atomic(path: &Path, data: &[u8]) -> IoResult<()> {
+  write_atomic_as(path, data, false)
+}
+
+/// Writes a secret atomically, like `write_atomic`, on a file that is
+/// readable by its owner only since it's created.
+pub fn write_secret(path: &Path, data: &[u8]) -> IoResult<()> {
+  write_atomic_as(path, data, true)
+}
+
+fn write_atomic_as(path: &Path, data: &[u8], secret: bool) -> IoResult<()> {
+  let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+  tmp_name.push(".tmp");
+  let tmp_path = path.with_file_name(tmp_name);
+  let mut options = OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  if secret {
+    // a leftover temporary file would keep its permissions
+    match std::fs::remove_file(&tmp_path) {
+      Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+      _ => {}
+    }
+    options.create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  }
+  let mut file = options.open(&tmp_path)?;
+  file.write_all(data)?;
+  file.sync_all()?;
+  std::fs::rename(&tmp_path, path)?;
+  sync_parent_dir(path)
+}
+
+/// Syncs the directory of `path`, so that a new or renamed entry on it
+/// survives a crash.
+#[cfg(unix)]
+pub fn sync_parent_dir(path: &Path) -> IoResult<()> {
+  match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+    _ => File::open(".")?.sync_all(),
+  }
+}
+
+/// Directories can't be synced on this platform.
+#[cfg(not(unix))]
+pub fn sync_parent_dir(_path: &Path) -> IoResult<()> {
+  Ok(())
+}
+
+impl PersistWorker {
+  pub fn spawn() -> Self {
+    let (sender, receiver) = mpsc::sync_channel(PERSIST_QUEUE_SIZE);
+    let thread = std::thread::spawn(move || {
+      // First error since the last flush
+      let mut error: Option<String> = None;
+      for job in receiver {
+        let result = match job {
+          PersistJob::Write { path, data } => {
+            data().and_then(|data| write_atomic(&path, &data)).map_err(|err| {
+              format!("Couldn't write '{}': {}", path.display(), err)
+            })
+          }
+          PersistJob::Delete { path } => match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+              Err(format!("Couldn't delete '{}': {}", path.display(), err))
+            }
+            _ => Ok(()),
+          },
+          PersistJob::Flush { done } => {
+            let _ = done.send(error.take());
+            Ok(())
+          }
+        };
+        if let Err(err) = result {
+          eprintln!("WARN: {}", err);
+          error.get_or_insert(err);
+        }
+      }
+    });
+    PersistWorker { sender: Some(sender), thread: Some(thread) }
+  }
+
+  fn send(&self, job: PersistJob) {
+    if let Some(sender) = &self.sender {
+      sender.send(job).expect("Persistence worker stopped.");
+    }
+  }
+
+  /// Queues an atomic write of `data` to `path`.
+  pub fn write(&self, path: PathBuf, data: Vec<u8>) {
+    self.write_with(path, move || Ok(data));
+  }
+
+  /// Queues an atomic write to `path` of the data built by `encode`, which
+  /// runs on the worker.
+  pub fn write_with(
+    &self,
+    path: PathBuf,
+    encode: impl FnOnce() -> IoResult<Vec<u8>> + Send + 'static,
+  ) {
+    self.send(PersistJob::Write { path, data: Box::new(encode) });
+  }
+
+  /// Queues the deletion of `path`. Missing files are ignored.
+  pub fn delete(&self, path: PathBuf) {
+    self.send(PersistJob::Delete { path });
+  }
+
+  /// Waits until all queued jobs are done. Returns the first error since the
+  /// last flush.
+  pub fn flush(&self) -> Result<(), String> {
+    let (done, wait) = mpsc::channel();
+    self.send(PersistJob::Flush { done });
+    match wait.recv() {
+      Ok(None) => Ok(()),
+      Ok(Some(err)) => Err(err),
+      Err(_) => Err("Persistence worker stopped.".to_string()),
+    }
+  }
+}
+
+impl Drop for PersistWorker {
+  fn drop(&mut self) {
+    // Closing the queue makes the worker finish the pending jobs and stop
+    self.sender.take();
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+// Versioned files
+// ===============
+//
+// Heaps and the runtime metadata are saved on files with the layout below. All
+// numbers are little-endian.
+//
+//   magic    : 8 bytes, the kind of file
+//   version  : u32, the layout version of the sections
+//   uuid     : u128, the heap the file belongs to (0 for metadata)
+//   length   : u64, number of bytes after the header
+//   sections : for each section,
+//     name     : u8 length, then the name bytes
+//     length   : u64
+//     checksum : 32 bytes, keccak256 of the data
+//     data     : `length` bytes
+//
+// A file whose length or checksums don't match wasn't completely written, and
+// is rejected. Sections written by older layouts are upgraded on load, by
+// `migrate_sections`.
+
+pub const HEAP_MAGIC: [u8; 8] = *b"KDLHEAP\0";
+pub const META_MAGIC: [u8; 8] = *b"KDLMETA\0";
+
+/// Current layout version. Version 0 are the headerless files written before
+/// versioning, with one file per section. Version 2 adds the state root to the
+/// `stat` section of heaps, version 3 the `msig` section of heaps, version 4
+/// the `nonc` section of heaps, and version 5 the `leaf` section of heaps.
+pub const FORMAT_VERSION: u32 = 5;
+
+const HEADER_SIZE: usize = 8 + 4 + 16 + 8;
+
+pub type Sections = Vec<(String, Vec<u8>)>;
+
+fn invalid_data(msg: String) -> Error {
+  Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Builds a versioned file with the current layout version.
+pub fn encode_versioned(
+  magic: &[u8; 8],
+  uuid: u128,
+  sections: &[(&str, Vec<u8>)],
+) -> Vec<u8> {
+  let mut body = Vec::new();
+  for (name, data) in sections {
+    body.push(name.len() as u8);
+    body.extend_from_slice(name.as_bytes());
+    body.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    body.extend_from_slice(&crate::crypto::Hash::keccak256_from_bytes(data).0);
+    body.extend_from_slice(data);
+  }
+  let mut file = Vec::with_capacity(HEADER_SIZE + body.len());
+  file.extend_from_slice(magic);
+  file.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+  file.extend_from_slice(&uuid.to_le_bytes());
+  file.extend_from_slice(&(body.len() as u64).to_le_bytes());
+  file.extend_from_slice(&body);
+  file
+}
+
+/// Reads a versioned file, checking its header and checksums. Returns the
+/// uuid and the sections, upgraded to the current layout.
+pub fn decode_versioned(
+  magic: &[u8; 8],
+  file: &[u8],
+) -> IoResult<(u128, Sections)> {
+  fn take<'a>(data: &mut &'a [u8], len: usize) -> IoResult<&'a [u8]> {
+    if data.len() < len {
+      return Err(invalid_data("Truncated file.".to_string()));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+  }
+  let mut data = file;
+  if take(&mut data, 8)? != magic {
+    return Err(invalid_data("Unknown file kind.".to_string()));
+  }
+  let version = u32::from_le_bytes(take(&mut data, 4)?.try_into().unwrap());
+  let uuid = u128::from_le_bytes(take(&mut data, 16)?.try_into().unwrap());
+  let length = u64::from_le_bytes(take(&mut data, 8)?.try_into().unwrap());
+  if data.len() as u64 != length {
+    return Err(invalid_data(format!(
+      "Partially written file: expected {} bytes, found {}.",
+      length,
+      data.len()
+    )));
+  }
+  let mut sections = Vec::new();
+  while !data.is_empty() {
+    let name_len = take(&mut data, 1)?[0] as usize;
+    let name = String::from_utf8_lossy(take(&mut data, name_len)?).to_string();
+    let len = u64::from_le_bytes(take(&mut data, 8)?.try_into().unwrap());
+    let checksum = take(&mut data, 32)?;
+    let section = take(&mut data, len as usize)?;
+    if crate::crypto::Hash::keccak256_from_bytes(section).0 != checksum {
+      return Err(invalid_data(format!("Corrupted section '{}'.", name)));
+    }
+    sections.push((name, section.to_vec()));
+  }
+  Ok((uuid, migrate_sections(version, sections)?))
+}
+
+/// Upgrades sections written by an older layout to the current one. A layout
+/// change must bump `FORMAT_VERSION` and add the step from the previous
+/// version here, so that existing state directories keep loading.
+pub fn migrate_sections(
+  version: u32,
+  sections: Sections,
+) -> IoResult<Sections> {
+  if version > FORMAT_VERSION {
+    return Err(invalid_data(format!(
+      "File layout version {} is newer than the supported {}.",
+      version, FORMAT_VERSION
+    )));
+  }
+  let mut version = version;
+  let mut sections = sections;
+  while version < FORMAT_VERSION {
+    sections = match version {
+      // Version 1 stores the version 0 files as sections, unchanged
+      0 => sections,
+      // Heaps saved before version 2 have an unknown state root, which is
+      // computed again once they are loaded
+      1 => sections
+        .into_iter()
+        .map(|(name, mut data)| {
+          if name == "stat" {
+            data.extend_from_slice(&[0xFF; 32]);
+          }
+          (name, data)
+        })
+        .collect(),
+      // Heaps saved before version 3 have no multi-signature owners
+      2 => {
+        let is_heap = sections.iter().any(|(name, _)| name == "ownr");
+        let mut sections = sections;
+        if is_heap {
+          sections.push(("msig".to_string(), vec![]));
+        }
+        sections
+      }
+      // Heaps saved before version 4 have no nonces
+      3 => {
+        let is_heap = sections.iter().any(|(name, _)| name == "ownr");
+        let mut sections = sections;
+        if is_heap {
+          sections.push(("nonc".to_string(), vec![]));
+        }
+        sections
+      }
+      // Heaps saved before version 5 have no hashes of their states, which are
+      // computed from the states when needed
+      4 => {
+        let is_heap = sections.iter().any(|(name, _)| name == "ownr");
+        let mut sections = sections;
+        if is_heap {
+          sections.push(("leaf".to_string(), vec![]));
+        }
+        sections
+      }
+      _ => unreachable!(),
+    };
+    version += 1;
+  }
+  Ok(sections)
+}
+
+/// Gets a section by name.
+pub fn get_section<'a>(
+  sections: &'a Sections,
+  name: &str,
+) -> IoResult<&'a [u8]> {
+  sections
+    .iter()
+    .find(|(section, _)| section == name)
+    .map(|(_, data)| &data[..])
+    .ok_or_else(|| invalid_data(format!("Missing section '{}'.", name)))
+}
+
+// Key-value store
+// ===============
+//
+// An embedded, append-only key-value store on a single file. Each `put` or
+// `delete` appends a record, and the latest record of a key wins:
+//
+//   key length   : u32
+//   value length : u32, or `KV_TOMBSTONE` for deletions
+//   checksum     : 8 bytes, the start of the keccak256 of the key and value
+//   key, value
+//
+// The index of the keys, with the place of their values on the file, is
+// rebuilt by scanning the file on `open`; values are read from the file when
+// asked for. A record that was partially written when the process died is
+// dropped, together with anything after it. Records that were overwritten or
+// deleted are dropped by `compact`, which `open` runs when they take most of
+// the file.
+
+const KV_TOMBSTONE: u32 = u32::MAX;
+const KV_RECORD_HEADER: usize = 4 + 4 + 8;
+
+/// Dead bytes a file must have before `open` compacts it.
+pub const KV_COMPACT_MIN: u64 = 1024 * 1024;
+
+pub struct KvStore {
+  path: PathBuf,
+  file: File,
+  index: HashMap<Vec<u8>, (u64, usize)>, // key -> offset and length of value
+  end: u64,
+  live: u64, // bytes of the records on the index
+}
+
+fn kv_checksum(key: &[u8], value: &[u8]) -> [u8; 8] {
+  let mut data = key.to_vec();
+  data.extend_from_slice(value);
+  let hash = crate::crypto::Hash::keccak256_from_bytes(&data);
+  hash.0[0..8].try_into().unwrap()
+}
+
+fn kv_record(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+  let val_len = value.map(|v| v.len() as u32).unwrap_or(KV_TOMBSTONE);
+  let value = value.unwrap_or(&[]);
+  let mut record =
+    Vec::with_capacity(KV_RECORD_HEADER + key.len() + value.len());
+  record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+  record.extend_from_slice(&val_len.to_le_bytes());
+  record.extend_from_slice(&kv_checksum(key, value));
+  record.extend_from_slice(key);
+  record.extend_from_slice(value);
+  record
+}
+
+fn kv_open_file(path: &Path) -> IoResult<File> {
+  std::fs::OpenOptions::new().read(true).append(true).create(true).open(path)
+}
+
+impl KvStore {
+  pub fn open(path: &Path) -> IoResult<KvStore> {
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let file = kv_open_file(path)?;
+    let len = file.metadata()?.len();
+    let mut db = KvStore {
+      path: path.to_path_buf(),
+      file,
+      index: HashMap::new(),
+      end: 0,
+      live: 0,
+    };
+    db.scan(len)?;
+    if db.end < len {
+      eprintln!(
+        "WARN: dropping {} bytes of incomplete records from '{}'.",
+        len - db.end,
+        path.display()
+      );
+      db.file.set_len(db.end)?;
+    }
+    if db.dead_bytes() > KV_COMPACT_MIN && db.dead_bytes() > db.live {
+      db.compact()?;
+    }
+    Ok(db)
+  }
+
+  // Reads the records of the file, one at a time, until the first incomplete
+  // one
+  fn scan(&mut self, len: u64) -> IoResult<()> {
+    let mut reader = BufReader::new(self.file.try_clone()?);
+    let mut header = [0; KV_RECORD_HEADER];
+    let mut pos = 0;
+    while pos + KV_RECORD_HEADER as u64 <= len {
+      reader.read_exact(&mut header)?;
+      let key_len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+      let val_len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+      let val_size = if val_len == KV_TOMBSTONE { 0 } else { val_len };
+      let size = (KV_RECORD_HEADER + key_len as usize) as u64 + val_size as u64;
+      if pos + size > len {
+        break;
+      }
+      let mut key = vec![0; key_len as usize];
+      let mut value = vec![0; val_size as usize];
+      reader.read_exact(&mut key)?;
+      reader.read_exact(&mut value)?;
+      if kv_checksum(&key, &value) != header[8..16] {
+        break;
+      }
+      let value = (val_len != KV_TOMBSTONE).then_some(&value[..]);
+      self.record(key, value, pos);
+      pos += size;
+    }
+    self.end = pos;
+    Ok(())
+  }
+
+  // Updates the index with a record written at `pos`
+  fn record(&mut self, key: Vec<u8>, value: Option<&[u8]>, pos: u64) {
+    let key_len = key.len();
+    let old = match value {
+      Some(value) => {
+        let val_start = pos + (KV_RECORD_HEADER + key_len) as u64;
+        self.live += (KV_RECORD_HEADER + key_len + value.len()) as u64;
+        self.index.insert(key, (val_start, value.len()))
+      }
+      None => self.index.remove(&key),
+    };
+    if let Some((_, len)) = old {
+      self.live -= (KV_RECORD_HEADER + key_len + len) as u64;
+    }
+  }
+
+  fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> IoResult<()> {
+    let record = kv_record(key, value);
+    self.file.write_all(&record)?;
+    let pos = self.end;
+    self.end += record.len() as u64;
+    self.record(key.to_vec(), value, pos);
+    Ok(())
+  }
+
+  pub fn put(&mut self, key: &[u8], value: &[u8]) -> IoResult<()> {
+    self.append(key, Some(value))
+  }
+
+  pub fn delete(&mut self, key: &[u8]) -> IoResult<()> {
+    if self.index.contains_key(key) {
+      self.append(key, None)?;
+    }
+    Ok(())
+  }
+
+  pub fn get(&mut self, key: &[u8]) -> IoResult<Option<Vec<u8>>> {
+    use std::io::{Seek, SeekFrom};
+    match self.index.get(key) {
+      None => Ok(None),
+      Some((offset, len)) => {
+        let mut value = vec![0; *len];
+        self.file.seek(SeekFrom::Start(*offset))?;
+        self.file.read_exact(&mut value)?;
+        Ok(Some(value))
+      }
+    }
+  }
+
+  pub fn contains(&self, key: &[u8]) -> bool {
+    self.index.contains_key(key)
+  }
+
+  pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+    self.index.keys()
+  }
+
+  /// Bytes of the file taken by overwritten and deleted records.
+  pub fn dead_bytes(&self) -> u64 {
+    self.end - self.live
+  }
+
+  /// Rewrites the file with only the latest record of each key. The new file
+  /// replaces the old one atomically.
+  pub fn compact(&mut self) -> IoResult<()> {
+    let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".compact");
+    let tmp_path = self.path.with_file_name(tmp_name);
+    let mut keys: Vec<Vec<u8>> = self.index.keys().cloned().collect();
+    keys.sort();
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    let mut index = HashMap::with_capacity(keys.len());
+    let mut end = 0;
+    for key in keys {
+      let value = self.get(&key)?.unwrap_or_default();
+      let record = kv_record(&key, Some(&value));
+      out.write_all(&record)?;
+      let val_start = end + (KV_RECORD_HEADER + key.len()) as u64;
+      index.insert(key, (val_start, value.len()));
+      end += record.len() as u64;
+    }
+    out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    std::fs::rename(&tmp_path, &self.path)?;
+    sync_parent_dir(&self.path)?;
+    self.file = kv_open_file(&self.path)?;
+    self.index = index;
+    self.end = end;
+    self.live = end;
+    Ok(())
+  }
+
+  /// Waits until the records are on disk.
+  pub fn sync(&self) -> IoResult<()> {
+    self.file.sync_data()
+  }
+}
+
+// Block storage
+// =============
+
+/// A block, as the block storage sees it: bytes, found by a hash. It is
+/// implemented by the blocks of the node.
+pub trait StorableBlock: Sized {
+  fn block_hash(&self) -> U256;
+  fn to_bytes(&self) -> Vec<u8>;
+  fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+/// A block, with its index entries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredBlock<B> {
+  pub block: B,
+  pub height: u128,
+  pub work: U256,
+  pub target: U256,
+}
+
+pub trait BlockStorage {
+  /// Saves an included block with its index entries. Blocks of every branch
+  /// are saved, so that the block tree can be restored.
+  fn write_block<B: StorableBlock>(
+    &mut self,
+    block: &StoredBlock<B>,
+  ) -> Result<(), String>;
+  /// Sets the block of the longest chain on a height, replacing the one that
+  /// was there. It becomes the tip.
+  fn write_chain(&mut self, height: u128, hash: &U256) -> Result<(), String>;
+  /// Deletes a block, and its state root. Used for blocks off the longest
+  /// chain that can't join it anymore.
+  fn delete_block(&mut self, hash: &U256) -> Result<(), String>;
+  /// Reads every saved block, in order of height.
+  fn read_blocks<B: StorableBlock>(
+    &mut self,
+  ) -> Result<Vec<StoredBlock<B>>, String>;
+  /// Reads the hashes of the longest chain, from the first block after genesis
+  /// to the tip.
+  fn read_chain(&mut self) -> Result<Vec<U256>, String>;
+  /// Saves the state root after computing a block.
+  fn write_state_root(
+    &mut self,
+    hash: &U256,
+    root: &U256,
+  ) -> Result<(), String>;
+  /// Reads the saved state roots, by block hash.
+  fn read_state_roots(&mut self) -> Result<Vec<(U256, U256)>, String>;
+  /// Waits until the writes so far are on disk.
+  fn commit(&mut self) -> Result<(), String>;
+}
+
+/// Block storage on a `KvStore`. Keys are:
+/// - `b{hash}`: the height, work and target of a block, then the block;
+/// - `h{height}`: the hash of the block of the longest chain on a height;
+/// - `r{hash}`: the state root after computing a block;
+/// - `tip`: the height of the tip.
+pub struct KvBlockStorage {
+  db: KvStore,
+}
+
+fn height_key(height: u128) -> Vec<u8> {
+  let mut key = b"h".to_vec();
+  key.extend_from_slice(&height.to_be_bytes());
+  key
+}
+
+fn block_key(hash: &U256) -> Vec<u8> {
+  let mut key = b"b".to_vec();
+  key.extend_from_slice(&u256_to_bytes(hash));
+  key
+}
+
+fn root_key(hash: &U256) -> Vec<u8> {
+  let mut key = b"r".to_vec();
+  key.extend_from_slice(&u256_to_bytes(hash));
+  key
+}
+
+fn u256_to_bytes(value: &U256) -> [u8; 32] {
+  let mut bytes = [0; 32];
+  value.to_big_endian(&mut bytes);
+  bytes
+}
+
+impl KvBlockStorage {
+  pub fn open(path: &Path) -> Result<KvBlockStorage, String> {
+    let db = KvStore::open(path).map_err(|err| {
+      format!("Couldn't open block storage '{}': {}", path.display(), err)
+    })?;
+    Ok(KvBlockStorage { db })
+  }
+
+  pub fn read_block<B: StorableBlock>(
+    &mut self,
+    hash: &U256,
+  ) -> IoResult<StoredBlock<B>> {
+    let data = self
+      .db
+      .get(&block_key(hash))?
+      .ok_or_else(|| invalid_data(format!("Missing block {:#x}.", hash)))?;
+    let stored: StoredBlock<B> = decode_stored_block(&data)
+      .ok_or_else(|| invalid_data(format!("Invalid block {:#x}.", hash)))?;
+    if stored.block.block_hash() != *hash {
+      return Err(invalid_data(format!("Inconsistent block {:#x}.", hash)));
+    }
+    Ok(stored)
+  }
+}
+
+// The height, work and target of a block, then the block
+fn encode_stored_block<B: StorableBlock>(stored: &StoredBlock<B>) -> Vec<u8> {
+  let mut data = stored.height.to_le_bytes().to_vec();
+  data.extend_from_slice(&u256_to_bytes(&stored.work));
+  data.extend_from_slice(&u256_to_bytes(&stored.target));
+  data.extend_from_slice(&stored.block.to_bytes());
+  data
+}
+
+fn decode_stored_block<B: StorableBlock>(
+  data: &[u8],
+) -> Option<StoredBlock<B>> {
+  if data.len() < 16 + 32 + 32 {
+    return None;
+  }
+  let height = u128::from_le_bytes(data[0..16].try_into().unwrap());
+  let work = U256::from_big_endian(&data[16..48]);
+  let target = U256::from_big_endian(&data[48..80]);
+  let block = B::from_bytes(&data[80..])?;
+  Some(StoredBlock { block, height, work, target })
+}
+
+impl BlockStorage for KvBlockStorage {
+  fn write_block<B: StorableBlock>(
+    &mut self,
+    stored: &StoredBlock<B>,
+  ) -> Result<(), String> {
+    let hash = stored.block.block_hash();
+    self
+      .db
+      .put(&block_key(&hash), &encode_stored_block(stored))
+      .map_err(|err| format!("Couldn't save block: {}", err))
+  }
+
+  fn write_chain(&mut self, height: u128, hash: &U256) -> Result<(), String> {
+    let write = |db: &mut KvStore| -> IoResult<()> {
+      db.put(&height_key(height), &u256_to_bytes(hash))?;
+      db.put(b"tip", &height.to_le_bytes())
+    };
+    write(&mut self.db).map_err(|err| format!("Couldn't save block: {}", err))
+  }
+
+  fn delete_block(&mut self, hash: &U256) -> Result<(), String> {
+    let delete = |db: &mut KvStore| -> IoResult<()> {
+      db.delete(&block_key(hash))?;
+      db.delete(&root_key(hash))
+    };
+    delete(&mut self.db)
+      .map_err(|err| format!("Couldn't delete block: {}", err))
+  }
+
+  fn read_blocks<B: StorableBlock>(
+    &mut self,
+  ) -> Result<Vec<StoredBlock<B>>, String> {
+    let hashes: Vec<U256> = self
+      .db
+      .keys()
+      .filter(|key| key.len() == 33 && key[0] == b'b')
+      .map(|key| U256::from_big_endian(&key[1..]))
+      .collect();
+    let mut blocks = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+      let block = self
+        .read_block(&hash)
+        .map_err(|err| format!("Couldn't read blocks: {}", err))?;
+      blocks.push(block);
+    }
+    blocks.sort_by_key(|stored| stored.height);
+    Ok(blocks)
+  }
+
+  fn read_chain(&mut self) -> Result<Vec<U256>, String> {
+    let read = |db: &mut KvStore| -> IoResult<Vec<U256>> {
+      let tip = match db.get(b"tip")? {
+        Some(tip) if tip.len() == 16 => {
+          u128::from_le_bytes(tip.try_into().unwrap())
+        }
+        Some(_) => return Err(invalid_data("Invalid tip entry.".to_string())),
+        None => return Ok(vec![]),
+      };
+      let mut chain = Vec::new();
+      for height in 1..=tip {
+        match db.get(&height_key(height))? {
+          Some(hash) => chain.push(U256::from_big_endian(&hash)),
+          None => break,
+        }
+      }
+      Ok(chain)
+    };
+    read(&mut self.db).map_err(|err| format!("Couldn't read blocks: {}", err))
+  }
+
+  fn write_state_root(
+    &mut self,
+    hash: &U256,
+    root: &U256,
+  ) -> Result<(), String> {
+    self
+      .db
+      .put(&root_key(hash), &u256_to_bytes(root))
+      .map_err(|err| format!("Couldn't save state root: {}", err))
+  }
+
+  fn read_state_roots(&mut self) -> Result<Vec<(U256, U256)>, String> {
+    let keys: Vec<Vec<u8>> = self
+      .db
+      .keys()
+      .filter(|key| key.len() == 33 && key[0] == b'r')
+      .cloned()
+      .collect();
+    let mut roots = Vec::with_capacity(keys.len());
+    for key in keys {
+      let root = self
+        .db
+        .get(&key)
+        .map_err(|err| format!("Couldn't read state roots: {}", err))?;
+      if let Some(root) = root.filter(|root| root.len() == 32) {
+        roots.push((
+          U256::from_big_endian(&key[1..]),
+          U256::from_big_endian(&root),
+        ));
+      }
+    }
+    Ok(roots)
+  }
+
+  fn commit(&mut self) -> Result<(), String> {
+    self.db.sync().map_err(|err| format!("Couldn't save blocks: {}", err))
+  }
+}
+
+/// Reads the blocks saved by older versions, with one file per height named
+/// `{height:0>16x}.kindelia_block.bin`, in order of height. Files with other
+/// names are ignored.
+pub fn read_legacy_blocks<B: StorableBlock>(
+  dir: &Path,
+) -> Vec<(u128, PathBuf, Option<B>)> {
+  let mut blocks = vec![];
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return blocks,
+  };
+  for entry in entries.flatten() {
+    let path = entry.path();
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let height = name
+      .strip_suffix(".kindelia_block.bin")
+      .and_then(|height| u128::from_str_radix(height, 16).ok());
+    if let Some(height) = height {
+      let block =
+        std::fs::read(&path).ok().and_then(|data| B::from_bytes(&data));
+      blocks.push((height, path, block));
+    }
+  }
+  blocks.sort_unstable_by_key(|(height, ..)| *height);
+  blocks
+}
+
+// State snapshots
+// ===============
+
+// A snapshot of the runtime state, used to bootstrap nodes without replaying
+// the chain, is saved on a versioned file of the `SNAPSHOT_MAGIC` kind, whose
+// uuid is the one of the heap. Its sections are the buffers of the heap, as
+// built by `Heap::serialize_canonical`, then:
+//   blocks     : the blocks of the longest chain, from height 1 to the tick of
+//                the state, each as a u64 length and its block storage entry
+//   state_hash : 32 bytes, the `state_hash` of the heap
+
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"KDLSNAP\0";
+
+/// The runtime state at a tick, with the blocks of the chain up to it.
+pub struct StateSnapshot<B> {
+  pub heap: Heap,
+  pub blocks: Vec<StoredBlock<B>>,
+  pub state_hash: crypto::Hash,
+}
+
+/// Hash of the contents of a heap, which doesn't depend on the order its
+/// entries were inserted on, nor on its uuid.
+pub fn state_hash(heap: &Heap) -> IoResult<crypto::Hash> {
+  let mut bytes = Vec::new();
+  for (name, data) in heap.serialize_canonical()? {
+    bytes.push(name.len() as u8);
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&data);
+  }
+  Ok(crypto::Hash::keccak256_from_bytes(&bytes))
+}
+
+pub fn encode_snapshot<B: StorableBlock>(
+  heap: &Heap,
+  blocks: &[StoredBlock<B>],
+) -> IoResult<(Vec<u8>, crypto::Hash)> {
+  let state_hash = state_hash(heap)?;
+  let mut sections: Vec<(&str, Vec<u8>)> = heap.serialize_canonical()?;
+  let mut blocks_data = Vec::new();
+  for stored in blocks {
+    let data = encode_stored_block(stored);
+    blocks_data.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    blocks_data.extend_from_slice(&data);
+  }
+  sections.push(("blocks", blocks_data));
+  sections.push(("state_hash", state_hash.0.to_vec()));
+  let data = encode_versioned(&SNAPSHOT_MAGIC, heap.uuid, &sections);
+  Ok((data, state_hash))
+}
+
+/// Loads a snapshot, checking that its state matches its state hash.
+pub fn decode_snapshot<B: StorableBlock>(
+  data: &[u8],
+) -> IoResult<StateSnapshot<B>> {
+  let (uuid, sections) = decode_versioned(&SNAPSHOT_MAGIC, data)?;
+  let heap = Heap::from_sections(uuid, &sections)?;
+  let state_hash = state_hash(&heap)?;
+  if get_section(&sections, "state_hash")? != state_hash.0 {
+    return Err(invalid_data("State doesn't match its hash.".to_string()));
+  }
+  let mut blocks = Vec::new();
+  let mut data = get_section(&sections, "blocks")?;
+  while !data.is_empty() {
+    let invalid = || invalid_data("Invalid block.".to_string());
+    let len = data.get(0..8).ok_or_else(invalid)?;
+    let len = u64::from_le_bytes(len.try_into().unwrap()) as usize;
+    let entry = data.get(8..8usize.saturating_add(len)).ok_or_else(invalid)?;
+    blocks.push(decode_stored_block(entry).ok_or_else(invalid)?);
+    data = &data[8 + len..];
+  }
+  Ok(StateSnapshot { heap, blocks, state_hash })
+}
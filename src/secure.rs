@@ -0,0 +1,526 @@
+// Secure transport
+// ================
+
+// An authenticated and encrypted `ProtoComm`, layered on any `PacketComm`.
+//
+// Every node has a static secp256k1 key. Before exchanging messages, two
+// nodes run a handshake, in the style of Noise's XX pattern:
+//
+//   -> INIT: e_i, s_i
+//   <- RESP: e_r, s_r, confirm
+//
+// where `e` are fresh ephemeral keys and `s` the static keys. Both sides mix
+// the shared secrets `ee`, `es` and `se` (see `derive`) into a key per
+// direction, plus a confirmation key. Only the owner of `s_r` can compute
+// `confirm`, and only the owner of `s_i` can encrypt with its session key, so
+// both peers are authenticated, and their messages kept secret, even if a
+// static key leaks later.
+//
+// After that, each `Message` travels in a DATA packet:
+//
+//   DATA: counter, AES-256-GCM(message)
+//
+// where the nonce is the counter, and the header is authenticated too.
+// Replayed and forged packets are dropped silently, like corrupted ones.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use bit_vec::BitVec;
+use hmac::{Hmac, Mac};
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::rand::rngs::OsRng;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+use crate::bits::ProtoSerialize;
+use crate::net::{PacketComm, PeerKey, ProtoComm};
+use crate::node::Message;
+use crate::persistence::write_secret;
+use crate::util::{bitvec_to_bytes, get_time};
+
+/// Packet kinds, on the first byte.
+pub const PACKET_INIT: u8 = 1;
+pub const PACKET_RESP: u8 = 2;
+pub const PACKET_DATA: u8 = 3;
+
+const KEY_SIZE: usize = 33; // compressed public key
+const MAC_SIZE: usize = 32; // HMAC-SHA256
+const TAG_SIZE: usize = 16; // AES-GCM tag
+const DATA_HEADER_SIZE: usize = 1 + 8;
+
+/// Bytes a DATA packet adds to the serialized message.
+pub const DATA_OVERHEAD: usize = DATA_HEADER_SIZE + TAG_SIZE;
+
+/// Time to wait for a RESP before sending the INIT again, in millis.
+pub const HANDSHAKE_TIMEOUT: u128 = 5 * 1000;
+
+/// Messages kept per peer while its handshake is running.
+pub const MAX_QUEUED: usize = 32;
+
+/// Handshakes kept at once, both the ones we started and the ones we answered.
+pub const MAX_HANDSHAKES: usize = 256;
+
+/// Time after which an unfinished handshake is forgotten, in millis.
+pub const HANDSHAKE_EXPIRY: u128 = 30 * 1000;
+
+/// Minimum time between two INITs answered to the same address, in millis.
+pub const INIT_INTERVAL: u128 = 1000;
+
+/// INITs answered per second, across all addresses.
+pub const MAX_INITS_PER_SECOND: u32 = 64;
+
+const PROTOCOL_NAME: &[u8] = b"Kindelia_secp256k1_AESGCM_SHA256";
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Keys
+// ====
+
+/// Loads the node's static secret key from a file, creating a new one if
+/// there is none.
+pub fn load_or_create_key(path: &Path) -> Result<SecretKey, String> {
+  if path.exists() {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+      format!("Could not read the node key '{}': {}", path.display(), err)
+    })?;
+    let bytes = hex::decode(text.trim()).map_err(|err| {
+      format!("Invalid node key '{}': {}", path.display(), err)
+    })?;
+    SecretKey::from_slice(&bytes)
+      .map_err(|err| format!("Invalid node key '{}': {}", path.display(), err))
+  } else {
+    let key = SecretKey::new(&mut OsRng::new().expect("OsRng"));
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir).map_err(|err| {
+        format!("Could not create '{}': {}", dir.display(), err)
+      })?;
+    }
+    write_secret(path, hex::encode(key.secret_bytes()).as_bytes()).map_err(
+      |err| {
+        format!("Could not write the node key '{}': {}", path.display(), err)
+      },
+    )?;
+    Ok(key)
+  }
+}
+
+/// Parses a peer as `<public key hex>@<address>`, or just `<address>`.
+pub fn parse_peer<A>(
+  code: &str,
+  parse_address: impl Fn(&str) -> A,
+) -> Result<(A, Option<PeerKey>), String> {
+  match code.split_once('@') {
+    Some((key, addr)) => {
+      let key = hex::decode(key)
+        .ok()
+        .and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+        .ok_or_else(|| format!("Invalid peer key on '{}'.", code))?;
+      Ok((parse_address(addr), Some(key)))
+    }
+    None => Ok((parse_address(code), None)),
+  }
+}
+
+// Key derivation
+// ==============
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+  let mut mac =
+    <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes any key");
+  for part in data {
+    mac.update(part);
+  }
+  mac.finalize().into_bytes().into()
+}
+
+fn ecdh(point: &PublicKey, scalar: &SecretKey) -> [u8; 32] {
+  SharedSecret::new(point, scalar).secret_bytes()
+}
+
+/// The keys agreed on a handshake.
+struct SessionKeys {
+  send: [u8; 32],
+  recv: [u8; 32],
+  confirm: [u8; 32],
+}
+
+/// Derives the session keys, HKDF-style, from the transcript and the shared
+/// secrets `ee`, `es` (initiator's ephemeral, responder's static) and `se`
+/// (initiator's static, responder's ephemeral).
+fn derive(
+  initiator: bool,
+  transcript: &[&PublicKey; 4],
+  ee: [u8; 32],
+  es: [u8; 32],
+  se: [u8; 32],
+) -> SessionKeys {
+  let mut hasher = Sha256::new();
+  hasher.update(PROTOCOL_NAME);
+  for key in transcript {
+    hasher.update(key.serialize());
+  }
+  let salt = hasher.finalize();
+  let prk = hmac(&salt, &[&ee, &es, &se]);
+  let i2r = hmac(&prk, &[b"initiator", &[1]]);
+  let r2i = hmac(&prk, &[b"responder", &[1]]);
+  let confirm = hmac(&prk, &[b"confirm", &[1]]);
+  if initiator {
+    SessionKeys { send: i2r, recv: r2i, confirm }
+  } else {
+    SessionKeys { send: r2i, recv: i2r, confirm }
+  }
+}
+
+// Sessions
+// ========
+
+/// An established session with a peer.
+struct Session {
+  peer_key: PeerKey,
+  send: Aes256Gcm,
+  recv: Aes256Gcm,
+  sent: u64,     // counter of the next packet we send
+  received: u64, // highest counter received
+  window: u64,   // bitmap of the 64 counters up to `received`
+  since: u128,   // when the handshake was done
+}
+
+impl Session {
+  fn new(peer_key: PeerKey, keys: &SessionKeys, now: u128) -> Session {
+    Session {
+      peer_key,
+      send: Aes256Gcm::new_from_slice(&keys.send).unwrap(),
+      recv: Aes256Gcm::new_from_slice(&keys.recv).unwrap(),
+      sent: 0,
+      received: 0,
+      window: 0,
+      since: now,
+    }
+  }
+
+  fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+  }
+
+  fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+    self.sent += 1;
+    let mut packet = vec![PACKET_DATA];
+    packet.extend_from_slice(&self.sent.to_be_bytes());
+    let payload = Payload { msg: plain, aad: &packet };
+    let nonce = Session::nonce(self.sent);
+    let cipher = self.send.encrypt(Nonce::from_slice(&nonce), payload);
+    packet.extend(cipher.expect("AES-GCM encryption"));
+    packet
+  }
+
+  /// Decrypts a DATA packet, if authentic and not seen before.
+  fn open(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() < DATA_OVERHEAD {
+      return None;
+    }
+    let (header, cipher) = packet.split_at(DATA_HEADER_SIZE);
+    let counter = u64::from_be_bytes(header[1..].try_into().unwrap());
+    if counter == 0 || self.was_received(counter) {
+      return None;
+    }
+    let nonce = Session::nonce(counter);
+    let payload = Payload { msg: cipher, aad: header };
+    let plain = self.recv.decrypt(Nonce::from_slice(&nonce), payload).ok()?;
+    self.mark_received(counter);
+    Some(plain)
+  }
+
+  fn was_received(&self, counter: u64) -> bool {
+    if counter > self.received {
+      false
+    } else {
+      let age = self.received - counter;
+      age >= 64 || self.window & (1 << age) != 0
+    }
+  }
+
+  fn mark_received(&mut self, counter: u64) {
+    if counter > self.received {
+      let shift = counter - self.received;
+      self.window = if shift >= 64 { 0 } else { self.window << shift };
+      self.window |= 1;
+      self.received = counter;
+    } else {
+      self.window |= 1 << (self.received - counter);
+    }
+  }
+}
+
+/// A handshake we started, waiting for the RESP.
+struct Initiation {
+  secret: SecretKey,
+  public: PublicKey,
+  sent_at: u128,
+}
+
+// Transport
+// =========
+
+/// Authenticated and encrypted transport over the packets of `C`.
+pub struct SecureComm<C: PacketComm> {
+  comm: C,
+  secret: SecretKey,
+  public: PublicKey,
+  sessions: HashMap<C::Address, Session>,
+  // sessions we answered, promoted when the peer first uses them
+  pending: HashMap<C::Address, Session>,
+  initiations: HashMap<C::Address, Initiation>,
+  queued: HashMap<C::Address, Vec<Vec<u8>>>,
+  pinned: HashMap<C::Address, PeerKey>,
+  // start of the current second, and INITs answered on it
+  answered: (u128, u32),
+}
+
+impl<C: PacketComm> SecureComm<C> {
+  pub fn new(comm: C, secret: SecretKey) -> Self {
+    let public = PublicKey::from_secret_key(&Secp256k1::new(), &secret);
+    SecureComm {
+      comm,
+      secret,
+      public,
+      sessions: HashMap::new(),
+      pending: HashMap::new(),
+      initiations: HashMap::new(),
+      queued: HashMap::new(),
+      pinned: HashMap::new(),
+      answered: (0, 0),
+    }
+  }
+
+  /// This node's static public key.
+  pub fn public_key(&self) -> PeerKey {
+    self.public
+  }
+
+  /// Only accepts sessions with `address` from the owner of `key`.
+  pub fn pin(&mut self, address: C::Address, key: PeerKey) {
+    self.pinned.insert(address, key);
+  }
+
+  fn accepts(&self, address: &C::Address, key: &PeerKey) -> bool {
+    self.pinned.get(address).is_none_or(|pinned| pinned == key)
+  }
+
+  fn send_bytes(&mut self, address: C::Address, bytes: &[u8]) {
+    let now = get_time();
+    let started = self.initiations.get(&address);
+    let waiting =
+      started.is_some_and(|init| init.sent_at + HANDSHAKE_TIMEOUT > now);
+    // A session we answered is only used when we aren't waiting for our own,
+    // as its INIT may be forged; otherwise, it's used once the peer uses it
+    let session = match self.sessions.get_mut(&address) {
+      Some(session) => Some(session),
+      None if !waiting => self.pending.get_mut(&address),
+      None => None,
+    };
+    if let Some(session) = session {
+      let packet = session.seal(bytes);
+      self.comm.packet_send(address, &packet);
+      return;
+    }
+    if started.is_none() && self.initiations.len() >= MAX_HANDSHAKES {
+      return;
+    }
+    let queue = self.queued.entry(address).or_default();
+    if queue.len() < MAX_QUEUED {
+      queue.push(bytes.to_vec());
+    }
+    if !waiting {
+      self.initiate(address, now);
+    }
+  }
+
+  /// Forgets the handshakes that didn't finish in time.
+  fn expire(&mut self, now: u128) {
+    let alive = |since: u128| since + HANDSHAKE_EXPIRY > now;
+    self.pending.retain(|_, session| alive(session.since));
+    self.initiations.retain(|_, init| alive(init.sent_at));
+    let initiations = &self.initiations;
+    self.queued.retain(|address, _| initiations.contains_key(address));
+  }
+
+  /// Whether an INIT from `address` can be answered now, counting it if so.
+  fn may_answer(&mut self, address: &C::Address, now: u128) -> bool {
+    let recent = self
+      .pending
+      .get(address)
+      .is_some_and(|session| session.since + INIT_INTERVAL > now);
+    let full = self.pending.len() >= MAX_HANDSHAKES
+      && !self.pending.contains_key(address);
+    if recent || full {
+      return false;
+    }
+    if self.answered.0 + 1000 <= now {
+      self.answered = (now, 0);
+    }
+    if self.answered.1 >= MAX_INITS_PER_SECOND {
+      return false;
+    }
+    self.answered.1 += 1;
+    true
+  }
+
+  fn initiate(&mut self, address: C::Address, now: u128) {
+    let secret = SecretKey::new(&mut OsRng::new().expect("OsRng"));
+    let public = PublicKey::from_secret_key(&Secp256k1::new(), &secret);
+    let mut packet = vec![PACKET_INIT];
+    packet.extend_from_slice(&public.serialize());
+    packet.extend_from_slice(&self.public.serialize());
+    self.comm.packet_send(address, &packet);
+    self
+      .initiations
+      .insert(address, Initiation { secret, public, sent_at: now });
+  }
+
+  fn flush(&mut self, address: C::Address) {
+    for bytes in self.queued.remove(&address).unwrap_or_default() {
+      self.send_bytes(address, &bytes);
+    }
+  }
+
+  fn on_init(&mut self, address: C::Address, packet: &[u8]) {
+    if packet.len() != 1 + 2 * KEY_SIZE {
+      return;
+    }
+    let e_i = PublicKey::from_slice(&packet[1..1 + KEY_SIZE]);
+    let s_i = PublicKey::from_slice(&packet[1 + KEY_SIZE..]);
+    let (e_i, s_i) = match (e_i, s_i) {
+      (Ok(e_i), Ok(s_i)) => (e_i, s_i),
+      _ => return,
+    };
+    if !self.accepts(&address, &s_i) {
+      return;
+    }
+    // When both sides start a handshake at once, both go on with the one of
+    // the greater ephemeral key. Ours is kept until it times out, even when
+    // we answer, as anyone can send an INIT from the peer's address.
+    let now = get_time();
+    if let Some(init) = self.initiations.get(&address) {
+      let fresh = init.sent_at + HANDSHAKE_TIMEOUT > now;
+      if fresh && init.public.serialize() > e_i.serialize() {
+        return;
+      }
+    }
+    // Answering costs three ECDHs and a session, so INITs are rate-limited
+    if !self.may_answer(&address, now) {
+      return;
+    }
+    let secret = SecretKey::new(&mut OsRng::new().expect("OsRng"));
+    let e_r = PublicKey::from_secret_key(&Secp256k1::new(), &secret);
+    let keys = derive(
+      false,
+      &[&e_i, &s_i, &e_r, &self.public],
+      ecdh(&e_i, &secret),
+      ecdh(&e_i, &self.secret),
+      ecdh(&s_i, &secret),
+    );
+    let mut packet = vec![PACKET_RESP];
+    packet.extend_from_slice(&e_r.serialize());
+    packet.extend_from_slice(&self.public.serialize());
+    packet.extend_from_slice(&hmac(&keys.confirm, &[b"confirm"]));
+    self.comm.packet_send(address, &packet);
+    self.pending.insert(address, Session::new(s_i, &keys, now));
+    self.flush(address);
+  }
+
+  fn on_resp(&mut self, address: C::Address, packet: &[u8]) {
+    if packet.len() != 1 + 2 * KEY_SIZE + MAC_SIZE {
+      return;
+    }
+    let init = match self.initiations.get(&address) {
+      Some(init) => init,
+      None => return,
+    };
+    let e_r = PublicKey::from_slice(&packet[1..1 + KEY_SIZE]);
+    let s_r = PublicKey::from_slice(&packet[1 + KEY_SIZE..1 + 2 * KEY_SIZE]);
+    let (e_r, s_r) = match (e_r, s_r) {
+      (Ok(e_r), Ok(s_r)) => (e_r, s_r),
+      _ => return,
+    };
+    if !self.accepts(&address, &s_r) {
+      return;
+    }
+    let keys = derive(
+      true,
+      &[&init.public, &self.public, &e_r, &s_r],
+      ecdh(&e_r, &init.secret),
+      ecdh(&s_r, &init.secret),
+      ecdh(&e_r, &self.secret),
+    );
+    let confirm = hmac(&keys.confirm, &[b"confirm"]);
+    if confirm[..] != packet[1 + 2 * KEY_SIZE..] {
+      return;
+    }
+    self.initiations.remove(&address);
+    self.pending.remove(&address);
+    self.sessions.insert(address, Session::new(s_r, &keys, get_time()));
+    self.flush(address);
+  }
+
+  fn on_data(&mut self, address: C::Address, packet: &[u8]) -> Option<Vec<u8>> {
+    if let Some(plain) =
+      self.sessions.get_mut(&address).and_then(|session| session.open(packet))
+    {
+      return Some(plain);
+    }
+    let plain = self.pending.get_mut(&address)?.open(packet)?;
+    // The peer proved its static key, so its session replaces the old one
+    let session = self.pending.remove(&address).unwrap();
+    self.sessions.insert(address, session);
+    self.flush(address);
+    Some(plain)
+  }
+}
+
+impl<C: PacketComm> ProtoComm for SecureComm<C> {
+  type Address = C::Address;
+  fn proto_send(
+    &mut self,
+    addresses: Vec<Self::Address>,
+    message: &Message<Self::Address>,
+  ) {
+    let bytes = bitvec_to_bytes(&message.proto_serialized());
+    for address in addresses {
+      self.send_bytes(address, &bytes);
+    }
+  }
+  fn proto_recv(&mut self) -> Vec<(Self::Address, Message<Self::Address>)> {
+    let mut messages = Vec::new();
+    self.expire(get_time());
+    for (address, packet) in self.comm.packet_recv() {
+      match packet.first() {
+        Some(&PACKET_INIT) => self.on_init(address, &packet),
+        Some(&PACKET_RESP) => self.on_resp(address, &packet),
+        Some(&PACKET_DATA) => {
+          if let Some(bytes) = self.on_data(address, &packet) {
+            let bits = BitVec::from_bytes(&bytes);
+            if let Some(msge) = Message::proto_deserialized(&bits) {
+              messages.push((address, msge));
+            }
+          }
+        }
+        _ => {}
+      }
+    }
+    messages
+  }
+  fn get_addr(&self) -> Self::Address {
+    self.comm.packet_addr()
+  }
+  fn max_message_size(&self) -> usize {
+    self.comm.max_packet_size() - DATA_OVERHEAD
+  }
+  fn get_peer_key(&self, address: &Self::Address) -> Option<PeerKey> {
+    self.sessions.get(address).map(|session| session.peer_key)
+  }
+}
@@ -0,0 +1,383 @@
+// TCP transport
+// =============
+
+// A reliable `ProtoComm`, over TCP streams, for nodes behind lossy links, and
+// for messages bigger than a datagram.
+//
+// Each message travels as a frame: its length, as a big-endian u32, followed
+// by its `ProtoSerialize`d bytes. Connections are opened on the first message
+// to a peer, and used in both directions. The side that connects starts with
+// its listening port (a big-endian u16), so that the other side knows the
+// peer's address. Peers that can't be reached are retried with an
+// exponential backoff, and the messages to them meanwhile are dropped, like
+// lost datagrams. So are the messages to new peers when there are too many
+// connections, or too many being opened.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use bit_vec::BitVec;
+
+use crate::bits::ProtoSerialize;
+use crate::net::{from_socket_addr, to_socket_addr, Address};
+use crate::net::{PacketComm, ProtoComm};
+use crate::node::Message;
+use crate::util::{bitvec_to_bytes, get_time};
+
+/// Max size of a frame, in bytes. Bigger ones close the connection.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Size messages are kept under when they can be split, in bytes.
+pub const MAX_TCP_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Max bytes waiting to be written to a peer, before giving up on it.
+pub const MAX_WRITE_BUFFER: usize = 4 * MAX_FRAME_SIZE;
+
+/// Max number of connections, open or being opened, in both directions.
+/// Ones above it are refused.
+pub const MAX_CONNECTIONS: usize = 256;
+
+/// Max number of connections being opened at once.
+pub const MAX_DIALING: usize = 16;
+
+/// Max bytes read from a peer before its frames are taken.
+pub const MAX_READ_BUFFER: usize = PORT_SIZE + LEN_SIZE + MAX_FRAME_SIZE;
+
+/// Messages kept per peer while connecting to it.
+pub const MAX_QUEUED: usize = 32;
+
+/// Time to wait for a connection to be accepted.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections with no traffic for this long are closed, in millis.
+pub const IDLE_TIMEOUT: u128 = 5 * 60 * 1000;
+
+/// First and max delay before connecting again to a peer that failed, in
+/// millis. It doubles on each failure.
+pub const BACKOFF_MIN: u128 = 1000;
+pub const BACKOFF_MAX: u128 = 5 * 60 * 1000;
+
+const LEN_SIZE: usize = 4;
+const PORT_SIZE: usize = 2;
+
+// Connections
+// ===========
+
+struct Connection {
+  stream: TcpStream,
+  addr: Option<Address>, // unknown on incoming ones, until the port arrives
+  remote: SocketAddr,
+  read_buf: Vec<u8>,
+  write_buf: Vec<u8>,
+  active_at: u128,
+}
+
+impl Connection {
+  fn new(stream: TcpStream, remote: SocketAddr) -> std::io::Result<Self> {
+    stream.set_nonblocking(true)?;
+    stream.set_nodelay(true)?;
+    Ok(Connection {
+      stream,
+      addr: None,
+      remote,
+      read_buf: Vec::new(),
+      write_buf: Vec::new(),
+      active_at: get_time(),
+    })
+  }
+
+  fn push_frame(&mut self, bytes: &[u8]) {
+    self.write_buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    self.write_buf.extend_from_slice(bytes);
+  }
+
+  /// Writes as much of the buffer as the socket takes. Returns if the
+  /// connection is still good.
+  fn flush(&mut self) -> bool {
+    while !self.write_buf.is_empty() {
+      match self.stream.write(&self.write_buf) {
+        Ok(0) => return false,
+        Ok(len) => {
+          self.write_buf.drain(..len);
+          self.active_at = get_time();
+        }
+        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+        Err(err) if err.kind() == ErrorKind::Interrupted => {}
+        Err(_) => return false,
+      }
+    }
+    self.write_buf.len() <= MAX_WRITE_BUFFER
+  }
+
+  /// Reads until a frame is complete, or nothing else is available. Returns
+  /// if the connection is still good.
+  fn fill(&mut self) -> bool {
+    let mut buffer = [0; 65536];
+    loop {
+      let room = MAX_READ_BUFFER - self.read_buf.len();
+      if room == 0 || self.has_frame() {
+        return true;
+      }
+      match self.stream.read(&mut buffer[..room.min(65536)]) {
+        Ok(0) => return false,
+        Ok(len) => {
+          self.read_buf.extend_from_slice(&buffer[..len]);
+          self.active_at = get_time();
+        }
+        Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+        Err(err) if err.kind() == ErrorKind::Interrupted => {}
+        Err(_) => return false,
+      }
+    }
+  }
+
+  /// If a complete frame has been read.
+  fn has_frame(&self) -> bool {
+    let start = if self.addr.is_none() { PORT_SIZE } else { 0 };
+    if self.read_buf.len() < start + LEN_SIZE {
+      return false;
+    }
+    let len = &self.read_buf[start..start + LEN_SIZE];
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    self.read_buf.len() >= start + LEN_SIZE + len
+  }
+
+  /// Takes the complete frames read. Returns `None` on a protocol error.
+  fn frames(&mut self) -> Option<Vec<Vec<u8>>> {
+    let mut start = 0;
+    if self.addr.is_none() {
+      if self.read_buf.len() < PORT_SIZE {
+        return Some(vec![]);
+      }
+      let port = u16::from_be_bytes([self.read_buf[0], self.read_buf[1]]);
+      let mut remote = self.remote;
+      remote.set_port(port);
+      self.addr = Some(from_socket_addr(&remote));
+      start = PORT_SIZE;
+    }
+    let mut frames = vec![];
+    while self.read_buf.len() - start >= LEN_SIZE {
+      let len = &self.read_buf[start..start + LEN_SIZE];
+      let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+      if len > MAX_FRAME_SIZE {
+        return None;
+      }
+      let end = start + LEN_SIZE + len;
+      if self.read_buf.len() < end {
+        break;
+      }
+      frames.push(self.read_buf[start + LEN_SIZE..end].to_vec());
+      start = end;
+    }
+    self.read_buf.drain(..start);
+    Some(frames)
+  }
+}
+
+struct Backoff {
+  failures: u32,
+  retry_at: u128,
+}
+
+// Transport
+// =========
+
+/// The TCP implementation of `ProtoComm`.
+pub struct TcpComm {
+  listener: TcpListener,
+  conns: Vec<Connection>,
+  // messages to peers we are connecting to
+  dialing: HashMap<Address, Vec<Vec<u8>>>,
+  dialed_tx: Sender<(Address, std::io::Result<TcpStream>)>,
+  dialed_rx: Receiver<(Address, std::io::Result<TcpStream>)>,
+  backoff: HashMap<Address, Backoff>,
+}
+
+impl TcpComm {
+  pub fn new(listener: TcpListener) -> std::io::Result<Self> {
+    listener.set_nonblocking(true)?;
+    let (dialed_tx, dialed_rx) = mpsc::channel();
+    Ok(TcpComm {
+      listener,
+      conns: Vec::new(),
+      dialing: HashMap::new(),
+      dialed_tx,
+      dialed_rx,
+      backoff: HashMap::new(),
+    })
+  }
+
+  /// Number of open connections.
+  pub fn connections(&self) -> usize {
+    self.conns.len()
+  }
+
+  /// Number of connections being opened.
+  pub fn dials(&self) -> usize {
+    self.dialing.len()
+  }
+
+  fn is_full(&self) -> bool {
+    self.conns.len() + self.dialing.len() >= MAX_CONNECTIONS
+  }
+
+  /// If connecting to `address` failed recently, so messages to it are
+  /// dropped for now.
+  pub fn is_backing_off(&self, address: &Address) -> bool {
+    let now = get_time();
+    self.backoff.get(address).is_some_and(|b| b.retry_at > now)
+  }
+
+  fn send_frame(&mut self, address: Address, bytes: &[u8]) {
+    if bytes.len() > MAX_FRAME_SIZE {
+      return;
+    }
+    if let Some(idx) = self.conns.iter().position(|c| c.addr == Some(address)) {
+      let conn = &mut self.conns[idx];
+      conn.push_frame(bytes);
+      if !conn.flush() {
+        self.conns.swap_remove(idx);
+      }
+      return;
+    }
+    if let Some(queue) = self.dialing.get_mut(&address) {
+      if queue.len() < MAX_QUEUED {
+        queue.push(bytes.to_vec());
+      }
+      return;
+    }
+    if self.is_backing_off(&address)
+      || self.is_full()
+      || self.dialing.len() >= MAX_DIALING
+    {
+      return;
+    }
+    self.dialing.insert(address, vec![bytes.to_vec()]);
+    let dialed_tx = self.dialed_tx.clone();
+    std::thread::spawn(move || {
+      let addr = SocketAddr::V4(to_socket_addr(&address));
+      let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT);
+      dialed_tx.send((address, stream)).ok();
+    });
+  }
+
+  fn on_dialed(
+    &mut self,
+    address: Address,
+    stream: std::io::Result<TcpStream>,
+  ) {
+    let queue = self.dialing.remove(&address).unwrap_or_default();
+    if self.is_full() {
+      return;
+    }
+    let remote = SocketAddr::V4(to_socket_addr(&address));
+    match stream.and_then(|stream| Connection::new(stream, remote)) {
+      Ok(mut conn) => {
+        self.backoff.remove(&address);
+        conn.addr = Some(address);
+        let port = self.listener.local_addr().map(|a| a.port()).unwrap_or(0);
+        conn.write_buf.extend_from_slice(&port.to_be_bytes());
+        for bytes in queue {
+          conn.push_frame(&bytes);
+        }
+        if conn.flush() {
+          self.conns.push(conn);
+        }
+      }
+      Err(_) => {
+        let now = get_time();
+        let backoff = self
+          .backoff
+          .entry(address)
+          .or_insert(Backoff { failures: 0, retry_at: 0 });
+        let delay = BACKOFF_MIN << backoff.failures.min(16);
+        backoff.failures += 1;
+        backoff.retry_at = now + delay.min(BACKOFF_MAX);
+      }
+    }
+  }
+
+  fn accept(&mut self) {
+    while let Ok((stream, remote)) = self.listener.accept() {
+      if self.is_full() {
+        continue;
+      }
+      if let Ok(conn) = Connection::new(stream, remote) {
+        self.conns.push(conn);
+      }
+    }
+  }
+
+  fn recv_frames(&mut self) -> Vec<(Address, Vec<u8>)> {
+    self.accept();
+    while let Ok((address, stream)) = self.dialed_rx.try_recv() {
+      self.on_dialed(address, stream);
+    }
+    let now = get_time();
+    // failures are forgotten once the max delay has passed after their retry
+    self.backoff.retain(|_, b| b.retry_at + BACKOFF_MAX > now);
+    let mut received = vec![];
+    self.conns.retain_mut(|conn| {
+      let good = conn.fill() && conn.flush();
+      let frames = match conn.frames() {
+        Some(frames) => frames,
+        None => return false,
+      };
+      if let Some(addr) = conn.addr {
+        received.extend(frames.into_iter().map(|frame| (addr, frame)));
+      }
+      good && conn.active_at + IDLE_TIMEOUT > now
+    });
+    received
+  }
+}
+
+impl ProtoComm for TcpComm {
+  type Address = Address;
+  fn proto_send(
+    &mut self,
+    addresses: Vec<Self::Address>,
+    message: &Message<Self::Address>,
+  ) {
+    let bytes = bitvec_to_bytes(&message.proto_serialized());
+    for address in addresses {
+      self.send_frame(address, &bytes);
+    }
+  }
+  fn proto_recv(&mut self) -> Vec<(Self::Address, Message<Self::Address>)> {
+    let mut messages = Vec::new();
+    for (addr, bytes) in self.recv_frames() {
+      let bits = BitVec::from_bytes(&bytes);
+      if let Some(msge) = Message::proto_deserialized(&bits) {
+        messages.push((addr, msge));
+      }
+    }
+    messages
+  }
+  fn get_addr(&self) -> Self::Address {
+    self.packet_addr()
+  }
+  fn max_message_size(&self) -> usize {
+    MAX_TCP_MESSAGE_SIZE
+  }
+}
+
+impl PacketComm for TcpComm {
+  type Address = Address;
+  fn packet_send(&mut self, address: Self::Address, bytes: &[u8]) {
+    self.send_frame(address, bytes);
+  }
+  fn packet_recv(&mut self) -> Vec<(Self::Address, Vec<u8>)> {
+    self.recv_frames()
+  }
+  fn packet_addr(&self) -> Self::Address {
+    // TODO: remove unwrap
+    from_socket_addr(&self.listener.local_addr().unwrap())
+  }
+  fn max_packet_size(&self) -> usize {
+    MAX_TCP_MESSAGE_SIZE
+  }
+}
@@ -0,0 +1,141 @@
+use rstest::rstest;
+
+use crate::abi::{self, Abi, Contract, Message};
+use crate::common::{Name, U120};
+use crate::hvm::{parse_code, StatementInfo, Term};
+use crate::test::util::{init_runtime, temp_dir, TempPath};
+
+const COUNTER: &str = "
+// A simple counter.
+// @contract
+// @message {Inc}         increments the counter
+// @message {Add amount}  adds `amount` to the counter
+// @message {Get}
+// @state #count
+fun (Counter action) {
+  (Counter {Inc}) =
+    ask x = (Take);
+    ask (Save (+ x #1));
+    (Done #0)
+  (Counter {Add n}) =
+    ask x = (Take);
+    ask (Save (+ x n));
+    (Done #0)
+  (Counter {Get}) =
+    ask x = (Load);
+    (Done x)
+} with { #0 }
+
+// {Inc} and {Get} are declared on genesis
+ctr {Add amount}
+
+// Not a contract
+fun (Id x) {
+  (Id x) = x
+}
+";
+
+fn message(name: &str, fields: &[&str], docs: Option<&str>) -> Message {
+  Message {
+    name: name.to_string(),
+    fields: fields.iter().map(|f| f.to_string()).collect(),
+    docs: docs.map(str::to_string),
+  }
+}
+
+#[test]
+fn abi_extract_annotations() {
+  let abi = abi::extract(COUNTER).unwrap();
+  let expected = Abi {
+    contracts: vec![Contract {
+      name: "Counter".to_string(),
+      docs: vec!["A simple counter.".to_string()],
+      messages: vec![
+        message("Inc", &[], Some("increments the counter")),
+        message("Add", &["amount"], Some("adds `amount` to the counter")),
+        message("Get", &[], None),
+      ],
+      state: Some("#count".to_string()),
+    }],
+  };
+  assert_eq!(abi, expected);
+  let json = serde_json::to_string(&abi).unwrap();
+  assert_eq!(serde_json::from_str::<Abi>(&json).unwrap(), abi);
+}
+
+#[test]
+fn abi_infer_messages() {
+  let code = std::fs::read_to_string("example/block_2.kdl").unwrap();
+  let code =
+    code.replace("fun (Counter action)", "// @contract\nfun (Counter action)");
+  let abi = abi::extract(&code).unwrap();
+  let messages = &abi.contracts[0].messages;
+  let expected = [
+    message("Inc", &[], None),
+    message("Get", &[], None),
+    message("Foo", &["val"], None),
+  ];
+  assert_eq!(messages, &expected);
+}
+
+#[rstest]
+#[case(
+  "// @message {Inc x}\nfun (C m) { (C {Inc}) = (Done #0) }\nctr {Inc}",
+  "has 1 fields, but the constructor has 0"
+)]
+#[case(
+  "// @contract\nfun (C m n) { (C m n) = (Done #0) }",
+  "must take a single argument"
+)]
+#[case(
+  "// @message Inc\nfun (C m) { (C m) = (Done #0) }",
+  "Invalid message annotation"
+)]
+fn abi_extract_errors(#[case] code: &str, #[case] error: &str) {
+  let err = abi::extract(code).unwrap_err();
+  assert!(err.contains(error), "{}", err);
+}
+
+#[rstest]
+fn abi_call_statement(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  for statement in parse_code(COUNTER).unwrap() {
+    rt.run_statement(&statement, true, false, None).unwrap();
+  }
+  let counter = Name::from_str("Counter").unwrap();
+  let add = Name::from_str("Add").unwrap();
+  let get = Name::from_str("Get").unwrap();
+  let message = Term::ctr(add, vec![Term::num(U120::from_u128_unchecked(5))]);
+  let add = abi::call_statement(counter, message);
+  rt.run_statement(&add, true, false, None).unwrap();
+  let get = abi::call_statement(counter, Term::ctr(get, vec![]));
+  match rt.run_statement(&get, true, false, None).unwrap() {
+    StatementInfo::Run { done_term, .. } => {
+      assert_eq!(done_term, Term::num(U120::from_u128_unchecked(5)))
+    }
+    info => panic!("Unexpected result: {:?}", info),
+  }
+}
+
+#[test]
+fn abi_rust_bindings() {
+  let mut abi = abi::extract(COUNTER).unwrap();
+  abi.contracts[0].name = "Counter.Main".to_string();
+  abi.contracts[0].messages.push(message("SendTo", &["to", "type"], None));
+  let code = abi::rust_bindings(&abi).unwrap();
+  assert!(
+    code.contains("/// State: `#count`\npub mod counter_main {"),
+    "{}",
+    code
+  );
+  assert!(code.contains("  /// `{Add amount}`: adds `amount` to the counter\n  pub fn add(amount: U120) -> Statement {\n"), "{}", code);
+  assert!(
+    code.contains("  pub fn send_to(to: U120, type_: U120) -> Statement {\n"),
+    "{}",
+    code
+  );
+  assert!(code.contains("vec![Term::num(to), Term::num(type_)]"), "{}", code);
+
+  abi.contracts[0].messages.push(message("Foo.Inc", &[], None));
+  assert!(abi::rust_bindings(&abi).is_err());
+}
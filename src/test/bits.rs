@@ -83,3 +83,37 @@ pub fn test_serializer_2() {
   let gots = deserialize_list(&bits, &mut index, &mut g_names).unwrap();
   assert_eq!(vals, gots);
 }
+
+#[test]
+fn multisigned_statements_keep_single_signature_encoding() {
+  use crate::bits::deserialize_fixlen;
+  use crate::crypto::Signature;
+  use crate::hvm::{add_sign, remove_sign, Statement};
+
+  let tag = |stmt: &Statement| {
+    deserialize_fixlen(4, &stmt.proto_serialized(), &mut 0).unwrap()
+  };
+  let run = Statement::Run { expr: Term::num(0.try_into().unwrap()), nonce: None, until: None, sign: vec![] };
+  let signed = add_sign(&run, Signature([1; 65]));
+  let multisigned = add_sign(&signed, Signature([2; 65]));
+  assert_eq!(tag(&run), 2);
+  assert_eq!(tag(&signed), 2);
+  assert_eq!(tag(&multisigned), 6);
+  // unsigned and single signed statements differ only on the signature
+  let bits = signed.proto_serialized();
+  let unsigned = remove_sign(&signed).proto_serialized();
+  assert_eq!(bits.len(), unsigned.len() + 65 * 8);
+  let decoded = Statement::proto_deserialized(&multisigned.proto_serialized());
+  assert_eq!(decoded, Some(multisigned));
+
+  // a list of less than 2 signatures has a shorter encoding, so is rejected
+  let mut bits = run.proto_serialized();
+  let len = bits.len();
+  bits.truncate(len - 1);
+  for (i, bit) in [false, true, true, false].into_iter().enumerate() {
+    bits.set(i, bit);
+  }
+  bits.extend([true].into_iter().chain(Signature([1; 65]).proto_serialized()));
+  bits.push(false);
+  assert_eq!(Statement::proto_deserialized(&bits), None);
+}
@@ -0,0 +1,102 @@
+use crate::deploy::{self, Cost, BLOCK_LIMITS};
+use crate::hvm::{parse_code, Statement};
+
+fn names(statements: &[Statement]) -> Vec<String> {
+  statements
+    .iter()
+    .map(|stmt| match stmt {
+      Statement::Fun { name, .. }
+      | Statement::Ctr { name, .. }
+      | Statement::Reg { name, .. } => name.to_string(),
+      Statement::Run { .. } => "run".to_string(),
+      Statement::Own { name, .. } => format!("own {}", name),
+    })
+    .collect()
+}
+
+const PROJECT: &str = "
+  run { (Done (Len {Cons #1 {Nil}})) }
+  fun (Len list) {
+    (Len {Nil}) = #0
+    (Len {Cons x xs}) = (+ #1 (Len xs))
+  }
+  ctr {Cons x xs}
+  ctr {Nil}
+  ctr {Foo.Bar}
+  reg Foo { #0 }
+  run { (Done {Foo.Bar}) }
+";
+
+#[test]
+fn deploy_dependencies() {
+  let statements = parse_code(PROJECT).unwrap();
+  let deps = deploy::dependencies(&statements);
+  assert_eq!(deps[0], [1, 2, 3]);
+  assert_eq!(deps[1], [2, 3]);
+  assert_eq!(deps[4], [5]);
+  assert!(deps[5].is_empty());
+  assert_eq!(deps[6], [0, 4]);
+}
+
+#[test]
+fn deploy_order() {
+  let statements = deploy::order(parse_code(PROJECT).unwrap());
+  assert_eq!(
+    names(&statements),
+    ["Cons", "Nil", "Len", "run", "Foo", "Foo.Bar", "run"]
+  );
+  // cycles don't make it fail
+  let code = "fun (A x) { (A x) = (B x) } fun (B x) { (B x) = (A x) }";
+  let statements = deploy::order(parse_code(code).unwrap());
+  assert_eq!(names(&statements), ["B", "A"]);
+}
+
+#[test]
+fn deploy_pack_splits_dependencies() {
+  let statements = deploy::order(parse_code(PROJECT).unwrap());
+  let costs: Vec<Cost> =
+    statements.iter().map(|stmt| Cost::of(stmt, None)).collect();
+  let batches = deploy::pack(statements, &costs, &BLOCK_LIMITS).unwrap();
+  let batches: Vec<Vec<String>> =
+    batches.iter().map(|batch| names(batch)).collect();
+  assert_eq!(
+    batches,
+    [
+      vec!["Cons", "Nil"],
+      vec!["Len"],
+      vec!["run", "Foo"],
+      vec!["Foo.Bar"],
+      vec!["run"]
+    ]
+  );
+}
+
+#[test]
+fn deploy_pack_respects_limits() {
+  let code = "ctr {A} ctr {B} ctr {C} run { (Done #1) } run { (Done #2) }";
+  let statements = parse_code(code).unwrap();
+  let costs = [
+    Cost { bytes: 400, mana: 0, size: 0 },
+    Cost { bytes: 400, mana: 0, size: 0 },
+    Cost { bytes: 400, mana: 0, size: 0 },
+    Cost { bytes: 10, mana: 3, size: 0 },
+    Cost { bytes: 10, mana: 3, size: 0 },
+  ];
+  let limits = Cost { bytes: 1000, mana: 5, size: 5 };
+  let batches = deploy::pack(statements.clone(), &costs, &limits).unwrap();
+  let sizes: Vec<usize> = batches.iter().map(|batch| batch.len()).collect();
+  assert_eq!(sizes, [2, 2, 1]);
+
+  let limits = Cost { bytes: 1000, mana: 2, size: 5 };
+  let err = deploy::pack(statements, &costs, &limits).unwrap_err();
+  assert!(err.starts_with("Statement #3 doesn't fit in a block"), "{}", err);
+}
+
+#[test]
+fn deploy_cost_of_transaction() {
+  let statement = parse_code("ctr {Pair a b}").unwrap().pop().unwrap();
+  let cost = Cost::of(&statement, None);
+  // transactions are padded to multiples of 5 bytes, plus 2 length bytes
+  assert_eq!((cost.bytes - 2) % 5, 0);
+  assert_eq!(cost.mana, 0);
+}
@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+
+use rstest::rstest;
+
+use crate::format::format_code;
+use crate::hvm::{parse_code, view_statements};
+
+fn kdl_files() -> Vec<PathBuf> {
+  let mut files = vec![PathBuf::from("genesis.kdl")];
+  for dir in ["example", "kdl"] {
+    for entry in std::fs::read_dir(dir).unwrap() {
+      let path = entry.unwrap().path();
+      if path.extension().map_or(false, |ext| ext == "kdl") {
+        files.push(path);
+      }
+    }
+  }
+  files.sort();
+  files
+}
+
+// Examples that are made to not parse, whose statements can't be compared
+const UNPARSED_FILES: &[&str] = &["kdl/num-literal-overflow.kdl"];
+
+fn count_comments(code: &str) -> usize {
+  code.matches("//").count()
+}
+
+#[test]
+fn format_is_idempotent() {
+  for file in kdl_files() {
+    let code = std::fs::read_to_string(&file).unwrap();
+    let fmt1 = format_code(&code)
+      .unwrap_or_else(|err| panic!("{}: {}", file.display(), err));
+    let fmt2 = format_code(&fmt1).unwrap();
+    assert_eq!(fmt1, fmt2, "{}", file.display());
+  }
+}
+
+#[test]
+fn format_preserves_statements_and_comments() {
+  for file in kdl_files() {
+    let code = std::fs::read_to_string(&file).unwrap();
+    let formatted = format_code(&code).unwrap();
+    assert_eq!(
+      count_comments(&code),
+      count_comments(&formatted),
+      "{}",
+      file.display()
+    );
+    if UNPARSED_FILES.iter().any(|unparsed| file == PathBuf::from(unparsed)) {
+      assert!(parse_code(&code).is_err(), "{}", file.display());
+      continue;
+    }
+    let stmts = parse_code(&code)
+      .unwrap_or_else(|err| panic!("{}: {}", file.display(), err));
+    let fmt_stmts = parse_code(&formatted).unwrap();
+    assert_eq!(
+      view_statements(&stmts),
+      view_statements(&fmt_stmts),
+      "{}",
+      file.display()
+    );
+  }
+}
+
+#[rstest]
+#[case(
+  "ctr {Pair a b}   // a pair\nctr   {Nil}\n\n\n\nrun{(Done {Pair #1 #2})}",
+  "ctr {Pair a b} // a pair\nctr {Nil}\n\nrun {\n  (Done {Pair #1 #2})\n}\n"
+)]
+#[case(
+  "fun (F x) { (F x) = ask y = (Take); // take\n (Done [x y]) }",
+  "fun (F x) {\n  (F x) =\n    ask y = (Take); // take\n    (Done [x y])\n}\n"
+)]
+#[case(
+  "run {\n  // first\n\n  (Done #x10)\n  // last\n}\n",
+  "run {\n  // first\n\n  (Done #x10)\n  // last\n}\n"
+)]
+#[case(
+  "run { (Done #0) }  nonce  #3 until #x100 sign { 00ab }",
+  "run {\n  (Done #0)\n} nonce #3 until #x100 sign {\n  00ab\n}\n"
+)]
+#[case(
+  "run { (Done #0) } until #9 nonce #3",
+  "run {\n  (Done #0)\n} nonce #3 until #9\n"
+)]
+#[case("reg Foo.Bar { 'Foo' }", "reg Foo.Bar { 'Foo' }\n")]
+#[case(
+  "own Foo.Bar {#x1} nonce  #0  own Foo { } nonce #1 until #9",
+  "own Foo.Bar { #x1 } nonce #0\nown Foo {} nonce #1 until #9\n"
+)]
+#[case(
+  "reg Foo { #2  of #x1  'Bar' } sign { 00ab } sign { 01cd }",
+  "reg Foo { #2 of #x1 'Bar' } sign {\n  00ab\n} sign {\n  01cd\n}\n"
+)]
+#[case(
+  "import   \"lib/list.kdl\" // list\nctr {A}",
+  "import \"lib/list.kdl\" // list\nctr {A}\n"
+)]
+fn format_cases(#[case] code: &str, #[case] expected: &str) {
+  assert_eq!(format_code(code).unwrap(), expected);
+}
+
+#[test]
+fn format_breaks_long_lines() {
+  let code = "run { (Done (Foo #1000000000 #2000000000 #3000000000 #4000000000 #5000000000 #6000000000)) }";
+  let expected = "run {\n  (Done\n    (Foo\n      #1000000000\n      #2000000000\n      #3000000000\n      #4000000000\n      #5000000000\n      #6000000000\n    )\n  )\n}\n";
+  let formatted = format_code(code).unwrap();
+  assert_eq!(formatted, expected);
+  assert!(formatted.lines().all(|line| line.len() <= crate::format::MAX_WIDTH));
+}
+
+#[rstest]
+#[case("run { (Done #1 }")]
+#[case("fun (F x) { (F x) }")]
+#[case("run { (Done $) }")]
+#[case("import \"list.kdl")]
+fn format_fails_on_syntax_errors(#[case] code: &str) {
+  assert!(format_code(code).is_err());
+}
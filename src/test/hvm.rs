@@ -8,11 +8,16 @@ use rstest::rstest;
 use rstest_reuse::{apply, template};
 
 use crate::common::{Name, U120};
+use crate::config::RollbackConfig;
+use crate::crypto;
 use crate::hvm::{
   self, init_u128_map, read_statements, readback_term, show_term, view_statements,
-  view_term, Rollback, Runtime, StatementInfo, Term, Heap
+  view_term, EntryDiff, RawCell, Rollback, Runtime, StatementInfo, Term, Heap
 };
 use crate::node;
+use crate::persistence::{
+  decode_versioned, encode_versioned, HEAP_MAGIC, META_MAGIC,
+};
 use crate::test::strategies::{func, heap, name, op2, statement, term};
 use crate::test::util::{
   self, advance, init_runtime, rollback, rollback_path, rollback_simple,
@@ -120,6 +125,54 @@ pub fn advanced_rollback_run_fail(
   ));
 }
 
+#[apply(hvm_cases)]
+pub fn exact_rollback(
+  fn_names: &[&str],
+  pre_code: &str,
+  code: &str,
+  validators: &[util::Validator],
+  temp_dir: TempPath,
+) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(pre_code, true, true);
+  let mut states = std::collections::HashMap::new();
+  for tick in 1..=1000 {
+    advance(&mut rt, tick, Some(code), validators);
+    states.insert(tick, RuntimeStateTest::new(&fn_names, &mut rt));
+  }
+  for back in [1, 5, 16] {
+    rt.rollback(1000 - back);
+    assert_eq!(rt.get_tick(), 1000 - back);
+    let state = RuntimeStateTest::new(&fn_names, &mut rt);
+    assert_eq!(state, states[&(1000 - back)]);
+    advance(&mut rt, 1000, Some(code), validators);
+    assert_eq!(RuntimeStateTest::new(&fn_names, &mut rt), states[&1000]);
+  }
+}
+
+#[rstest]
+fn rollback_config(temp_dir: TempPath) {
+  let genesis_stmts = hvm::parse_code(crate::constants::GENESIS_CODE).unwrap();
+  let conf = RollbackConfig { max_heaps: 4, keep: 4, ..Default::default() };
+  let mut rt =
+    hvm::init_runtime_with(temp_dir.path.clone(), &genesis_stmts, conf);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  advance(&mut rt, 100, Some(COUNTER), &counter_validators());
+  // 2 heaps are left for snapshots
+  let mut snapshots = 0;
+  let mut back = rt.get_back();
+  while let Rollback::Cons { tail, .. } = &*back.clone() {
+    snapshots += 1;
+    back = tail.clone();
+  }
+  assert!(snapshots <= 2, "{}", view_rollback_ticks(&rt));
+  let count = rt.read_disk(Name::from_str("Count").unwrap().into());
+  rt.rollback(99);
+  assert_eq!(rt.get_tick(), 99);
+  advance(&mut rt, 100, Some(COUNTER), &counter_validators());
+  assert_eq!(rt.read_disk(Name::from_str("Count").unwrap().into()), count);
+}
+
 #[apply(hvm_cases)]
 pub fn stack_overflow(
   _fn_names: &[&str],
@@ -144,8 +197,606 @@ pub fn stack_overflow2(temp_dir: TempPath) {
   rt.run_statements_from_code(COUNTER_STACKOVERFLOW, false, true);
 }
 
+#[rstest]
+fn persisted_state_is_restored(temp_dir: TempPath) {
+  let fn_names = ["Count", "Store"];
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  advance(&mut rt, 40, Some(COUNTER), &counter_validators());
+  let last = match *rt.get_back() {
+    Rollback::Cons { head, .. } => rt.get_heap(head).tick,
+    Rollback::Nil => panic!("No snapshot"),
+  };
+  rt.rollback(last);
+  let s1 = RuntimeStateTest::new(&fn_names, &mut rt);
+  advance(&mut rt, 45, Some(COUNTER), &counter_validators());
+  rt.flush().unwrap();
+  // ticks after the latest snapshot are not persisted
+  rt.restore_state().unwrap();
+  assert_eq!(rt.get_tick(), last);
+  assert_eq!(RuntimeStateTest::new(&fn_names, &mut rt), s1);
+}
+
+#[rstest]
+fn namespaces_are_persisted(temp_dir: TempPath) {
+  let code = std::fs::read_to_string("example/block_4.kdl").unwrap();
+  let foo = Name::from_str("Foo").unwrap();
+  let mut rt = init_runtime(&temp_dir.path);
+  advance(&mut rt, 10, None, &[]);
+  rt.open();
+  rt.run_statements_from_code(&code, true, false);
+  rt.commit();
+  let owner = rt.get_owner(&foo);
+  assert!(owner.is_some());
+  advance(&mut rt, 40, None, &[]);
+  rt.flush().unwrap();
+  rt.restore_state().unwrap();
+  assert_eq!(rt.get_owner(&foo), owner);
+  // and are undone by rollbacks
+  rt.rollback(10);
+  assert_eq!(rt.get_owner(&foo), None);
+}
+
+#[rstest]
+fn indexes_and_statement_hashes_are_persisted(temp_dir: TempPath) {
+  let name = Name::from_str("Test").unwrap();
+  let mut rt = init_runtime(&temp_dir.path);
+  advance(&mut rt, 10, None, &[]);
+  rt.open();
+  rt.run_statements_from_code("fun (Test x) { (Test ~) = #0 }", true, false);
+  rt.commit();
+  let indx = rt.get_index(&name).unwrap();
+  let hash = (rt.get_sth0(indx), rt.get_sth1(indx));
+  assert!(hash.0.is_some() && hash.1.is_some());
+  advance(&mut rt, 40, None, &[]);
+  rt.flush().unwrap();
+  rt.restore_state().unwrap();
+  assert_eq!(rt.get_index(&name), Some(indx));
+  assert_eq!((rt.get_sth0(indx), rt.get_sth1(indx)), hash);
+  // and are undone by rollbacks
+  rt.rollback(10);
+  assert_eq!(rt.get_index(&name), None);
+  assert_eq!((rt.get_sth0(indx), rt.get_sth1(indx)), (None, None));
+}
+
+// The account of a small secret key, as the genesis namer's `0x1`
+fn small_account(skey: u8) -> crypto::Account {
+  let mut bytes = [0; 32];
+  bytes[31] = skey;
+  crypto::Account::from_private_key(&bytes)
+}
+
+// Signs a statement with the accounts of the given secret keys
+fn multisigned(code: &str, skeys: &[u8]) -> hvm::Statement {
+  let (.., mut stmts) = read_statements(code).unwrap();
+  let stmt = stmts.remove(0);
+  let hash = hvm::hash_statement(&stmt);
+  skeys.iter().fold(stmt, |stmt, skey| {
+    hvm::add_sign(&stmt, small_account(*skey).sign(&hash))
+  })
+}
+
+#[rstest]
+fn multisig_owners_need_threshold(temp_dir: TempPath) {
+  // keys 2, 3 and 4 own "Club", 2 of them must sign
+  let keys: Vec<String> = (2..=4)
+    .map(|skey| {
+      format!("#x{:0>30x}", *U120::from(small_account(skey).name))
+    })
+    .collect();
+  let reg = format!("reg Club {{ #2 of {} }}", keys.join(" "));
+  let ctr = "ctr {Club.Pair a b}";
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.open();
+  let run = |rt: &mut Runtime, stmt: hvm::Statement| {
+    rt.run_statements(&[stmt], true, false).remove(0)
+  };
+  // invalid thresholds are rejected
+  let bad = format!("reg Club {{ #4 of {} }}", keys.join(" "));
+  assert!(run(&mut rt, multisigned(&bad, &[1])).is_err());
+  // and thresholds that don't fit don't parse, instead of wrapping to 2
+  let big = format!("reg Club {{ #x10000000000000002 of {} }}", keys.join(" "));
+  assert!(read_statements(&big).is_err());
+  // top-level names are registered by the namer (key 1)
+  assert!(run(&mut rt, multisigned(&reg, &[1])).is_ok());
+  let club = Name::from_str("Club").unwrap();
+  let owner = rt.get_owner(&club).unwrap();
+  assert_eq!(rt.get_multisig(&owner).unwrap().keys.len(), 3);
+
+  assert!(run(&mut rt, multisigned(ctr, &[])).is_err());
+  assert!(run(&mut rt, multisigned(ctr, &[2])).is_err());
+  assert!(run(&mut rt, multisigned(ctr, &[2, 2])).is_err());
+  assert!(run(&mut rt, multisigned(ctr, &[2, 5])).is_err());
+  assert!(run(&mut rt, multisigned(ctr, &[3, 5, 4])).is_ok());
+  let sub = "reg Club.Sub { #x0 }";
+  assert!(run(&mut rt, multisigned(sub, &[4])).is_err());
+  assert!(run(&mut rt, multisigned(sub, &[4, 2])).is_ok());
+  rt.commit();
+  assert_eq!(rt.get_root(), rt.compute_root());
+
+  // the key set is persisted
+  advance(&mut rt, 40, None, &[]);
+  rt.flush().unwrap();
+  rt.restore_state().unwrap();
+  assert_eq!(rt.get_owner(&club), Some(owner));
+  assert!(rt.get_multisig(&owner).is_some());
+  assert_eq!(rt.get_root(), rt.compute_root());
+  rt.rollback(0);
+  assert!(rt.get_multisig(&owner).is_none());
+}
+
+#[rstest]
+fn ethereum_signatures_are_accepted(temp_dir: TempPath) {
+  let (.., mut stmts) = read_statements("reg Eth { #x0 }").unwrap();
+  let stmt = stmts.remove(0);
+  let hash = hvm::hash_statement(&stmt);
+  let namer = small_account(1);
+  let sign = namer.sign_eth(&hash);
+  assert!(sign.is_eth_prefixed());
+  assert_ne!(sign, namer.sign(&hash));
+  assert_eq!(sign.signer_name(&hash), Some(namer.name));
+  // wallets give `r ++ s ++ v`, with `v` as 27 / 28 or as 0 / 1
+  let mut rsv = [&sign.0[1..], &sign.0[..1]].concat();
+  assert_eq!(crypto::Signature::from_eth_rsv(&rsv), Some(sign.clone()));
+  rsv[64] -= crypto::ETH_RECOVERY_OFFSET;
+  assert_eq!(crypto::Signature::from_eth_rsv(&rsv), Some(sign.clone()));
+  rsv[64] = 2;
+  assert_eq!(crypto::Signature::from_eth_rsv(&rsv), None);
+  assert_eq!(crypto::Signature::from_eth_rsv(&rsv[..64]), None);
+
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.open();
+  let stmt = hvm::set_sign(&stmt, sign);
+  assert!(rt.run_statements(&[stmt], true, false).remove(0).is_ok());
+  assert!(rt.get_owner(&Name::from_str("Eth").unwrap()).is_some());
+}
+
+#[rstest]
+fn signed_runs_are_not_replayed(temp_dir: TempPath) {
+  let subj = format!("#x{:0>30x}", *U120::from(small_account(2).name));
+  let guarded = |guard: &str| format!("run {{ (Done #0) }} {}", guard);
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.open();
+  let run = |rt: &mut Runtime, stmt: hvm::Statement| {
+    rt.run_statements(&[stmt], true, false).remove(0)
+  };
+  // the guards can be written in either order, but only once
+  let (.., stmts) = read_statements(&guarded("until #9 nonce #1")).unwrap();
+  assert_eq!(stmts, read_statements(&guarded("nonce #1 until #9")).unwrap().1);
+  assert!(read_statements(&guarded("nonce #1 nonce #2")).is_err());
+  let first = multisigned(&guarded("nonce #0"), &[2]);
+  assert!(run(&mut rt, first.clone()).is_ok());
+  // the same statement can't run again, nor skip nonces
+  assert!(run(&mut rt, first).is_err());
+  assert!(run(&mut rt, multisigned(&guarded("nonce #2"), &[2])).is_err());
+  assert!(run(&mut rt, multisigned(&guarded("nonce #1"), &[2])).is_ok());
+  // each subject has its own nonces, and unsigned runs have none
+  assert!(run(&mut rt, multisigned(&guarded("nonce #0"), &[3])).is_ok());
+  assert!(run(&mut rt, multisigned(&guarded("nonce #0"), &[])).is_err());
+  assert_eq!(rt.get_nonce(&small_account(2).name.into()), 2);
+
+  let tick = rt.get_tick();
+  let until = |tick| guarded(&format!("until #{}", tick));
+  assert!(run(&mut rt, multisigned(&until(tick), &[])).is_ok());
+  assert!(run(&mut rt, multisigned(&until(tick - 1), &[])).is_err());
+  let both = guarded(&format!("nonce #2 until #{}", tick - 1));
+  assert!(run(&mut rt, multisigned(&both, &[2])).is_err());
+  // the expired statement didn't consume its nonce
+  let nonce = format!("run {{ ((Nonce {}) @n (Done n)) }}", subj);
+  if let Ok(StatementInfo::Run { done_term, .. }) = run(&mut rt, multisigned(&nonce, &[])) {
+    assert_eq!(view_term(&done_term), "#2");
+  } else {
+    panic!("Nonce wasn't read");
+  }
+  // a run signed by many runs as the first signer, and its signatures can't
+  // be reordered to run again as another one
+  let many = multisigned(&guarded("nonce #0"), &[4, 5]);
+  assert!(run(&mut rt, many.clone()).is_ok());
+  let mut reordered = many;
+  if let hvm::Statement::Run { sign, .. } = &mut reordered {
+    sign.reverse();
+  }
+  assert!(run(&mut rt, reordered).is_err());
+  let nonces = [4, 5].map(|skey| rt.get_nonce(&small_account(skey).name.into()));
+  assert_eq!(nonces.iter().sum::<u64>(), 1);
+  rt.commit();
+  assert_eq!(rt.get_root(), rt.compute_root());
+}
+
+#[rstest]
+fn namespaces_are_transferred(temp_dir: TempPath) {
+  let subj = |skey| format!("#x{:0>30x}", *U120::from(small_account(skey).name));
+  let own = |ownr: &str, nonce| format!("own Foo {{{}}} nonce #{}", ownr, nonce);
+  let mut rt = init_runtime(&temp_dir.path);
+  let run = |rt: &mut Runtime, stmt: hvm::Statement| {
+    rt.run_statements(&[stmt], true, false).remove(0)
+  };
+  let ctr = |name| format!("ctr {{Foo.{}}}", name);
+  advance(&mut rt, 10, None, &[]);
+  rt.open();
+  assert!(run(&mut rt, multisigned(&own("", 0), &[1])).is_err());
+  let reg = format!("reg Foo {{ {} }}", subj(2));
+  assert!(run(&mut rt, multisigned(&reg, &[1])).is_ok());
+  // only the owner can transfer
+  let to_3 = own(&subj(3), 0);
+  assert!(run(&mut rt, multisigned(&to_3, &[1])).is_err());
+  assert!(run(&mut rt, multisigned(&to_3, &[2])).is_ok());
+  assert!(run(&mut rt, multisigned(&ctr("A"), &[2])).is_err());
+  assert!(run(&mut rt, multisigned(&ctr("A"), &[3])).is_ok());
+  // a transfer can't be replayed, even when the namespace is back with its signer
+  assert!(run(&mut rt, multisigned(&own(&subj(2), 0), &[3])).is_ok());
+  assert!(run(&mut rt, multisigned(&to_3, &[2])).is_err());
+  assert!(run(&mut rt, multisigned(&own(&subj(3), 1), &[2])).is_ok());
+  rt.commit();
+  let foo = Name::from_str("Foo").unwrap();
+  let owner = rt.get_owner(&foo);
+  let tick = rt.get_tick();
+
+  // to a multi-signature owner, which renounces it
+  rt.open();
+  let to_msig = format!("#2 of {} {}", subj(2), subj(4));
+  assert!(run(&mut rt, multisigned(&own(&to_msig, 1), &[3])).is_ok());
+  assert!(run(&mut rt, multisigned(&own("", 2), &[2])).is_err());
+  assert!(run(&mut rt, multisigned(&own("", 0), &[4, 2])).is_ok());
+  assert_eq!(rt.get_owner(&foo), Some(hvm::NO_OWNER));
+  assert!(run(&mut rt, multisigned(&ctr("B"), &[])).is_err());
+  assert!(run(&mut rt, multisigned(&own(&to_msig, 1), &[4, 2])).is_err());
+  rt.commit();
+  assert_eq!(rt.get_root(), rt.compute_root());
+
+  rt.rollback(tick);
+  assert_eq!(rt.get_owner(&foo), owner);
+  assert_eq!(rt.get_root(), rt.compute_root());
+  rt.rollback(tick - 1);
+  assert_eq!(rt.get_owner(&foo), None);
+}
+
+#[rstest]
+fn reordered_transfers_are_not_replayed(temp_dir: TempPath) {
+  let subj = |skey| format!("#x{:0>30x}", *U120::from(small_account(skey).name));
+  let own = |ownr: &str, nonce| format!("own Foo {{{}}} nonce #{}", ownr, nonce);
+  let mut rt = init_runtime(&temp_dir.path);
+  let run = |rt: &mut Runtime, stmt: hvm::Statement| {
+    rt.run_statements(&[stmt], true, false).remove(0)
+  };
+  rt.open();
+  let msig = format!("#2 of {} {}", subj(5), subj(6));
+  assert!(run(&mut rt, multisigned(&format!("reg Foo {{ {} }}", msig), &[1])).is_ok());
+  let foo = Name::from_str("Foo").unwrap();
+  let owner = rt.get_owner(&foo);
+  // the key set transfers to key 3, which gives it back
+  let to_3 = own(&subj(3), 0);
+  assert!(run(&mut rt, multisigned(&to_3, &[5, 6])).is_ok());
+  assert!(run(&mut rt, multisigned(&own(&msig, 0), &[3])).is_ok());
+  assert_eq!(rt.get_owner(&foo), owner);
+  // the same signatures, in another order, don't transfer it again
+  assert!(run(&mut rt, multisigned(&to_3, &[6, 5])).is_err());
+  assert!(run(&mut rt, multisigned(&to_3, &[5, 6])).is_err());
+  assert_eq!(rt.get_owner(&foo), owner);
+  assert_eq!(rt.get_nonce(&owner.unwrap()), 1);
+}
+
+// Rewrites the heap and metadata files of a state directory on the
+// headerless format used before versioning, with a file per buffer.
+fn write_legacy_files(path: &PathBuf, uuids: &[u128]) {
+  let state = std::fs::read(path.join("_state_.bin")).unwrap();
+  let (_, sections) = decode_versioned(&META_MAGIC, &state).unwrap();
+  for (name, data) in sections {
+    std::fs::write(path.join(format!("_{}_", name)), data).unwrap();
+  }
+  std::fs::remove_file(path.join("_state_.bin")).unwrap();
+  for uuid in uuids {
+    let file = Heap::file_path(*uuid, path);
+    let (_, sections) =
+      decode_versioned(&HEAP_MAGIC, &std::fs::read(&file).unwrap()).unwrap();
+    for (name, data) in sections {
+      let name = format!("{:0>32x}.{}.bin", uuid, name);
+      std::fs::write(path.join(name), data).unwrap();
+    }
+    std::fs::remove_file(file).unwrap();
+  }
+}
+
+fn snapshot_uuids(rt: &Runtime) -> Vec<u128> {
+  let mut uuids = vec![];
+  let mut back = rt.get_back();
+  while let Rollback::Cons { head, tail, .. } = &*back {
+    uuids.push(rt.get_heap(*head).uuid);
+    back = tail.clone();
+  }
+  uuids
+}
+
+#[rstest]
+fn legacy_state_files_are_migrated(temp_dir: TempPath) {
+  let fn_names = ["Count", "Store"];
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  advance(&mut rt, 40, Some(COUNTER), &counter_validators());
+  rt.flush().unwrap();
+  rt.restore_state().unwrap();
+  let s1 = RuntimeStateTest::new(&fn_names, &mut rt);
+
+  write_legacy_files(&temp_dir.path, &snapshot_uuids(&rt));
+  rt.restore_state().unwrap();
+  assert_eq!(RuntimeStateTest::new(&fn_names, &mut rt), s1);
+
+  // the next snapshot is written on the current format
+  advance(&mut rt, 60, Some(COUNTER), &counter_validators());
+  rt.flush().unwrap();
+  assert!(temp_dir.path.join("_state_.bin").exists());
+  assert!(!temp_dir.path.join("_keeps_").exists());
+}
+
+#[rstest]
+fn corrupted_heap_file_is_rejected(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  advance(&mut rt, 40, Some(COUNTER), &counter_validators());
+  rt.flush().unwrap();
+  let file = Heap::file_path(snapshot_uuids(&rt)[0], &temp_dir.path);
+  let mut data = std::fs::read(&file).unwrap();
+  let last = data.len() - 1;
+  data[last] ^= 1;
+  std::fs::write(&file, &data).unwrap();
+  let err = rt.restore_state().unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+  assert!(err.to_string().starts_with("Corrupted section"), "{}", err);
+}
+
+#[rstest]
+fn state_root_is_incremental(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  let genesis = rt.get_root();
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  rt.commit();
+  let mut roots = vec![rt.get_root()];
+  for tick in 1..=60 {
+    advance(&mut rt, tick, Some(COUNTER), &counter_validators());
+    assert_eq!(rt.get_root(), rt.compute_root());
+    roots.push(rt.get_root());
+  }
+  // the counter changes the state on every tick
+  assert!(roots.windows(2).all(|pair| pair[0] != pair[1]));
+  // rollbacks restore the root of the state they go back to
+  rt.rollback(50);
+  assert_eq!(rt.get_root(), roots[50]);
+  rt.rollback(25);
+  assert_eq!(rt.get_tick(), 0);
+  assert_eq!(rt.get_root(), genesis);
+  assert_eq!(rt.get_root(), rt.compute_root());
+}
+
+#[rstest]
+fn state_root_of_old_heaps_is_computed(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  advance(&mut rt, 40, Some(COUNTER), &counter_validators());
+  rt.flush().unwrap();
+  rt.restore_state().unwrap();
+  let root = rt.get_root();
+
+  // rewrites the heaps on the layout version 1, which has no state root
+  for uuid in snapshot_uuids(&rt) {
+    let file = Heap::file_path(uuid, &temp_dir.path);
+    let (_, sections) =
+      decode_versioned(&HEAP_MAGIC, &std::fs::read(&file).unwrap()).unwrap();
+    let sections: Vec<_> = sections
+      .iter()
+      .map(|(name, data)| match name.as_str() {
+        "stat" => (name.as_str(), data[..data.len() - 32].to_vec()),
+        _ => (name.as_str(), data.clone()),
+      })
+      .collect();
+    let mut data = encode_versioned(&HEAP_MAGIC, uuid, &sections);
+    data[8..12].copy_from_slice(&1u32.to_le_bytes());
+    std::fs::write(&file, data).unwrap();
+  }
+  rt.restore_state().unwrap();
+  assert_eq!(rt.get_root(), root);
+}
+
+#[rstest]
+fn saved_states_are_hashed_once(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  rt.commit();
+  let store = Name::from_str("Store").unwrap().into();
+  let state = rt.read_disk(store).unwrap();
+  let root = rt.get_root();
+  // reading a state back to hash it is charged to the run that saves it
+  let mana = rt.get_mana();
+  assert!(rt.write_disk(store, state, mana).is_err());
+  rt.write_disk(store, state, u64::MAX).unwrap();
+  assert!(rt.get_mana() > mana);
+  assert_eq!(rt.get_root(), root);
+  // the hash is kept with the state, on the snapshots and heap files
+  rt.commit();
+  advance(&mut rt, 40, Some(COUNTER), &counter_validators());
+  assert_eq!(rt.get_root(), rt.compute_root());
+  rt.flush().unwrap();
+  let heaps: Vec<_> = snapshot_uuids(&rt)
+    .into_iter()
+    .map(|uuid| Heap::deserialize(uuid, &temp_dir.path).unwrap())
+    .collect();
+  assert!(heaps.iter().any(|heap| heap.leaf.leafs.contains_key(&store)));
+  rt.restore_state().unwrap();
+  assert_eq!(rt.get_root(), rt.compute_root());
+}
+
+#[rstest]
+fn state_hashes_of_old_heaps_are_computed(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  advance(&mut rt, 40, Some(COUNTER), &counter_validators());
+  rt.flush().unwrap();
+  rt.restore_state().unwrap();
+  let root = rt.get_root();
+
+  // rewrites the heaps on the layout version 4, which has no hashes of the states
+  for uuid in snapshot_uuids(&rt) {
+    let file = Heap::file_path(uuid, &temp_dir.path);
+    let (_, sections) =
+      decode_versioned(&HEAP_MAGIC, &std::fs::read(&file).unwrap()).unwrap();
+    let sections: Vec<_> = sections
+      .iter()
+      .filter(|(name, _)| name != "leaf")
+      .map(|(name, data)| (name.as_str(), data.clone()))
+      .collect();
+    let mut data = encode_versioned(&HEAP_MAGIC, uuid, &sections);
+    data[8..12].copy_from_slice(&4u32.to_le_bytes());
+    std::fs::write(&file, data).unwrap();
+  }
+  rt.restore_state().unwrap();
+  assert_eq!(rt.get_root(), root);
+  assert_eq!(rt.compute_root(), root);
+  advance(&mut rt, 50, Some(COUNTER), &counter_validators());
+  assert_eq!(rt.get_root(), rt.compute_root());
+}
+
+#[rstest]
+fn state_diff_lists_changed_entries(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  rt.commit();
+  let old = rt.list_state();
+  let code = "
+    ctr {Pair a b}
+    fun (Id x) {
+      (Id x) = x
+    }
+    run {
+      ask (Call 'Store' {StoreAdd});
+      (Done #0)
+    }
+  ";
+  let name = |name| Name::from_str(name).unwrap();
+  rt.run_statements_from_code(code, true, true);
+  rt.set_owner(name("Foo"), U120::ZERO);
+  rt.commit();
+  let new = rt.list_state();
+
+  let diff = hvm::diff_states(&old, &new);
+  let id = new.funcs[&name("Id")].clone();
+  assert_eq!(diff.funcs, vec![(name("Id"), EntryDiff::Added(id))]);
+  assert_eq!(diff.ctrs, vec![(name("Pair"), EntryDiff::Added(2))]);
+  assert_eq!(diff.ownrs, vec![(name("Foo"), EntryDiff::Added(U120::ZERO))]);
+  assert_eq!(diff.states.len(), 1);
+  assert!(matches!(diff.states[0], (store, EntryDiff::Changed(..)) if store == name("Store")));
+  assert_eq!(diff.to_string().lines().last(), Some("  => {Succ {Zero}}"));
+  // the reverse diff removes what was added
+  let diff = hvm::diff_states(&new, &old);
+  assert_eq!(diff.ctrs, vec![(name("Pair"), EntryDiff::Removed(2))]);
+  assert!(hvm::diff_states(&new, &new).is_empty());
+}
+
+#[apply(hvm_cases)]
+fn compaction_keeps_the_state(
+  fn_names: &[&str],
+  pre_code: &str,
+  code: &str,
+  validators: &[util::Validator],
+  temp_dir: TempPath,
+) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(pre_code, true, true);
+  advance(&mut rt, 100, Some(code), validators);
+  let s1 = RuntimeStateTest::new(fn_names, &mut rt);
+  let (root, listing) = (rt.get_root(), rt.list_state());
+
+  let compaction = rt.compact().unwrap();
+  assert!(compaction.span_after < compaction.span_before);
+  assert!(compaction.span_after >= compaction.live);
+  assert_eq!(RuntimeStateTest::new(fn_names, &mut rt), s1);
+  assert_eq!(rt.get_root(), root);
+  assert_eq!(rt.compute_root(), root);
+  assert!(hvm::diff_states(&listing, &rt.list_state()).is_empty());
+  // the nodes are already dense, so nothing is written
+  let again = rt.compact().unwrap();
+  assert_eq!(again.moved, 0);
+  assert_eq!(again.written, 0);
+  assert_eq!(again.span_after, compaction.span_after);
+}
+
+#[rstest]
+fn compaction_runs_at_snapshots(temp_dir: TempPath) {
+  let fn_names = ["Count", "Store"];
+  let genesis_stmts = hvm::parse_code(crate::constants::GENESIS_CODE).unwrap();
+  let conf = RollbackConfig { compact: true, ..Default::default() };
+  let mut plain = init_runtime(&temp_dir.path.join("plain"));
+  let mut rt = hvm::init_runtime_with(temp_dir.path.clone(), &genesis_stmts, conf);
+  for rt in [&mut plain, &mut rt] {
+    rt.run_statements_from_code(PRE_COUNTER, true, true);
+    advance(rt, 100, Some(COUNTER), &counter_validators());
+  }
+  assert_eq!(plain.get_last_compaction(), None);
+  let compaction = rt.get_last_compaction().unwrap();
+  if let Rollback::Cons { head, .. } = *rt.get_back() {
+    assert_eq!(rt.get_heap(head).tick, compaction.tick);
+  }
+  assert_eq!(
+    RuntimeStateTest::new(&fn_names, &mut rt),
+    RuntimeStateTest::new(&fn_names, &mut plain)
+  );
+  assert_eq!(rt.get_root(), plain.get_root());
+  // rollbacks undo compactions too
+  rt.rollback(compaction.tick - 1);
+  plain.rollback(compaction.tick - 1);
+  assert_eq!(
+    RuntimeStateTest::new(&fn_names, &mut rt),
+    RuntimeStateTest::new(&fn_names, &mut plain)
+  );
+}
+
+#[apply(hvm_cases)]
+fn invariants_hold(
+  fn_names: &[&str],
+  pre_code: &str,
+  code: &str,
+  validators: &[util::Validator],
+  temp_dir: TempPath,
+) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(pre_code, true, true);
+  assert_eq!(rt.check_invariants(), Ok(()));
+  advance(&mut rt, 100, Some(code), validators);
+  assert_eq!(rt.check_invariants(), Ok(()));
+  rt.rollback(70);
+  assert_eq!(rt.check_invariants(), Ok(()));
+  rt.compact().unwrap();
+  assert_eq!(rt.check_invariants(), Ok(()));
+  rt.flush().unwrap();
+  rt.restore_state().unwrap();
+  assert_eq!(rt.check_invariants(), Ok(()));
+}
+
+#[rstest]
+fn invariant_violations_are_found(temp_dir: TempPath) {
+  let mut rt = init_runtime(&temp_dir.path);
+  rt.run_statements_from_code(PRE_COUNTER, true, true);
+  advance(&mut rt, 20, Some(COUNTER), &counter_validators());
+  let fails = |rt: &Runtime, msg: &str| match rt.check_invariants() {
+    Err(errs) => errs.iter().any(|err| err.contains(msg)),
+    Ok(()) => false,
+  };
+  let size = rt.get_size();
+  rt.set_size(size + 1);
+  assert!(fails(&rt, "words are allocated"));
+  rt.set_size(size);
+
+  // `Store` holds a chain of `Succ`
+  let store = rt.read_disk(Name::from_str("Store").unwrap().into()).unwrap();
+  let field = hvm::get_loc(store, 0);
+  let succ = rt.read(field);
+  rt.write(hvm::get_loc(succ, 0), RawCell::new_unchecked(0));
+  assert!(fails(&rt, "points to the free word"));
+  rt.write(field, hvm::Var(field));
+  assert!(fails(&rt, "Dangling VAR"));
+  rt.write(field, succ);
+}
+
 #[apply(hvm_cases)]
-#[ignore = "heap persistence disabled"]
+#[ignore = "slow"]
 pub fn persistence1(
   fn_names: &[&str],
   pre_code: &str,
@@ -590,7 +1241,8 @@ proptest! {
   fn serialize_deserialize_heap(heap in heap()) {
     let h1 = heap;
     let path = temp_dir();
-    h1.serialize(&path.path, false).unwrap();
+    std::fs::create_dir_all(&path.path).unwrap();
+    h1.save_to(&path.path).unwrap();
     if let Ok(h2) = Heap::deserialize(h1.uuid, &path.path) {
         assert_eq!(h1, h2);
     }
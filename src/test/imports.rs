@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use crate::hvm::{parse_code, view_statements};
+use crate::imports::{load_code, load_file, parse_source, strip_imports};
+
+// Writes the files to a fresh directory on the temp dir
+fn project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("kindelia-imports-{}", name));
+  let _ = std::fs::remove_dir_all(&dir);
+  for (path, code) in files {
+    let path = dir.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, code).unwrap();
+  }
+  dir
+}
+
+#[test]
+fn strip_imports_keeps_offsets() {
+  let code = "// lib\nimport \"a.kdl\"\nimport  \"lib/b.kdl\" // b\n\nctr {A}";
+  let (imports, stripped) = strip_imports(code).unwrap();
+  let paths: Vec<&str> = imports.iter().map(|i| i.path.as_str()).collect();
+  assert_eq!(paths, ["a.kdl", "lib/b.kdl"]);
+  assert_eq!(&code[imports[0].range.clone()], "import \"a.kdl\"");
+  assert_eq!(stripped.len(), code.len());
+  assert_eq!(stripped.find("ctr"), code.find("ctr"));
+  assert_eq!(parse_source(code).unwrap().1, parse_code("ctr {A}").unwrap());
+}
+
+#[test]
+fn strip_imports_fails_on_bad_paths() {
+  assert!(strip_imports("import a.kdl").is_err());
+  assert!(strip_imports("import \"a.kdl").is_err());
+  assert!(strip_imports("import \"\"").is_err());
+  // imports after statements are not directives
+  assert!(parse_source("ctr {A}\nimport \"a.kdl\"").is_err());
+}
+
+#[test]
+fn load_in_dependency_order() {
+  let dir = project(
+    "order",
+    &[
+      (
+        "main.kdl",
+        "import \"lib/b.kdl\"\nimport \"a.kdl\"\nrun { (Done {B}) }",
+      ),
+      ("a.kdl", "import \"lib/b.kdl\"\nctr {A}"),
+      ("lib/b.kdl", "import \"c.kdl\"\nctr {B}"),
+      ("lib/c.kdl", "ctr {C}"),
+    ],
+  );
+  let stmts = load_file(&dir.join("main.kdl")).unwrap();
+  let expected = parse_code("ctr {C} ctr {B} ctr {A} run { (Done {B}) }");
+  assert_eq!(view_statements(&stmts), view_statements(&expected.unwrap()));
+
+  let stmts = load_code("import \"a.kdl\" ctr {D}", &dir).unwrap();
+  let expected = parse_code("ctr {C} ctr {B} ctr {A} ctr {D}");
+  assert_eq!(view_statements(&stmts), view_statements(&expected.unwrap()));
+}
+
+#[test]
+fn load_deduplicates_definitions() {
+  let dir = project(
+    "dedup",
+    &[
+      ("main.kdl", "import \"a.kdl\"\nctr {Pair a b}\nctr {A}"),
+      ("a.kdl", "ctr {Pair a b}\nctr {A}\nctr {A}"),
+    ],
+  );
+  let stmts = load_file(&dir.join("main.kdl")).unwrap();
+  // repeated definitions on a single file are kept
+  let expected = parse_code("ctr {Pair a b} ctr {A} ctr {A}").unwrap();
+  assert_eq!(view_statements(&stmts), view_statements(&expected));
+}
+
+#[test]
+fn load_fails_on_conflicting_definitions() {
+  let dir = project(
+    "conflict",
+    &[
+      ("main.kdl", "import \"a.kdl\"\nctr {Pair a}"),
+      ("a.kdl", "ctr {Pair a b}"),
+    ],
+  );
+  let err = load_file(&dir.join("main.kdl")).unwrap_err();
+  assert!(err.contains("Constructor 'Pair' is defined differently"), "{}", err);
+}
+
+#[test]
+fn load_fails_on_cycles() {
+  let dir = project(
+    "cycle",
+    &[
+      ("main.kdl", "import \"a.kdl\""),
+      ("a.kdl", "import \"b.kdl\"\nctr {A}"),
+      ("b.kdl", "import \"a.kdl\"\nctr {B}"),
+    ],
+  );
+  let err = load_file(&dir.join("main.kdl")).unwrap_err();
+  assert!(err.starts_with("Import cycle:"), "{}", err);
+  assert!(err.ends_with("a.kdl."), "{}", err);
+}
+
+#[test]
+fn load_fails_on_missing_files() {
+  let dir = project("missing", &[("main.kdl", "import \"nope.kdl\"")]);
+  let err = load_file(&dir.join("main.kdl")).unwrap_err();
+  assert!(err.contains("nope.kdl"), "{}", err);
+}
@@ -0,0 +1,114 @@
+use rstest::rstest;
+
+use crate::common::Name;
+use crate::crypto::Account;
+use crate::keystore::{Kdf, KeyFile, Keystore};
+use crate::test::util::{temp_dir, TempPath};
+
+// Test vector of the Web3 Secret Storage Definition
+const PBKDF2_KEY_FILE: &str = r#"{
+  "crypto": {
+    "cipher": "aes-128-ctr",
+    "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+    "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+    "kdf": "pbkdf2",
+    "kdfparams": {
+      "c": 262144,
+      "dklen": 32,
+      "prf": "hmac-sha256",
+      "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+    },
+    "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+  },
+  "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+  "version": 3,
+  "address": "008aeeda4d805471df9b2a5b0f38a0c3bcba786b"
+}"#;
+
+const SECRET: &str =
+  "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d";
+
+fn secret() -> [u8; 32] {
+  hex::decode(SECRET).unwrap().try_into().unwrap()
+}
+
+#[test]
+fn ethereum_key_file_is_decrypted() {
+  let key: KeyFile = serde_json::from_str(PBKDF2_KEY_FILE).unwrap();
+  assert_eq!(key.decrypt("testpassword").unwrap(), secret());
+  assert!(key.decrypt("wrongpassword").is_err());
+}
+
+#[test]
+fn key_file_roundtrip() {
+  let key = KeyFile::encrypt(&secret(), "pass", Kdf::scrypt(10)).unwrap();
+  let account = Account::from_private_key(&secret());
+  assert_eq!(key.get_address().unwrap().0, account.address.0);
+  assert_eq!(Name::from_address(&account.address), account.name);
+
+  let json = serde_json::to_value(&key).unwrap();
+  assert_eq!(json["version"], 3);
+  assert_eq!(json["crypto"]["kdf"], "scrypt");
+  assert_eq!(json["crypto"]["kdfparams"]["n"], 1024);
+  let key: KeyFile = serde_json::from_value(json).unwrap();
+  assert_eq!(key.decrypt("pass").unwrap(), secret());
+  assert!(key.decrypt("Pass").is_err());
+}
+
+#[test]
+fn costly_key_files_are_refused() {
+  let key: KeyFile = serde_json::from_str(PBKDF2_KEY_FILE).unwrap();
+  let with_kdf = |kdf: Kdf| {
+    let mut key = key.clone();
+    key.crypto.kdf = kdf;
+    key
+  };
+  let pbkdf2 = |dklen, c| Kdf::Pbkdf2 {
+    dklen,
+    c,
+    prf: "hmac-sha256".to_string(),
+    salt: vec![0; 32],
+  };
+  let scrypt =
+    |dklen, n, r, p| Kdf::Scrypt { dklen, n, r, p, salt: vec![0; 32] };
+  let refused = [
+    pbkdf2(32, u32::MAX),
+    pbkdf2(1 << 30, 1),
+    scrypt(32, 1 << 40, 8, 1),
+    scrypt(32, 1 << 10, 1 << 20, 1),
+    scrypt(32, 1 << 10, 8, 1 << 20),
+    scrypt(1 << 30, 1 << 10, 8, 1),
+  ];
+  for kdf in refused {
+    assert!(
+      with_kdf(kdf.clone()).decrypt("testpassword").is_err(),
+      "{:?}",
+      kdf
+    );
+    assert!(KeyFile::encrypt(&secret(), "pass", kdf).is_err());
+  }
+}
+
+#[rstest]
+fn keystore_saves_keys_by_alias(temp_dir: TempPath) {
+  let keystore = Keystore::new(&temp_dir.path.join("keystore"));
+  assert!(keystore.list().unwrap().is_empty());
+  assert!(keystore.get("bob").is_err());
+
+  let key = KeyFile::encrypt(&secret(), "pass", Kdf::scrypt(10)).unwrap();
+  keystore.insert("bob", &key).unwrap();
+  keystore.insert("alice", &key).unwrap();
+  assert!(keystore.insert("bob", &key).is_err());
+  assert!(keystore.insert("../bob", &key).is_err());
+  assert!(keystore.insert("", &key).is_err());
+
+  assert_eq!(keystore.list().unwrap(), ["alice", "bob"]);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let path = temp_dir.path.join("keystore/bob.json");
+    let mode = std::fs::metadata(path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+  }
+  assert_eq!(keystore.get("bob").unwrap(), key);
+}
@@ -0,0 +1,89 @@
+use crate::lsp::{Analyzer, DefKind, Origin, Pos, Severity};
+
+const CODE: &str = "\
+// A pair
+ctr {Pair a b}
+
+fun (Swap p) {
+  (Swap {Pair a b}) = {Pair b a}
+}
+
+run {
+  ask x = (Take);
+  (Done (Swap {Pair x}))
+}
+";
+
+fn pos(line: u32, col: u32) -> Pos {
+  Pos { line, col }
+}
+
+#[test]
+fn lsp_diagnostics() {
+  let analyzer = Analyzer::new();
+  let doc = analyzer.analyze(CODE);
+  assert_eq!(doc.diagnostics.len(), 1);
+  let diag = &doc.diagnostics[0];
+  assert_eq!(diag.severity, Severity::Error);
+  assert_eq!(diag.span.start, pos(9, 15));
+  assert!(diag.message.contains("Arity mismatch for 'Pair'"));
+
+  let doc = analyzer.analyze(
+    "run {\n  (Done (Foo #1))\n}\nfun (Bar x) {\n  (Bar x) = (+ x x)\n}\n",
+  );
+  let messages: Vec<_> =
+    doc.diagnostics.iter().map(|d| (d.severity, d.message.as_str())).collect();
+  assert_eq!(
+    messages,
+    [
+      (
+        Severity::Warning,
+        "Function 'Foo' is not defined in this file nor in genesis."
+      ),
+      (Severity::Error, "'x' is not used linearly in body, in rule '0'"),
+    ]
+  );
+
+  let doc = analyzer.analyze("run {\n  (Done #1\n}\n");
+  assert_eq!(doc.diagnostics.len(), 1);
+  assert_eq!(doc.diagnostics[0].span.start, pos(2, 0));
+}
+
+#[test]
+fn lsp_definition() {
+  let analyzer = Analyzer::new();
+  let doc = analyzer.analyze(CODE);
+  let def = analyzer.definition(&doc, pos(9, 10)).unwrap();
+  assert_eq!((def.kind, def.origin), (DefKind::Fun, Origin::Document));
+  assert_eq!(def.span.start, pos(3, 5));
+  let def = analyzer.definition(&doc, pos(8, 12)).unwrap();
+  assert_eq!(def.name.to_string(), "Take");
+  assert_eq!(def.origin, Origin::Genesis);
+  assert!(analyzer.definition(&doc, pos(8, 6)).is_none());
+}
+
+#[test]
+fn lsp_hover() {
+  let analyzer = Analyzer::new();
+  let doc = analyzer.analyze(CODE);
+  let hover = analyzer.hover(&doc, pos(1, 6)).unwrap();
+  assert!(hover.contains("ctr {Pair a b}"));
+  assert!(hover.contains("A pair"));
+  let hover = analyzer.hover(&doc, pos(3, 6)).unwrap();
+  assert!(hover.contains("- arity: 1"));
+  assert!(hover.contains("allocs 2, FUN-CTR mana 4"));
+}
+
+#[test]
+fn lsp_completion() {
+  let analyzer = Analyzer::new();
+  let text = "run {\n  {TA\n  (Sw\n}\nfun (Swap p) {\n  (Swap p) = p\n}\n";
+  let doc = analyzer.analyze(text);
+  let items = analyzer.completions(&doc, text, pos(1, 5));
+  let labels: Vec<_> = items.iter().map(|item| item.label.as_str()).collect();
+  assert_eq!(labels, ["TAKE", "TA"]);
+  assert!(items[0].io);
+  let items = analyzer.completions(&doc, text, pos(2, 5));
+  let labels: Vec<_> = items.iter().map(|item| item.label.as_str()).collect();
+  assert_eq!(labels, ["Swap"]);
+}
@@ -11,8 +11,19 @@ mod strategies;
 mod util;
 
 // test modules
+mod abi;
 mod bits;
+mod deploy;
+mod format;
 mod hasher;
 mod hvm;
+mod imports;
+mod keystore;
+mod lsp;
+mod nat;
 mod network;
 mod node;
+mod offline;
+mod persistence;
+mod secure;
+mod tcp;
@@ -0,0 +1,59 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+use crate::nat::{self, Mapping};
+use crate::net::Transport;
+
+/// A NAT-PMP gateway on localhost, answering with `result` to mappings.
+fn fake_gateway(result: u16) -> SocketAddrV4 {
+  let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let addr = match socket.local_addr().unwrap() {
+    SocketAddr::V4(addr) => addr,
+    SocketAddr::V6(_) => unreachable!(),
+  };
+  std::thread::spawn(move || {
+    let mut buffer = [0; 16];
+    while let Ok((size, from)) = socket.recv_from(&mut buffer) {
+      let request = &buffer[..size];
+      let mut answer = vec![0, 128 + request[1], 0, 0, 0, 0, 0, 42];
+      if request[1] == 0 {
+        answer.extend([1, 2, 3, 4]);
+      } else {
+        answer[2..4].copy_from_slice(&result.to_be_bytes());
+        answer.extend(&request[4..6]); // internal port
+        answer.extend(
+          &(u16::from_be_bytes([request[6], request[7]]) + 1).to_be_bytes(),
+        );
+        answer.extend(&3600_u32.to_be_bytes());
+      }
+      socket.send_to(&answer, from).unwrap();
+    }
+  });
+  addr
+}
+
+#[test]
+fn ports_are_mapped_with_natpmp() {
+  let gateway = fake_gateway(0);
+  let mapping = nat::map_natpmp(gateway, Transport::Udp, 42000).unwrap();
+  let external = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 42001);
+  assert_eq!(mapping, Mapping { external, lifetime: 3600 });
+
+  // refused mappings are errors
+  let gateway = fake_gateway(2);
+  assert!(nat::map_natpmp(gateway, Transport::Tcp, 42000).is_err());
+}
+
+#[test]
+fn default_gateway_is_found() {
+  let table = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF
+eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000
+";
+  let gateway = nat::parse_default_gateway(table);
+  assert_eq!(gateway, Some(Ipv4Addr::new(192, 168, 0, 1)));
+  assert_eq!(
+    nat::parse_default_gateway(&table[..table.find("eth0\t0000").unwrap()]),
+    None
+  );
+}
@@ -70,8 +70,10 @@ fn network() {
         ui: Some(config::UiConfig { json: true, tags: vec![] }),
         api: None,
         ws: Some(ws_config), // Some(ws_config),
+        rollback: config::RollbackConfig::default(),
+        check_invariants: false,
       };
-      node::start(node_cfg, socket, initial_peers);
+      node::start(node_cfg, socket, initial_peers, None);
     });
     threads.push(socket_thread);
   }
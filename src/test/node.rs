@@ -1,10 +1,24 @@
+use std::net::UdpSocket;
+use std::path::Path;
+
+use primitive_types::U256;
 use proptest::collection::vec;
 use proptest::proptest;
+use rstest::rstest;
 
 use crate::bits::ProtoSerialize;
-use crate::node;
+use crate::config::RollbackConfig;
+use crate::crypto::{self, Keccakable};
+use crate::hvm;
+use crate::net::{self, ProtoComm};
+use crate::node::{
+  self, Block, Body, HashedBlock, Message, Node, Peer, PeerProtocol,
+  PeersStore, CAPABILITIES, MAX_OBSERVERS, PROTOCOL_VERSION, TIME_PER_BLOCK,
+};
+use crate::persistence::{self, KvBlockStorage};
 use crate::test::strategies::statement;
-use crate::util;
+use crate::test::util::{temp_dir, TempPath};
+use crate::util::{self, get_time};
 
 proptest! {
   #[test]
@@ -17,3 +31,445 @@ proptest! {
     assert_eq!(s1, s2);
   }
 }
+
+type TestNode = Node<UdpSocket, KvBlockStorage>;
+
+// Returns the events receiver too, so that it stays open
+#[cfg(feature = "events")]
+type Events = std::sync::mpsc::Receiver<crate::events::NodeEventEmittedInfo>;
+#[cfg(not(feature = "events"))]
+type Events = ();
+
+fn test_node(path: &Path) -> (TestNode, Events) {
+  let comm = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let storage = KvBlockStorage::open(&path.join("blocks.kv")).unwrap();
+  #[cfg(feature = "events")]
+  let (event_tx, events) = std::sync::mpsc::channel();
+  #[cfg(not(feature = "events"))]
+  let events = ();
+  let (_, node) = Node::new(
+    path.to_path_buf(),
+    0xCAFE,
+    RollbackConfig::default(),
+    vec![],
+    comm,
+    storage,
+    None,
+    #[cfg(feature = "events")]
+    event_tx,
+  );
+  (node, events)
+}
+
+// Mines an empty block on top of the tip
+fn mine_block(node: &TestNode, time: u128) -> HashedBlock {
+  let target = node.get_tip_target();
+  let mut meta = 0;
+  loop {
+    let block = Block::new(node.tip, time, meta, Body { data: vec![0] });
+    let block = block.hashed();
+    if U256::from(block.get_hash()) >= target {
+      return block;
+    }
+    meta += 1;
+  }
+}
+
+// Mines blocks on the expected pace, so that the target stays the same
+fn mine_blocks(node: &mut TestNode, count: u128) {
+  let height = node.height[&node.tip];
+  let start = get_time() - 100 * TIME_PER_BLOCK;
+  for i in 0..count {
+    let block = mine_block(node, start + (height + i) * TIME_PER_BLOCK);
+    node.add_block(&block);
+  }
+}
+
+#[rstest]
+fn restart_computes_blocks_after_snapshot(temp_dir: TempPath) {
+  let (mut node, _events) = test_node(&temp_dir.path);
+  mine_blocks(&mut node, 40);
+  let tip = node.tip;
+  assert_eq!(node.height[&tip], 40);
+  assert_eq!(node.runtime.get_tick(), 40);
+  let work = node.work[&tip];
+  node.runtime.flush().unwrap();
+  drop(node);
+
+  let (mut node, _events) = test_node(&temp_dir.path);
+  let restored = node.runtime.get_tick();
+  assert!(restored > 0 && restored < 40, "{}", restored);
+  node.load_blocks();
+  assert_eq!(node.tip, tip);
+  assert_eq!(node.work[&tip], work);
+  assert_eq!(node.runtime.get_tick(), 40);
+  // only the blocks after the snapshot were computed, plus genesis
+  assert_eq!(node.results.len() as u64, 40 - restored + 1);
+
+  mine_blocks(&mut node, 1);
+  assert_eq!(node.height[&node.tip], 41);
+  assert_eq!(node.runtime.get_tick(), 41);
+}
+
+#[rstest]
+fn old_forks_are_pruned(temp_dir: TempPath) {
+  use crate::persistence::BlockStorage;
+  let (mut node, _events) = test_node(&temp_dir.path);
+  mine_blocks(&mut node, 10);
+  // a fork of two blocks from height 2, lighter than the longest chain, as the
+  // work of a block depends on its hash
+  let mut prev = node.get_block_hash_by_index(2).unwrap();
+  let max_work = (node.work[&node.tip] - node.work[&prev]) / 2;
+  let mut fork = vec![];
+  for meta in [1_000_000, 1_000_001] {
+    let time = node.block[&prev].time + 1;
+    let block = (meta..)
+      .map(|meta| Block::new(prev, time, meta, Body { data: vec![0] }).hashed())
+      .find(|block| {
+        let bhash = U256::from(block.get_hash());
+        bhash >= node.get_tip_target() && node::get_hash_work(bhash) < max_work
+      })
+      .unwrap();
+    prev = block.get_hash().into();
+    node.add_block(&block);
+    fork.push(prev);
+  }
+  assert!(fork.iter().all(|bhash| node.block.contains_key(bhash)));
+
+  node.prune_blocks(8);
+  assert!(fork.iter().all(|bhash| node.block.contains_key(bhash)));
+  node.prune_blocks(7);
+  assert!(fork.iter().all(|bhash| !node.block.contains_key(bhash)));
+  assert_eq!(node.children[&node.get_block_hash_by_index(2).unwrap()].len(), 1);
+  assert_eq!(node.block.len(), 11);
+  let stored: Vec<node::StoredBlock> = node.storage.read_blocks().unwrap();
+  assert_eq!(stored.len(), 10);
+}
+
+#[rstest]
+fn restart_without_blocks_resets_runtime(temp_dir: TempPath) {
+  let (mut node, _events) = test_node(&temp_dir.path);
+  mine_blocks(&mut node, 40);
+  node.runtime.flush().unwrap();
+  drop(node);
+  std::fs::remove_file(temp_dir.path.join("blocks.kv")).unwrap();
+
+  let (mut node, _events) = test_node(&temp_dir.path);
+  assert!(node.runtime.get_tick() > 0);
+  node.load_blocks();
+  assert_eq!(node.tip, node.genesis_hash);
+  assert_eq!(node.runtime.get_tick(), 0);
+}
+
+#[rstest]
+fn snapshots_with_forged_roots_are_refused(temp_dir: TempPath) {
+  let (path_a, path_b) = (temp_dir.path.join("a"), temp_dir.path.join("b"));
+  let file = temp_dir.path.join("state.snapshot");
+  let (mut node_a, _events) = test_node(&path_a);
+  mine_blocks(&mut node_a, 40);
+  node_a.runtime.flush().unwrap();
+  node::export_snapshot(&path_a, None, &file).unwrap();
+
+  let data = std::fs::read(&file).unwrap();
+  let mut snapshot =
+    persistence::decode_snapshot::<HashedBlock>(&data).unwrap();
+  assert_ne!(snapshot.heap.rot0, hvm::U128_NONE);
+  snapshot.heap.rot0 ^= 1;
+  let (data, state_hash) =
+    persistence::encode_snapshot(&snapshot.heap, &snapshot.blocks).unwrap();
+  std::fs::write(&file, data).unwrap();
+  let err = node::import_snapshot(&path_b, &file, &state_hash).unwrap_err();
+  assert!(err.contains("state root"), "{}", err);
+}
+
+#[rstest]
+fn snapshot_bootstraps_node(temp_dir: TempPath) {
+  let (path_a, path_b) = (temp_dir.path.join("a"), temp_dir.path.join("b"));
+  let file = temp_dir.path.join("state.snapshot");
+  let (mut node_a, _events) = test_node(&path_a);
+  mine_blocks(&mut node_a, 40);
+  node_a.runtime.flush().unwrap();
+
+  assert!(node::export_snapshot(&path_a, Some(40), &file).is_err());
+  let (tick, state_hash) = node::export_snapshot(&path_a, None, &file).unwrap();
+  assert!(tick > 0 && tick < 40, "{}", tick);
+  // the state hash must be the expected one
+  let other_hash = crypto::Hash([0; 32]);
+  assert!(node::import_snapshot(&path_b, &file, &other_hash).is_err());
+  assert_eq!(
+    node::import_snapshot(&path_b, &file, &state_hash).unwrap(),
+    (tick, state_hash.clone())
+  );
+  // only empty nodes can import snapshots
+  assert!(node::import_snapshot(&path_b, &file, &state_hash).is_err());
+
+  let (mut node_b, _events) = test_node(&path_b);
+  assert_eq!(node_b.runtime.get_tick(), tick);
+  node_b.load_blocks();
+  assert_eq!(Some(node_b.tip), node_a.get_block_hash_by_index(tick));
+  assert_eq!(node_b.runtime.get_tick(), tick);
+  // no block was computed, besides genesis
+  assert_eq!(node_b.results.len(), 1);
+  // blocks after the snapshot are verified and computed
+  for height in tick + 1..=40 {
+    let bhash = node_a.get_block_hash_by_index(height).unwrap();
+    node_b.add_block(&node_a.block[&bhash].clone());
+  }
+  assert_eq!(node_b.tip, node_a.tip);
+  assert_eq!(node_b.work[&node_b.tip], node_a.work[&node_a.tip]);
+  assert_eq!(node_b.runtime.get_tick(), 40);
+}
+
+#[rstest]
+fn saved_states_are_listed(temp_dir: TempPath) {
+  let (mut node, _events) = test_node(&temp_dir.path);
+  mine_blocks(&mut node, 40);
+  node.runtime.flush().unwrap();
+
+  assert!(node::saved_state_listing(&temp_dir.path, Some(40)).is_err());
+  let (tick, listing) =
+    node::saved_state_listing(&temp_dir.path, None).unwrap();
+  assert!(tick > 0 && tick < 40, "{}", tick);
+  assert!(!listing.funcs.is_empty());
+  // it's the state the node had at that tick
+  node.runtime.rollback(tick);
+  assert_eq!(node.runtime.get_tick(), tick);
+  assert!(hvm::diff_states(&listing, &node.runtime.list_state()).is_empty());
+}
+
+#[rstest]
+fn saved_state_is_checked(temp_dir: TempPath) {
+  let (mut node, _events) = test_node(&temp_dir.path);
+  node.check_invariants = true;
+  mine_blocks(&mut node, 40);
+  node.runtime.flush().unwrap();
+
+  assert!(node::check_saved_state(&temp_dir.path, Some(40)).is_err());
+  let (tick, errors) = node::check_saved_state(&temp_dir.path, None).unwrap();
+  assert!(tick > 0 && tick < 40, "{}", tick);
+  assert_eq!(errors, Vec::<String>::new());
+}
+
+#[rstest]
+fn peers_negotiate_protocol(temp_dir: TempPath) {
+  use std::time::Duration;
+  let (mut node, _events) = test_node(&temp_dir.path);
+  let hello = |version, genesis| Message::Hello {
+    magic: 0xCAFE,
+    version,
+    capabilities: u64::MAX,
+    height: 7,
+    genesis,
+    challenge: 42,
+  };
+  let mut peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+  peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+  let addr = peer.get_addr();
+
+  node.handle_message(addr, &hello(PROTOCOL_VERSION + 1, node.genesis_hash));
+  let protocol = PeerProtocol {
+    version: PROTOCOL_VERSION,
+    capabilities: CAPABILITIES,
+    height: 7,
+  };
+  assert_eq!(node.peers.get_protocol(&addr), Some(protocol));
+  // the node replies with its own hello, only the first time
+  node.handle_message(addr, &hello(PROTOCOL_VERSION, node.genesis_hash));
+  let mut replies = vec![];
+  for _ in 0..3 {
+    replies.extend(peer.proto_recv());
+  }
+  let hellos: Vec<_> = replies
+    .iter()
+    .filter(|(_, msg)| matches!(msg, Message::Hello { .. }))
+    .collect();
+  assert_eq!(hellos.len(), 1);
+  match &hellos[0].1 {
+    Message::Hello { version, genesis, height, challenge, .. } => {
+      assert_eq!(*version, PROTOCOL_VERSION);
+      assert_eq!(*genesis, node.genesis_hash);
+      assert_eq!(*height, 0);
+      assert_eq!(*challenge, node.peers.challenge(&addr));
+    }
+    msg => panic!("Expected a hello, got {:?}", msg),
+  }
+  // and tells where it sees the peer, on every hello, echoing its challenge
+  let observed = replies.iter().filter(|(_, msg)| {
+    matches!(msg, Message::Observed { address, challenge: 42, .. } if *address == addr)
+  });
+  assert_eq!(observed.count(), 2);
+
+  // a hello from another chain is ignored, but its address isn't rejected,
+  // since the transport doesn't authenticate it, so it could be forged
+  let other = UdpSocket::bind("127.0.0.1:0").unwrap().get_addr();
+  node.handle_message(other, &hello(PROTOCOL_VERSION, node.genesis_hash + 1));
+  assert!(!node.peers.is_rejected(&other, get_time()));
+  assert!(node.peers.get_protocol(&other).is_none());
+  // but it isn't sent to for a while, or until it says a good hello
+  let notice = Message::NoticeTheseBlocks {
+    magic: 0xCAFE,
+    gossip: true,
+    blocks: vec![],
+    peers: vec![],
+  };
+  let now = get_time();
+  let later = now + node::INCOMPATIBLE_TIMEOUT;
+  assert!(!node.peers.can_send(&other, &notice, now));
+  assert!(node.peers.can_send(&other, &notice, later));
+  node.handle_message(other, &hello(PROTOCOL_VERSION, node.genesis_hash));
+  assert!(node.peers.can_send(&other, &notice, now));
+  // and newer messages only go to the peers that have their version
+  let legacy = net::parse_address("10.0.0.1:42000");
+  let observed =
+    Message::Observed { magic: 0xCAFE, address: legacy, challenge: 0 };
+  assert!(node.peers.can_send(&legacy, &notice, now));
+  assert!(!node.peers.can_send(&legacy, &observed, now));
+  assert!(node.peers.can_send(&other, &observed, now));
+  assert!(
+    node::negotiate(&node.genesis_hash, 0, 0, 0, &node.genesis_hash).is_err()
+  );
+}
+
+#[rstest]
+fn external_address_is_learned_from_peers(temp_dir: TempPath) {
+  use std::time::Duration;
+  let (mut node, _events) = test_node(&temp_dir.path);
+  let external = net::parse_address("1.2.3.4:42000");
+  let peer = |n| net::parse_address(&format!("10.0.0.{}:42000", n));
+  let challenge = node.peers.challenge(&peer(1));
+  let observed = |address, challenge| Message::Observed {
+    magic: 0xCAFE,
+    address,
+    challenge,
+  };
+
+  // peers that didn't say hello are not heard, since they could be forged
+  for n in 1..=3 {
+    node.handle_message(peer(n), &observed(external, challenge));
+  }
+  assert_eq!(node.advertised_address(), None);
+  let protocol =
+    PeerProtocol { version: PROTOCOL_VERSION, capabilities: 0, height: 0 };
+  for n in 1..=4 {
+    node.peers.set_protocol(&peer(n), protocol);
+  }
+  // nor peers that didn't get our hello, as they don't know its challenge
+  assert_ne!(node.peers.challenge(&peer(2)), challenge);
+  for n in 2..=4 {
+    node.handle_message(peer(n), &observed(external, challenge));
+  }
+  assert_eq!(node.advertised_address(), None);
+  let observed = |n, address| {
+    let challenge = node.peers.challenge(&peer(n));
+    observed(address, challenge)
+  };
+
+  // a few peers are not trusted, nor a peer on another network
+  let (one, two, three) =
+    (observed(1, external), observed(2, external), observed(3, external));
+  let moved = observed(3, peer(9));
+  let mut other_network = observed(4, external);
+  if let Message::Observed { magic, .. } = &mut other_network {
+    *magic = 0xBEEF;
+  }
+  node.handle_message(peer(1), &one);
+  node.handle_message(peer(2), &two);
+  node.handle_message(peer(4), &other_network);
+  assert_eq!(node.advertised_address(), None);
+  node.handle_message(peer(3), &three);
+  assert_eq!(node.advertised_address(), Some(external));
+  // a peer changing its mind breaks the quorum
+  node.handle_message(peer(3), &moved);
+  assert_eq!(node.advertised_address(), None);
+  // and so does a peer going away
+  node.handle_message(peer(3), &three);
+  assert_eq!(node.advertised_address(), Some(external));
+  node.peers.inactivate_peer(&peer(1));
+  assert_eq!(node.advertised_address(), None);
+
+  // when there are too many reports, new peers aren't heard until old ones
+  // go away, so a flood can't push out the honest ones
+  let many = |n| net::parse_address(&format!("10.0.1.1:{}", 1000 + n));
+  for n in 0..MAX_OBSERVERS as u16 {
+    let challenge = node.peers.challenge(&many(n));
+    node.peers.set_protocol(&many(n), protocol);
+    node.peers.set_observed(&many(n), peer(9), challenge);
+  }
+  node.peers.set_protocol(&peer(1), protocol);
+  node.handle_message(peer(1), &one);
+  assert_eq!(node.advertised_address(), Some(peer(9)));
+  for n in 0..MAX_OBSERVERS as u16 {
+    node.peers.inactivate_peer(&many(n));
+  }
+  assert_eq!(node.advertised_address(), None);
+  node.handle_message(peer(1), &one);
+  assert_eq!(node.advertised_address(), Some(external));
+
+  // the address on the config wins
+  let configured = net::parse_address("5.6.7.8:42000");
+  node.advertise_address = Some(configured);
+  assert_eq!(node.advertised_address(), Some(configured));
+
+  // it is shared with the peers, but not noticed back by us
+  let mut other = UdpSocket::bind("127.0.0.1:0").unwrap();
+  other.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+  node.send_blocks_to(vec![other.get_addr()], false, vec![], 3);
+  let got = other.proto_recv();
+  assert_eq!(got.len(), 1);
+  match &got[0].1 {
+    Message::NoticeTheseBlocks { peers, .. } => {
+      assert!(peers.iter().any(|peer| peer.address == configured));
+    }
+    msg => panic!("Expected a notice, got {:?}", msg),
+  }
+  let notice = Message::NoticeTheseBlocks {
+    magic: 0xCAFE,
+    gossip: false,
+    blocks: vec![],
+    peers: vec![Peer { address: configured, seen_at: get_time() }],
+  };
+  node.handle_message(peer(1), &notice);
+  assert!(node.peers.get_all().iter().all(|peer| peer.address != configured));
+}
+
+#[test]
+fn legacy_peers_are_greeted() {
+  let mut peers = PeersStore::new();
+  let addr = net::parse_address("10.0.0.1:42000");
+  let now = get_time();
+  #[cfg(feature = "events")]
+  let (event_tx, _events) = std::sync::mpsc::channel();
+  peers.see_peer(
+    Peer { address: addr, seen_at: now },
+    #[cfg(feature = "events")]
+    event_tx,
+  );
+  assert_eq!(peers.to_greet(now), vec![addr]);
+  assert!(peers.to_greet(now + 1).is_empty());
+  assert_eq!(peers.to_greet(now + node::GREET_DELAY), vec![addr]);
+  let protocol =
+    PeerProtocol { version: PROTOCOL_VERSION, capabilities: 0, height: 0 };
+  assert!(peers.set_protocol(&addr, protocol));
+  assert!(peers.to_greet(now + 2 * node::GREET_DELAY).is_empty());
+  // a peer that comes back after timing out is greeted again
+  peers.inactivate_peer(&addr);
+  assert_eq!(peers.get_protocol(&addr), None);
+  peers.activate(&addr, Peer { address: addr, seen_at: now });
+  assert_eq!(peers.to_greet(now + 2 * node::GREET_DELAY), vec![addr]);
+}
+
+#[test]
+fn rejected_peers_expire() {
+  let mut peers = PeersStore::new();
+  let addr = net::parse_address("10.0.0.1:42000");
+  let now = get_time();
+  let protocol =
+    PeerProtocol { version: PROTOCOL_VERSION, capabilities: 0, height: 0 };
+  peers.activate(&addr, Peer { address: addr, seen_at: now });
+  peers.set_protocol(&addr, protocol);
+  peers.reject(&addr, now);
+  assert!(peers.is_rejected(&addr, now + 1));
+  assert_eq!(peers.get_protocol(&addr), None);
+  assert!(peers.get_all_active().is_empty());
+  assert!(!peers.is_rejected(&addr, now + node::REJECT_TIMEOUT));
+}
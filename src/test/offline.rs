@@ -0,0 +1,68 @@
+use crate::crypto::Account;
+use crate::hvm::{self, parse_code};
+use crate::offline::{self, Batch};
+
+const CODE: &str = "
+  reg Foo { #x2b5ad5c4795c026514f8317c7a215e }
+  ctr {Foo.Pair a b}
+  run { (Done #42) }
+";
+
+fn secret(skey: u8) -> [u8; 32] {
+  let mut bytes = [0; 32];
+  bytes[31] = skey;
+  bytes
+}
+
+#[test]
+fn batches_are_signed_offline() {
+  let statements = parse_code(CODE).unwrap();
+  let batch = Batch::new(&statements);
+  // the batch travels as JSON
+  let json = serde_json::to_string(&batch).unwrap();
+  let batch: Batch = serde_json::from_str(&json).unwrap();
+  assert_eq!(batch.get_statements().unwrap(), statements);
+  assert_eq!(batch.statements[2].code, "run {\n  (Done #42)\n}");
+
+  let signed = batch.sign(&secret(1), false).unwrap();
+  assert!(signed.sign(&secret(2), false).is_err());
+  assert!(signed.sign(&secret(1), true).is_err());
+  let signed = signed.sign(&secret(2), true).unwrap();
+  // signing doesn't change the hashes
+  for (entry, signed) in batch.statements.iter().zip(&signed.statements) {
+    assert_eq!(entry.hash, signed.hash);
+    assert_eq!(entry.code, signed.code);
+  }
+  // and the signatures are kept sorted by signer
+  let mut names: Vec<_> = [1, 2]
+    .iter()
+    .map(|skey| Some(Account::from_private_key(&secret(*skey)).name))
+    .collect();
+  names.sort_by_key(|name| name.map(|name| *name));
+  for (stmt, unsigned) in
+    signed.get_statements().unwrap().iter().zip(&statements)
+  {
+    assert_eq!(&hvm::remove_sign(stmt), unsigned);
+    assert_eq!(offline::signers(stmt), names);
+  }
+}
+
+#[test]
+fn tampered_batches_are_rejected() {
+  let statements = parse_code(CODE).unwrap();
+  let mut batch = Batch::new(&statements);
+  batch.statements.swap(0, 1);
+  batch.statements[0].hash = batch.statements[1].hash.clone();
+  assert!(batch.get_statements().is_err());
+  assert!(batch.sign(&secret(1), false).is_err());
+
+  // the code shown to signers must be the one of the statement
+  let mut batch = Batch::new(&statements);
+  batch.statements[2].code = "run {\n  (Done #0)\n}".to_string();
+  assert!(batch.get_statements().is_err());
+  assert!(batch.sign(&secret(1), false).is_err());
+
+  let mut batch = Batch::new(&statements);
+  batch.version += 1;
+  assert!(batch.get_statements().is_err());
+}
@@ -0,0 +1,279 @@
+use primitive_types::U256;
+use rstest::rstest;
+
+use crate::bits::ProtoSerialize;
+use crate::crypto::Keccakable;
+use crate::hvm::{init_heap, Heap, Loc, RawCell};
+use crate::node::{Block, Body, HashedBlock, StoredBlock};
+
+use crate::persistence::{
+  decode_snapshot, decode_versioned, encode_snapshot, encode_versioned,
+  read_legacy_blocks, state_hash, BlockStorage, KvBlockStorage, KvStore,
+  PersistWorker, FORMAT_VERSION, HEAP_MAGIC, META_MAGIC, SNAPSHOT_MAGIC,
+};
+use crate::test::util::{temp_dir, TempPath};
+use crate::util::bitvec_to_bytes;
+
+#[rstest]
+fn persist_worker_writes_in_order(temp_dir: TempPath) {
+  std::fs::create_dir_all(&temp_dir.path).unwrap();
+  let file = temp_dir.path.join("a.bin");
+  let other = temp_dir.path.join("b.bin");
+  std::fs::write(&other, b"old").unwrap();
+
+  let worker = PersistWorker::spawn();
+  worker.write(file.clone(), b"first".to_vec());
+  worker.write(file.clone(), b"second".to_vec());
+  worker.delete(other.clone());
+  worker.delete(temp_dir.path.join("missing.bin"));
+  worker.flush().unwrap();
+
+  assert_eq!(std::fs::read(&file).unwrap(), b"second");
+  assert!(!other.exists());
+  assert!(!temp_dir.path.join("a.bin.tmp").exists());
+}
+
+#[rstest]
+fn persist_worker_reports_errors(temp_dir: TempPath) {
+  let worker = PersistWorker::spawn();
+  worker.write(temp_dir.path.join("nope").join("a.bin"), vec![1]);
+  let err = worker.flush().unwrap_err();
+  assert!(err.starts_with("Couldn't write"), "{}", err);
+  // errors are reported once
+  worker.flush().unwrap();
+}
+
+#[rstest]
+fn persist_worker_finishes_on_drop(temp_dir: TempPath) {
+  std::fs::create_dir_all(&temp_dir.path).unwrap();
+  let worker = PersistWorker::spawn();
+  for i in 0..100 {
+    worker.write(temp_dir.path.join(format!("{}.bin", i)), vec![i]);
+  }
+  drop(worker);
+  for i in 0..100 {
+    let data = std::fs::read(temp_dir.path.join(format!("{}.bin", i)));
+    assert_eq!(data.unwrap(), [i]);
+  }
+}
+
+#[test]
+fn versioned_file_round_trip() {
+  let sections = [("a", vec![1, 2, 3]), ("b", vec![])];
+  let file = encode_versioned(&HEAP_MAGIC, 42, &sections);
+  let (uuid, decoded) = decode_versioned(&HEAP_MAGIC, &file).unwrap();
+  assert_eq!(uuid, 42);
+  assert_eq!(
+    decoded,
+    [("a".to_string(), vec![1, 2, 3]), ("b".to_string(), vec![])]
+  );
+}
+
+#[test]
+fn versioned_file_rejects_bad_files() {
+  let file = encode_versioned(&HEAP_MAGIC, 42, &[("a", vec![1, 2, 3])]);
+  let error =
+    |data: &[u8]| decode_versioned(&HEAP_MAGIC, data).unwrap_err().to_string();
+  // partial writes
+  assert!(error(&file[..file.len() - 1]).starts_with("Partially written"));
+  assert!(error(&file[..10]).starts_with("Truncated"));
+  // corrupted data
+  let mut corrupted = file.clone();
+  *corrupted.last_mut().unwrap() ^= 1;
+  assert_eq!(error(&corrupted), "Corrupted section 'a'.");
+  // wrong kind of file
+  assert!(decode_versioned(&META_MAGIC, &file).is_err());
+  // newer layouts
+  let mut newer = file;
+  newer[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+  assert!(error(&newer).contains("is newer than the supported"));
+}
+
+#[rstest]
+fn kv_store_reopens(temp_dir: TempPath) {
+  let path = temp_dir.path.join("db.kv");
+  let mut db = KvStore::open(&path).unwrap();
+  db.put(b"a", b"1").unwrap();
+  db.put(b"b", b"2").unwrap();
+  db.put(b"a", b"3").unwrap();
+  db.delete(b"b").unwrap();
+  assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+  assert_eq!(db.get(b"b").unwrap(), None);
+  drop(db);
+
+  let mut db = KvStore::open(&path).unwrap();
+  assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+  assert!(!db.contains(b"b"));
+  assert_eq!(db.keys().count(), 1);
+}
+
+#[rstest]
+fn kv_store_drops_partial_records(temp_dir: TempPath) {
+  let path = temp_dir.path.join("db.kv");
+  let mut db = KvStore::open(&path).unwrap();
+  db.put(b"a", b"1").unwrap();
+  db.put(b"b", b"2").unwrap();
+  drop(db);
+  let data = std::fs::read(&path).unwrap();
+  std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+  let mut db = KvStore::open(&path).unwrap();
+  assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+  assert_eq!(db.get(b"b").unwrap(), None);
+  // new records go after the last complete one
+  db.put(b"c", b"3").unwrap();
+  drop(db);
+  let mut db = KvStore::open(&path).unwrap();
+  assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+}
+
+fn stored_block(prev: U256, height: u128, meta: u128) -> StoredBlock {
+  let block = Block::new(prev, height, meta, Body { data: vec![0] });
+  StoredBlock {
+    block: block.hashed(),
+    height,
+    work: U256::from(height * 10),
+    target: U256::from(7),
+  }
+}
+
+fn hash(stored: &StoredBlock) -> U256 {
+  stored.block.get_hash().into()
+}
+
+#[rstest]
+fn kv_block_storage_chain(temp_dir: TempPath) {
+  let path = temp_dir.path.join("blocks.kv");
+  let mut storage = KvBlockStorage::open(&path).unwrap();
+  assert!(storage.read_chain().unwrap().is_empty());
+  let b1 = stored_block(U256::zero(), 1, 0);
+  let b2 = stored_block(hash(&b1), 2, 0);
+  let b3 = stored_block(hash(&b2), 3, 0);
+  for block in [&b1, &b2, &b3] {
+    storage.write_block(block).unwrap();
+    storage.write_chain(block.height, &hash(block)).unwrap();
+  }
+  // a reorg to a shorter chain
+  let c2 = stored_block(hash(&b1), 2, 1);
+  storage.write_block(&c2).unwrap();
+  storage.write_chain(2, &hash(&c2)).unwrap();
+  drop(storage);
+
+  let mut storage = KvBlockStorage::open(&path).unwrap();
+  assert_eq!(storage.read_chain().unwrap(), [hash(&b1), hash(&c2)]);
+  // blocks of every branch are kept
+  let blocks: Vec<StoredBlock> = storage.read_blocks().unwrap();
+  let heights: Vec<u128> = blocks.iter().map(|b| b.height).collect();
+  assert_eq!(heights, [1, 2, 2, 3]);
+  assert!(blocks.contains(&b2) && blocks.contains(&c2));
+
+  storage.delete_block(&hash(&b2)).unwrap();
+  storage.commit().unwrap();
+  let blocks: Vec<StoredBlock> = storage.read_blocks().unwrap();
+  assert!(!blocks.contains(&b2) && blocks.len() == 3);
+}
+
+#[rstest]
+fn kv_store_compacts(temp_dir: TempPath) {
+  let path = temp_dir.path.join("db.kv");
+  let mut db = KvStore::open(&path).unwrap();
+  let value = vec![7; 1024];
+  for i in 0..2048_u32 {
+    db.put(&(i % 4).to_le_bytes(), &value).unwrap();
+  }
+  db.delete(&0_u32.to_le_bytes()).unwrap();
+  let size = std::fs::metadata(&path).unwrap().len();
+  assert!(db.dead_bytes() > size / 2);
+  drop(db);
+
+  // opening it drops the overwritten and deleted records
+  let mut db = KvStore::open(&path).unwrap();
+  assert_eq!(db.dead_bytes(), 0);
+  assert!(std::fs::metadata(&path).unwrap().len() < 4 * 1100);
+  assert_eq!(db.keys().count(), 3);
+  assert_eq!(db.get(&3_u32.to_le_bytes()).unwrap(), Some(value.clone()));
+  assert_eq!(db.get(&0_u32.to_le_bytes()).unwrap(), None);
+  // and keeps working on the new file
+  db.put(b"new", b"1").unwrap();
+  drop(db);
+  let mut db = KvStore::open(&path).unwrap();
+  assert_eq!(db.get(b"new").unwrap(), Some(b"1".to_vec()));
+  assert_eq!(db.get(&1_u32.to_le_bytes()).unwrap(), Some(value));
+}
+
+fn heap_with_nodes(locs: &[u64]) -> Heap {
+  let mut heap = init_heap();
+  heap.tick = 2;
+  for loc in locs {
+    let cell = RawCell::new(*loc as u128 + 100).unwrap();
+    heap.memo.nodes.insert(Loc::new(*loc).unwrap(), cell);
+  }
+  heap
+}
+
+#[rstest]
+fn persist_worker_encodes_shared_heaps(temp_dir: TempPath) {
+  std::fs::create_dir_all(&temp_dir.path).unwrap();
+  let mut heap = std::sync::Arc::new(heap_with_nodes(&[1, 2]));
+  let path = Heap::file_path(heap.uuid, &temp_dir.path);
+  let worker = PersistWorker::spawn();
+  let saved = heap.clone();
+  worker.write_with(path, move || saved.encode());
+  // changes after queueing it are not saved
+  let cell = RawCell::new(42).unwrap();
+  std::sync::Arc::make_mut(&mut heap)
+    .memo
+    .nodes
+    .insert(Loc::new(3).unwrap(), cell);
+  worker.flush().unwrap();
+  let loaded = Heap::deserialize(heap.uuid, &temp_dir.path).unwrap();
+  assert_eq!(loaded.memo.nodes, heap_with_nodes(&[1, 2]).memo.nodes);
+}
+
+#[test]
+fn snapshot_round_trip() {
+  let heap = heap_with_nodes(&[1, 2, 3]);
+  let b1 = stored_block(U256::zero(), 1, 0);
+  let b2 = stored_block(hash(&b1), 2, 0);
+  let (file, hash) = encode_snapshot(&heap, &[b1.clone(), b2.clone()]).unwrap();
+  let snapshot = decode_snapshot::<HashedBlock>(&file).unwrap();
+  assert_eq!(snapshot.state_hash, hash);
+  assert_eq!(snapshot.heap.uuid, heap.uuid);
+  assert_eq!(snapshot.heap.tick, 2);
+  assert_eq!(snapshot.heap.memo.nodes, heap.memo.nodes);
+  assert_eq!(snapshot.blocks, [b1, b2]);
+  // the state hash only depends on the contents of the heap
+  let same = heap_with_nodes(&[3, 1, 2]);
+  assert_eq!(state_hash(&same).unwrap(), hash);
+  assert_ne!(state_hash(&heap_with_nodes(&[1, 2])).unwrap(), hash);
+}
+
+#[test]
+fn snapshot_rejects_wrong_state_hash() {
+  let (file, _) =
+    encode_snapshot::<HashedBlock>(&heap_with_nodes(&[1]), &[]).unwrap();
+  let (uuid, mut sections) = decode_versioned(&SNAPSHOT_MAGIC, &file).unwrap();
+  let (_, memo) = sections.iter_mut().find(|(name, _)| name == "memo").unwrap();
+  memo[0] ^= 1;
+  let sections: Vec<(&str, Vec<u8>)> =
+    sections.iter().map(|(name, data)| (name.as_str(), data.clone())).collect();
+  let file = encode_versioned(&SNAPSHOT_MAGIC, uuid, &sections);
+  let error = decode_snapshot::<HashedBlock>(&file).err().unwrap().to_string();
+  assert_eq!(error, "State doesn't match its hash.");
+}
+
+#[rstest]
+fn legacy_blocks_are_read_in_order(temp_dir: TempPath) {
+  std::fs::create_dir_all(&temp_dir.path).unwrap();
+  for height in [2, 1, 16] {
+    let block = stored_block(U256::zero(), height, 0).block;
+    let data = bitvec_to_bytes(&block.proto_serialized());
+    let name = format!("{:0>16x}.kindelia_block.bin", height);
+    std::fs::write(temp_dir.path.join(name), data).unwrap();
+  }
+  std::fs::write(temp_dir.path.join("notes.txt"), b"hi").unwrap();
+  let blocks = read_legacy_blocks::<HashedBlock>(&temp_dir.path);
+  let heights: Vec<u128> = blocks.iter().map(|(height, ..)| *height).collect();
+  assert_eq!(heights, [1, 2, 16]);
+  assert!(blocks.iter().all(|(_, _, block)| block.is_some()));
+}
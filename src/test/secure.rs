@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+use crate::net::{self, Address, PacketComm, ProtoComm};
+use crate::node::Message;
+use crate::secure::{self, SecureComm, PACKET_DATA, PACKET_INIT, PACKET_RESP};
+
+use super::util::temp_dir;
+
+/// In-memory packets, where the test can see and inject everything.
+type Wire = Arc<Mutex<HashMap<Address, Vec<(Address, Vec<u8>)>>>>;
+
+struct Pipe {
+  addr: Address,
+  wire: Wire,
+}
+
+impl PacketComm for Pipe {
+  type Address = Address;
+  fn packet_send(&mut self, address: Address, bytes: &[u8]) {
+    let mut wire = self.wire.lock().unwrap();
+    wire.entry(address).or_default().push((self.addr, bytes.to_vec()));
+  }
+  fn packet_recv(&mut self) -> Vec<(Address, Vec<u8>)> {
+    self.wire.lock().unwrap().remove(&self.addr).unwrap_or_default()
+  }
+  fn packet_addr(&self) -> Address {
+    self.addr
+  }
+}
+
+fn pipe(wire: &Wire, port: u16) -> SecureComm<Pipe> {
+  let addr = Address::IPv4 { val0: 10, val1: 0, val2: 0, val3: 1, port };
+  let key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+  SecureComm::new(Pipe { addr, wire: wire.clone() }, key)
+}
+
+fn ask(n: u64) -> Message<Address> {
+  Message::GiveMeThatBlock { magic: 0xCAFE, bhash: n.into() }
+}
+
+fn asked(received: &[(Address, Message<Address>)]) -> Vec<u64> {
+  let get = |msg: &Message<Address>| match msg {
+    Message::GiveMeThatBlock { bhash, .. } => bhash.low_u64(),
+    msg => panic!("Unexpected message {:?}", msg),
+  };
+  received.iter().map(|(_, msg)| get(msg)).collect()
+}
+
+/// Delivers the packets on the wire until it is quiet.
+fn exchange(
+  a: &mut SecureComm<Pipe>,
+  b: &mut SecureComm<Pipe>,
+) -> (Vec<u64>, Vec<u64>) {
+  let (mut got_a, mut got_b) = (vec![], vec![]);
+  for _ in 0..4 {
+    got_a.extend(asked(&a.proto_recv()));
+    got_b.extend(asked(&b.proto_recv()));
+  }
+  (got_a, got_b)
+}
+
+#[test]
+fn peers_handshake_and_exchange_messages() {
+  let wire = Wire::default();
+  let (mut a, mut b) = (pipe(&wire, 1), pipe(&wire, 2));
+  let (addr_a, addr_b) = (a.get_addr(), b.get_addr());
+
+  // messages sent before the handshake are queued, and delivered after it
+  a.proto_send(vec![addr_b], &ask(1));
+  a.proto_send(vec![addr_b], &ask(2));
+  assert_eq!(exchange(&mut a, &mut b), (vec![], vec![1, 2]));
+  b.proto_send(vec![addr_a], &ask(3));
+  assert_eq!(exchange(&mut a, &mut b), (vec![3], vec![]));
+
+  // both sides know who they are talking to
+  assert_eq!(a.get_peer_key(&addr_b), Some(b.public_key()));
+  assert_eq!(b.get_peer_key(&addr_a), Some(a.public_key()));
+
+  // nothing travels in plain text
+  a.proto_send(vec![addr_b], &ask(4));
+  let packets = wire.lock().unwrap().get(&addr_b).cloned().unwrap();
+  assert_eq!(packets.len(), 1);
+  assert_eq!(packets[0].1[0], PACKET_DATA);
+  assert_eq!(asked(&b.proto_recv()), vec![4]);
+}
+
+#[test]
+fn tampered_and_replayed_packets_are_dropped() {
+  let wire = Wire::default();
+  let (mut a, mut b) = (pipe(&wire, 1), pipe(&wire, 2));
+  let addr_b = b.get_addr();
+  a.proto_send(vec![addr_b], &ask(1));
+  exchange(&mut a, &mut b);
+
+  a.proto_send(vec![addr_b], &ask(2));
+  let packet = wire.lock().unwrap().get(&addr_b).cloned().unwrap().remove(0);
+  let inject = |packet: &(Address, Vec<u8>)| {
+    wire.lock().unwrap().entry(addr_b).or_default().push(packet.clone());
+  };
+
+  // a flipped bit on the payload or on the header
+  wire.lock().unwrap().clear();
+  for i in [packet.1.len() - 1, 4] {
+    let mut tampered = packet.clone();
+    tampered.1[i] ^= 1;
+    inject(&tampered);
+  }
+  assert_eq!(asked(&b.proto_recv()), Vec::<u64>::new());
+
+  // the original goes, but only once
+  inject(&packet);
+  inject(&packet);
+  assert_eq!(asked(&b.proto_recv()), vec![2]);
+  inject(&packet);
+  assert_eq!(asked(&b.proto_recv()), Vec::<u64>::new());
+
+  // out of order packets are still accepted
+  a.proto_send(vec![addr_b], &ask(3));
+  a.proto_send(vec![addr_b], &ask(4));
+  wire.lock().unwrap().get_mut(&addr_b).unwrap().reverse();
+  assert_eq!(asked(&b.proto_recv()), vec![4, 3]);
+}
+
+#[test]
+fn pinned_peers_must_own_their_key() {
+  let wire = Wire::default();
+  let (mut a, mut b) = (pipe(&wire, 1), pipe(&wire, 2));
+  let addr_b = b.get_addr();
+  let other = SecretKey::new(&mut secp256k1::rand::thread_rng());
+  a.pin(addr_b, PublicKey::from_secret_key(&Secp256k1::new(), &other));
+  a.proto_send(vec![addr_b], &ask(1));
+  assert_eq!(exchange(&mut a, &mut b), (vec![], vec![]));
+  assert_eq!(a.get_peer_key(&addr_b), None);
+  assert_eq!(b.get_peer_key(&a.get_addr()), None);
+}
+
+#[test]
+fn simultaneous_handshakes_agree() {
+  let wire = Wire::default();
+  let (mut a, mut b) = (pipe(&wire, 1), pipe(&wire, 2));
+  let (addr_a, addr_b) = (a.get_addr(), b.get_addr());
+  a.proto_send(vec![addr_b], &ask(1));
+  b.proto_send(vec![addr_a], &ask(2));
+  assert_eq!(exchange(&mut a, &mut b), (vec![2], vec![1]));
+  a.proto_send(vec![addr_b], &ask(3));
+  b.proto_send(vec![addr_a], &ask(4));
+  assert_eq!(exchange(&mut a, &mut b), (vec![4], vec![3]));
+}
+
+#[test]
+fn forged_inits_dont_abort_handshakes() {
+  let wire = Wire::default();
+  let (mut a, mut b) = (pipe(&wire, 1), pipe(&wire, 2));
+  let (addr_a, addr_b) = (a.get_addr(), b.get_addr());
+  a.proto_send(vec![addr_b], &ask(1));
+  // someone else sends an INIT to `a` from the address of `b`, with an
+  // ephemeral key that wins the tie-break
+  let sent = wire.lock().unwrap()[&addr_b][0].1.clone();
+  let forged = loop {
+    let secret = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    let public = PublicKey::from_secret_key(&Secp256k1::new(), &secret);
+    if public.serialize()[..] > sent[1..1 + 33] {
+      break public.serialize();
+    }
+  };
+  let mut packet = vec![PACKET_INIT];
+  packet.extend_from_slice(&forged);
+  packet.extend_from_slice(&forged);
+  wire.lock().unwrap().entry(addr_a).or_default().push((addr_b, packet));
+  // `a` answers it, but still finishes its handshake with `b`
+  a.proto_recv();
+  a.proto_send(vec![addr_b], &ask(2));
+  let (_, got_b) = exchange(&mut a, &mut b);
+  assert!(got_b.contains(&2));
+  assert_eq!(a.get_peer_key(&addr_b), Some(b.public_key()));
+  assert_eq!(b.get_peer_key(&addr_a), Some(a.public_key()));
+}
+
+#[test]
+fn handshakes_are_rate_limited() {
+  let wire = Wire::default();
+  let mut b = pipe(&wire, 2);
+  let addr_b = b.get_addr();
+  let inject = |port: u16| {
+    let from = Address::IPv4 { val0: 10, val1: 0, val2: 1, val3: 1, port };
+    let key = || {
+      let secret = SecretKey::new(&mut secp256k1::rand::thread_rng());
+      PublicKey::from_secret_key(&Secp256k1::new(), &secret).serialize()
+    };
+    let mut packet = vec![PACKET_INIT];
+    packet.extend_from_slice(&key());
+    packet.extend_from_slice(&key());
+    wire.lock().unwrap().entry(addr_b).or_default().push((from, packet));
+  };
+  let answered = || {
+    let mut wire = wire.lock().unwrap();
+    let packets = wire.drain().flat_map(|(_, packets)| packets);
+    packets.filter(|(_, packet)| packet[0] == PACKET_RESP).count()
+  };
+
+  // an address is answered once, even if it sends INITs again right away
+  inject(1);
+  inject(1);
+  b.proto_recv();
+  assert_eq!(answered(), 1);
+
+  // a flood from many addresses only gets some answers per second
+  for port in 2..1000 {
+    inject(port);
+  }
+  b.proto_recv();
+  assert!(answered() <= secure::MAX_INITS_PER_SECOND as usize);
+}
+
+#[test]
+fn secure_comm_over_udp() {
+  let socket = |_| {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    SecureComm::new(socket, key)
+  };
+  let (mut a, mut b) = (socket(0), socket(1));
+  let addr_b = b.get_addr();
+  a.proto_send(vec![addr_b], &ask(1));
+  let mut received = vec![];
+  for _ in 0..100 {
+    a.proto_recv();
+    received.extend(b.proto_recv());
+    if !received.is_empty() {
+      break;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  assert_eq!(received.len(), 1);
+  assert_eq!(received[0].0, a.get_addr());
+}
+
+#[test]
+fn node_key_is_kept() {
+  let dir = temp_dir();
+  let path = dir.path.join("node.key");
+  let key = secure::load_or_create_key(&path).unwrap();
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+  }
+  assert_eq!(secure::load_or_create_key(&path).unwrap(), key);
+  std::fs::write(&path, "not a key").unwrap();
+  assert!(secure::load_or_create_key(&path).is_err());
+
+  let parse = |code| secure::parse_peer(code, net::parse_address);
+  let (addr, pinned) = parse("127.0.0.1:42000").unwrap();
+  assert_eq!((addr, pinned), (net::parse_address("127.0.0.1:42000"), None));
+  let public = PublicKey::from_secret_key(&Secp256k1::new(), &key);
+  let code = format!("{}@127.0.0.1", hex::encode(public.serialize()));
+  assert_eq!(parse(&code).unwrap().1, Some(public));
+  assert!(parse("beef@127.0.0.1").is_err());
+}
@@ -5,7 +5,7 @@ use crate::{
   common::{Name, U120},
   hvm::{
     init_u128_map, init_name_map, init_u120_map, init_loc_map, Arits, CompFunc, CompRule, Func, Funcs, Hashs,
-    Heap, Nodes, Oper, Ownrs, Rollback, Rule, Runtime, Loc, RawCell,
+    Heap, Leafs, Msigs, Multisig, Nodes, Noncs, Oper, Owner, Ownrs, Rollback, Rule, Runtime, Loc, RawCell,
     Statement, Store, Term, Var, Indxs,
   },
   util::{U128Map, NameMap, U120Map, LocMap},
@@ -121,19 +121,30 @@ pub fn sign() -> impl Strategy<Value = crypto::Signature> {
   (vec(any::<u8>(), 65)).prop_map(|s| crypto::Signature(s.try_into().unwrap()))
 }
 
+// generate registry owners
+pub fn owner() -> impl Strategy<Value = Owner> {
+  prop_oneof![
+    u120().prop_map(Owner::Subj),
+    (0_u64..8, vec(u120(), 0..8))
+      .prop_map(|(thld, keys)| Owner::Msig(Multisig { thld, keys })),
+  ]
+}
+
 // generate statements
 pub fn statement() -> impl Strategy<Value = Statement> {
   prop_oneof![
-    (small_name(), vec(name(), 0..10), func(), term(), option::of(sign()))
+    (small_name(), vec(name(), 0..10), func(), term(), vec(sign(), 0..3))
       .prop_map(|(name, args, func, init, sign)| {
         Statement::Fun { name, args, func, init: Some(init), sign }
       }),
-    (small_name(), vec(name(), 0..10), option::of(sign()))
+    (small_name(), vec(name(), 0..10), vec(sign(), 0..3))
       .prop_map(|(name, args, sign)| { Statement::Ctr { name, args, sign } }),
-    (term(), option::of(sign()))
-      .prop_map(|(t, s)| { Statement::Run { expr: t, sign: s } }),
-    (name(), u120(), option::of(sign()))
+    (term(), option::of(any::<u64>()), option::of(any::<u64>()), vec(sign(), 0..3))
+      .prop_map(|(t, nonce, until, s)| { Statement::Run { expr: t, nonce, until, sign: s } }),
+    (name(), owner(), vec(sign(), 0..3))
       .prop_map(|(name, ownr, sign)| { Statement::Reg { name, ownr, sign } }),
+    (name(), option::of(owner()), any::<u64>(), option::of(any::<u64>()), vec(sign(), 0..3))
+      .prop_map(|(name, ownr, nonce, until, sign)| { Statement::Own { name, ownr, nonce, until, sign } }),
   ]
 }
 pub fn hash() -> impl Strategy<Value = crypto::Hash> {
@@ -248,6 +259,8 @@ pub fn heap() -> impl Strategy<Value = Heap> {
     any::<u128>(),
     any::<u128>(),
     any::<u128>(),
+    any::<u128>(),
+    any::<u128>(),
   );
 
   (
@@ -266,7 +279,7 @@ pub fn heap() -> impl Strategy<Value = Heap> {
       |(
         (mcap, tick, funs, dups),
         (mana, next, size, rwts),
-        (uuid, meta, hax1, hax0, time),
+        (uuid, meta, hax1, hax0, time, rot0, rot1),
         memo,
         disk,
         arit,
@@ -282,6 +295,9 @@ pub fn heap() -> impl Strategy<Value = Heap> {
         hash,
         indx,
         file: Funcs { funcs: init_name_map() }, // TODO, fix?
+        msig: Msigs { msigs: init_u120_map() },
+        nonc: Noncs { noncs: init_u120_map() },
+        leaf: Leafs { leafs: init_u120_map() },
         uuid,
         memo,
         tick,
@@ -295,6 +311,8 @@ pub fn heap() -> impl Strategy<Value = Heap> {
         hax0,
         hax1,
         time,
+        rot0,
+        rot1,
       },
     )
 }
@@ -340,6 +358,11 @@ pub fn message() -> impl Strategy<Value = Message<Address>> {
     ),
     (u256(), any::<u32>()).prop_map(|(h, m)| Message::GiveMeThatBlock { bhash: h, magic: m }),
     (transaction(), any::<u32>())
-      .prop_map(|(t, m)| Message::PleaseMineThisTransaction { tx: t, magic: m })
+      .prop_map(|(t, m)| Message::PleaseMineThisTransaction { tx: t, magic: m }),
+    (any::<u32>(), any::<u32>(), any::<u64>(), any::<u128>(), u256(), any::<u64>()).prop_map(
+      |(m, v, c, h, g, x)| Message::Hello { magic: m, version: v, capabilities: c, height: h, genesis: g, challenge: x },
+    ),
+    (address(), any::<u32>(), any::<u64>())
+      .prop_map(|(a, m, x)| Message::Observed { address: a, magic: m, challenge: x }),
   ]
 }
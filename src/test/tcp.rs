@@ -0,0 +1,152 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use secp256k1::SecretKey;
+
+use crate::net::{self, Address, PacketComm, ProtoComm};
+use crate::node::{Message, MAX_UDP_SIZE_SLOW};
+use crate::secure::SecureComm;
+use crate::tcp::{TcpComm, MAX_DIALING, MAX_FRAME_SIZE, MAX_TCP_MESSAGE_SIZE};
+
+fn tcp_comm() -> TcpComm {
+  TcpComm::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap()
+}
+
+fn ask(n: u64) -> Message<Address> {
+  Message::GiveMeThatBlock { magic: 0xCAFE, bhash: n.into() }
+}
+
+/// Calls `recv` until something arrives, running `other` meanwhile.
+fn wait<T>(
+  mut recv: impl FnMut() -> Vec<T>,
+  mut other: impl FnMut(),
+) -> Vec<T> {
+  for _ in 0..200 {
+    let received = recv();
+    if !received.is_empty() {
+      return received;
+    }
+    other();
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  vec![]
+}
+
+#[test]
+fn tcp_peers_exchange_messages() {
+  let (mut a, mut b) = (tcp_comm(), tcp_comm());
+  let (addr_a, addr_b) = (a.get_addr(), b.get_addr());
+  a.proto_send(vec![addr_b], &ask(1));
+  a.proto_send(vec![addr_b], &ask(2));
+  let mut got = wait(|| b.proto_recv(), || drop(a.proto_recv()));
+  got.extend(b.proto_recv());
+  // the sender is known by its listening address
+  let got: Vec<_> =
+    got.iter().map(|(addr, msg)| (*addr, format!("{:?}", msg))).collect();
+  let expected = [1, 2].map(|n| (addr_a, format!("{:?}", ask(n))));
+  assert_eq!(got, expected);
+
+  // the answer goes back on the same connection
+  b.proto_send(vec![addr_a], &ask(3));
+  let got = wait(|| a.proto_recv(), || ());
+  assert_eq!(got.len(), 1);
+  assert_eq!(got[0].0, addr_b);
+  assert_eq!((a.connections(), b.connections()), (1, 1));
+}
+
+#[test]
+fn tcp_carries_messages_bigger_than_datagrams() {
+  let (mut a, mut b) = (tcp_comm(), tcp_comm());
+  assert!(a.max_message_size() > MAX_UDP_SIZE_SLOW);
+  let big: Vec<u8> = (0..MAX_TCP_MESSAGE_SIZE).map(|i| i as u8).collect();
+  a.packet_send(b.get_addr(), &big);
+  let got = wait(|| b.packet_recv(), || drop(a.packet_recv()));
+  assert_eq!(got.len(), 1);
+  assert!(got[0].1 == big);
+}
+
+#[test]
+fn tcp_frames_are_reassembled() {
+  let mut comm = tcp_comm();
+  let mut stream = TcpStream::connect(comm.get_addr().to_string()).unwrap();
+  let frame =
+    |bytes: &[u8]| [&(bytes.len() as u32).to_be_bytes(), bytes].concat();
+  let bytes =
+    [&4242_u16.to_be_bytes()[..], &frame(b"hello"), &frame(b"")].concat();
+  // byte by byte
+  for byte in bytes {
+    stream.write_all(&[byte]).unwrap();
+    stream.flush().unwrap();
+    comm.packet_recv();
+  }
+  stream.write_all(&frame(b"world")).unwrap();
+  let got = wait(|| comm.packet_recv(), || ());
+  assert_eq!(got.len(), 1);
+  assert_eq!(got[0].0, net::parse_address("127.0.0.1:4242"));
+  assert_eq!(got[0].1, b"world");
+
+  // too big frames close the connection
+  let len = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+  stream.write_all(&len).unwrap();
+  stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+  for _ in 0..100 {
+    comm.packet_recv();
+    if comm.connections() == 0 {
+      break;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  assert_eq!(comm.connections(), 0);
+  assert_eq!(stream.read(&mut [0; 8]).unwrap_or(0), 0);
+}
+
+#[test]
+fn unreachable_tcp_peers_are_backed_off() {
+  let mut comm = tcp_comm();
+  let closed = tcp_comm().get_addr(); // dropped, so nobody listens there
+  comm.proto_send(vec![closed], &ask(1));
+  for _ in 0..500 {
+    comm.proto_recv();
+    if comm.is_backing_off(&closed) {
+      break;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  assert!(comm.is_backing_off(&closed));
+  assert_eq!(comm.connections(), 0);
+}
+
+#[test]
+fn tcp_dials_are_limited() {
+  let mut comm = tcp_comm();
+  for port in 1..100 {
+    let addr = net::parse_address(&format!("127.0.0.1:{}", port));
+    comm.proto_send(vec![addr], &ask(1));
+  }
+  assert_eq!(comm.dials(), MAX_DIALING);
+  for _ in 0..500 {
+    comm.proto_recv();
+    if comm.dials() == 0 {
+      break;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  assert_eq!(comm.dials(), 0);
+}
+
+#[test]
+fn secure_comm_over_tcp() {
+  let secure = |comm| {
+    let key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    SecureComm::new(comm, key)
+  };
+  let (mut a, mut b) = (secure(tcp_comm()), secure(tcp_comm()));
+  let (addr_a, addr_b) = (a.get_addr(), b.get_addr());
+  assert!(a.max_message_size() > MAX_UDP_SIZE_SLOW);
+  a.proto_send(vec![addr_b], &ask(1));
+  let got = wait(|| b.proto_recv(), || drop(a.proto_recv()));
+  assert_eq!(got.len(), 1);
+  assert_eq!(got[0].0, addr_a);
+  assert_eq!(b.get_peer_key(&addr_a), Some(a.public_key()));
+}
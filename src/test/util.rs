@@ -256,7 +256,7 @@ where
     name: "Done".try_into().unwrap(),
     args: [term.clone()].to_vec(),
   };
-  let stmt = Statement::Run { expr: term, sign: None };
+  let stmt = Statement::Run { expr: term, nonce: None, until: None, sign: vec![] };
   let result = rt.run_statement(&stmt, false, true, None).unwrap();
 
   if let StatementInfo::Run { done_term, .. } = result {
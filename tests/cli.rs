@@ -1,4 +1,5 @@
 mod cli {
+  use primitive_types::U256;
   use rstest::rstest;
   use std::convert::TryInto;
   use std::env::temp_dir;
@@ -95,6 +96,138 @@ mod cli {
     assert_eq!(results, expected_results)
   }
 
+  #[test]
+  fn test_imports() {
+    let dir = temp_dir().join(format!("crate.{:x}", fastrand::u128(..)));
+    std::fs::create_dir_all(&dir).unwrap();
+    let block_2 = std::env::current_dir().unwrap().join("example/block_2.kdl");
+    let main = dir.join("main.kdl");
+    let code = format!(
+      "import \"{}\"\nimport \"twice.kdl\"\nrun {{ ask n = (Call 'Counter' {{Get}}); (Done (Twice n)) }}",
+      block_2.display()
+    );
+    std::fs::write(&main, code).unwrap();
+    std::fs::write(
+      dir.join("twice.kdl"),
+      format!(
+        "import \"{}\"\nfun (Twice x) {{ (Twice x) = (* x #2) }}",
+        block_2.display()
+      ),
+    )
+    .unwrap();
+    let output = kindelia!().args(["test", main.to_str().unwrap()]).output();
+    let output = get_stdout(&output.unwrap());
+    assert_eq!(get_runs_result(&output), ["#3", "#6"]);
+  }
+
+  #[test]
+  fn publish_skips_deployed() {
+    let file = temp_dir().join(format!("crate.{:x}.kdl", fastrand::u128(..)));
+    let code = format!("{}\nctr {{T3 a b c}}\nrun {{ (Done #1) }}", FUN_CODE);
+    std::fs::write(&file, code).unwrap();
+    let run = hvm::parse_code("run { (Done #1) }").unwrap().pop().unwrap();
+
+    let server = httpmock::MockServer::start();
+    server.mock(|when, then| {
+      when.method(httpmock::Method::GET).path("/functions/Test");
+      then.status(200).json_body_obj(&fun_response_1().0);
+    });
+    server.mock(|when, then| {
+      when.method(httpmock::Method::GET).path("/constructor/T3");
+      then.status(200).json_body_obj(&ctr_response_1());
+    });
+    let publish = server.mock(|when, then| {
+      when
+        .method(httpmock::Method::POST)
+        .path("/publish")
+        .json_body_obj(&vec![api::HexStatement::from(run)]);
+      then.status(200).json_body_obj(&vec![Ok::<(), ()>(())]);
+    });
+    let mock_url = format!("http://127.0.0.1:{}/", server.port());
+
+    let output = kindelia!()
+      .args(["--api", &mock_url, "publish", file.to_str().unwrap()])
+      .output()
+      .unwrap();
+    publish.assert();
+    let err = get_stderr(&output);
+    assert!(err.contains("Skipping 'Test': already deployed."), "{}", err);
+    assert!(err.contains("Skipping 'T3': already deployed."), "{}", err);
+    assert_eq!(
+      get_stdout(&output),
+      "Transaction #0: PUBLISHED (tx added to mempool)"
+    );
+  }
+
+  #[test]
+  fn abi_json_and_rust() {
+    let dir = temp_dir().join(format!("crate.{:x}", fastrand::u128(..)));
+    std::fs::create_dir_all(&dir).unwrap();
+    let code = "// @contract\n// @message {Inc} increments\nfun (Counter m) {\n  (Counter {Inc}) = (Done #0)\n}";
+    let file = dir.join("counter.kdl");
+    std::fs::write(&file, code).unwrap();
+
+    let output = kindelia!().args(["abi", file.to_str().unwrap()]).output();
+    let output = get_stdout(&output.unwrap());
+    let abi: kindelia::abi::Abi = serde_json::from_str(&output).unwrap();
+    assert_eq!(abi.contracts[0].name, "Counter");
+    assert_eq!(
+      abi.contracts[0].messages[0].docs.as_deref(),
+      Some("increments")
+    );
+
+    let json = dir.join("counter.abi.json");
+    std::fs::write(&json, &output).unwrap();
+    let output =
+      kindelia!().args(["abi", json.to_str().unwrap(), "--rust"]).output();
+    let output = get_stdout(&output.unwrap());
+    assert!(output.contains("pub mod counter {"), "{}", output);
+    assert!(output.contains("pub fn inc() -> Statement {"), "{}", output);
+  }
+
+  #[test]
+  fn deploy_dry_run() {
+    let file = temp_dir().join(format!("crate.{:x}.kdl", fastrand::u128(..)));
+    let code = "run { (Done {Pair #1 #2}) }\nctr {Pair a b}";
+    std::fs::write(&file, code).unwrap();
+    let stmts = kindelia::deploy::order(hvm::parse_code(code).unwrap());
+    let infos = vec![
+      hvm::StatementInfo::Ctr {
+        name: "Pair".try_into().unwrap(),
+        args: vec!["a".try_into().unwrap(), "b".try_into().unwrap()],
+      },
+      hvm::StatementInfo::Run {
+        done_term: hvm::Term::num(common::U120::ZERO),
+        used_mana: 10,
+        size_diff: 3,
+        end_size: 3,
+      },
+    ];
+
+    // nothing is deployed, so `/constructor/Pair` answers 404
+    let server = httpmock::MockServer::start();
+    let run = server.mock(|when, then| {
+      when.method(httpmock::Method::POST).path("/run");
+      then.status(200).json_body_obj(&infos);
+    });
+    let mock_url = format!("http://127.0.0.1:{}/", server.port());
+
+    let output = kindelia!()
+      .args(["--api", &mock_url, "deploy", "--dry-run", file.to_str().unwrap()])
+      .output()
+      .unwrap();
+    run.assert();
+    let bytes: Vec<usize> = stmts
+      .iter()
+      .map(|stmt| kindelia::deploy::Cost::of(stmt, None).bytes)
+      .collect();
+    let expected = format!(
+      "Batch #0: 1 statements, {} bytes, 0 mana, 0 size\nBatch #1: 1 statements, {} bytes, 10 mana, 3 size",
+      bytes[0], bytes[1]
+    );
+    assert_eq!(get_stdout(&output), expected, "{}", get_stderr(&output));
+  }
+
   #[rstest]
   #[case("example/block_1.kdl")]
   #[case("example/block_2.kdl")]
@@ -113,6 +246,33 @@ mod cli {
     assert_eq!(str_0, str_1);
   }
 
+  #[rstest]
+  #[case("example/block_1.kdl")]
+  #[case("example/block_5.kdl")]
+  #[case("kdl/list.kdl")]
+  #[case("genesis.kdl")]
+  fn fmt_check(#[case] file: &str) {
+    let temp_dir = temp_dir();
+    let temp_file =
+      temp_dir.join(format!("crate.{:x}.kdl", fastrand::u128(..)));
+
+    // formats the file and saves it in a temp file
+    let output = kindelia!().args(["fmt", file]).output().unwrap();
+    assert!(output.status.success());
+    std::fs::write(&temp_file, &output.stdout).unwrap();
+
+    // the formatted output passes the check
+    kindelia!()
+      .args(["fmt", "--check", temp_file.to_str().unwrap()])
+      .assert()
+      .success();
+  }
+
+  #[test]
+  fn fmt_check_unformatted() {
+    kindelia!().args(["fmt", "--check", "kdl/list.kdl"]).assert().failure();
+  }
+
   #[rstest]
   #[case(
     "example/private_key_1_namer",
@@ -182,6 +342,13 @@ mod cli {
   #[case("/stats", None, stats_response_1(), "stats mana", "400")]
   #[case("/stats", None, stats_response_1(), "stats space", "500")]
   #[case("/stats", None, stats_response_1(), "stats tick", "700")]
+  #[case(
+    "/stats",
+    None,
+    stats_response_1(),
+    "stats state-root",
+    "0x00000000000000000000000000000000000000000000000000000000000000ff"
+  )]
   // not working because the lack of u128 deserialzation support
   // #[case("/peers/",None,peers_response_1(),"peers","0.0.0.1:42000\n0.0.0.1:42001")]
   // not working because the `with { ~ }` syntax
@@ -268,6 +435,7 @@ mod cli {
       mana: 400,
       space: 500,
       tick: 700,
+      state_root: U256::from(0xff).into(),
     }
   }
 }